@@ -1,38 +1,184 @@
+use alsa::mixer::{MilliBel, Selem, SelemChannelId, SelemId};
 use librespot_playback::mixer::{Mixer, MixerConfig};
-use log::error;
+use log::{error, info, warn};
 use std::error::Error;
 
+/// The range of the volume below the highest volume of elements with
+/// decibel information, unless it's configured or the element's is smaller.
+const DEFAULT_RANGE_DB: f64 = 60.0;
+/// The elements looked for, in order, when no mixer is configured.
+const USUAL_ELEMENTS: [&str; 5] = ["Master", "PCM", "Speaker", "Headphone", "Digital"];
+
 #[derive(Clone)]
 pub struct AlsaMixer {
     pub device: String,
     pub mixer: String,
+    pub index: u32,
     pub linear_scaling: bool,
+    /// The range the volume is mapped to, linear in decibels, below the
+    /// highest volume of the element.
+    pub range_db: Option<f64>,
+}
+
+/// The control device of the card of an ALSA PCM, e.g. `hw:CARD=Audio` for
+/// `plughw:CARD=Audio,DEV=0`, or the PCM itself if it names no card.
+pub(crate) fn control_device(pcm: &str) -> String {
+    let card = pcm.split_once(':').and_then(|(plugin, args)| {
+        if !matches!(
+            plugin,
+            "hw" | "plughw" | "sysdefault" | "front" | "iec958" | "dmix"
+        ) {
+            return None;
+        }
+        args.split(',')
+            .find_map(|arg| arg.strip_prefix("CARD="))
+            .map(|card| format!("CARD={}", card))
+            .or_else(|| {
+                let first = args.split(',').next()?;
+                (!first.is_empty() && !first.contains('=')).then(|| first.to_string())
+            })
+    });
+    match card {
+        Some(card) => format!("hw:{}", card),
+        None => pcm.to_string(),
+    }
+}
+
+/// The element to use among the playback elements of a device: the first
+/// one with a usual name, or else the first one.
+fn pick_element(elements: &[(String, u32)]) -> Option<(String, u32)> {
+    USUAL_ELEMENTS
+        .iter()
+        .find_map(|usual| elements.iter().find(|(name, _)| name == usual))
+        .or_else(|| elements.first())
+        .cloned()
+}
+
+fn detect_element(device: &str) -> Option<(String, u32)> {
+    let mixer = alsa::mixer::Mixer::new(device, false).ok()?;
+    let elements: Vec<_> = mixer
+        .iter()
+        .filter_map(Selem::new)
+        .filter(|selem| selem.has_playback_volume())
+        .filter_map(|selem| {
+            let id = selem.get_id();
+            Some((id.get_name().ok()?.to_string(), id.get_index()))
+        })
+        .collect();
+    pick_element(&elements)
+}
+
+/// The level in decibels for a volume, linear in decibels over the range.
+fn volume_to_db(volume: u16, max_db: f64, range_db: f64) -> f64 {
+    max_db - range_db * (1.0 - volume as f64 / 0xFFFF as f64)
+}
+
+fn db_to_volume(db: f64, max_db: f64, range_db: f64) -> u16 {
+    ((1.0 - (max_db - db) / range_db).clamp(0.0, 1.0) * 0xFFFF as f64).round() as u16
+}
+
+/// The raw value of the element for a volume, linear over its values.
+fn volume_to_raw(volume: u16, min: i64, max: i64) -> i64 {
+    min + ((max - min) as f64 * volume as f64 / 0xFFFF as f64).round() as i64
+}
+
+fn raw_to_volume(raw: i64, min: i64, max: i64) -> u16 {
+    if max <= min {
+        return 0xFFFF;
+    }
+    let fraction = (raw - min) as f64 / (max - min) as f64;
+    (fraction.clamp(0.0, 1.0) * 0xFFFF as f64).round() as u16
 }
 
 impl AlsaMixer {
-    fn set_volume_with_err(&self, volume: u16) -> Result<(), Box<dyn Error>> {
-        let mixer = alsa::mixer::Mixer::new(&self.device, false)?;
+    /// The mixer of the element, or, if none is configured, of the first
+    /// usual one of the device.
+    pub(crate) fn new(
+        device: String,
+        mixer: Option<String>,
+        index: Option<u32>,
+        linear_scaling: bool,
+        range_db: Option<f64>,
+    ) -> Self {
+        let (mixer, index) = match mixer {
+            Some(mixer) => (mixer, index.unwrap_or(0)),
+            None => match detect_element(&device) {
+                Some((mixer, detected)) => {
+                    info!("Using the alsa mixer element '{}' of {}", mixer, device);
+                    (mixer, index.unwrap_or(detected))
+                }
+                None => {
+                    warn!("Found no alsa mixer element of {}, using 'Master'", device);
+                    ("Master".to_string(), index.unwrap_or(0))
+                }
+            },
+        };
+        Self {
+            device,
+            mixer,
+            index,
+            linear_scaling,
+            range_db,
+        }
+    }
 
-        let selem_id = alsa::mixer::SelemId::new(&self.mixer, 0);
-        let elem = mixer.find_selem(&selem_id).ok_or_else(|| {
+    fn find_selem<'a>(&self, mixer: &'a alsa::mixer::Mixer) -> Result<Selem<'a>, String> {
+        let selem_id = SelemId::new(&self.mixer, self.index);
+        mixer.find_selem(&selem_id).ok_or_else(|| {
             format!(
-                "Couldn't find selem with name '{}'.",
-                selem_id.get_name().unwrap_or("unnamed")
+                "Couldn't find selem with name '{}' and index {}.",
+                self.mixer, self.index
             )
-        })?;
-
-        let (min, max) = elem.get_playback_volume_range();
+        })
+    }
 
-        let volume_steps = (max - min) as f64;
-        let normalised_volume = if self.linear_scaling {
-            ((f64::from(volume) / f64::from(u16::MAX)) * volume_steps) as i64 + min
-        } else {
-            (f64::from(volume).log(f64::from(u16::MAX)) * volume_steps).floor() as i64 + min
-        };
+    /// The highest level of the element and the range below it the volume
+    /// is mapped to, unless it's mapped linearly to the raw values.
+    fn db_range(&self, elem: &Selem) -> Option<(f64, f64)> {
+        if self.linear_scaling {
+            return None;
+        }
+        let (min, max) = elem.get_playback_db_range();
+        let (min, max) = (min.to_db() as f64, max.to_db() as f64);
+        // elements without decibel information report an empty range
+        if max <= min {
+            return None;
+        }
+        let range = self.range_db.unwrap_or(DEFAULT_RANGE_DB).min(max - min);
+        Some((max, range))
+    }
 
-        elem.set_playback_volume_all(normalised_volume)?;
+    fn set_volume_with_err(&self, volume: u16) -> Result<(), Box<dyn Error>> {
+        let mixer = alsa::mixer::Mixer::new(&self.device, false)?;
+        let elem = self.find_selem(&mixer)?;
+        let (min, max) = elem.get_playback_volume_range();
+        match self.db_range(&elem) {
+            Some((max_db, range_db)) if volume > 0 => {
+                let db = volume_to_db(volume, max_db, range_db);
+                elem.set_playback_db_all(MilliBel::from_db(db as f32), alsa::Round::Floor)?
+            }
+            // the lowest value, which mutes most elements
+            Some(_) => elem.set_playback_volume_all(min)?,
+            None => elem.set_playback_volume_all(volume_to_raw(volume, min, max))?,
+        }
         Ok(())
     }
+
+    fn volume_with_err(&self) -> Result<u16, Box<dyn Error>> {
+        let mixer = alsa::mixer::Mixer::new(&self.device, false)?;
+        let elem = self.find_selem(&mixer)?;
+        let channel = SelemChannelId::mono();
+        Ok(match self.db_range(&elem) {
+            Some((max_db, range_db)) => {
+                let db = elem.get_playback_vol_db(channel)?.to_db() as f64;
+                db_to_volume(db, max_db, range_db)
+            }
+            None => {
+                let (min, max) = elem.get_playback_volume_range();
+                raw_to_volume(elem.get_playback_volume(channel)?, min, max)
+            }
+        })
+    }
 }
 
 impl Mixer for AlsaMixer {
@@ -40,31 +186,19 @@ impl Mixer for AlsaMixer {
         AlsaMixer {
             device: "default".to_string(),
             mixer: "Master".to_string(),
+            index: 0,
             linear_scaling: false,
+            range_db: None,
         }
     }
 
     fn volume(&self) -> u16 {
-        let selem_id = alsa::mixer::SelemId::new(&self.mixer, 0);
-        let mixer = alsa::mixer::Mixer::new(&self.device, false).ok();
-        let vol = mixer
-            .as_ref()
-            .and_then(|mixer| mixer.find_selem(&selem_id))
-            .and_then(|elem| {
-                let (min, max) = elem.get_playback_volume_range();
-                elem.get_playback_volume(alsa::mixer::SelemChannelId::mono())
-                    .ok()
-                    .map(|volume| {
-                        let volume_steps = max - min + 1;
-                        ((volume - min) * (0xFFFF / volume_steps)) as u16
-                    })
-            });
-        match vol {
-            Some(vol) => vol,
-            None => {
+        match self.volume_with_err() {
+            Ok(volume) => volume,
+            Err(e) => {
                 error!(
-                    "Couldn't read volume from alsa device with name \"{}\".",
-                    self.device
+                    "Couldn't read volume from alsa device with name \"{}\": {}",
+                    self.device, e
                 );
                 0
             }
@@ -78,3 +212,34 @@ impl Mixer for AlsaMixer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mapping() {
+        assert_eq!(control_device("plughw:CARD=Audio,DEV=0"), "hw:CARD=Audio");
+        assert_eq!(control_device("hw:1,0"), "hw:1");
+        assert_eq!(control_device("sysdefault:DEV=0,CARD=DAC"), "hw:CARD=DAC");
+        assert_eq!(control_device("default"), "default");
+        assert_eq!(control_device("equal"), "equal");
+
+        let element = |name: &str| (name.to_string(), 0);
+        assert_eq!(
+            pick_element(&[element("Headphone"), element("PCM")]),
+            Some(element("PCM"))
+        );
+        assert_eq!(pick_element(&[element("DAC")]), Some(element("DAC")));
+        assert_eq!(pick_element(&[]), None);
+
+        // a USB DAC going down to -106 dB, over the default range
+        assert_eq!(volume_to_db(0xFFFF, 0.0, 60.0), 0.0);
+        assert_eq!(volume_to_db(0x8000, 0.0, 60.0).round(), -30.0);
+        assert_eq!(db_to_volume(-30.0, 0.0, 60.0), 0x8000);
+        assert_eq!(db_to_volume(-100.0, 0.0, 60.0), 0);
+        assert_eq!(volume_to_raw(0x8000, 0, 100), 50);
+        assert_eq!(raw_to_volume(50, 0, 100), 0x8000);
+        assert_eq!(raw_to_volume(0, 0, 0), 0xFFFF);
+    }
+}
@@ -0,0 +1,303 @@
+use crate::main_loop::SinkBuilder;
+use librespot_playback::{
+    audio_backend::{Sink, SinkError, SinkResult},
+    config::AudioFormat,
+    convert::Converter,
+    decoder::AudioPacket,
+};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+/// An audio backend that isn't built into librespot, like the SDK of an
+/// amplifier.
+///
+/// It's passed interleaved stereo samples at 44.1 kHz, between -1 and 1.
+pub trait AudioSink: Send {
+    /// Called when the playback starts, or resumes.
+    fn start(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called when the playback pauses or stops.
+    fn stop(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()>;
+}
+
+/// Opens the sink on the device, the default one if none is configured.
+pub type AudioSinkFactory =
+    Arc<dyn Fn(Option<String>) -> io::Result<Box<dyn AudioSink>> + Send + Sync>;
+
+static REGISTERED: Mutex<Vec<(String, AudioSinkFactory)>> = Mutex::new(Vec::new());
+
+/// Makes the backend available as `backend = "<name>"`, and in the
+/// `outputs`, replacing one registered with the same name before.
+///
+/// Backends have to be registered before spotifyd sets up the playback.
+pub fn register_backend(name: &str, factory: AudioSinkFactory) {
+    let mut registered = REGISTERED.lock().unwrap();
+    registered.retain(|(registered, _)| registered != name);
+    registered.push((name.to_string(), factory));
+}
+
+pub(crate) fn registered_backend(name: &str) -> Option<AudioSinkFactory> {
+    let registered = REGISTERED.lock().unwrap();
+    registered
+        .iter()
+        .find(|(registered, _)| registered == name)
+        .map(|(_, factory)| factory.clone())
+}
+
+/// A librespot sink writing to the registered one, which is opened when the
+/// playback starts, so that it can fail like the built in backends.
+struct RegisteredSink {
+    factory: AudioSinkFactory,
+    device: Option<String>,
+    sink: Option<Box<dyn AudioSink>>,
+}
+
+impl Sink for RegisteredSink {
+    fn start(&mut self) -> SinkResult<()> {
+        let sink = match self.sink {
+            Some(ref mut sink) => sink,
+            None => self.sink.insert(
+                (self.factory)(self.device.clone())
+                    .map_err(|e| SinkError::ConnectionRefused(e.to_string()))?,
+            ),
+        };
+        sink.start()
+            .map_err(|e| SinkError::StateChange(e.to_string()))
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        match self.sink {
+            Some(ref mut sink) => sink
+                .stop()
+                .map_err(|e| SinkError::StateChange(e.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        let Some(ref mut sink) = self.sink else {
+            return Err(SinkError::NotConnected(
+                "the sink wasn't started".to_string(),
+            ));
+        };
+        let samples = match packet {
+            AudioPacket::Samples(samples) => converter.f64_to_f32(&samples),
+            AudioPacket::Raw(_) => {
+                return Err(SinkError::OnWrite(
+                    "registered backends only play decoded audio".to_string(),
+                ))
+            }
+        };
+        sink.write(&samples)
+            .map_err(|e| SinkError::OnWrite(e.to_string()))
+    }
+}
+
+/// A sink builder opening the registered backend, which always gets the
+/// samples as floats.
+pub(crate) fn sink_builder(factory: AudioSinkFactory) -> SinkBuilder {
+    Arc::new(move |device: Option<String>, _: AudioFormat| {
+        Box::new(RegisteredSink {
+            factory: factory.clone(),
+            device,
+            sink: None,
+        }) as Box<dyn Sink>
+    })
+}
+
+/// The name a plugin is registered with, its file name without the `lib`
+/// prefix and the extension, e.g. `amp` for `libamp.so`.
+pub(crate) fn plugin_name(path: &std::path::Path) -> Option<String> {
+    let stem = path.file_stem()?.to_str()?;
+    Some(stem.strip_prefix("lib").unwrap_or(stem).to_string())
+}
+
+/// Loads the audio backends of the `backend_plugins`.
+///
+/// A plugin is a shared library with these functions, which return 0 on
+/// success:
+///
+/// ```c
+/// void *spotifyd_sink_open(const char *device); // NULL on failure, device may be NULL
+/// int spotifyd_sink_start(void *sink);
+/// int spotifyd_sink_write(void *sink, const float *samples, size_t len);
+/// int spotifyd_sink_stop(void *sink);
+/// void spotifyd_sink_close(void *sink);
+/// ```
+#[cfg(feature = "backend_plugins")]
+pub(crate) mod plugin {
+    use super::{register_backend, AudioSink};
+    use libloading::Library;
+    use std::{
+        ffi::CString,
+        io,
+        os::raw::{c_char, c_int, c_void},
+        path::Path,
+        sync::Arc,
+    };
+
+    type Handle = *mut c_void;
+
+    struct Functions {
+        open: unsafe extern "C" fn(*const c_char) -> Handle,
+        start: unsafe extern "C" fn(Handle) -> c_int,
+        write: unsafe extern "C" fn(Handle, *const f32, usize) -> c_int,
+        stop: unsafe extern "C" fn(Handle) -> c_int,
+        close: unsafe extern "C" fn(Handle),
+        /// Unloaded last, once nothing points into it anymore.
+        _library: Library,
+    }
+
+    struct PluginSink {
+        functions: Arc<Functions>,
+        handle: Handle,
+    }
+
+    // the plugin is only called from the thread of the player at a time
+    unsafe impl Send for PluginSink {}
+
+    fn check(name: &str, result: c_int) -> io::Result<()> {
+        match result {
+            0 => Ok(()),
+            code => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} failed with {}", name, code),
+            )),
+        }
+    }
+
+    impl AudioSink for PluginSink {
+        fn start(&mut self) -> io::Result<()> {
+            check("spotifyd_sink_start", unsafe {
+                (self.functions.start)(self.handle)
+            })
+        }
+
+        fn stop(&mut self) -> io::Result<()> {
+            check("spotifyd_sink_stop", unsafe {
+                (self.functions.stop)(self.handle)
+            })
+        }
+
+        fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+            check("spotifyd_sink_write", unsafe {
+                (self.functions.write)(self.handle, samples.as_ptr(), samples.len())
+            })
+        }
+    }
+
+    impl Drop for PluginSink {
+        fn drop(&mut self) {
+            unsafe { (self.functions.close)(self.handle) }
+        }
+    }
+
+    /// Loads the plugin and registers its backend, returning its name.
+    pub(crate) fn load(path: &Path) -> Result<String, String> {
+        let fail = |e: String| format!("failed to load {}: {}", path.display(), e);
+        let name = super::plugin_name(path).ok_or_else(|| fail("it has no name".to_string()))?;
+        let functions = unsafe {
+            let library = Library::new(path).map_err(|e| fail(e.to_string()))?;
+            let get = |symbol: &str| fail(format!("it has no {}", symbol));
+            let open = *library
+                .get(b"spotifyd_sink_open\0")
+                .map_err(|_| get("spotifyd_sink_open"))?;
+            let start = *library
+                .get(b"spotifyd_sink_start\0")
+                .map_err(|_| get("spotifyd_sink_start"))?;
+            let write = *library
+                .get(b"spotifyd_sink_write\0")
+                .map_err(|_| get("spotifyd_sink_write"))?;
+            let stop = *library
+                .get(b"spotifyd_sink_stop\0")
+                .map_err(|_| get("spotifyd_sink_stop"))?;
+            let close = *library
+                .get(b"spotifyd_sink_close\0")
+                .map_err(|_| get("spotifyd_sink_close"))?;
+            Functions {
+                open,
+                start,
+                write,
+                stop,
+                close,
+                _library: library,
+            }
+        };
+        let functions = Arc::new(functions);
+        register_backend(
+            &name,
+            Arc::new(move |device: Option<String>| {
+                let device = device
+                    .map(CString::new)
+                    .transpose()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let device_ptr = device.as_ref().map_or(std::ptr::null(), |d| d.as_ptr());
+                let handle = unsafe { (functions.open)(device_ptr) };
+                if handle.is_null() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "spotifyd_sink_open failed",
+                    ));
+                }
+                Ok(Box::new(PluginSink {
+                    functions: functions.clone(),
+                    handle,
+                }) as Box<dyn AudioSink>)
+            }),
+        );
+        Ok(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    struct Recorder(Arc<Mutex<Vec<f32>>>);
+
+    impl AudioSink for Recorder {
+        fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+            self.0.lock().unwrap().extend_from_slice(samples);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_registered_backend() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let recorder = written.clone();
+        register_backend(
+            "recorder",
+            Arc::new(move |_: Option<String>| {
+                Ok(Box::new(Recorder(recorder.clone())) as Box<dyn AudioSink>)
+            }),
+        );
+        assert!(registered_backend("unknown").is_none());
+
+        let builder = sink_builder(registered_backend("recorder").unwrap());
+        let mut sink = builder(None, AudioFormat::S16);
+        let mut converter = Converter::new(None);
+        assert!(sink
+            .write(AudioPacket::Samples(vec![0.5]), &mut converter)
+            .is_err());
+        sink.start().unwrap();
+        sink.write(AudioPacket::Samples(vec![0.5, -0.25]), &mut converter)
+            .unwrap();
+        assert_eq!(*written.lock().unwrap(), [0.5, -0.25]);
+
+        assert_eq!(
+            plugin_name(Path::new("/usr/lib/libamp.so")).as_deref(),
+            Some("amp")
+        );
+        assert_eq!(plugin_name(Path::new("amp.dylib")).as_deref(), Some("amp"));
+    }
+}
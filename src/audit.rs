@@ -0,0 +1,319 @@
+use crate::{
+    control::{ControlCommand, PlaybackControl},
+    events::SpotifydEvent,
+    state::PlaybackState,
+};
+use chrono::{DateTime, Local};
+use librespot_core::Error;
+use log::warn;
+use serde::{Serialize, Serializer};
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How many entries are kept in memory.
+const RECENT_ENTRIES: usize = 100;
+
+/// Events this soon after a command of a local interface are attributed to
+/// that command instead of a Spotify Connect client.
+const LOCAL_COMMAND_WINDOW: Duration = Duration::from_secs(1);
+
+pub type SharedAuditLog = Arc<Mutex<AuditLog>>;
+
+/// The interface a command was sent through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum CommandSource {
+    /// A [`ControlHandle`](crate::control::ControlHandle) of the library API.
+    #[serde(rename = "api")]
+    Api,
+    #[serde(rename = "dbus")]
+    DBus,
+    /// A Spotify Connect client. Its commands are applied by librespot, so
+    /// they are inferred from the events.
+    #[serde(rename = "spotify_connect")]
+    SpotifyConnect,
+    /// spotifyd itself, e.g. when skipping an unavailable track.
+    #[serde(rename = "spotifyd")]
+    Spotifyd,
+    /// The control socket.
+    #[serde(rename = "socket")]
+    Socket,
+    /// The HTTP API.
+    #[serde(rename = "http")]
+    Http,
+}
+
+/// The client that sent a command through a local interface, as far as the
+/// interface knows it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommandClient {
+    /// e.g. the name of the token of the HTTP API, or the bus name of the
+    /// D-Bus sender.
+    pub name: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+/// A command that changed the playback, as recorded in the audit log.
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditEntry {
+    #[serde(serialize_with = "serialize_time")]
+    pub time: DateTime<Local>,
+    pub source: CommandSource,
+    /// The name of the client that sent the command, if known.
+    pub client: Option<String>,
+    /// The address of the client, e.g. of the one that connected the session
+    /// via the discovery or sent the HTTP request, if known.
+    pub client_ip: Option<IpAddr>,
+    pub command: String,
+    /// The error the command failed with.
+    pub error: Option<String>,
+}
+
+fn serialize_time<S: Serializer>(time: &DateTime<Local>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339())
+}
+
+impl AuditEntry {
+    pub(crate) fn new<T, E: Display>(
+        source: CommandSource,
+        client: Option<String>,
+        command: impl Into<String>,
+        result: &Result<T, E>,
+    ) -> Self {
+        Self {
+            time: Local::now(),
+            source,
+            client,
+            client_ip: None,
+            command: command.into(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        }
+    }
+
+    /// The entry of a command sent by the client through a local interface.
+    pub(crate) fn sent_by<T, E: Display>(
+        source: CommandSource,
+        client: CommandClient,
+        command: impl Into<String>,
+        result: &Result<T, E>,
+    ) -> Self {
+        let mut entry = Self::new(source, client.name, command, result);
+        entry.client_ip = client.ip;
+        entry
+    }
+}
+
+/// The record of the commands that changed the playback, so that operators of
+/// shared spaces can see who did what.
+///
+/// The recent entries are kept in memory. If a path is configured, every
+/// entry is also appended to that file as a JSON line.
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    file: Option<File>,
+    recent: VecDeque<AuditEntry>,
+    last_local_command: Option<Instant>,
+}
+
+impl AuditLog {
+    pub(crate) fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            ..Default::default()
+        }
+    }
+
+    /// The most recent entries, oldest first.
+    pub fn recent(&self) -> impl Iterator<Item = &AuditEntry> {
+        self.recent.iter()
+    }
+
+    pub(crate) fn record(&mut self, entry: AuditEntry) {
+        if entry.source != CommandSource::SpotifyConnect {
+            self.last_local_command = Some(Instant::now());
+        }
+        if let Some(ref path) = self.path {
+            if self.file.is_none() {
+                match OpenOptions::new().append(true).create(true).open(path) {
+                    Ok(file) => self.file = Some(file),
+                    Err(e) => warn!("Failed to open {}: {}", path.display(), e),
+                }
+            }
+            if let Some(ref mut file) = self.file {
+                let line = serde_json::to_string(&entry).unwrap();
+                if let Err(e) = writeln!(file, "{}", line) {
+                    warn!("Failed to write to {}: {}", path.display(), e);
+                    self.file = None;
+                }
+            }
+        }
+        if self.recent.len() == RECENT_ENTRIES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(entry);
+    }
+
+    /// Records a command inferred from the events, unless a local interface
+    /// has just sent a command that explains them.
+    pub(crate) fn record_connect(
+        &mut self,
+        client: Option<String>,
+        client_ip: Option<IpAddr>,
+        command: &str,
+    ) {
+        if let Some(last) = self.last_local_command {
+            if last.elapsed() < LOCAL_COMMAND_WINDOW {
+                return;
+            }
+        }
+        let mut entry = AuditEntry::new(
+            CommandSource::SpotifyConnect,
+            client,
+            command,
+            &Ok::<(), Error>(()),
+        );
+        entry.client_ip = client_ip;
+        self.record(entry);
+    }
+
+    /// Records that the account connected a session, so that it's known who
+    /// was connected when.
+    pub(crate) fn record_session(&mut self, user_name: &str, client_ip: Option<IpAddr>) {
+        let mut entry = AuditEntry::new(
+            CommandSource::SpotifyConnect,
+            None,
+            format!("Connect({:?})", user_name),
+            &Ok::<(), Error>(()),
+        );
+        entry.client_ip = client_ip;
+        self.record(entry);
+    }
+}
+
+/// Applies the command and records it with its result in the audit log.
+pub(crate) fn apply_audited<C: PlaybackControl + ?Sized>(
+    audit_log: &SharedAuditLog,
+    source: CommandSource,
+    client: CommandClient,
+    command: ControlCommand,
+    control: &C,
+    state: &PlaybackState,
+) -> Result<(), Error> {
+    let result = command.apply(control, state);
+    audit_log.lock().unwrap().record(AuditEntry::sent_by(
+        source,
+        client,
+        format!("{:?}", command),
+        &result,
+    ));
+    result
+}
+
+/// Infers the commands of the Spotify Connect clients from the events.
+#[derive(Debug, Default)]
+pub(crate) struct ConnectCommands {
+    track_id: Option<String>,
+    track_ended: bool,
+    paused: bool,
+}
+
+impl ConnectCommands {
+    /// Returns the command that presumably caused the event.
+    pub(crate) fn observe(&mut self, event: &SpotifydEvent) -> Option<&'static str> {
+        match event {
+            SpotifydEvent::EndOfTrack { .. } => {
+                self.track_ended = true;
+                None
+            }
+            SpotifydEvent::TrackChanged(info) => {
+                let previous = self.track_id.replace(info.track_id.clone());
+                // the first track is loaded, or the next one after the end
+                let changed = matches!(previous, Some(id) if id != info.track_id);
+                (changed && !self.track_ended).then_some("ChangeTrack")
+            }
+            SpotifydEvent::Playing { .. } => {
+                self.track_ended = false;
+                std::mem::replace(&mut self.paused, false).then_some("Play")
+            }
+            SpotifydEvent::Paused { .. } => {
+                let was_paused = std::mem::replace(&mut self.paused, true);
+                (!was_paused && !self.track_ended).then_some("Pause")
+            }
+            SpotifydEvent::Seeked { .. } => Some("Seek"),
+            SpotifydEvent::VolumeChanged { .. } => Some("SetVolume"),
+            SpotifydEvent::ShuffleChanged { .. } => Some("Shuffle"),
+            SpotifydEvent::RepeatChanged { .. } => Some("Repeat"),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::TrackInfo;
+
+    #[test]
+    fn test_infers_connect_commands() {
+        let track = |id: &str| {
+            SpotifydEvent::TrackChanged(TrackInfo {
+                track_id: id.to_string(),
+                ..Default::default()
+            })
+        };
+        let playing = || SpotifydEvent::Playing {
+            play_request_id: 1,
+            track_id: "a".to_string(),
+            position_ms: 0,
+        };
+        let paused = || SpotifydEvent::Paused {
+            play_request_id: 1,
+            track_id: "a".to_string(),
+            position_ms: 0,
+        };
+        let mut commands = ConnectCommands::default();
+
+        assert_eq!(commands.observe(&track("a")), None);
+        assert_eq!(commands.observe(&playing()), None);
+        assert_eq!(commands.observe(&paused()), Some("Pause"));
+        assert_eq!(commands.observe(&playing()), Some("Play"));
+        assert_eq!(commands.observe(&track("b")), Some("ChangeTrack"));
+
+        // the track ended on its own
+        commands.observe(&SpotifydEvent::EndOfTrack {
+            play_request_id: 1,
+            track_id: "b".to_string(),
+        });
+        assert_eq!(commands.observe(&track("c")), None);
+        assert_eq!(commands.observe(&paused()), None);
+    }
+
+    #[test]
+    fn test_local_commands_are_not_attributed_to_clients() {
+        let mut log = AuditLog::new(None);
+        let phone_ip = "192.168.1.20".parse().ok();
+        log.record_connect(Some("Phone".to_string()), phone_ip, "Pause");
+        log.record(AuditEntry::new(
+            CommandSource::DBus,
+            None,
+            "Next",
+            &Err::<(), _>("no session"),
+        ));
+        log.record_connect(Some("Phone".to_string()), phone_ip, "ChangeTrack");
+
+        let recent: Vec<_> = log.recent().collect();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].client.as_deref(), Some("Phone"));
+        assert_eq!(recent[0].client_ip, phone_ip);
+        assert_eq!(recent[1].source, CommandSource::DBus);
+        assert_eq!(recent[1].error.as_deref(), Some("no session"));
+    }
+}
@@ -0,0 +1,147 @@
+use log::{debug, info, warn};
+use std::{io, net::IpAddr, net::SocketAddr};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpSocket, TcpStream},
+};
+use url::Url;
+
+/// Where the outgoing connections to Spotify originate from.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutgoingBind {
+    /// The source address of the connections.
+    pub address: Option<IpAddr>,
+    /// The network interface the connections are bound to with
+    /// `SO_BINDTODEVICE`, which requires `CAP_NET_RAW`.
+    pub interface: Option<String>,
+}
+
+/// The target of an HTTP `CONNECT` request, e.g. `ap.spotify.com:443`.
+fn connect_target(request_line: &str) -> Option<&str> {
+    let mut parts = request_line.split_whitespace();
+    if parts.next()? != "CONNECT" {
+        return None;
+    }
+    let target = parts.next()?;
+    parts.next()?.starts_with("HTTP/").then_some(target)
+}
+
+impl OutgoingBind {
+    async fn connect(&self, target: &str) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address found");
+        for addr in tokio::net::lookup_host(target).await? {
+            // the source address decides the family of the destination
+            if self
+                .address
+                .map_or(false, |a| a.is_ipv4() != addr.is_ipv4())
+            {
+                continue;
+            }
+            match self.connect_to(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn connect_to(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        #[cfg(target_os = "linux")]
+        if let Some(ref interface) = self.interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        if let Some(address) = self.address {
+            socket.bind(SocketAddr::new(address, 0))?;
+        }
+        socket.connect(addr).await
+    }
+
+    /// Handles the `CONNECT` request of a client of the proxy.
+    async fn serve(&self, mut client: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(&mut client);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await?;
+        // skip the headers
+        let mut header = String::new();
+        loop {
+            header.clear();
+            if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+                break;
+            }
+        }
+        drop(reader);
+
+        let Some(target) = connect_target(&request_line) else {
+            client
+                .write_all(b"HTTP/1.1 405 Method Not Allowed\r\n\r\n")
+                .await?;
+            return Ok(());
+        };
+        let mut upstream = match self.connect(target).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                warn!("Failed to connect to {}: {}", target, e);
+                client
+                    .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")
+                    .await?;
+                return Ok(());
+            }
+        };
+        debug!("Connected to {} via the bound proxy", target);
+        client
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        Ok(())
+    }
+}
+
+/// Starts a local HTTP proxy whose connections originate from the bound
+/// address or interface, and returns its URL.
+///
+/// librespot can't bind its connections itself, but it connects to the access
+/// point and all of its HTTP requests through its proxy, so it's given this
+/// one.
+pub(crate) async fn start(bind: OutgoingBind) -> io::Result<Url> {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let url = Url::parse(&format!("http://{}", listener.local_addr()?)).unwrap();
+    info!("Binding the connections to Spotify with {:?}", bind);
+    tokio::spawn(async move {
+        loop {
+            let client = match listener.accept().await {
+                Ok((client, _)) => client,
+                Err(e) => {
+                    warn!("Failed to accept a connection of the bound proxy: {}", e);
+                    continue;
+                }
+            };
+            let bind = bind.clone();
+            tokio::spawn(async move {
+                if let Err(e) = bind.serve(client).await {
+                    debug!("A connection of the bound proxy failed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_target() {
+        assert_eq!(
+            connect_target("CONNECT ap-gew4.spotify.com:4070 HTTP/1.1\r\n"),
+            Some("ap-gew4.spotify.com:4070")
+        );
+        assert_eq!(connect_target("GET / HTTP/1.1\r\n"), None);
+        assert_eq!(connect_target("CONNECT\r\n"), None);
+    }
+}
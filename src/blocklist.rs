@@ -0,0 +1,127 @@
+use crate::events::TrackInfo;
+use log::{info, warn};
+use std::{
+    collections::HashSet,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// The tracks that are never played, listed by the URIs of the tracks or
+/// their artists in a file, one per line. Lines starting with `#` are
+/// comments.
+///
+/// The file is read again whenever it has changed, so entries added by
+/// `spotifyd ctl block-current` or by hand apply right away.
+#[derive(Debug)]
+pub(crate) struct Blocklist {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    uris: HashSet<String>,
+}
+
+fn parse(content: &str) -> HashSet<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+impl Blocklist {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            modified: None,
+            uris: HashSet::new(),
+        }
+    }
+
+    fn reload_if_changed(&mut self) {
+        let modified = match fs::metadata(&self.path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            // the blocklist hasn't been created yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.uris.clear();
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to read {}: {}", self.path.display(), e);
+                return;
+            }
+        };
+        if self.modified == Some(modified) {
+            return;
+        }
+        match fs::read_to_string(&self.path) {
+            Ok(content) => {
+                self.uris = parse(&content);
+                self.modified = Some(modified);
+                info!(
+                    "Loaded {} entries from {}",
+                    self.uris.len(),
+                    self.path.display()
+                );
+            }
+            Err(e) => warn!("Failed to read {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn find(&self, track: &TrackInfo) -> Option<&str> {
+        std::iter::once(&track.uri)
+            .chain(&track.artist_uris)
+            .find(|uri| self.uris.contains(*uri))
+            .map(String::as_str)
+    }
+
+    /// Returns the entry that blocks the track, if any.
+    pub(crate) fn blocks(&mut self, track: &TrackInfo) -> Option<String> {
+        self.reload_if_changed();
+        self.find(track).map(str::to_string)
+    }
+}
+
+/// Adds the URI to the blocklist at the path, creating it if needed.
+pub(crate) fn append(path: &Path, uri: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    writeln!(file, "{}", uri)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_tracks_and_artists() {
+        let mut blocklist = Blocklist::new(PathBuf::new());
+        blocklist.uris = parse(
+            "# office speakers\n\
+             spotify:track:4uLU6hMCjMI75M1A2tKUQC\n\
+             \n  spotify:artist:0OdUWJ0sBjDrqHygGUXeCF  \n",
+        );
+        assert_eq!(blocklist.uris.len(), 2);
+
+        let track = |uri: &str, artist_uris: &[&str]| TrackInfo {
+            uri: uri.to_string(),
+            artist_uris: artist_uris.iter().map(|uri| uri.to_string()).collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            blocklist.find(&track("spotify:track:4uLU6hMCjMI75M1A2tKUQC", &[])),
+            Some("spotify:track:4uLU6hMCjMI75M1A2tKUQC")
+        );
+        assert_eq!(
+            blocklist.find(&track(
+                "spotify:track:other",
+                &["spotify:artist:x", "spotify:artist:0OdUWJ0sBjDrqHygGUXeCF"]
+            )),
+            Some("spotify:artist:0OdUWJ0sBjDrqHygGUXeCF")
+        );
+        assert_eq!(
+            blocklist.find(&track("spotify:track:other", &["spotify:artist:x"])),
+            None
+        );
+    }
+}
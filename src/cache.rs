@@ -0,0 +1,333 @@
+use crate::{
+    cache_layout::{cached_username, is_audio_dir, CacheLayout},
+    config::{CacheAction, SpotifydConfig},
+    encryption::EncryptedCredentials,
+};
+use color_eyre::eyre::{self, WrapErr};
+use librespot_core::{session::Session, spotify_id::SpotifyId};
+use librespot_metadata::{Metadata, Track};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// How many of the tracks played since the start were in the audio cache.
+#[derive(Debug, Default)]
+pub(crate) struct CacheHits {
+    hits: AtomicU64,
+    lookups: AtomicU64,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct HitRate {
+    hits: u64,
+    lookups: u64,
+}
+
+impl CacheHits {
+    fn record(&self, hit: bool) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn rate(&self) -> HitRate {
+        HitRate {
+            hits: self.hits.load(Ordering::Relaxed),
+            lookups: self.lookups.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Looks up whether the track that is being loaded is in the audio cache.
+///
+/// librespot doesn't tell, so a track counts as a hit if any of its files is
+/// cached. Those that are being downloaded are only written to the cache once
+/// complete, after the lookup.
+pub(crate) async fn record_lookup(session: Session, track_id: String, hits: Arc<CacheHits>) {
+    let Some(cache) = session.cache() else {
+        return;
+    };
+    let Ok(id) = SpotifyId::from_base62(&track_id) else {
+        return;
+    };
+    // episodes aren't counted
+    let track = match Track::get(&session, &id).await {
+        Ok(track) => track,
+        Err(e) => {
+            debug!("Failed to look up whether {} is cached: {}", track_id, e);
+            return;
+        }
+    };
+    let mut paths = track
+        .files
+        .values()
+        .filter_map(|&file| cache.file_path(file))
+        .peekable();
+    // there are no paths without the audio cache
+    if paths.peek().is_none() {
+        return;
+    }
+    hits.record(paths.any(|path| path.exists()));
+}
+
+/// The directories of the caches, the shared one and those of the users.
+fn cache_dirs(layout: &CacheLayout) -> Vec<PathBuf> {
+    let mut dirs = vec![layout.root.clone()];
+    if let Ok(entries) = fs::read_dir(layout.root.join("users")) {
+        dirs.extend(
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter(|path| path.is_dir()),
+        );
+    }
+    dirs
+}
+
+/// The audio files in the cache directory.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct AudioFiles {
+    count: u64,
+    bytes: u64,
+}
+
+fn audio_dirs(dir: &Path) -> impl Iterator<Item = PathBuf> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|entry| is_audio_dir(&entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+}
+
+fn audio_files(dir: &Path) -> AudioFiles {
+    let mut files = AudioFiles::default();
+    for audio_dir in audio_dirs(dir) {
+        let entries = fs::read_dir(audio_dir).into_iter().flatten();
+        for metadata in entries.filter_map(|entry| entry.ok()?.metadata().ok()) {
+            if metadata.is_file() {
+                files.count += 1;
+                files.bytes += metadata.len();
+            }
+        }
+    }
+    files
+}
+
+/// Removes the file, returning whether it existed.
+fn remove_file(path: &Path) -> io::Result<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// How many tracks were cached, as reported by the running instance through
+/// its control socket.
+#[cfg(unix)]
+async fn running_hits(config: &SpotifydConfig) -> eyre::Result<HitRate> {
+    use crate::control_socket::{request, SocketCommand};
+
+    let reply = request(config, &SocketCommand::CacheHits).await?;
+    Ok(serde_json::from_value(reply["cache_hits"].clone())?)
+}
+
+#[cfg(not(unix))]
+async fn running_hits(_: &SpotifydConfig) -> eyre::Result<HitRate> {
+    eyre::bail!("the control socket requires a unix system")
+}
+
+fn print_stats(layout: &CacheLayout) {
+    for dir in cache_dirs(layout) {
+        let files = audio_files(&dir);
+        let credentials = dir.join("credentials.json").exists();
+        println!(
+            "{}: {} audio files, {:.1} MB{}",
+            dir.display(),
+            files.count,
+            files.bytes as f64 / 1_000_000.0,
+            if credentials {
+                ", credentials cached"
+            } else {
+                ""
+            }
+        );
+    }
+    if layout.root.join("credentials.enc").exists() {
+        println!("Encrypted credentials cached");
+    }
+}
+
+/// Prints the size of the caches, or clears their audio files or the
+/// credentials, of all users or of one.
+pub async fn run(config: &SpotifydConfig, action: &CacheAction) -> eyre::Result<()> {
+    let Some(ref layout) = config.cache_layout else {
+        eyre::bail!("no cache_path is configured");
+    };
+    match action {
+        CacheAction::Stats => {
+            print_stats(layout);
+            match running_hits(config).await {
+                Ok(HitRate { hits, lookups }) if lookups > 0 => println!(
+                    "Hit rate since the start: {:.0}% ({} of {} tracks)",
+                    hits as f64 / lookups as f64 * 100.0,
+                    hits,
+                    lookups
+                ),
+                Ok(_) => println!("No tracks have been played since the start"),
+                Err(e) => println!("Hit rate unknown, spotifyd isn't reachable: {}", e),
+            }
+        }
+        CacheAction::ClearAudio => {
+            for dir in cache_dirs(layout) {
+                let files = audio_files(&dir);
+                for audio_dir in audio_dirs(&dir) {
+                    fs::remove_dir_all(&audio_dir)
+                        .wrap_err_with(|| format!("could not remove {}", audio_dir.display()))?;
+                }
+                if files.count > 0 {
+                    println!("Removed {} audio files from {}", files.count, dir.display());
+                }
+            }
+        }
+        CacheAction::ClearCredentials => {
+            let mut paths: Vec<_> = cache_dirs(layout)
+                .into_iter()
+                .map(|dir| dir.join("credentials.json"))
+                .collect();
+            paths.push(layout.root.join("credentials.enc"));
+            for path in paths {
+                if remove_file(&path)
+                    .wrap_err_with(|| format!("could not remove {}", path.display()))?
+                {
+                    println!("Removed {}", path.display());
+                }
+            }
+        }
+        CacheAction::ForgetUser { username } => {
+            let forgotten = forget_user(
+                Some(layout),
+                config.encrypted_credentials.as_ref(),
+                config.use_keyring,
+                username,
+            )?;
+            for removed in &forgotten.removed {
+                println!("Removed {}", removed);
+            }
+            if forgotten.is_empty() {
+                println!("No credentials of {} are cached", username);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What was removed to forget a user.
+#[derive(Debug, Default)]
+pub(crate) struct Forgotten {
+    /// The files and keyring entries that held the credentials.
+    pub(crate) removed: Vec<String>,
+    /// Whether the user was the one reattached on start.
+    pub(crate) was_last: bool,
+}
+
+impl Forgotten {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.removed.is_empty() && !self.was_last
+    }
+}
+
+/// Removes the stored credentials of the user, who has to select the device
+/// in the Spotify app again, from the cache or the keyring.
+pub(crate) fn forget_user(
+    layout: Option<&CacheLayout>,
+    encrypted_credentials: Option<&EncryptedCredentials>,
+    use_keyring: bool,
+    username: &str,
+) -> eyre::Result<Forgotten> {
+    let mut forgotten = Forgotten::default();
+    if let Some(layout) = layout {
+        let mut paths = Vec::new();
+        let cached = layout.user_dir(username).join("credentials.json");
+        if cached_username(&cached).as_deref() == Some(username) {
+            paths.push(cached);
+        }
+        // there's only one file, of the user who connected last
+        let encrypted = encrypted_credentials.and_then(|e| e.load());
+        if encrypted
+            .and_then(|credentials| credentials.username)
+            .as_deref()
+            == Some(username)
+        {
+            paths.push(layout.root.join("credentials.enc"));
+        }
+        for path in paths {
+            remove_file(&path).wrap_err_with(|| format!("could not remove {}", path.display()))?;
+            forgotten.removed.push(path.display().to_string());
+        }
+        forgotten.was_last = layout
+            .forget_last_user(username)
+            .wrap_err("could not forget the user who connected last")?;
+    }
+    // the keyring holds them instead of the cache with use_keyring
+    #[cfg(feature = "dbus_keyring")]
+    if use_keyring
+        && crate::secrets::delete_credentials(username)
+            .wrap_err("could not remove the credentials from the keyring")?
+    {
+        forgotten
+            .removed
+            .push(format!("the credentials of {} from the keyring", username));
+    }
+    #[cfg(not(feature = "dbus_keyring"))]
+    let _ = use_keyring;
+    Ok(forgotten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audio_files() {
+        let root = std::env::temp_dir().join(format!("spotifyd-cache-{}", std::process::id()));
+        fs::create_dir_all(root.join("a3")).unwrap();
+        fs::create_dir_all(root.join("users/alice/0f")).unwrap();
+        fs::write(root.join("a3/1b2c"), [0; 100]).unwrap();
+        fs::write(root.join("users/alice/0f/4d5e"), [0; 50]).unwrap();
+        fs::write(root.join("play_history"), "").unwrap();
+        let layout = CacheLayout {
+            root: root.clone(),
+            credentials: true,
+            audio: true,
+            size_limit: None,
+            per_user: true,
+        };
+
+        assert_eq!(cache_dirs(&layout).len(), 2);
+        assert_eq!(
+            audio_files(&root),
+            AudioFiles {
+                count: 1,
+                bytes: 100
+            }
+        );
+        assert_eq!(audio_files(&root.join("users/alice")).bytes, 50);
+
+        let hits = CacheHits::default();
+        hits.record(true);
+        hits.record(false);
+        let rate = serde_json::to_value(hits.rate()).unwrap();
+        assert_eq!(rate, serde_json::json!({"hits": 1, "lookups": 2}));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+use librespot_core::{authentication::Credentials, cache::Cache};
+use log::{info, warn};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Where the caches are stored in the `cache_path`.
+///
+/// With a cache per user, every account gets its own directory under
+/// `users/`, so that switching between the accounts connecting via Spotify
+/// Connect doesn't mix their credentials and files. The cache of the shared
+/// layout is moved to the directory of the user it belongs to.
+#[derive(Clone, Debug)]
+pub struct CacheLayout {
+    pub(crate) root: PathBuf,
+    pub(crate) credentials: bool,
+    pub(crate) audio: bool,
+    pub(crate) size_limit: Option<u64>,
+    pub(crate) per_user: bool,
+}
+
+/// The file naming the user who connected last via the discovery.
+const LAST_USER: &str = "last_user";
+
+/// Whether the directory holds audio files, which are stored in directories
+/// named after the first two hex digits of their id.
+pub(crate) fn is_audio_dir(name: &str) -> bool {
+    name.len() == 2 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Whether the file or directory of the shared layout belongs to the cache.
+fn is_shared_entry(name: &str) -> bool {
+    name == "credentials.json" || name == "volume" || is_audio_dir(name)
+}
+
+/// The name of the user's directory, keeping it within `users/`.
+fn user_dir_name(username: &str) -> String {
+    username
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect::<String>()
+        .trim_start_matches('.')
+        .to_string()
+}
+
+/// The user whose credentials are cached in the file.
+pub(crate) fn cached_username(path: &Path) -> Option<String> {
+    let data = fs::read(path).ok()?;
+    let credentials: serde_json::Value = serde_json::from_slice(&data).ok()?;
+    Some(credentials.get("username")?.as_str()?.to_string())
+}
+
+impl CacheLayout {
+    pub(crate) fn per_user(&self) -> bool {
+        self.per_user
+    }
+
+    /// The cache of the user, or the shared cache. With a cache per user,
+    /// there is no cache until the user is known.
+    pub(crate) fn cache(&self, username: Option<&str>) -> Option<Cache> {
+        let dir = match (self.per_user, username) {
+            (false, _) => self.root.clone(),
+            (true, Some(username)) => {
+                let dir = self.user_dir(username);
+                if let Err(e) = self.migrate(username, &dir) {
+                    warn!("Failed to move the cache to {}: {}", dir.display(), e);
+                }
+                dir
+            }
+            (true, None) => return None,
+        };
+        Cache::new(
+            self.credentials.then_some(&dir),
+            Some(&dir),
+            self.audio.then_some(&dir),
+            self.size_limit,
+        )
+        .map_err(|e| warn!("Cache couldn't be initialized: {e}"))
+        .ok()
+    }
+
+    /// The directory of the user's cache.
+    pub(crate) fn user_dir(&self, username: &str) -> PathBuf {
+        match self.per_user {
+            true => self.root.join("users").join(user_dir_name(username)),
+            false => self.root.clone(),
+        }
+    }
+
+    /// Remembers the user who connected via the discovery, to reattach them
+    /// after a restart.
+    pub(crate) fn remember_user(&self, username: &str) {
+        if let Err(e) = fs::write(self.root.join(LAST_USER), username) {
+            warn!("Failed to remember the user who connected: {}", e);
+        }
+    }
+
+    /// The user who connected last via the discovery, if they're remembered.
+    pub(crate) fn last_user(&self) -> Option<String> {
+        fs::read_to_string(self.root.join(LAST_USER)).ok()
+    }
+
+    /// The cached credentials of the user who connected last via the
+    /// discovery.
+    pub(crate) fn last_user_credentials(&self) -> Option<Credentials> {
+        let username = self.last_user()?;
+        let credentials = self.cache(Some(&username))?.credentials()?;
+        (credentials.username.as_deref() == Some(username.as_str())).then_some(credentials)
+    }
+
+    /// Forgets that the user connected last, if they did. Returns whether
+    /// they did.
+    pub(crate) fn forget_last_user(&self, username: &str) -> io::Result<bool> {
+        let path = self.root.join(LAST_USER);
+        match fs::read_to_string(&path) {
+            Ok(last) if last == username => fs::remove_file(path).map(|()| true),
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Moves the shared cache to the user's directory, if it belongs to them.
+    fn migrate(&self, username: &str, dir: &Path) -> io::Result<()> {
+        if dir.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(dir)?;
+
+        let owner = cached_username(&self.root.join("credentials.json"));
+        if owner.as_deref() != Some(username) {
+            return Ok(());
+        }
+        info!("Moving the cache of {} to {}", username, dir.display());
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if is_shared_entry(&name.to_string_lossy()) {
+                fs::rename(entry.path(), dir.join(name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_dirs() {
+        assert_eq!(user_dir_name("alice"), "alice");
+        assert_eq!(user_dir_name("../../etc"), "_.._etc");
+        assert_eq!(user_dir_name("..hidden"), "hidden");
+
+        assert!(is_shared_entry("credentials.json"));
+        assert!(is_shared_entry("a3"));
+        assert!(!is_shared_entry("users"));
+        assert!(!is_shared_entry("play_history"));
+    }
+
+    #[test]
+    fn test_last_user() {
+        let root = std::env::temp_dir().join(format!("spotifyd-last-user-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let layout = CacheLayout {
+            root: root.clone(),
+            credentials: true,
+            audio: false,
+            size_limit: None,
+            per_user: true,
+        };
+        assert_eq!(layout.user_dir("alice"), root.join("users/alice"));
+
+        layout.remember_user("alice");
+        assert!(!layout.forget_last_user("bob").unwrap());
+        assert!(layout.forget_last_user("alice").unwrap());
+        assert!(!root.join(LAST_USER).exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+use crate::control;
+use futures::{future, SinkExt, StreamExt};
+use librespot_playback::{
+    config::AudioFormat,
+    mixer::{Mixer, MixerConfig},
+    NUM_CHANNELS, SAMPLE_RATE,
+};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tokio::{net::TcpStream, sync::watch};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+/// How long to wait before connecting again after CamillaDSP went away.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// The lowest volume of CamillaDSP, used when the slider is at 0.
+const MIN_VOLUME_DB: f64 = -150.0;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// The volume of CamillaDSP, muted at its lowest.
+fn volume_db(volume: u16) -> f64 {
+    control::volume_db(volume).max(MIN_VOLUME_DB)
+}
+
+/// The name of the sample format in the config of CamillaDSP.
+fn format_name(format: AudioFormat) -> &'static str {
+    match format {
+        AudioFormat::F64 => "FLOAT64LE",
+        AudioFormat::F32 => "FLOAT32LE",
+        AudioFormat::S32 => "S32LE",
+        AudioFormat::S24 => "S24LE",
+        AudioFormat::S24_3 => "S24LE3",
+        AudioFormat::S16 => "S16LE",
+    }
+}
+
+/// A mixer leaving the volume to CamillaDSP, sending it to the task syncing
+/// with it.
+pub(crate) struct CamillaMixer {
+    volume: Arc<watch::Sender<u16>>,
+}
+
+impl CamillaMixer {
+    pub(crate) fn new(volume: Arc<watch::Sender<u16>>) -> Self {
+        Self { volume }
+    }
+}
+
+impl Mixer for CamillaMixer {
+    fn open(_: MixerConfig) -> CamillaMixer {
+        CamillaMixer {
+            volume: Arc::new(watch::channel(u16::MAX).0),
+        }
+    }
+
+    fn volume(&self) -> u16 {
+        *self.volume.borrow()
+    }
+
+    fn set_volume(&self, volume: u16) {
+        self.volume.send_replace(volume);
+    }
+}
+
+/// Sends the command, and returns the value of its reply.
+async fn request(socket: &mut Socket, command: Value) -> Result<Value, String> {
+    let name = match command {
+        Value::String(ref name) => name.clone(),
+        _ => command
+            .as_object()
+            .and_then(|command| command.keys().next().cloned())
+            .unwrap_or_default(),
+    };
+    socket
+        .send(Message::Text(command.to_string()))
+        .await
+        .map_err(|e| e.to_string())?;
+    while let Some(message) = socket.next().await {
+        let Message::Text(text) = message.map_err(|e| e.to_string())? else {
+            continue;
+        };
+        let reply: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+        let reply = &reply[&name];
+        if reply["result"] != "Ok" {
+            return Err(format!("{} failed: {}", name, reply["result"]));
+        }
+        return Ok(reply["value"].clone());
+    }
+    Err("the connection was closed".to_string())
+}
+
+/// Sets the capture of CamillaDSP to the sample rate and the format of the
+/// audio written to it, if they differ.
+async fn sync_format(socket: &mut Socket, format: AudioFormat) -> Result<(), String> {
+    let config = request(socket, json!("GetConfigJson")).await?;
+    let mut config: Value = config
+        .as_str()
+        .and_then(|config| serde_json::from_str(config).ok())
+        .ok_or("CamillaDSP has no config loaded")?;
+    let devices = &mut config["devices"];
+    if devices["capture"]["channels"] != NUM_CHANNELS {
+        warn!(
+            "CamillaDSP captures {} channels, but the audio has {}",
+            devices["capture"]["channels"], NUM_CHANNELS
+        );
+    }
+    let format = format_name(format);
+    if devices["samplerate"] == SAMPLE_RATE && devices["capture"]["format"] == format {
+        return Ok(());
+    }
+    info!(
+        "Setting the capture of CamillaDSP to {} Hz in {}",
+        SAMPLE_RATE, format
+    );
+    devices["samplerate"] = json!(SAMPLE_RATE);
+    devices["capture"]["format"] = json!(format);
+    request(socket, json!({ "SetConfigJson": config.to_string() }))
+        .await
+        .map(drop)
+}
+
+async fn run(
+    socket: &mut Socket,
+    format: AudioFormat,
+    volume: &mut Option<watch::Receiver<u16>>,
+) -> Result<(), String> {
+    sync_format(socket, format).await?;
+    let mut send_volume = true;
+    loop {
+        if let Some(volume) = volume.as_mut().filter(|_| send_volume) {
+            let db = volume_db(*volume.borrow_and_update());
+            request(socket, json!({ "SetVolume": db })).await?;
+        }
+        let changed = async {
+            match volume.as_mut() {
+                Some(volume) => volume.changed().await.is_ok(),
+                None => future::pending().await,
+            }
+        };
+        let changed = tokio::select! {
+            changed = changed => Some(changed),
+            message = socket.next() => match message {
+                Some(Ok(_)) => None,
+                Some(Err(e)) => return Err(e.to_string()),
+                None => return Err("the connection was closed".to_string()),
+            },
+        };
+        send_volume = changed == Some(true);
+        if changed == Some(false) {
+            // the mixers are gone, only the format is kept in sync
+            *volume = None;
+        }
+    }
+}
+
+/// Keeps CamillaDSP listening on its websocket at the address in sync: its
+/// capture with the audio spotifyd writes to it, and its volume with the
+/// mixer if it's given, connecting again whenever CamillaDSP restarts.
+pub(crate) async fn sync(
+    address: String,
+    format: AudioFormat,
+    mut volume: Option<watch::Receiver<u16>>,
+) {
+    let url = format!("ws://{}", address);
+    loop {
+        match tokio_tungstenite::connect_async(url.as_str()).await {
+            Ok((mut socket, _)) => {
+                info!("Connected to CamillaDSP at {}", address);
+                if let Err(e) = run(&mut socket, format, &mut volume).await {
+                    warn!("Lost the connection to CamillaDSP: {}", e);
+                }
+            }
+            Err(e) => debug!("Failed to connect to CamillaDSP at {}: {}", address, e),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_volume_db() {
+        assert_eq!(volume_db(u16::MAX), 0.0);
+        assert_eq!(volume_db(0), MIN_VOLUME_DB);
+        assert!((volume_db(u16::MAX / 2) + 30.0).abs() < 0.01);
+
+        let (sender, receiver) = watch::channel(u16::MAX);
+        let mixer = CamillaMixer::new(Arc::new(sender));
+        mixer.set_volume(1000);
+        assert_eq!(*receiver.borrow(), 1000);
+        assert_eq!(mixer.volume(), 1000);
+    }
+}
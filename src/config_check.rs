@@ -0,0 +1,262 @@
+use crate::config::{
+    self, CliConfig, FileConfig, LoggingConfig, ProfileConfig, SharedConfigValues, SpotifydConfig,
+};
+use color_eyre::eyre;
+use librespot_playback::audio_backend::BACKENDS;
+use serde::{
+    de::{self, Visitor},
+    forward_to_deserialize_any, Deserialize, Deserializer,
+};
+use std::{env, fs, path::Path};
+
+/// The sections of the config file.
+const SECTIONS: [&str; 4] = ["global", "spotifyd", "logging", "profile"];
+
+/// A deserializer that only records the fields of the struct deserialized
+/// from it, the keys that are known.
+struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+impl<'de, 'a> Deserializer<'de> for FieldNames<'a> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        _: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = fields;
+        Err(de::Error::custom("only the fields are recorded"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+fn field_names<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+/// The number of characters to insert, remove or replace to turn one word
+/// into the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let replaced = previous[j] + usize::from(a != *b);
+            current.push(replaced.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// The known key closest to the unknown one, if it's likely a typo of it.
+fn suggestion<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    let key = key.replace('-', "_");
+    known
+        .iter()
+        .map(|known| (edit_distance(&key, known), *known))
+        .filter(|(distance, known)| *distance <= (known.len() / 3).max(1))
+        .min()
+        .map(|(_, known)| known)
+}
+
+/// The keys of the config file that aren't used, with a suggestion for each
+/// one that looks like a typo.
+fn unknown_keys(content: &str) -> Result<Vec<(String, Option<&'static str>)>, toml::de::Error> {
+    let mut unknown = Vec::new();
+    let _: FileConfig = serde_ignored::deserialize(toml::Deserializer::new(content), |path| {
+        unknown.push(path.to_string())
+    })?;
+    let options = field_names::<SharedConfigValues>();
+    Ok(unknown
+        .into_iter()
+        .map(|path| {
+            let segments: Vec<_> = path.split('.').collect();
+            let suggestion = match segments[..] {
+                [section] => suggestion(section, &SECTIONS),
+                ["global" | "spotifyd", option] => suggestion(option, options),
+                ["logging", option] => suggestion(option, field_names::<LoggingConfig>()),
+                ["profile", _, option] => suggestion(option, field_names::<ProfileConfig>()),
+                _ => None,
+            };
+            (path, suggestion)
+        })
+        .collect())
+}
+
+/// The problem with the shell running the hooks, if it can't be run.
+fn check_shell(shell: &str) -> Option<String> {
+    let path = if shell.contains('/') {
+        Some(Path::new(shell).to_path_buf())
+    } else {
+        env::var_os("PATH").and_then(|paths| {
+            env::split_paths(&paths)
+                .map(|dir| dir.join(shell))
+                .find(|path| path.is_file())
+        })
+    };
+    let Some(path) = path else {
+        return Some(format!("the shell {} isn't in PATH", shell));
+    };
+    match fs::metadata(&path) {
+        Ok(metadata) if !metadata.is_file() => Some(format!("the shell {} isn't a file", shell)),
+        #[cfg(unix)]
+        Ok(metadata) => {
+            use std::os::unix::fs::PermissionsExt;
+            (metadata.permissions().mode() & 0o111 == 0)
+                .then(|| format!("the shell {} isn't executable", path.display()))
+        }
+        #[cfg(not(unix))]
+        Ok(_) => None,
+        Err(e) => Some(format!("the shell {} can't be used: {}", path.display(), e)),
+    }
+}
+
+/// The problems with the audio backends and devices that can't be used.
+fn check_audio(config: &SpotifydConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    #[cfg(feature = "backend_plugins")]
+    for path in &config.backend_plugins {
+        if let Err(e) = crate::audio_sink::plugin::load(path) {
+            problems.push(e);
+        }
+    }
+    if config.outputs.is_empty() {
+        problems.extend(check_output(
+            config.backend.as_deref(),
+            config.audio_device.as_deref(),
+        ));
+        return problems;
+    }
+    problems.extend(config.outputs.iter().filter_map(|output| {
+        let backend = output.backend.as_ref().map(|backend| backend.to_string());
+        let backend = backend.as_deref().or(config.backend.as_deref());
+        check_output(backend, output.device.as_deref())
+    }));
+    problems
+}
+
+/// The problem with the audio backend and device, if they can't be used.
+#[cfg_attr(not(feature = "alsa_backend"), allow(unused_variables))]
+fn check_output(backend: Option<&str>, device: Option<&str>) -> Option<String> {
+    let backend = backend.unwrap_or_default();
+    let built_in = BACKENDS.iter().any(|(name, _)| *name == backend)
+        || (cfg!(feature = "jack_backend") && backend == "jack")
+        || (cfg!(feature = "pipewire_backend") && backend == "pipewire");
+    if !built_in && crate::audio_sink::registered_backend(backend).is_none() {
+        return Some(format!("the {} backend isn't built in", backend));
+    }
+    #[cfg(feature = "alsa_backend")]
+    if backend == "alsa" {
+        let device = device.unwrap_or("default");
+        match alsa::PCM::new(device, alsa::Direction::Playback, true) {
+            // another program, or spotifyd itself, is playing on it
+            Err(e) if e.errno() == libc::EBUSY => (),
+            Err(e) => return Some(format!("the alsa device {} can't be opened: {}", device, e)),
+            Ok(_) => (),
+        }
+    }
+    None
+}
+
+/// Checks the config file and the config it results in, printing the
+/// problems found, and fails if there are any.
+pub fn run(mut cli_config: CliConfig) -> eyre::Result<()> {
+    let mut problems = Vec::new();
+    match cli_config
+        .config_path
+        .clone()
+        .or_else(config::get_config_file)
+    {
+        Some(path) => match fs::read_to_string(&path) {
+            Ok(content) => match unknown_keys(&content) {
+                Ok(unknown) => {
+                    println!("Checking {}", path.display());
+                    for (key, suggestion) in unknown {
+                        problems.push(match suggestion {
+                            Some(suggestion) => {
+                                format!("unknown key {}, did you mean {}?", key, suggestion)
+                            }
+                            None => format!("unknown key {}", key),
+                        });
+                    }
+                }
+                Err(e) => eyre::bail!("{} is invalid: {}", path.display(), e),
+            },
+            Err(e) => eyre::bail!("failed to read {}: {}", path.display(), e),
+        },
+        None => println!("There's no config file, checking the defaults"),
+    }
+
+    cli_config.load_config_file_values()?;
+    let config = config::get_internal_config(cli_config);
+    problems.extend(check_audio(&config));
+    if !config.hooks.is_empty() {
+        problems.extend(check_shell(&config.shell));
+    }
+
+    for problem in &problems {
+        println!("error: {}", problem);
+    }
+    match problems.len() {
+        0 => {
+            println!("The config is valid");
+            Ok(())
+        }
+        1 => eyre::bail!("found a problem in the config"),
+        count => eyre::bail!("found {} problems in the config", count),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_keys() {
+        let content = r#"
+            [global]
+            device_name = "Kitchen"
+            volume_controler = "alsa"
+            bitrate = 320
+            something_else = true
+
+            [logging]
+            levels = { "spotifyd::dsp" = "debug" }
+            level = "debug"
+
+            [profile.alice]
+            username = "alice"
+            device_nme = "Alice's Kitchen"
+
+            [globl]
+        "#;
+        assert_eq!(
+            unknown_keys(content).unwrap(),
+            vec![
+                (
+                    "global.volume_controler".to_string(),
+                    Some("volume_controller")
+                ),
+                ("global.something_else".to_string(), None),
+                ("logging.level".to_string(), Some("levels")),
+                ("profile.alice.device_nme".to_string(), Some("device_name")),
+                ("globl".to_string(), Some("global")),
+            ]
+        );
+        assert!(unknown_keys("[global]\nbitrate = ").is_err());
+    }
+}
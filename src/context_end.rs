@@ -0,0 +1,169 @@
+use crate::events::SpotifydEvent;
+#[cfg(feature = "web_api")]
+use crate::{config::RadioSeed, events::EventBus, web_api};
+#[cfg(feature = "web_api")]
+use librespot_core::{session::Session, spotify_id::SpotifyId, Error};
+#[cfg(feature = "web_api")]
+use log::{error, info};
+
+/// Detects the end of the context from the events.
+///
+/// When the last track of the context ends, librespot loads the first track
+/// again, but doesn't start playing it. So the end of a track followed by a
+/// pause, instead of the next track playing, marks the end of the context.
+#[derive(Debug, Default)]
+pub(crate) struct ContextEndDetector {
+    ended_track: Option<String>,
+}
+
+impl ContextEndDetector {
+    /// Returns the last track of the context, if the event marks its end.
+    pub(crate) fn observe(&mut self, event: &SpotifydEvent) -> Option<String> {
+        match event {
+            SpotifydEvent::EndOfTrack { track_id, .. } => {
+                self.ended_track = Some(track_id.clone());
+                None
+            }
+            SpotifydEvent::Playing { .. } => {
+                self.ended_track = None;
+                None
+            }
+            SpotifydEvent::Paused { .. } | SpotifydEvent::Stopped { .. } => self.ended_track.take(),
+            _ => None,
+        }
+    }
+}
+
+/// Starts a radio on this device, based on the seed and the last track, or
+/// publishes the end of the queue if it fails.
+#[cfg(feature = "web_api")]
+pub(crate) async fn start_radio(
+    session: Session,
+    device_name: String,
+    seed: RadioSeed,
+    last_track_id: String,
+    event_bus: EventBus,
+) {
+    let result = try_start_radio(session, device_name, seed, Some(last_track_id.clone())).await;
+    if let Err(e) = result {
+        error!("Failed to start the radio: {}", e);
+        event_bus.publish(SpotifydEvent::QueueEnded {
+            track_id: last_track_id,
+        });
+    }
+}
+
+#[cfg(feature = "web_api")]
+async fn try_start_radio(
+    session: Session,
+    device_name: String,
+    seed: RadioSeed,
+    last_track_id: Option<String>,
+) -> Result<(), Error> {
+    use librespot_metadata::{Metadata, Track};
+    use rspotify::{
+        model::{ArtistId, PlayableId, TrackId},
+        prelude::*,
+    };
+
+    if let RadioSeed::Context(ref uri) = seed {
+        info!("Playback reached the end of the context, playing {}", uri);
+        return web_api::play_context(&session, &device_name, uri).await;
+    }
+
+    let track_id =
+        last_track_id.ok_or_else(|| Error::failed_precondition("no track has been played"))?;
+    // the artist is taken from the metadata of the track
+    let artist_id = match seed {
+        RadioSeed::Artist => {
+            let id = SpotifyId::from_base62(&track_id)?;
+            let track = Track::get(&session, &id).await?;
+            let artist = track
+                .artists
+                .0
+                .first()
+                .ok_or_else(|| Error::not_found("the track has no artist"))?;
+            Some(artist.id.to_base62()?)
+        }
+        _ => None,
+    };
+
+    info!("Playback reached the end of the context, starting a radio");
+    web_api::with_client(&session, move |client| {
+        let device_id = web_api::device_id(&client, &device_name)?;
+        let (artist_id, track_id) = match artist_id {
+            Some(ref id) => (Some(ArtistId::from_id(id)), None),
+            None => (None, Some(TrackId::from_id(&track_id))),
+        };
+        let artist_id = artist_id.transpose().map_err(Error::invalid_argument)?;
+        let track_id = track_id.transpose().map_err(Error::invalid_argument)?;
+        let recommendations = client
+            .recommendations(
+                [],
+                artist_id.map(|id| [id]),
+                None::<[&str; 0]>,
+                track_id.map(|id| [id]),
+                None,
+                Some(50),
+            )
+            .map_err(Error::unavailable)?;
+        let tracks = recommendations
+            .tracks
+            .into_iter()
+            .filter_map(|track| track.id.map(PlayableId::from));
+        client
+            .start_uris_playback(tracks, Some(&device_id), None, None)
+            .map_err(Error::unavailable)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detects_context_end() {
+        let track_id = || "4uLU6hMCjMI75M1A2tKUQC".to_string();
+        let mut detector = ContextEndDetector::default();
+
+        // the next track of the context starts playing
+        assert_eq!(
+            detector.observe(&SpotifydEvent::EndOfTrack {
+                play_request_id: 1,
+                track_id: track_id(),
+            }),
+            None
+        );
+        assert_eq!(
+            detector.observe(&SpotifydEvent::Playing {
+                play_request_id: 2,
+                track_id: track_id(),
+                position_ms: 0,
+            }),
+            None
+        );
+
+        // the first track of the context is loaded, but paused
+        detector.observe(&SpotifydEvent::EndOfTrack {
+            play_request_id: 2,
+            track_id: track_id(),
+        });
+        assert_eq!(
+            detector.observe(&SpotifydEvent::Paused {
+                play_request_id: 3,
+                track_id: "2takcwOaAZWiXQijPHIx7B".to_string(),
+                position_ms: 0,
+            }),
+            Some(track_id())
+        );
+        assert_eq!(
+            detector.observe(&SpotifydEvent::Paused {
+                play_request_id: 3,
+                track_id: track_id(),
+                position_ms: 0,
+            }),
+            None
+        );
+    }
+}
@@ -0,0 +1,347 @@
+use crate::{
+    audit::{CommandClient, CommandSource},
+    config::VolumeStep,
+    state::PlaybackState,
+};
+use librespot_connect::spirc::Spirc;
+use librespot_core::Error;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+/// The playback controls offered by spotifyd.
+///
+/// This is implemented by librespot's [`Spirc`] and by [`ControlHandle`],
+/// which can be used while the main loop is running.
+pub trait PlaybackControl {
+    fn play(&self) -> Result<(), Error>;
+    fn pause(&self) -> Result<(), Error>;
+    fn play_pause(&self) -> Result<(), Error>;
+    fn next(&self) -> Result<(), Error>;
+    fn prev(&self) -> Result<(), Error>;
+    fn volume_up(&self) -> Result<(), Error>;
+    fn volume_down(&self) -> Result<(), Error>;
+    fn set_volume(&self, volume: u16) -> Result<(), Error>;
+    fn shuffle(&self, shuffle: bool) -> Result<(), Error>;
+    fn repeat(&self, repeat: bool) -> Result<(), Error>;
+    fn seek(&self, position_ms: u32) -> Result<(), Error>;
+}
+
+impl PlaybackControl for Spirc {
+    fn play(&self) -> Result<(), Error> {
+        Spirc::play(self)
+    }
+
+    fn pause(&self) -> Result<(), Error> {
+        Spirc::pause(self)
+    }
+
+    fn play_pause(&self) -> Result<(), Error> {
+        Spirc::play_pause(self)
+    }
+
+    fn next(&self) -> Result<(), Error> {
+        Spirc::next(self)
+    }
+
+    fn prev(&self) -> Result<(), Error> {
+        Spirc::prev(self)
+    }
+
+    fn volume_up(&self) -> Result<(), Error> {
+        Spirc::volume_up(self)
+    }
+
+    fn volume_down(&self) -> Result<(), Error> {
+        Spirc::volume_down(self)
+    }
+
+    fn set_volume(&self, volume: u16) -> Result<(), Error> {
+        Spirc::set_volume(self, volume)
+    }
+
+    fn shuffle(&self, shuffle: bool) -> Result<(), Error> {
+        Spirc::shuffle(self, shuffle)
+    }
+
+    fn repeat(&self, repeat: bool) -> Result<(), Error> {
+        Spirc::repeat(self, repeat)
+    }
+
+    fn seek(&self, position_ms: u32) -> Result<(), Error> {
+        Spirc::set_position_ms(self, position_ms)
+    }
+}
+
+/// A command sent through a [`ControlHandle`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlCommand {
+    Play,
+    Pause,
+    PlayPause,
+    Next,
+    Prev,
+    VolumeUp,
+    VolumeDown,
+    SetVolume(u16),
+    Shuffle(bool),
+    Repeat(bool),
+    Seek(u32),
+    /// Seeks to the next chapter of an episode.
+    NextChapter,
+    /// Seeks to the previous chapter of an episode, or the start of the
+    /// current one.
+    PrevChapter,
+}
+
+/// The volume in librespot's range for a percentage, up to 100.
+pub(crate) fn volume_from_percent(percent: u8) -> u16 {
+    (percent.min(100) as u32 * 0xFFFF / 100) as u16
+}
+
+/// The percentage of a volume in librespot's range.
+pub(crate) fn volume_percent(volume: u16) -> u8 {
+    (volume as u32 * 100 / 0xFFFF) as u8
+}
+
+/// The range of the volume in decibels, below 0 dB at the highest volume.
+/// The volume is linear in decibels over it, like librespot's software
+/// volume, and 0 mutes.
+pub(crate) const VOLUME_RANGE_DB: f64 = 60.0;
+
+/// The gain in decibels of a volume in librespot's range, minus infinity
+/// when muted.
+pub(crate) fn volume_db(volume: u16) -> f64 {
+    if volume == 0 {
+        f64::NEG_INFINITY
+    } else {
+        VOLUME_RANGE_DB * (volume as f64 / 0xFFFF as f64 - 1.0)
+    }
+}
+
+/// The volume in librespot's range for a gain in decibels, up to 0 dB.
+pub(crate) fn volume_from_db(db: f64) -> u16 {
+    ((db / VOLUME_RANGE_DB + 1.0).clamp(0.0, 1.0) * 0xFFFF as f64).round() as u16
+}
+
+/// The volume for the logs, e.g. `40% (-36.0 dB)`.
+pub(crate) fn display_volume(volume: u16) -> String {
+    if volume == 0 {
+        "0% (muted)".to_string()
+    } else {
+        format!("{}% ({:.1} dB)", volume_percent(volume), volume_db(volume))
+    }
+}
+
+impl ControlCommand {
+    /// Replaces a step of the volume up or down by setting the volume a step
+    /// away from the current one, if both are known, instead of leaving the
+    /// step to librespot.
+    pub(crate) fn with_volume_step(self, step: Option<VolumeStep>, volume: Option<u16>) -> Self {
+        match (self, step, volume) {
+            (ControlCommand::VolumeUp, Some(step), Some(volume)) => {
+                ControlCommand::SetVolume(step.apply(volume, true))
+            }
+            (ControlCommand::VolumeDown, Some(step), Some(volume)) => {
+                ControlCommand::SetVolume(step.apply(volume, false))
+            }
+            _ => self,
+        }
+    }
+
+    /// Applies the command, using the playback state for commands that depend
+    /// on it.
+    pub fn apply<C: PlaybackControl + ?Sized>(
+        self,
+        control: &C,
+        state: &PlaybackState,
+    ) -> Result<(), Error> {
+        match self {
+            ControlCommand::Play => control.play(),
+            ControlCommand::Pause => control.pause(),
+            ControlCommand::PlayPause => control.play_pause(),
+            ControlCommand::Next => control.next(),
+            ControlCommand::Prev => control.prev(),
+            ControlCommand::VolumeUp => control.volume_up(),
+            ControlCommand::VolumeDown => control.volume_down(),
+            ControlCommand::SetVolume(volume) => control.set_volume(volume),
+            ControlCommand::Shuffle(shuffle) => control.shuffle(shuffle),
+            ControlCommand::Repeat(repeat) => control.repeat(repeat),
+            ControlCommand::Seek(position_ms) => control.seek(position_ms),
+            ControlCommand::NextChapter | ControlCommand::PrevChapter => {
+                match state.chapter_position(self == ControlCommand::NextChapter) {
+                    Some(position_ms) => control.seek(position_ms),
+                    None => Err(Error::failed_precondition("no chapter to go to")),
+                }
+            }
+        }
+    }
+}
+
+/// Controls the playback of a running [`MainLoop`](crate::main_loop::MainLoop).
+///
+/// The commands are applied to the current session. While no session is
+/// active, they are queued until the next one starts.
+#[derive(Clone, Debug)]
+pub struct ControlHandle {
+    tx: UnboundedSender<(ControlCommand, CommandSource, CommandClient)>,
+    source: CommandSource,
+    client: CommandClient,
+}
+
+impl ControlHandle {
+    pub(crate) fn new(
+        tx: UnboundedSender<(ControlCommand, CommandSource, CommandClient)>,
+        source: CommandSource,
+    ) -> Self {
+        Self {
+            tx,
+            source,
+            client: Default::default(),
+        }
+    }
+
+    /// A handle whose commands are recorded in the audit log as sent by the
+    /// client.
+    pub(crate) fn for_client(&self, client: CommandClient) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            source: self.source,
+            client,
+        }
+    }
+
+    pub fn send(&self, command: ControlCommand) -> Result<(), Error> {
+        self.tx
+            .send((command, self.source, self.client.clone()))
+            .map_err(|_| Error::unavailable("spotifyd has shut down"))
+    }
+}
+
+impl PlaybackControl for ControlHandle {
+    fn play(&self) -> Result<(), Error> {
+        self.send(ControlCommand::Play)
+    }
+
+    fn pause(&self) -> Result<(), Error> {
+        self.send(ControlCommand::Pause)
+    }
+
+    fn play_pause(&self) -> Result<(), Error> {
+        self.send(ControlCommand::PlayPause)
+    }
+
+    fn next(&self) -> Result<(), Error> {
+        self.send(ControlCommand::Next)
+    }
+
+    fn prev(&self) -> Result<(), Error> {
+        self.send(ControlCommand::Prev)
+    }
+
+    fn volume_up(&self) -> Result<(), Error> {
+        self.send(ControlCommand::VolumeUp)
+    }
+
+    fn volume_down(&self) -> Result<(), Error> {
+        self.send(ControlCommand::VolumeDown)
+    }
+
+    fn set_volume(&self, volume: u16) -> Result<(), Error> {
+        self.send(ControlCommand::SetVolume(volume))
+    }
+
+    fn shuffle(&self, shuffle: bool) -> Result<(), Error> {
+        self.send(ControlCommand::Shuffle(shuffle))
+    }
+
+    fn repeat(&self, repeat: bool) -> Result<(), Error> {
+        self.send(ControlCommand::Repeat(repeat))
+    }
+
+    fn seek(&self, position_ms: u32) -> Result<(), Error> {
+        self.send(ControlCommand::Seek(position_ms))
+    }
+}
+
+/// The receiving end of the [`ControlHandle`]s, owned by the main loop.
+pub(crate) type ControlReceiver = UnboundedReceiver<(ControlCommand, CommandSource, CommandClient)>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_handle_forwards_commands() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let handle = ControlHandle::new(tx, CommandSource::Api);
+
+        handle.play_pause().unwrap();
+        handle.set_volume(1000).unwrap();
+
+        assert_eq!(
+            rx.try_recv(),
+            Ok((
+                ControlCommand::PlayPause,
+                CommandSource::Api,
+                Default::default()
+            ))
+        );
+        assert_eq!(
+            rx.try_recv(),
+            Ok((
+                ControlCommand::SetVolume(1000),
+                CommandSource::Api,
+                Default::default()
+            ))
+        );
+
+        let client = CommandClient {
+            name: Some("wall panel".to_string()),
+            ip: None,
+        };
+        handle.for_client(client.clone()).pause().unwrap();
+        assert_eq!(
+            rx.try_recv(),
+            Ok((ControlCommand::Pause, CommandSource::Api, client))
+        );
+
+        drop(rx);
+        assert!(handle.next().is_err());
+    }
+
+    #[test]
+    fn test_with_volume_step() {
+        let step = Some(VolumeStep::Percent(10.0));
+        assert_eq!(
+            ControlCommand::VolumeUp.with_volume_step(step, Some(0)),
+            ControlCommand::SetVolume(6554)
+        );
+        assert_eq!(
+            ControlCommand::VolumeDown.with_volume_step(step, Some(0)),
+            ControlCommand::SetVolume(0)
+        );
+        assert_eq!(
+            ControlCommand::VolumeUp.with_volume_step(step, None),
+            ControlCommand::VolumeUp
+        );
+        assert_eq!(
+            ControlCommand::VolumeUp.with_volume_step(None, Some(0)),
+            ControlCommand::VolumeUp
+        );
+    }
+
+    #[test]
+    fn test_volume_db() {
+        assert_eq!(volume_db(0xFFFF), 0.0);
+        assert_eq!(volume_db(0), f64::NEG_INFINITY);
+        assert_eq!(volume_from_db(0.0), 0xFFFF);
+        assert_eq!(volume_from_db(6.0), 0xFFFF);
+        assert_eq!(volume_from_db(-VOLUME_RANGE_DB), 0);
+        assert_eq!(volume_from_db(f64::NEG_INFINITY), 0);
+        for volume in [1, 1000, 0x8000, 0xFFFE] {
+            assert_eq!(volume_from_db(volume_db(volume)), volume);
+        }
+        assert_eq!(display_volume(0x8000), "50% (-30.0 dB)");
+        assert_eq!(display_volume(0), "0% (muted)");
+    }
+}
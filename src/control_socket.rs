@@ -0,0 +1,481 @@
+use crate::{
+    cache::{CacheHits, HitRate},
+    config::{LocalAction, SleepArg, SpotifydConfig, VolumeLevel, VolumeStep},
+    control::{ControlCommand, ControlHandle},
+    discoverable::Discoverable,
+    lock::{DoNotDisturb, LockOwner},
+    profiles::Profiles,
+    sleep_timer::{SleepTimer, SleepTimerReport},
+    state::{HistoryReport, SharedPlaybackState, StatusReport},
+};
+use color_eyre::eyre::{self, eyre};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+/// A command accepted on the control socket, as one JSON object per line,
+/// e.g. `{"command": "seek", "position_ms": 60000}`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub(crate) enum SocketCommand {
+    Play,
+    Pause,
+    PlayPause,
+    Next,
+    Previous,
+    Seek {
+        position_ms: u32,
+    },
+    /// Sets the volume, between 0 and 100, or in decibels like `"-12dB"`.
+    Volume {
+        volume: VolumeLevel,
+    },
+    /// Steps the volume up, by the `volume_step` unless another `step` is
+    /// given.
+    VolumeUp {
+        step: Option<VolumeStep>,
+    },
+    VolumeDown {
+        step: Option<VolumeStep>,
+    },
+    Status,
+    /// The tracks that could not be played.
+    History,
+    Lock,
+    Unlock,
+    /// Shows or hides the device in the Spotify apps, keeping the session.
+    Discoverable {
+        discoverable: bool,
+    },
+    /// Switches to the account of the `[profile.<name>]` section, ending the
+    /// current session.
+    Profile {
+        name: String,
+    },
+    /// Pauses the playback after the duration, fading out over the last
+    /// minute.
+    Sleep {
+        duration_ms: u64,
+    },
+    CancelSleep,
+    /// When the sleep timer runs out.
+    SleepTimer,
+    /// How many of the tracks played since the start were in the audio cache.
+    CacheHits,
+}
+
+impl From<&LocalAction> for SocketCommand {
+    fn from(action: &LocalAction) -> Self {
+        match *action {
+            LocalAction::Play => SocketCommand::Play,
+            LocalAction::Pause => SocketCommand::Pause,
+            LocalAction::PlayPause => SocketCommand::PlayPause,
+            LocalAction::Next => SocketCommand::Next,
+            LocalAction::Previous => SocketCommand::Previous,
+            LocalAction::Seek { position } => SocketCommand::Seek {
+                position_ms: position.saturating_mul(1000),
+            },
+            LocalAction::Volume { volume } => SocketCommand::Volume { volume },
+            LocalAction::VolumeUp { step } => SocketCommand::VolumeUp { step },
+            LocalAction::VolumeDown { step } => SocketCommand::VolumeDown { step },
+            LocalAction::Status => SocketCommand::Status,
+            LocalAction::Lock => SocketCommand::Lock,
+            LocalAction::Unlock => SocketCommand::Unlock,
+            LocalAction::Discoverable { state } => SocketCommand::Discoverable {
+                discoverable: state.into(),
+            },
+            LocalAction::Profile { ref name } => SocketCommand::Profile { name: name.clone() },
+            LocalAction::Sleep { duration } => match duration {
+                Some(SleepArg::After(duration)) => SocketCommand::Sleep {
+                    duration_ms: duration.as_millis() as u64,
+                },
+                Some(SleepArg::Cancel) => SocketCommand::CancelSleep,
+                None => SocketCommand::SleepTimer,
+            },
+        }
+    }
+}
+
+/// The reply to a command, as one JSON object per line.
+#[derive(Debug, Default, Serialize)]
+struct Reply {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<StatusReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history: Option<HistoryReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_hits: Option<HitRate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sleep_timer: Option<SleepTimerReport>,
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Self {
+            ok: true,
+            ..Default::default()
+        }
+    }
+
+    fn error(error: impl ToString) -> Self {
+        Self {
+            error: Some(error.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Handler {
+    control: ControlHandle,
+    playback_state: SharedPlaybackState,
+    do_not_disturb: Arc<DoNotDisturb>,
+    discoverable: Arc<Discoverable>,
+    profiles: Arc<Profiles>,
+    sleep_timer: Arc<SleepTimer>,
+    cache_hits: Arc<CacheHits>,
+}
+
+impl Handler {
+    fn handle(&self, line: &str) -> Reply {
+        let command = match serde_json::from_str(line) {
+            Ok(command) => command,
+            Err(e) => return Reply::error(format!("invalid command: {}", e)),
+        };
+        let command = match command {
+            SocketCommand::Play => ControlCommand::Play,
+            SocketCommand::Pause => ControlCommand::Pause,
+            SocketCommand::PlayPause => ControlCommand::PlayPause,
+            SocketCommand::Next => ControlCommand::Next,
+            SocketCommand::Previous => ControlCommand::Prev,
+            SocketCommand::Seek { position_ms } => ControlCommand::Seek(position_ms),
+            SocketCommand::Volume { volume } => ControlCommand::SetVolume(volume.volume()),
+            // the main loop steps by the volume_step otherwise
+            SocketCommand::VolumeUp { step } => ControlCommand::VolumeUp
+                .with_volume_step(step, self.playback_state.read().unwrap().volume),
+            SocketCommand::VolumeDown { step } => ControlCommand::VolumeDown
+                .with_volume_step(step, self.playback_state.read().unwrap().volume),
+            SocketCommand::Status => {
+                let state = self.playback_state.read().unwrap();
+                let status = StatusReport::new(&state, self.do_not_disturb.is_locked());
+                return Reply {
+                    status: Some(status),
+                    ..Reply::ok()
+                };
+            }
+            SocketCommand::History => {
+                let state = self.playback_state.read().unwrap();
+                return Reply {
+                    history: Some(HistoryReport::new(&state)),
+                    ..Reply::ok()
+                };
+            }
+            SocketCommand::Lock => {
+                let owner = LockOwner::current(&self.playback_state.read().unwrap());
+                self.do_not_disturb.lock(owner);
+                return Reply::ok();
+            }
+            SocketCommand::Unlock => {
+                self.do_not_disturb.unlock();
+                return Reply::ok();
+            }
+            SocketCommand::Discoverable { discoverable } => {
+                return match self.discoverable.set_discoverable(discoverable) {
+                    Ok(()) => Reply::ok(),
+                    Err(e) => Reply::error(e),
+                };
+            }
+            SocketCommand::Profile { name } => {
+                return match self.profiles.switch(&name) {
+                    Ok(()) => Reply::ok(),
+                    Err(e) => Reply::error(e),
+                };
+            }
+            SocketCommand::Sleep { duration_ms } => {
+                return match self.sleep_timer.set(Duration::from_millis(duration_ms)) {
+                    Ok(()) => Reply::ok(),
+                    Err(e) => Reply::error(e),
+                };
+            }
+            SocketCommand::CancelSleep => {
+                self.sleep_timer.cancel();
+                return Reply::ok();
+            }
+            SocketCommand::SleepTimer => {
+                return Reply {
+                    sleep_timer: Some(self.sleep_timer.report()),
+                    ..Reply::ok()
+                };
+            }
+            SocketCommand::CacheHits => {
+                return Reply {
+                    cache_hits: Some(self.cache_hits.rate()),
+                    ..Reply::ok()
+                };
+            }
+        };
+        match self.control.send(command) {
+            Ok(()) => Reply::ok(),
+            Err(e) => Reply::error(e),
+        }
+    }
+
+    async fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut reply = serde_json::to_vec(&self.handle(&line)).unwrap();
+            reply.push(b'\n');
+            writer.write_all(&reply).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Accepts commands on the Unix socket at the path, which only the user
+/// running spotifyd may connect to.
+pub(crate) async fn serve(
+    path: PathBuf,
+    control: ControlHandle,
+    playback_state: SharedPlaybackState,
+    do_not_disturb: Arc<DoNotDisturb>,
+    discoverable: Arc<Discoverable>,
+    profiles: Arc<Profiles>,
+    sleep_timer: Arc<SleepTimer>,
+    cache_hits: Arc<CacheHits>,
+) {
+    if UnixStream::connect(&path).await.is_ok() {
+        error!(
+            "Another instance is listening on {}, not accepting any commands",
+            path.display()
+        );
+        return;
+    }
+    // the socket of a previous instance is left behind
+    let _ = fs::remove_file(&path);
+    let listener = match bind_private(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to listen on {}: {}", path.display(), e);
+            return;
+        }
+    };
+    info!("Accepting commands on {}", path.display());
+
+    let handler = Handler {
+        control,
+        playback_state,
+        do_not_disturb,
+        discoverable,
+        profiles,
+        sleep_timer,
+        cache_hits,
+    };
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept a connection of the control socket: {}", e);
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handler.serve(stream).await {
+                debug!("A connection of the control socket failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Listens on a socket at the path that only the user may connect to.
+///
+/// The socket is created with the permissions left by the umask, so it's
+/// bound in a directory nobody else may enter, restricted, and only then
+/// moved to the path. Connecting needs access to the directory, so nobody
+/// else can connect in between.
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = path.with_file_name(format!(".{}.{}", file_name, std::process::id()));
+    // left behind by an instance that crashed
+    let _ = fs::remove_dir_all(&dir);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let bound = dir.join(&*file_name);
+    let result = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+/// Sends the command to the running instance through its control socket, and
+/// returns its reply.
+pub(crate) async fn request(
+    config: &SpotifydConfig,
+    command: &SocketCommand,
+) -> eyre::Result<serde_json::Value> {
+    let path = config
+        .control_socket
+        .as_ref()
+        .ok_or_else(|| eyre!("no control_socket is configured"))?;
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| eyre!("failed to connect to {}: {}", path.display(), e))?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut request = serde_json::to_vec(command)?;
+    request.push(b'\n');
+    writer.write_all(&request).await?;
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| eyre!("spotifyd closed the connection without a reply"))?;
+
+    let reply: serde_json::Value = serde_json::from_str(&line)?;
+    if reply["ok"] != true {
+        return Err(eyre!("{}", reply["error"].as_str().unwrap_or("failed")));
+    }
+    Ok(reply)
+}
+
+/// Sends the command to the running instance through its control socket, and
+/// prints the status or the sleep timer it replies with.
+pub async fn run(config: &SpotifydConfig, action: &LocalAction) -> eyre::Result<()> {
+    let reply = request(config, &SocketCommand::from(action)).await?;
+    if let Some(status) = reply.get("status") {
+        println!("{}", status);
+    }
+    if let Some(sleep_timer) = reply.get("sleep_timer") {
+        match sleep_timer["remaining_ms"].as_u64() {
+            Some(remaining_ms) => {
+                let remaining = remaining_ms / 1000;
+                println!(
+                    "The playback pauses in {}m{:02}s",
+                    remaining / 60,
+                    remaining % 60
+                );
+            }
+            None => println!("No sleep timer is set"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::CommandSource;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_commands() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let handler = Handler {
+            control: ControlHandle::new(tx, CommandSource::Socket),
+            playback_state: Default::default(),
+            do_not_disturb: Default::default(),
+            discoverable: Arc::new(Discoverable::new(true)),
+            profiles: Arc::new(Profiles::new(Vec::new(), None)),
+            sleep_timer: Default::default(),
+            cache_hits: Default::default(),
+        };
+
+        assert!(
+            handler
+                .handle(r#"{"command": "seek", "position_ms": 60000}"#)
+                .ok
+        );
+        assert_eq!(
+            rx.try_recv(),
+            Ok((
+                ControlCommand::Seek(60000),
+                CommandSource::Socket,
+                Default::default()
+            ))
+        );
+        assert!(handler.handle(r#"{"command": "volume", "volume": 100}"#).ok);
+        assert_eq!(
+            rx.try_recv(),
+            Ok((
+                ControlCommand::SetVolume(0xFFFF),
+                CommandSource::Socket,
+                Default::default()
+            ))
+        );
+        assert!(
+            handler
+                .handle(r#"{"command": "volume", "volume": "-30dB"}"#)
+                .ok
+        );
+        assert_eq!(
+            rx.try_recv(),
+            Ok((
+                ControlCommand::SetVolume(0x8000),
+                CommandSource::Socket,
+                Default::default()
+            ))
+        );
+
+        assert!(handler.handle(r#"{"command": "lock"}"#).ok);
+        let reply = handler.handle(r#"{"command": "status"}"#);
+        assert_eq!(reply.status.map(|status| status.locked), Some(true));
+        let reply = handler.handle(r#"{"command": "history"}"#);
+        assert!(reply.history.unwrap().unavailable_tracks.is_empty());
+
+        assert!(
+            handler
+                .handle(r#"{"command": "discoverable", "discoverable": false}"#)
+                .ok
+        );
+        assert!(!handler.discoverable.is_discoverable());
+        let reply = handler.handle(r#"{"command": "profile", "name": "alice"}"#);
+        assert!(!reply.ok);
+
+        assert!(
+            handler
+                .handle(r#"{"command": "sleep", "duration_ms": 1800000}"#)
+                .ok
+        );
+        let reply = handler.handle(r#"{"command": "sleep_timer"}"#);
+        assert!(reply.sleep_timer.unwrap().remaining_ms.is_some());
+        assert!(handler.handle(r#"{"command": "cancel_sleep"}"#).ok);
+        assert_eq!(handler.sleep_timer.remaining(), None);
+
+        let reply = handler.handle(r#"{"command": "rewind"}"#);
+        assert!(!reply.ok);
+        assert!(reply.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_bind_private() {
+        let dir = std::env::temp_dir().join(format!("spotifyd-socket-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("spotifyd.sock");
+
+        let _listener = bind_private(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // the directory it was bound in is gone
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
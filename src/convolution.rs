@@ -0,0 +1,245 @@
+use crate::config::ImpulseResponse;
+use librespot_playback::{NUM_CHANNELS, SAMPLE_RATE};
+use realfft::{num_complex::Complex, ComplexToReal, RealFftPlanner, RealToComplex};
+use std::{collections::VecDeque, path::Path, sync::Arc};
+
+/// How many frames the impulse response is partitioned in, and how many
+/// frames the output of the convolution is delayed by.
+const BLOCK_SIZE: usize = 1024;
+/// Longer impulse responses are cut, at about three seconds.
+const MAX_LENGTH: usize = 1 << 17;
+
+/// Reads the channels of a WAV file, and its sample rate.
+fn read_wav(path: &Path) -> Result<(u32, Vec<Vec<f64>>), String> {
+    let mut reader = hound::WavReader::open(path).map_err(|e| e.to_string())?;
+    let spec = reader.spec();
+    let channels = spec.channels as usize;
+    if channels == 0 || channels > NUM_CHANNELS as usize {
+        return Err(format!(
+            "it has {} channels, only mono and stereo are supported",
+            channels
+        ));
+    }
+    let samples: Vec<f64> = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .samples::<f32>()
+            .map(|sample| sample.map(f64::from))
+            .collect(),
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f64;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f64 * scale))
+                .collect()
+        }
+    }
+    .map_err(|e| e.to_string())?;
+    if samples.is_empty() {
+        return Err("it is empty".to_string());
+    }
+    let channels = (0..channels)
+        .map(|channel| {
+            samples
+                .iter()
+                .skip(channel)
+                .step_by(channels)
+                .copied()
+                .collect()
+        })
+        .collect();
+    Ok((spec.sample_rate, channels))
+}
+
+/// An impulse response cut into partitions of a block, each transformed to
+/// the frequency domain, for each of its channels.
+pub(crate) struct Filter {
+    channels: Vec<Vec<Vec<Complex<f64>>>>,
+}
+
+impl Filter {
+    /// Loads the impulse response for the sample rate of the output.
+    pub(crate) fn load(impulse_response: &ImpulseResponse) -> Result<Self, String> {
+        let path = impulse_response
+            .file(SAMPLE_RATE)
+            .ok_or_else(|| format!("there is no impulse response for {} Hz", SAMPLE_RATE))?;
+        let (sample_rate, channels) =
+            read_wav(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        if sample_rate != SAMPLE_RATE {
+            return Err(format!(
+                "{} is sampled at {} Hz instead of {} Hz",
+                path.display(),
+                sample_rate,
+                SAMPLE_RATE
+            ));
+        }
+        Ok(Self::new(&channels))
+    }
+
+    fn new(channels: &[Vec<f64>]) -> Self {
+        let forward = RealFftPlanner::new().plan_fft_forward(2 * BLOCK_SIZE);
+        // the transforms aren't normalised, the inverse one of the
+        // convolution is scaled here once instead
+        let scale = 1.0 / (2 * BLOCK_SIZE) as f64;
+        let channels = channels
+            .iter()
+            .map(|response| {
+                response[..response.len().min(MAX_LENGTH)]
+                    .chunks(BLOCK_SIZE)
+                    .map(|partition| {
+                        let mut input = vec![0.0; 2 * BLOCK_SIZE];
+                        input[..partition.len()].copy_from_slice(partition);
+                        let mut spectrum = forward.make_output_vec();
+                        forward.process(&mut input, &mut spectrum).unwrap();
+                        for bin in &mut spectrum {
+                            *bin *= scale;
+                        }
+                        spectrum
+                    })
+                    .collect()
+            })
+            .collect();
+        Self { channels }
+    }
+
+    /// The partitions applied to the channel, a mono response applying to
+    /// both.
+    fn partitions(&self, channel: usize) -> &[Vec<Complex<f64>>] {
+        &self.channels[channel.min(self.channels.len() - 1)]
+    }
+}
+
+/// Convolves the interleaved stereo audio with a filter, by uniformly
+/// partitioned overlap-save: each block of the input is transformed once,
+/// and multiplied with each partition of the response in the frequency
+/// domain, so that long responses stay cheap enough for e.g. a Raspberry Pi.
+pub(crate) struct Convolver {
+    filter: Arc<Filter>,
+    forward: Arc<dyn RealToComplex<f64>>,
+    inverse: Arc<dyn ComplexToReal<f64>>,
+    /// The previous and the current block of each channel's input.
+    input: Vec<Vec<f64>>,
+    /// The spectra of the recent blocks of each channel's input, the latest
+    /// first, one for each partition of the filter.
+    spectra: Vec<VecDeque<Vec<Complex<f64>>>>,
+    /// The output of the previous block, interleaved.
+    output: Vec<f64>,
+    /// How many frames of the current block have been received.
+    position: usize,
+    buffer: Vec<f64>,
+    sum: Vec<Complex<f64>>,
+}
+
+impl Convolver {
+    pub(crate) fn new(filter: Arc<Filter>) -> Self {
+        let mut planner = RealFftPlanner::new();
+        let forward = planner.plan_fft_forward(2 * BLOCK_SIZE);
+        let inverse = planner.plan_fft_inverse(2 * BLOCK_SIZE);
+        let channels = NUM_CHANNELS as usize;
+        let spectra = (0..channels)
+            .map(|channel| {
+                (0..filter.partitions(channel).len())
+                    .map(|_| forward.make_output_vec())
+                    .collect()
+            })
+            .collect();
+        Self {
+            input: vec![vec![0.0; 2 * BLOCK_SIZE]; channels],
+            spectra,
+            output: vec![0.0; channels * BLOCK_SIZE],
+            position: 0,
+            buffer: forward.make_input_vec(),
+            sum: forward.make_output_vec(),
+            filter,
+            forward,
+            inverse,
+        }
+    }
+
+    fn convolve(&mut self) {
+        let channels = self.input.len();
+        for channel in 0..channels {
+            let input = &mut self.input[channel];
+            self.buffer.copy_from_slice(input);
+            input.copy_within(BLOCK_SIZE.., 0);
+
+            let spectra = &mut self.spectra[channel];
+            let mut spectrum = spectra.pop_back().unwrap();
+            self.forward
+                .process(&mut self.buffer, &mut spectrum)
+                .unwrap();
+            spectra.push_front(spectrum);
+
+            self.sum.fill(Complex::default());
+            for (spectrum, partition) in spectra.iter().zip(self.filter.partitions(channel)) {
+                for ((sum, x), h) in self.sum.iter_mut().zip(spectrum).zip(partition) {
+                    *sum += x * h;
+                }
+            }
+            // the inverse transform of a real signal, rounding aside
+            self.sum[0].im = 0.0;
+            self.sum[BLOCK_SIZE].im = 0.0;
+            self.inverse
+                .process(&mut self.sum, &mut self.buffer)
+                .unwrap();
+
+            // the first half wrapped around, only the second one is valid
+            for (frame, sample) in self.buffer[BLOCK_SIZE..].iter().enumerate() {
+                self.output[frame * channels + channel] = *sample;
+            }
+        }
+    }
+
+    pub(crate) fn process(&mut self, samples: &mut [f64]) {
+        let channels = self.input.len();
+        for frame in samples.chunks_exact_mut(channels) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                self.input[channel][BLOCK_SIZE + self.position] = *sample;
+                *sample = self.output[self.position * channels + channel];
+            }
+            self.position += 1;
+            if self.position == BLOCK_SIZE {
+                self.convolve();
+                self.position = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convolution() {
+        // a response longer than a partition
+        let mut response = vec![0.0; BLOCK_SIZE + 8];
+        response[0] = 1.0;
+        response[1] = 0.5;
+        response[BLOCK_SIZE + 3] = 0.25;
+        let mut convolver = Convolver::new(Arc::new(Filter::new(&[response])));
+
+        let mut samples = vec![0.0; 2 * 3 * BLOCK_SIZE];
+        samples[0] = 1.0;
+        samples[3] = -1.0;
+        // in several writes, not aligned with the blocks
+        for chunk in samples.chunks_mut(2 * 300) {
+            convolver.process(chunk);
+        }
+        let frame = |i: usize| {
+            (
+                samples[2 * (BLOCK_SIZE + i)],
+                samples[2 * (BLOCK_SIZE + i) + 1],
+            )
+        };
+        let close = |(left, right): (f64, f64), (l, r): (f64, f64)| {
+            (left - l).abs() < 1e-9 && (right - r).abs() < 1e-9
+        };
+        assert!(samples[..2 * BLOCK_SIZE].iter().all(|s| *s == 0.0));
+        assert!(close(frame(0), (1.0, 0.0)));
+        assert!(close(frame(1), (0.5, -1.0)));
+        assert!(close(frame(2), (0.0, -0.5)));
+        assert!(close(frame(BLOCK_SIZE + 3), (0.25, 0.0)));
+        assert!(close(frame(BLOCK_SIZE + 4), (0.0, -0.25)));
+        assert!(close(frame(BLOCK_SIZE + 5), (0.0, 0.0)));
+    }
+}
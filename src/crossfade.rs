@@ -0,0 +1,274 @@
+use crate::events::SpotifydEvent;
+use librespot_playback::{
+    audio_backend::{Sink, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+    player::PlayerEventChannel,
+    NUM_CHANNELS, SAMPLE_RATE,
+};
+use std::{
+    f64::consts::FRAC_PI_2,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// The events of the player, handed to the sink once the player exists.
+pub(crate) type PlayerEventSlot = Arc<Mutex<Option<PlayerEventChannel>>>;
+
+const CHANNELS: usize = NUM_CHANNELS as usize;
+
+fn frames(ms: u32) -> u64 {
+    ms as u64 * SAMPLE_RATE as u64 / 1000
+}
+
+/// The tail of the previous track, fading out over the start of the next.
+struct Fade {
+    tail: Vec<f64>,
+    /// How many samples of the tail have been mixed in.
+    mixed: usize,
+}
+
+impl Fade {
+    /// Mixes the tail into the samples with equal power, and returns whether
+    /// the whole tail has been mixed in.
+    fn mix(&mut self, samples: &mut [f64]) -> bool {
+        let frames = self.tail.len() / CHANNELS;
+        for (i, frame) in samples.chunks_exact_mut(CHANNELS).enumerate() {
+            let offset = self.mixed + i * CHANNELS;
+            if offset >= self.tail.len() {
+                break;
+            }
+            let t = (offset / CHANNELS) as f64 / frames as f64 * FRAC_PI_2;
+            let (fade_in, fade_out) = t.sin_cos();
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                *sample = *sample * fade_in + self.tail[offset + channel] * fade_out;
+            }
+        }
+        self.mixed = (self.mixed + samples.len()).min(self.tail.len());
+        self.mixed == self.tail.len()
+    }
+}
+
+/// Follows the position in the current track through the events of the
+/// player, which it sends before the samples they concern, to hold back the
+/// last samples of the track until the next one starts.
+struct Crossfader {
+    fade_frames: u64,
+    /// The length of the current track.
+    duration_frames: Option<u64>,
+    /// How many frames of the current track have been written.
+    position: u64,
+    /// The samples held back at the end of the current track.
+    tail: Vec<f64>,
+    /// Whether the current track ended, so that the next samples are the
+    /// start of the next track.
+    ended: bool,
+    fade: Option<Fade>,
+}
+
+impl Crossfader {
+    fn new(crossfade: Duration) -> Self {
+        Self {
+            fade_frames: frames(crossfade.as_millis() as u32),
+            duration_frames: None,
+            position: 0,
+            tail: Vec::new(),
+            ended: false,
+            fade: None,
+        }
+    }
+
+    fn observe(&mut self, event: &SpotifydEvent) {
+        match *event {
+            SpotifydEvent::TrackChanged(ref info) => {
+                self.duration_frames = Some(frames(info.duration_ms));
+            }
+            SpotifydEvent::EndOfTrack { .. } => self.ended = true,
+            SpotifydEvent::Playing { position_ms, .. }
+            | SpotifydEvent::Paused { position_ms, .. }
+            | SpotifydEvent::PositionCorrection { position_ms, .. } => {
+                self.position = frames(position_ms);
+            }
+            SpotifydEvent::Loading { position_ms, .. } => {
+                // another track was loaded before this one ended
+                if !self.ended {
+                    self.tail.clear();
+                }
+                self.position = frames(position_ms);
+            }
+            SpotifydEvent::Seeked { position_ms, .. } => {
+                self.tail.clear();
+                self.position = frames(position_ms);
+            }
+            SpotifydEvent::Stopped { .. } => {
+                self.tail.clear();
+                self.fade = None;
+                self.ended = false;
+            }
+            _ => (),
+        }
+    }
+
+    /// Where the samples of the current track start being held back.
+    fn tail_start(&self) -> Option<u64> {
+        let duration = self.duration_frames?;
+        // too short to overlap with both of its neighbours
+        (duration >= 2 * self.fade_frames).then(|| duration - self.fade_frames)
+    }
+
+    /// Returns the samples to write, with the start of a track faded in over
+    /// the tail of the previous one and without its own tail.
+    fn process(&mut self, mut samples: Vec<f64>) -> Vec<f64> {
+        if std::mem::take(&mut self.ended) && !self.tail.is_empty() {
+            self.fade = Some(Fade {
+                tail: std::mem::take(&mut self.tail),
+                mixed: 0,
+            });
+        }
+        if let Some(ref mut fade) = self.fade {
+            if fade.mix(&mut samples) {
+                self.fade = None;
+            }
+        }
+
+        let start = self.position;
+        self.position += (samples.len() / CHANNELS) as u64;
+        let Some(tail_start) = self.tail_start() else {
+            return samples;
+        };
+        let kept = tail_start.saturating_sub(start) as usize * CHANNELS;
+        if kept >= samples.len() {
+            return samples;
+        }
+        self.tail.extend(samples.drain(kept..));
+        // a track longer than announced plays on, only its end is held back
+        let excess = self
+            .tail
+            .len()
+            .saturating_sub(self.fade_frames as usize * CHANNELS);
+        samples.extend(self.tail.drain(..excess));
+        samples
+    }
+
+    /// The held back tail, once the track ended without a next one.
+    fn flush(&mut self) -> Option<Vec<f64>> {
+        if !self.ended || self.tail.is_empty() {
+            return None;
+        }
+        self.ended = false;
+        Some(std::mem::take(&mut self.tail))
+    }
+}
+
+/// A sink crossfading consecutive tracks, by holding back the end of each
+/// track and mixing it into the start of the next one.
+pub(crate) struct CrossfadeSink {
+    inner: Box<dyn Sink>,
+    slot: PlayerEventSlot,
+    events: Option<PlayerEventChannel>,
+    crossfader: Crossfader,
+}
+
+impl CrossfadeSink {
+    pub(crate) fn new(inner: Box<dyn Sink>, crossfade: Duration, slot: PlayerEventSlot) -> Self {
+        Self {
+            inner,
+            slot,
+            events: None,
+            crossfader: Crossfader::new(crossfade),
+        }
+    }
+
+    /// Catches up with the events the player sent before its next samples.
+    fn observe_events(&mut self) {
+        if self.events.is_none() {
+            self.events = self.slot.lock().unwrap().take();
+        }
+        let Some(ref mut events) = self.events else {
+            return;
+        };
+        while let Ok(event) = events.try_recv() {
+            self.crossfader.observe(&event.into());
+        }
+    }
+}
+
+impl Sink for CrossfadeSink {
+    fn start(&mut self) -> SinkResult<()> {
+        self.inner.start()
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        self.observe_events();
+        // the end of the context, there's no next track to fade into
+        if let Some(tail) = self.crossfader.flush() {
+            let mut converter = Converter::new(None);
+            self.inner
+                .write(AudioPacket::Samples(tail), &mut converter)?;
+        }
+        self.inner.stop()
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        self.observe_events();
+        match packet {
+            AudioPacket::Samples(samples) => {
+                let samples = self.crossfader.process(samples);
+                if samples.is_empty() {
+                    return Ok(());
+                }
+                self.inner.write(AudioPacket::Samples(samples), converter)
+            }
+            // passed through undecoded, there are no samples to mix
+            packet => self.inner.write(packet, converter),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::TrackInfo;
+
+    #[test]
+    fn test_crossfade() {
+        // 10 ms of fade in tracks of 40 ms
+        let mut crossfader = Crossfader::new(Duration::from_millis(10));
+        let fade = frames(10) as usize * CHANNELS;
+        let track = || {
+            SpotifydEvent::TrackChanged(TrackInfo {
+                duration_ms: 40,
+                ..Default::default()
+            })
+        };
+        crossfader.observe(&track());
+        let written = crossfader.process(vec![1.0; frames(40) as usize * CHANNELS]);
+        assert_eq!(written.len(), frames(30) as usize * CHANNELS);
+
+        crossfader.observe(&SpotifydEvent::EndOfTrack {
+            play_request_id: 1,
+            track_id: "4uLU6hMCjMI75M1A2tKUQC".to_string(),
+        });
+        crossfader.observe(&track());
+        crossfader.observe(&SpotifydEvent::Playing {
+            play_request_id: 2,
+            track_id: "2takcwOaAZWiXQijPHIx7B".to_string(),
+            position_ms: 0,
+        });
+        let written = crossfader.process(vec![0.0; frames(20) as usize * CHANNELS]);
+        assert_eq!(written.len(), frames(20) as usize * CHANNELS);
+        // the previous track fades out over the start of this one
+        assert_eq!(written[0], 1.0);
+        assert!(written[fade / 2] > 0.5 && written[fade / 2] < 1.0);
+        assert_eq!(written[fade], 0.0);
+
+        // the end of the context plays out without fading
+        crossfader.process(vec![0.5; frames(20) as usize * CHANNELS]);
+        assert_eq!(crossfader.flush(), None);
+        crossfader.observe(&SpotifydEvent::EndOfTrack {
+            play_request_id: 2,
+            track_id: "2takcwOaAZWiXQijPHIx7B".to_string(),
+        });
+        assert_eq!(crossfader.flush(), Some(vec![0.5; fade]));
+    }
+}
@@ -0,0 +1,345 @@
+use crate::{
+    blocklist,
+    config::{CtlAction, CtlArgs, LibraryKind, SpotifydConfig, Switch},
+    control::ControlCommand,
+    search::{self, Hit},
+    setup,
+    state::PlaybackState,
+    web_api::{self, RemoteControl, SpotifyUri},
+};
+use color_eyre::eyre::{self, eyre};
+use librespot_core::{session::Session, Error};
+use rspotify::{
+    model::{
+        Market, PlayableItem, PlaylistId, SearchResult, SearchType, SimplifiedArtist,
+        SimplifiedPlaylist,
+    },
+    prelude::*,
+    AuthCodeSpotify, ClientResult,
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// What `spotifyd ctl` does with the devices.
+enum Request {
+    Command(ControlCommand),
+    Devices,
+    Transfer(String),
+    Open(String),
+    MadeForYou(String),
+    BlockCurrent { blocklist: PathBuf, artist: bool },
+    Library { kind: LibraryKind, json: bool },
+    Lock(bool),
+    Discoverable(bool),
+    ForgetUser(String),
+}
+
+impl Request {
+    fn new(action: &CtlAction, config: &SpotifydConfig) -> eyre::Result<Self> {
+        let command = match *action {
+            CtlAction::Play => ControlCommand::Play,
+            CtlAction::Pause => ControlCommand::Pause,
+            CtlAction::PlayPause => ControlCommand::PlayPause,
+            CtlAction::Next => ControlCommand::Next,
+            CtlAction::Previous => ControlCommand::Prev,
+            CtlAction::Volume { volume } => ControlCommand::SetVolume(volume.volume()),
+            CtlAction::VolumeUp { .. } => ControlCommand::VolumeUp,
+            CtlAction::VolumeDown { .. } => ControlCommand::VolumeDown,
+            CtlAction::Devices => return Ok(Request::Devices),
+            CtlAction::Transfer { ref target } => {
+                return Ok(Request::Transfer(
+                    target.clone().unwrap_or_else(|| config.device_name.clone()),
+                ))
+            }
+            CtlAction::Open { ref uri } => {
+                SpotifyUri::parse(uri).map_err(|e| eyre!("invalid URI {}: {}", uri, e))?;
+                return Ok(Request::Open(uri.clone()));
+            }
+            CtlAction::PlayMix { number } => {
+                if !(1..=6).contains(&number) {
+                    return Err(eyre!("there are Daily Mixes 1 to 6, not {}", number));
+                }
+                return Ok(Request::MadeForYou(format!("Daily Mix {}", number)));
+            }
+            CtlAction::PlayDiscoverWeekly => {
+                return Ok(Request::MadeForYou("Discover Weekly".to_string()))
+            }
+            CtlAction::Library { kind, json } => return Ok(Request::Library { kind, json }),
+            CtlAction::Lock => return Ok(Request::Lock(true)),
+            CtlAction::Unlock => return Ok(Request::Lock(false)),
+            CtlAction::Discoverable { state } => {
+                return Ok(Request::Discoverable(state == Switch::On))
+            }
+            CtlAction::ForgetUser { ref username } => {
+                return Ok(Request::ForgetUser(username.clone()))
+            }
+            CtlAction::BlockCurrent { artist } => {
+                let blocklist = config
+                    .blocklist
+                    .clone()
+                    .ok_or_else(|| eyre!("no blocklist is configured"))?;
+                return Ok(Request::BlockCurrent { blocklist, artist });
+            }
+        };
+        Ok(Request::Command(command))
+    }
+}
+
+/// Prints one line per device, e.g. `* Living Room (Speaker, 40%)`.
+fn print_devices(client: &AuthCodeSpotify) -> Result<(), Error> {
+    for device in client.device().map_err(Error::unavailable)? {
+        let volume = device
+            .volume_percent
+            .map(|percent| format!(", {}%", percent))
+            .unwrap_or_default();
+        println!(
+            "{} {} ({:?}{})",
+            if device.is_active { "*" } else { " " },
+            device.name,
+            device._type,
+            volume
+        );
+    }
+    Ok(())
+}
+
+/// Finds the account's personalized playlist with the name, e.g.
+/// `Daily Mix 1`, among the followed playlists or, as the made-for-you ones
+/// are only listed there once they're saved, the results of a search.
+fn made_for_you(client: &AuthCodeSpotify, name: &str) -> Result<PlaylistId<'static>, Error> {
+    let is_match = |playlist: &SimplifiedPlaylist| {
+        playlist.owner.id.id() == "spotify" && playlist.name.eq_ignore_ascii_case(name)
+    };
+    for playlist in client.current_user_playlists() {
+        let playlist = playlist.map_err(Error::unavailable)?;
+        if is_match(&playlist) {
+            return Ok(playlist.id);
+        }
+    }
+    let result = client
+        .search(
+            name,
+            SearchType::Playlist,
+            Some(Market::FromToken),
+            None,
+            Some(20),
+            None,
+        )
+        .map_err(Error::unavailable)?;
+    if let SearchResult::Playlists(page) = result {
+        if let Some(playlist) = page.items.into_iter().find(is_match) {
+            return Ok(playlist.id);
+        }
+    }
+    Err(Error::not_found(format!(
+        "could not find the playlist {:?}",
+        name
+    )))
+}
+
+/// The saved items of the kind, in the order of the library.
+fn library(client: &AuthCodeSpotify, kind: LibraryKind) -> Result<Vec<Hit>, Error> {
+    let artists =
+        |artists: &[SimplifiedArtist]| search::join_names(artists.iter().map(|a| a.name.as_str()));
+    let items: ClientResult<Vec<_>> = match kind {
+        LibraryKind::Playlists => client
+            .current_user_playlists()
+            .map(|playlist| {
+                let playlist = playlist?;
+                Ok(Some(Hit {
+                    by: playlist.owner.display_name,
+                    uri: playlist.id.uri(),
+                    name: playlist.name,
+                }))
+            })
+            .collect(),
+        LibraryKind::Albums => client
+            .current_user_saved_albums(Some(Market::FromToken))
+            .map(|saved| {
+                let album = saved?.album;
+                Ok(Some(Hit {
+                    by: artists(&album.artists),
+                    uri: album.id.uri(),
+                    name: album.name,
+                }))
+            })
+            .collect(),
+        LibraryKind::Liked => client
+            .current_user_saved_tracks(Some(Market::FromToken))
+            .map(|saved| {
+                let track = saved?.track;
+                // local files have no id
+                Ok(track.id.map(|id| Hit {
+                    by: artists(&track.artists),
+                    uri: id.uri(),
+                    name: track.name,
+                }))
+            })
+            .collect(),
+    };
+    Ok(items
+        .map_err(Error::unavailable)?
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+/// Prints the saved items of the kind, one per line or as a JSON array of
+/// objects with their `name`, `by` and `uri`, for front-ends building a
+/// picker.
+fn print_library(client: &AuthCodeSpotify, kind: LibraryKind, json: bool) -> Result<(), Error> {
+    let items = library(client, kind)?;
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string(&items).map_err(Error::internal)?
+        );
+    } else {
+        for (index, item) in items.iter().enumerate() {
+            println!("{}", item.line(index + 1));
+        }
+    }
+    Ok(())
+}
+
+/// Adds the current track of the active device, or its first artist, to the
+/// blocklist and skips it.
+fn block_current(client: &AuthCodeSpotify, blocklist: &Path, artist: bool) -> Result<(), Error> {
+    let item = client
+        .current_playing(None, None::<Vec<_>>)
+        .map_err(Error::unavailable)?
+        .and_then(|playing| playing.item)
+        .ok_or_else(|| Error::failed_precondition("nothing is playing"))?;
+    let PlayableItem::Track(track) = item else {
+        return Err(Error::unimplemented("only tracks can be blocked"));
+    };
+    let uri = if artist {
+        track
+            .artists
+            .first()
+            .and_then(|a| a.id.as_ref())
+            .map(|id| id.uri())
+    } else {
+        track.id.as_ref().map(|id| id.uri())
+    }
+    .ok_or_else(|| Error::not_found("the current track has no URI"))?;
+
+    blocklist::append(blocklist, &uri)?;
+    println!("Blocked {}", uri);
+    client.next_track(None).map_err(Error::unavailable)
+}
+
+/// Calls the method of the D-Bus interface of the running instances, for the
+/// commands that are handled by the daemon rather than the account, like the
+/// "do not disturb" lock.
+#[cfg(feature = "dbus_mpris")]
+fn call_instances<A>(config: &SpotifydConfig, method: &str, args: A) -> eyre::Result<()>
+where
+    A: dbus::arg::AppendAll + Clone,
+{
+    use crate::config::DBusType;
+    use dbus::blocking::Connection;
+    use std::time::Duration;
+
+    let timeout = Duration::from_secs(5);
+    let connection = match config.dbus_type {
+        DBusType::Session => Connection::new_session(),
+        DBusType::System => Connection::new_system(),
+    }?;
+    let bus = connection.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", timeout);
+    let (names,): (Vec<String>,) = bus.method_call("org.freedesktop.DBus", "ListNames", ())?;
+    let instances: Vec<_> = names
+        .into_iter()
+        .filter(|name| name.starts_with("org.mpris.MediaPlayer2.spotifyd"))
+        .collect();
+    if instances.is_empty() {
+        return Err(eyre!("no spotifyd instance with MPRIS enabled is running"));
+    }
+    for name in instances {
+        connection
+            .with_proxy(name, "/rs/spotifyd/Controls", timeout)
+            .method_call::<(), _, _, _>("rs.spotifyd.Controls", method, args.clone())?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "dbus_mpris"))]
+fn call_instances<A>(_config: &SpotifydConfig, _method: &str, _args: A) -> eyre::Result<()> {
+    Err(eyre!(
+        "the running spotifyd is reached via D-Bus, which requires the dbus_mpris feature"
+    ))
+}
+
+/// A client of the Web API, authorized by a session of its own with the
+/// configured or cached credentials.
+pub(crate) async fn connect(config: &SpotifydConfig) -> eyre::Result<AuthCodeSpotify> {
+    let credentials = setup::configured_credentials(config).ok_or_else(|| {
+        eyre!("this command requires configured credentials or credentials cached by the daemon")
+    })?;
+    let session = Session::new(config.session_config.clone(), config.cache.clone());
+    session
+        .connect(credentials, false)
+        .await
+        .map_err(|e| eyre!("failed to connect to spotify: {}", e))?;
+    web_api::client(&session)
+        .await
+        .map_err(|e| eyre!("failed to get a token for the Web API: {}", e))
+}
+
+/// Sends the command to a device of the account.
+pub async fn run(config: &SpotifydConfig, args: &CtlArgs) -> eyre::Result<()> {
+    let request = Request::new(&args.action, config)?;
+    // the lock, the discovery and the credentials are the daemon's, not the
+    // account's
+    match request {
+        Request::Lock(true) => return call_instances(config, "Lock", ()),
+        Request::Lock(false) => return call_instances(config, "Unlock", ()),
+        Request::Discoverable(discoverable) => {
+            return call_instances(config, "Discoverable", (discoverable,))
+        }
+        Request::ForgetUser(ref username) => {
+            return call_instances(config, "ForgetUser", (username.as_str(),))
+        }
+        _ => {}
+    }
+    let client = Arc::new(connect(config).await?);
+
+    let device = args.device.clone();
+    let device_name = config.device_name.clone();
+    let volume_step = match args.action {
+        CtlAction::VolumeUp { step } | CtlAction::VolumeDown { step } => step,
+        _ => None,
+    }
+    .or(config.volume_step);
+    tokio::task::spawn_blocking(move || match request {
+        Request::Devices => print_devices(&client),
+        Request::Transfer(target) => web_api::transfer(&client, &target),
+        Request::Open(uri) => {
+            let device_id = web_api::device_id(&client, &device.unwrap_or(device_name))?;
+            let uri = SpotifyUri::parse(&uri).map_err(Error::invalid_argument)?;
+            web_api::open_uri(&client, &device_id, uri).map_err(Error::unavailable)
+        }
+        Request::MadeForYou(name) => {
+            let device_id = web_api::device_id(&client, &device.unwrap_or(device_name))?;
+            let playlist = made_for_you(&client, &name)?;
+            println!("Playing {}", name);
+            web_api::open_uri(&client, &device_id, SpotifyUri::Context(playlist.into()))
+                .map_err(Error::unavailable)
+        }
+        Request::BlockCurrent { blocklist, artist } => block_current(&client, &blocklist, artist),
+        Request::Library { kind, json } => print_library(&client, kind, json),
+        Request::Lock(_) | Request::Discoverable(_) | Request::ForgetUser(_) => unreachable!(),
+        Request::Command(command) => {
+            let device_id = device
+                .map(|name| web_api::device_id(&client, &name))
+                .transpose()?;
+            let control = RemoteControl::new(client, device_id, volume_step);
+            command.apply(&control, &PlaybackState::default())
+        }
+    })
+    .await
+    .unwrap()
+    .map_err(|e| eyre!("{}", e))
+}
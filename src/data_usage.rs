@@ -0,0 +1,282 @@
+use crate::{config::SpotifydConfig, metered::Metered};
+use chrono::{Duration as DateDuration, Local, NaiveDate};
+use color_eyre::eyre::{self, WrapErr};
+use librespot_playback::{
+    audio_backend::{Sink, SinkResult},
+    config::Bitrate,
+    convert::Converter,
+    decoder::AudioPacket,
+    NUM_CHANNELS, SAMPLE_RATE,
+};
+use log::warn;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How often the usage is written to the file at most.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// How many days of usage are kept in the file.
+const KEPT_DAYS: i64 = 92;
+
+/// The bytes downloaded on each day, by the date like `2024-05-01`, which
+/// sorts them by date.
+type Days = BTreeMap<String, u64>;
+
+fn date_key(date: NaiveDate) -> String {
+    date.format("%Y-%m-%d").to_string()
+}
+
+fn month_key(date: NaiveDate) -> String {
+    date.format("%Y-%m").to_string()
+}
+
+fn read_days(path: &Path) -> io::Result<Days> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Days::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// How much data has been downloaded.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    /// Since the current session connected.
+    pub session_bytes: u64,
+    pub today_bytes: u64,
+    pub month_bytes: u64,
+    pub monthly_cap_bytes: Option<u64>,
+}
+
+impl Usage {
+    fn on(days: &Days, today: NaiveDate) -> Self {
+        let month = month_key(today);
+        Self {
+            today_bytes: days.get(&date_key(today)).copied().unwrap_or(0),
+            month_bytes: days
+                .iter()
+                .filter(|(date, _)| date.starts_with(&month))
+                .map(|(_, bytes)| bytes)
+                .sum(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Counters {
+    days: Days,
+    session: u64,
+    last_save: Instant,
+}
+
+/// The data downloaded for the playback, estimated from the bitrate and the
+/// length of the audio played. The bytes of each day are kept in the file at
+/// the given path, so that the usage of the month is counted across restarts.
+///
+/// Once the usage of the month reaches the cap, the connection is treated as
+/// metered until the next month.
+#[derive(Debug)]
+pub struct DataUsage {
+    path: Option<PathBuf>,
+    monthly_cap: Option<u64>,
+    metered: Arc<Metered>,
+    counters: Mutex<Counters>,
+}
+
+impl DataUsage {
+    pub(crate) fn load(
+        path: Option<PathBuf>,
+        monthly_cap: Option<u64>,
+        metered: Arc<Metered>,
+    ) -> Self {
+        let mut days = match path.as_deref().map(read_days) {
+            Some(Ok(days)) => days,
+            Some(Err(e)) => {
+                warn!("Ignoring the invalid data usage: {}", e);
+                Days::new()
+            }
+            None => Days::new(),
+        };
+        let oldest = date_key(Local::now().date_naive() - DateDuration::days(KEPT_DAYS));
+        days.retain(|date, _| *date >= oldest);
+        let usage = Self {
+            path,
+            monthly_cap,
+            metered,
+            counters: Mutex::new(Counters {
+                days,
+                session: 0,
+                last_save: Instant::now(),
+            }),
+        };
+        usage.check_cap(&usage.usage());
+        usage
+    }
+
+    fn check_cap(&self, usage: &Usage) {
+        if let Some(cap) = self.monthly_cap {
+            self.metered.set_capped(usage.month_bytes >= cap);
+        }
+    }
+
+    pub(crate) fn start_session(&self) {
+        self.counters.lock().unwrap().session = 0;
+    }
+
+    pub(crate) fn add(&self, bytes: u64) {
+        let save = {
+            let mut counters = self.counters.lock().unwrap();
+            counters.session += bytes;
+            *counters
+                .days
+                .entry(date_key(Local::now().date_naive()))
+                .or_default() += bytes;
+            counters.last_save.elapsed() >= SAVE_INTERVAL
+        };
+        if save {
+            self.check_cap(&self.usage());
+            self.save();
+        }
+    }
+
+    pub fn usage(&self) -> Usage {
+        let counters = self.counters.lock().unwrap();
+        Usage {
+            session_bytes: counters.session,
+            monthly_cap_bytes: self.monthly_cap,
+            ..Usage::on(&counters.days, Local::now().date_naive())
+        }
+    }
+
+    pub(crate) fn save(&self) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.last_save = Instant::now();
+        let Some(ref path) = self.path else {
+            return;
+        };
+        let content = serde_json::to_string(&counters.days).unwrap();
+        if let Err(e) = fs::write(path, content) {
+            warn!("Failed to write {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// The bytes of the encoded audio for each decoded sample, at the bitrate.
+fn bytes_per_sample(bitrate: Bitrate) -> f64 {
+    let kbps = match bitrate {
+        Bitrate::Bitrate96 => 96,
+        Bitrate::Bitrate160 => 160,
+        Bitrate::Bitrate320 => 320,
+    };
+    kbps as f64 * 1000.0 / 8.0 / (SAMPLE_RATE * NUM_CHANNELS as u32) as f64
+}
+
+/// A sink counting the data of the audio written to it.
+pub(crate) struct UsageSink {
+    inner: Box<dyn Sink>,
+    usage: Arc<DataUsage>,
+    bytes_per_sample: f64,
+}
+
+impl UsageSink {
+    pub(crate) fn new(inner: Box<dyn Sink>, usage: Arc<DataUsage>, bitrate: Bitrate) -> Self {
+        Self {
+            inner,
+            usage,
+            bytes_per_sample: bytes_per_sample(bitrate),
+        }
+    }
+}
+
+impl Sink for UsageSink {
+    fn start(&mut self) -> SinkResult<()> {
+        self.inner.start()
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        self.inner.stop()
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        let bytes = match packet {
+            AudioPacket::Samples(ref samples) => {
+                (samples.len() as f64 * self.bytes_per_sample) as u64
+            }
+            // passed through undecoded, as it was downloaded
+            AudioPacket::Raw(ref bytes) => bytes.len() as u64,
+        };
+        self.usage.add(bytes);
+        self.inner.write(packet, converter)
+    }
+}
+
+/// Formats the bytes in megabytes, e.g. `12.3 MB`.
+fn megabytes(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / 1_000_000.0)
+}
+
+/// Prints the data downloaded on each day of the month, as recorded by the
+/// running spotifyd.
+pub fn print_stats(config: &SpotifydConfig) -> eyre::Result<()> {
+    let Some(ref path) = config.data_usage else {
+        eyre::bail!("the data usage is only recorded with a cache_path");
+    };
+    let days = read_days(path).wrap_err_with(|| format!("could not read {}", path.display()))?;
+    let today = Local::now().date_naive();
+    let month = month_key(today);
+    for (date, bytes) in days.iter().filter(|(date, _)| date.starts_with(&month)) {
+        println!("{}  {:>10}", date, megabytes(*bytes));
+    }
+    let usage = Usage::on(&days, today);
+    println!("Today: {}", megabytes(usage.today_bytes));
+    match config.data_cap {
+        Some(cap) => println!(
+            "This month: {} of {}",
+            megabytes(usage.month_bytes),
+            megabytes(cap)
+        ),
+        None => println!("This month: {}", megabytes(usage.month_bytes)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage() {
+        let days: Days = [
+            ("2024-04-30".to_string(), 1000),
+            ("2024-05-01".to_string(), 2000),
+            ("2024-05-02".to_string(), 3000),
+        ]
+        .into_iter()
+        .collect();
+        let usage = Usage::on(&days, NaiveDate::from_ymd_opt(2024, 5, 2).unwrap());
+        assert_eq!(usage.today_bytes, 3000);
+        assert_eq!(usage.month_bytes, 5000);
+
+        // a minute at 160 kbit/s
+        let samples = 60.0 * (SAMPLE_RATE * NUM_CHANNELS as u32) as f64;
+        let bytes = samples * bytes_per_sample(Bitrate::Bitrate160);
+        assert!((bytes - 1_200_000.0).abs() < 1.0);
+        assert_eq!(megabytes(1_234_567), "1.2 MB");
+
+        let metered = Arc::new(Metered::new(crate::config::MeteredMode::Off));
+        let usage = DataUsage::load(None, Some(1000), metered.clone());
+        assert!(!metered.is_capped());
+        usage.add(1000);
+        usage.counters.lock().unwrap().last_save -= SAVE_INTERVAL;
+        usage.add(1);
+        assert!(metered.is_capped());
+        assert_eq!(usage.usage().session_bytes, 1001);
+    }
+}
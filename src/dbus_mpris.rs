@@ -1,4 +1,19 @@
-use crate::config::DBusType;
+use crate::{
+    audit::{apply_audited, AuditEntry, CommandClient, CommandSource, SharedAuditLog},
+    cache,
+    cache_layout::CacheLayout,
+    config::{DBusType, MprisQuit, VolumeStep},
+    control::{ControlCommand, PlaybackControl},
+    discoverable::Discoverable,
+    encryption::EncryptedCredentials,
+    events::{EventBus, EventSubscriber, SpotifydEvent},
+    lock::{DoNotDisturb, LockOwner},
+    process::run_program,
+    profiles::Profiles,
+    sleep_timer::SleepTimer,
+    state::{PlaybackState, SharedPlaybackState},
+    web_api::{self, SpotifyUri},
+};
 use chrono::{prelude::*, Duration};
 use dbus::{
     arg::{RefArg, Variant},
@@ -13,61 +28,90 @@ use futures::{
     task::{Context, Poll},
     Future,
 };
-use librespot_connect::spirc::Spirc;
 use librespot_core::{
     keymaster::{get_token, Token as LibrespotToken},
     mercury::MercuryError,
     session::Session,
-    spotify_id::SpotifyAudioType,
+    Error,
 };
-use librespot_playback::player::PlayerEvent;
 use log::{error, info, warn};
 use rspotify::{
-    model::{
-        offset::Offset, parse_uri, AlbumId, ArtistId, EpisodeId, IdError, PlayableItem, PlaylistId,
-        RepeatState, ShowId, TrackId, Type,
-    },
+    model::{EpisodeId, PlayableItem, PlaylistId, TrackId},
     prelude::*,
-    AuthCodeSpotify, Token as RspotifyToken,
+    AuthCodeSpotify, ClientError, Token as RspotifyToken,
+};
+use std::{
+    collections::HashMap,
+    env,
+    pin::Pin,
+    sync::{Arc, Mutex},
 };
-use std::{collections::HashMap, env, pin::Pin, sync::Arc};
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::{mpsc::UnboundedSender, Notify};
+
+/// What the `Raise` and `Quit` methods of the MPRIS interface, and the `Lock`,
+/// `Unlock`, `Discoverable`, `ForgetUser`, `SwitchProfile` and `Sleep` methods
+/// of spotifyd's own one, do.
+#[derive(Clone, Debug)]
+pub(crate) struct MprisActions {
+    pub(crate) shell: String,
+    /// The command run to raise the player, e.g. opening the web player.
+    pub(crate) raise_cmd: Option<String>,
+    pub(crate) quit: MprisQuit,
+    pub(crate) shutdown_request: Arc<Notify>,
+    pub(crate) do_not_disturb: Arc<DoNotDisturb>,
+    pub(crate) discoverable: Arc<Discoverable>,
+    /// Where the credentials of the users are stored, to forget them.
+    pub(crate) cache_layout: Option<CacheLayout>,
+    pub(crate) encrypted_credentials: Option<EncryptedCredentials>,
+    pub(crate) use_keyring: bool,
+    /// Ends the session of a forgotten user.
+    pub(crate) forget_user: UnboundedSender<String>,
+    pub(crate) profiles: Arc<Profiles>,
+    pub(crate) sleep_timer: Arc<SleepTimer>,
+    pub(crate) volume_step: Option<VolumeStep>,
+}
 
 pub struct DbusServer {
     session: Session,
-    spirc: Arc<Spirc>,
+    control: Arc<dyn PlaybackControl + Send + Sync>,
+    actions: MprisActions,
     spotify_client: Arc<AuthCodeSpotify>,
     dbus_type: DBusType,
     #[allow(clippy::type_complexity)]
     token_request: Option<Pin<Box<dyn Future<Output = Result<LibrespotToken, MercuryError>>>>>,
     dbus_future: Option<Pin<Box<dyn Future<Output = ()>>>>,
     device_name: String,
-    event_rx: UnboundedReceiver<PlayerEvent>,
-    event_tx: Option<UnboundedSender<PlayerEvent>>,
+    event_bus: EventBus,
+    playback_state: SharedPlaybackState,
+    audit_log: SharedAuditLog,
 }
 
 const CLIENT_ID: &str = "2c1ea588dfbc4a989e2426f8385297c3";
-const SCOPE: &str =
-    "user-read-playback-state,user-modify-playback-state,user-read-currently-playing";
+const SCOPE: &str = "user-read-playback-state,user-modify-playback-state,user-read-currently-playing,playlist-read-private";
 
 impl DbusServer {
     pub fn new(
         session: Session,
-        spirc: Arc<Spirc>,
+        control: Arc<dyn PlaybackControl + Send + Sync>,
+        actions: MprisActions,
         device_name: String,
-        event_rx: UnboundedReceiver<PlayerEvent>,
+        event_bus: EventBus,
+        playback_state: SharedPlaybackState,
+        audit_log: SharedAuditLog,
         dbus_type: DBusType,
     ) -> DbusServer {
         DbusServer {
             session,
-            spirc,
+            control,
+            actions,
             spotify_client: Default::default(),
             dbus_type,
             token_request: None,
             dbus_future: None,
             device_name,
-            event_rx,
-            event_tx: None,
+            event_bus,
+            playback_state,
+            audit_log,
         }
     }
 }
@@ -76,11 +120,6 @@ impl Future for DbusServer {
     type Output = ();
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
-        if self.event_tx.is_some() {
-            if let Poll::Ready(Some(msg)) = self.event_rx.poll_recv(cx) {
-                self.event_tx.as_ref().unwrap().send(msg).unwrap();
-            }
-        }
         let needs_token = match *self.spotify_client.get_token().lock().unwrap() {
             Some(ref token) => token.is_expired(),
             None => true,
@@ -109,13 +148,14 @@ impl Future for DbusServer {
                     if self.dbus_future.is_none() {
                         self.spotify_client = Arc::new(AuthCodeSpotify::from_token(api_token));
 
-                        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-                        self.event_tx = Some(tx);
                         self.dbus_future = Some(Box::pin(create_dbus_server(
                             Arc::clone(&self.spotify_client),
-                            self.spirc.clone(),
+                            self.control.clone(),
+                            self.actions.clone(),
                             self.device_name.clone(),
-                            rx,
+                            self.event_bus.subscribe(),
+                            self.playback_state.clone(),
+                            self.audit_log.clone(),
                             self.dbus_type,
                         )));
                     } else {
@@ -148,18 +188,14 @@ impl Future for DbusServer {
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum PlaybackStatus {
-    Playing,
-    Paused,
-    Stopped,
-}
-
 async fn create_dbus_server(
     spotify_api_client: Arc<AuthCodeSpotify>,
-    spirc: Arc<Spirc>,
+    control: Arc<dyn PlaybackControl + Send + Sync>,
+    actions: MprisActions,
     device_name: String,
-    mut event_rx: UnboundedReceiver<PlayerEvent>,
+    mut events: EventSubscriber,
+    playback_state: SharedPlaybackState,
+    audit_log: SharedAuditLog,
     dbus_type: DBusType,
 ) {
     let (resource, conn) = match dbus_type {
@@ -183,6 +219,38 @@ async fn create_dbus_server(
         .await
         .expect("Failed to register dbus player name");
 
+    // applies the command, recording it in the audit log
+    let apply_command = {
+        let control = control.clone();
+        let state = playback_state.clone();
+        let audit_log = audit_log.clone();
+        let volume_step = actions.volume_step;
+        Arc::new(move |command: ControlCommand, client: CommandClient| {
+            let state = state.read().unwrap();
+            let command = command.with_volume_step(volume_step, state.volume);
+            apply_audited(
+                &audit_log,
+                CommandSource::DBus,
+                client,
+                command,
+                &*control,
+                &state,
+            )
+            .map_err(|err| MethodErr::failed(&err))
+        })
+    };
+
+    // records a command sent through the Web API in the audit log
+    let record_command = {
+        let audit_log = audit_log.clone();
+        Arc::new(
+            move |command: String, client: CommandClient, result: Result<(), ClientError>| {
+                let entry = AuditEntry::sent_by(CommandSource::DBus, client, command, &result);
+                audit_log.lock().unwrap().record(entry);
+            },
+        )
+    };
+
     let mut cr = Crossroads::new();
     cr.set_async_support(Some((
         conn.clone(),
@@ -194,27 +262,49 @@ async fn create_dbus_server(
     // The following methods and properties are part of the MediaPlayer2 interface.
     // https://specifications.freedesktop.org/mpris-spec/latest/Media_Player.html
     let media_player2_interface = cr.register("org.mpris.MediaPlayer2", |b| {
+        let raise_cmd = actions.raise_cmd.clone();
+        let shell = actions.shell.clone();
         b.method("Raise", (), (), move |_, _, (): ()| {
-            // noop
+            if let Some(ref cmd) = raise_cmd {
+                let (shell, cmd) = (shell.clone(), cmd.clone());
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = run_program(&shell, &cmd) {
+                        error!("Failed to raise the player: {}", e);
+                    }
+                });
+            }
             Ok(())
         });
-        let local_spirc = spirc.clone();
-        b.method("Quit", (), (), move |_, _, (): ()| {
-            local_spirc.shutdown();
+        let quit = actions.quit;
+        let shutdown_request = actions.shutdown_request.clone();
+        let local_audit_log = audit_log.clone();
+        b.method("Quit", (), (), move |ctx, _, (): ()| {
+            if quit == MprisQuit::Ignore {
+                return Ok(());
+            }
+            shutdown_request.notify_one();
+            local_audit_log.lock().unwrap().record(AuditEntry::sent_by(
+                CommandSource::DBus,
+                caller(ctx.message()),
+                "Quit",
+                &Ok::<_, Error>(()),
+            ));
             Ok(())
         });
+        let can_quit = actions.quit == MprisQuit::Shutdown;
         b.property("CanQuit")
             .emits_changed_const()
-            .get(|_, _| Ok(true));
+            .get(move |_, _| Ok(can_quit));
+        let can_raise = actions.raise_cmd.is_some();
         b.property("CanRaise")
             .emits_changed_const()
-            .get(|_, _| Ok(false));
+            .get(move |_, _| Ok(can_raise));
         b.property("CanSetFullscreen")
             .emits_changed_const()
             .get(|_, _| Ok(false));
         b.property("HasTrackList")
             .emits_changed_const()
-            .get(|_, _| Ok(false));
+            .get(|_, _| Ok(true));
         b.property("Identity")
             .emits_changed_const()
             .get(|_, _| Ok("Spotifyd".to_string()));
@@ -230,53 +320,35 @@ async fn create_dbus_server(
     // https://specifications.freedesktop.org/mpris-spec/latest/Player_Interface.html
 
     let player_interface: IfaceToken<()> = cr.register("org.mpris.MediaPlayer2.Player", |b| {
-        let local_spirc = spirc.clone();
-        b.method("VolumeUp", (), (), move |_, _, (): ()| {
-            local_spirc.volume_up();
-            Ok(())
-        })
-        .deprecated();
-        let local_spirc = spirc.clone();
-        b.method("VolumeDown", (), (), move |_, _, (): ()| {
-            local_spirc.volume_down();
-            Ok(())
-        })
-        .deprecated();
-        let local_spirc = spirc.clone();
-        b.method("Next", (), (), move |_, _, (): ()| {
-            local_spirc.next();
-            Ok(())
-        });
-        let local_spirc = spirc.clone();
-        b.method("Previous", (), (), move |_, _, (): ()| {
-            local_spirc.prev();
-            Ok(())
-        });
-        let local_spirc = spirc.clone();
-        b.method("Pause", (), (), move |_, _, (): ()| {
-            local_spirc.pause();
-            Ok(())
-        });
-        let local_spirc = spirc.clone();
-        b.method("PlayPause", (), (), move |_, _, (): ()| {
-            local_spirc.play_pause();
-            Ok(())
-        });
-        let local_spirc = spirc.clone();
-        b.method("Play", (), (), move |_, _, (): ()| {
-            local_spirc.play();
-            Ok(())
-        });
-        let local_spirc = spirc.clone();
-        b.method("Stop", (), (), move |_, _, (): ()| {
+        for (name, command) in [
+            ("VolumeUp", ControlCommand::VolumeUp),
+            ("VolumeDown", ControlCommand::VolumeDown),
+        ] {
+            let apply = apply_command.clone();
+            b.method(name, (), (), move |ctx, _, (): ()| {
+                apply(command, caller(ctx.message()))
+            })
+            .deprecated();
+        }
+        for (name, command) in [
+            ("Next", ControlCommand::Next),
+            ("Previous", ControlCommand::Prev),
+            ("Pause", ControlCommand::Pause),
+            ("PlayPause", ControlCommand::PlayPause),
+            ("Play", ControlCommand::Play),
             // TODO: add real stop implementation.
-            local_spirc.pause();
-            Ok(())
-        });
+            ("Stop", ControlCommand::Pause),
+        ] {
+            let apply = apply_command.clone();
+            b.method(name, (), (), move |ctx, _, (): ()| {
+                apply(command, caller(ctx.message()))
+            });
+        }
 
         let mv_device_name = device_name.clone();
         let sp_client = Arc::clone(&spotify_api_client);
-        b.method("Seek", ("offset",), (), move |_, _, (pos,): (i64,)| {
+        let record = record_command.clone();
+        b.method("Seek", ("offset",), (), move |ctx, _, (pos,): (i64,)| {
             if let Ok(Some(playback)) = sp_client.current_playback(None, None::<Vec<_>>) {
                 if playback.device.name == mv_device_name {
                     let new_pos = playback
@@ -293,10 +365,13 @@ async fn create_dbus_server(
                         // MPRIS spec: negative values should be treated as 0
                         let new_pos = new_pos.max(Duration::zero());
                         if new_pos <= duration {
-                            let _ = sp_client.seek_track(new_pos, playback.device.id.as_deref());
+                            let result =
+                                sp_client.seek_track(new_pos, playback.device.id.as_deref());
+                            record(format!("Seek({})", pos), caller(ctx.message()), result);
                         } else {
                             // MPRIS spec: values beyond track bounds should act like Next
-                            let _ = sp_client.next_track(playback.device.id.as_deref());
+                            let result = sp_client.next_track(playback.device.id.as_deref());
+                            record(format!("Seek({})", pos), caller(ctx.message()), result);
                         }
                     }
                 }
@@ -306,11 +381,12 @@ async fn create_dbus_server(
 
         let mv_device_name = device_name.clone();
         let sp_client = Arc::clone(&spotify_api_client);
+        let record = record_command.clone();
         b.method(
             "SetPosition",
             ("track_id", "position"),
             (),
-            move |_, _, (track_id, pos): (dbus::Path, i64)| {
+            move |ctx, _, (track_id, pos): (dbus::Path, i64)| {
                 if let Ok(Some(playback)) = sp_client.current_playback(None, None::<Vec<_>>) {
                     let (track_matches, duration) = if let Some(item) = playback.item {
                         let track_matches = item
@@ -333,10 +409,15 @@ async fn create_dbus_server(
                         && (0..=duration).contains(&pos)
                     {
                         // pos is in microseconds, seek_track takes milliseconds
-                        let _ = sp_client.seek_track(
+                        let result = sp_client.seek_track(
                             Duration::milliseconds(pos / 1000),
                             playback.device.id.as_deref(),
                         );
+                        record(
+                            format!("SetPosition({})", pos),
+                            caller(ctx.message()),
+                            result,
+                        );
                     }
                 }
                 Ok(())
@@ -345,115 +426,51 @@ async fn create_dbus_server(
 
         let mv_device_name = device_name.clone();
         let sp_client = Arc::clone(&spotify_api_client);
-        b.method("OpenUri", ("uri",), (), move |_, _, (uri,): (String,)| {
-            enum AnyId<'a> {
-                Playable(PlayableId<'a>),
-                Context(PlayContextId<'a>),
-            }
+        let record = record_command.clone();
+        b.method("OpenUri", ("uri",), (), move |ctx, _, (uri,): (String,)| {
+            let id = SpotifyUri::parse(&uri).map_err(|e| MethodErr::invalid_arg(&e))?;
 
-            fn uri_to_id(uri: &str) -> Result<AnyId<'_>, IdError> {
-                use AnyId::*;
-                Ok(match parse_uri(uri)? {
-                    (Type::Track, id) => Playable(TrackId::from_id(id)?.into()),
-                    (Type::Episode, id) => Playable(EpisodeId::from_id(id)?.into()),
-                    (Type::Artist, id) => Context(ArtistId::from_id(id)?.into()),
-                    (Type::Album, id) => Context(AlbumId::from_id(id)?.into()),
-                    (Type::Playlist, id) => Context(PlaylistId::from_id(id)?.into()),
-                    (Type::Show, id) => Context(ShowId::from_id(id)?.into()),
-                    (Type::User | Type::Collection | Type::Collectionyourepisodes, _) => {
-                        Err(IdError::InvalidType)?
-                    }
-                })
-            }
-
-            let id = uri_to_id(&uri).map_err(|e| MethodErr::invalid_arg(&e))?;
-
-            let device_id = get_device_id(&sp_client, &mv_device_name, true);
-
-            if let Some(device_id) = device_id {
-                match id {
-                    AnyId::Playable(id) => {
-                        let _ = sp_client.start_uris_playback(
-                            Some(id),
-                            Some(&device_id),
-                            Some(Offset::Position(Duration::zero())),
-                            None,
-                        );
-                    }
-                    AnyId::Context(id) => {
-                        let _ = sp_client.start_context_playback(
-                            id,
-                            Some(&device_id),
-                            Some(Offset::Position(Duration::zero())),
-                            None,
-                        );
-                    }
+            // the item is played here, even if another device is active
+            let Some(device_id) = get_device_id(&sp_client, &mv_device_name, false) else {
+                let msg = format!("Could not find device with name {}", mv_device_name);
+                warn!("OpenUri: {}", msg);
+                return Err(MethodErr::failed(&msg));
+            };
+            let result = web_api::open_uri(&sp_client, &device_id, id);
+            let error = result.as_ref().err().map(|err| err.to_string());
+            record(format!("OpenUri({})", uri), caller(ctx.message()), result);
+            match error {
+                None => Ok(()),
+                Some(err) => {
+                    let e = format!("OpenUri failed: {}", err);
+                    error!("{}", e);
+                    Err(MethodErr::failed(&e))
                 }
             }
-            Ok(())
         });
 
-        let mv_device_name = device_name.clone();
-        let sp_client = Arc::clone(&spotify_api_client);
+        let state = playback_state.clone();
         b.property("PlaybackStatus")
             .emits_changed_false()
-            .get(move |_, _| {
-                if let Ok(Some(playback)) = sp_client.current_playback(None, None::<Vec<_>>) {
-                    if playback.device.name == mv_device_name {
-                        if playback.is_playing {
-                            return Ok("Playing".to_string());
-                        } else {
-                            return Ok("Paused".to_string());
-                        }
-                    }
-                }
-                Ok("Stopped".to_string())
-            });
+            .get(move |_, _| Ok(state.read().unwrap().status.as_str().to_string()));
 
-        let mv_device_name = device_name.clone();
-        let sp_client = Arc::clone(&spotify_api_client);
-        let sp_client2 = Arc::clone(&spotify_api_client);
+        let state = playback_state.clone();
+        let apply = apply_command.clone();
         b.property("Shuffle")
             .emits_changed_false()
-            .get(move |_, _| {
-                let shuffle_status = sp_client
-                    .current_playback(None, None::<Vec<_>>)
-                    .ok()
-                    .flatten()
-                    .map_or(false, |p| p.shuffle_state);
-                Ok(shuffle_status)
-            })
-            .set(move |_, _, value| {
-                let device_id = get_device_id(&sp_client2, &mv_device_name, true);
-                if let Some(device_id) = device_id {
-                    match sp_client2.shuffle(value, Some(&device_id)) {
-                        Ok(_) => Ok(None),
-                        Err(err) => {
-                            let e = format!("SetShuffle failed: {}", err);
-                            error!("{}", e);
-                            Err(MethodErr::failed(&e))
-                        }
-                    }
-                } else {
-                    let msg = format!("Could not find device with name {}", mv_device_name);
-                    warn!("SetShuffle: {}", msg);
-                    Err(MethodErr::failed(&msg))
-                }
+            .get(move |_, _| Ok(state.read().unwrap().shuffle))
+            // the change is announced once librespot has applied it
+            .set(move |ctx, _, value| {
+                let client = ctx.message().map(caller).unwrap_or_default();
+                apply(ControlCommand::Shuffle(value), client).map(|()| None)
             });
 
         b.property("Rate").emits_changed_const().get(|_, _| Ok(1.0));
 
-        let sp_client = Arc::clone(&spotify_api_client);
-        b.property("Volume").emits_changed_false().get(move |_, _| {
-            let vol = sp_client
-                .current_playback(None, None::<Vec<_>>)
-                .ok()
-                .flatten()
-                .and_then(|p| p.device.volume_percent)
-                .unwrap_or(0) as f64;
-
-            Ok(vol)
-        });
+        let state = playback_state.clone();
+        b.property("Volume")
+            .emits_changed_false()
+            .get(move |_, _| Ok(state.read().unwrap().volume.map_or(0.0, mpris_volume)));
 
         b.property("MaximumRate")
             .emits_changed_const()
@@ -462,37 +479,24 @@ async fn create_dbus_server(
             .emits_changed_const()
             .get(|_, _| Ok(1.0));
 
-        let sp_client = Arc::clone(&spotify_api_client);
+        let state = playback_state.clone();
+        let apply = apply_command.clone();
         b.property("LoopStatus")
             .emits_changed_false()
-            .get(move |_, _| {
-                let status =
-                    if let Ok(Some(player)) = sp_client.current_playback(None, None::<Vec<_>>) {
-                        match player.repeat_state {
-                            RepeatState::Off => "None",
-                            RepeatState::Track => "Track",
-                            RepeatState::Context => "Playlist",
-                        }
-                    } else {
-                        "None"
-                    }
-                    .to_string();
-                Ok(status)
+            .get(move |_, _| Ok(loop_status(&state.read().unwrap()).to_string()))
+            .set(move |ctx, _, value: String| {
+                let repeat = parse_loop_status(&value).ok_or_else(|| {
+                    MethodErr::invalid_arg(&format!("unknown loop status {}", value))
+                })?;
+                let client = ctx.message().map(caller).unwrap_or_default();
+                apply(ControlCommand::Repeat(repeat), client).map(|()| None)
             });
 
-        let sp_client = Arc::clone(&spotify_api_client);
+        let state = playback_state.clone();
         b.property("Position")
             .emits_changed_false()
-            .get(move |_, _| {
-                let pos = sp_client
-                    .current_playback(None, None::<Vec<_>>)
-                    .ok()
-                    .flatten()
-                    .and_then(|p| p.progress?.num_microseconds())
-                    .unwrap_or(0);
-
-                Ok(pos)
-            });
+            // position should be in microseconds
+            .get(move |_, _| Ok(state.read().unwrap().position_ms() as i64 * 1000));
 
         let sp_client = Arc::clone(&spotify_api_client);
         b.property("Metadata")
@@ -528,17 +532,383 @@ async fn create_dbus_server(
         }
     });
 
+    // The following methods and properties are part of the MediaPlayer2.TrackList interface,
+    // which reflects the queue of Spotify Connect.
+    // https://specifications.freedesktop.org/mpris-spec/latest/Track_List_Interface.html
+    let track_list = Arc::new(Mutex::new(
+        fetch_track_list(&spotify_api_client).unwrap_or_default(),
+    ));
+
+    let track_list_interface: IfaceToken<()> =
+        cr.register("org.mpris.MediaPlayer2.TrackList", |b| {
+            let list = track_list.clone();
+            b.method(
+                "GetTracksMetadata",
+                ("track_ids",),
+                ("metadata",),
+                move |_, _, (track_ids,): (Vec<dbus::Path<'static>>,)| {
+                    let list = list.lock().unwrap();
+                    let metadata = track_ids
+                        .iter()
+                        .filter_map(|track_id| {
+                            let item = list
+                                .iter()
+                                .find(|item| item_path(item).as_ref() == Some(track_id))?;
+                            let mut m: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+                            insert_metadata(&mut m, item.clone());
+                            Some(m)
+                        })
+                        .collect::<Vec<_>>();
+                    Ok((metadata,))
+                },
+            );
+
+            // Spotify Connect only appends to the queue, so the track is added
+            // after the queued ones rather than after_track
+            let list = track_list.clone();
+            let mv_device_name = device_name.clone();
+            let sp_client = Arc::clone(&spotify_api_client);
+            let record = record_command.clone();
+            b.method(
+                "AddTrack",
+                ("uri", "after_track", "set_as_current"),
+                (),
+                move |ctx, _, (uri, _, set_as_current): (String, dbus::Path, bool)| {
+                    let SpotifyUri::Playable(id) =
+                        SpotifyUri::parse(&uri).map_err(|e| MethodErr::invalid_arg(&e))?
+                    else {
+                        return Err(MethodErr::invalid_arg(
+                            "only tracks and episodes can be added",
+                        ));
+                    };
+                    let Some(device_id) = get_device_id(&sp_client, &mv_device_name, false) else {
+                        let msg = format!("Could not find device with name {}", mv_device_name);
+                        warn!("AddTrack: {}", msg);
+                        return Err(MethodErr::failed(&msg));
+                    };
+                    let result = if set_as_current {
+                        web_api::open_uri(&sp_client, &device_id, SpotifyUri::Playable(id))
+                    } else {
+                        sp_client.add_item_to_queue(id, Some(&device_id))
+                    };
+                    let error = result.as_ref().err().map(|err| err.to_string());
+                    record(format!("AddTrack({})", uri), caller(ctx.message()), result);
+                    if let Some(err) = error {
+                        let e = format!("AddTrack failed: {}", err);
+                        error!("{}", e);
+                        return Err(MethodErr::failed(&e));
+                    }
+                    if let Some(items) = fetch_track_list(&sp_client) {
+                        ctx.push_msg(track_list_replaced(&items));
+                        *list.lock().unwrap() = items;
+                    }
+                    Ok(())
+                },
+            );
+            // the Web API has no way to remove an item from the queue
+            b.method(
+                "RemoveTrack",
+                ("track_id",),
+                (),
+                |_, _, (_,): (dbus::Path,)| {
+                    Err::<(), _>(MethodErr::failed(
+                        "Spotify Connect can't remove tracks from the queue",
+                    ))
+                },
+            );
+
+            let list = track_list.clone();
+            let apply = apply_command.clone();
+            b.method(
+                "GoTo",
+                ("track_id",),
+                (),
+                move |ctx, _, (track_id,): (dbus::Path,)| {
+                    let index = list
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .position(|item| item_path(item).as_ref() == Some(&track_id));
+                    // Spotify Connect can only skip to the next track
+                    for _ in 0..index.unwrap_or(0) {
+                        apply(ControlCommand::Next, caller(ctx.message()))?;
+                    }
+                    Ok(())
+                },
+            );
+
+            b.signal::<(Vec<dbus::Path<'static>>, dbus::Path<'static>), _>(
+                "TrackListReplaced",
+                ("tracks", "current_track"),
+            );
+
+            let list = track_list.clone();
+            b.property("Tracks")
+                .emits_changed_false()
+                .get(move |_, _| Ok(track_ids(&list.lock().unwrap())));
+            // tracks can be added, but not removed
+            b.property("CanEditTracks")
+                .emits_changed_const()
+                .get(|_, _| Ok(true));
+        });
+
+    // The following methods and properties are part of the MediaPlayer2.Playlists interface,
+    // which lists the playlists of the user.
+    // https://specifications.freedesktop.org/mpris-spec/latest/Playlists_Interface.html
+    let active_playlist: Arc<Mutex<Option<MprisPlaylist>>> = Arc::new(Mutex::new(None));
+
+    let playlists_interface: IfaceToken<()> =
+        cr.register("org.mpris.MediaPlayer2.Playlists", |b| {
+            let mv_device_name = device_name.clone();
+            let sp_client = Arc::clone(&spotify_api_client);
+            let record = record_command.clone();
+            let active = active_playlist.clone();
+            b.method(
+                "ActivatePlaylist",
+                ("playlist_id",),
+                (),
+                move |ctx, _, (playlist_id,): (dbus::Path,)| {
+                    let Some((id, playlist)) = fetch_playlists(&sp_client)?
+                        .into_iter()
+                        .find(|(_, playlist)| playlist.0 == playlist_id)
+                    else {
+                        return Err(MethodErr::invalid_arg(&format!(
+                            "unknown playlist {}",
+                            playlist_id
+                        )));
+                    };
+                    let Some(device_id) = get_device_id(&sp_client, &mv_device_name, false) else {
+                        let msg = format!("Could not find device with name {}", mv_device_name);
+                        warn!("ActivatePlaylist: {}", msg);
+                        return Err(MethodErr::failed(&msg));
+                    };
+
+                    let result =
+                        sp_client.start_context_playback(id.into(), Some(&device_id), None, None);
+                    let error = result.as_ref().err().map(|err| err.to_string());
+                    record(
+                        format!("ActivatePlaylist({})", playlist.1),
+                        caller(ctx.message()),
+                        result,
+                    );
+                    if let Some(err) = error {
+                        let e = format!("ActivatePlaylist failed: {}", err);
+                        error!("{}", e);
+                        return Err(MethodErr::failed(&e));
+                    }
+
+                    *active.lock().unwrap() = Some(playlist.clone());
+                    let mut changed_properties: HashMap<String, Variant<Box<dyn RefArg>>> =
+                        HashMap::new();
+                    changed_properties.insert(
+                        "ActivePlaylist".to_owned(),
+                        Variant(Box::new((true, playlist))),
+                    );
+                    let msg =
+                    dbus::nonblock::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged {
+                        interface_name: "org.mpris.MediaPlayer2.Playlists".to_owned(),
+                        changed_properties,
+                        invalidated_properties: Vec::new(),
+                    };
+                    ctx.push_msg(
+                        msg.to_emit_message(&dbus::Path::new("/org/mpris/MediaPlayer2").unwrap()),
+                    );
+                    Ok(())
+                },
+            );
+
+            let sp_client = Arc::clone(&spotify_api_client);
+            b.method(
+                "GetPlaylists",
+                ("index", "max_count", "order", "reverse_order"),
+                ("playlists",),
+                move |_, _, (index, max_count, order, reverse_order): (u32, u32, String, bool)| {
+                    let mut playlists: Vec<_> = fetch_playlists(&sp_client)?
+                        .into_iter()
+                        .map(|(_, playlist)| playlist)
+                        .collect();
+                    if order == "Alphabetical" {
+                        playlists.sort_by_key(|playlist| playlist.1.to_lowercase());
+                    }
+                    if reverse_order {
+                        playlists.reverse();
+                    }
+                    Ok((playlists
+                        .into_iter()
+                        .skip(index as usize)
+                        .take(max_count as usize)
+                        .collect::<Vec<_>>(),))
+                },
+            );
+
+            let sp_client = Arc::clone(&spotify_api_client);
+            b.property("PlaylistCount")
+                .emits_changed_false()
+                .get(move |_, _| {
+                    let page = sp_client
+                        .current_user_playlists_manual(Some(1), None)
+                        .map_err(|e| {
+                            MethodErr::failed(&format!("Couldn't fetch the playlists: {}", e))
+                        })?;
+                    Ok(page.total)
+                });
+            b.property("Orderings")
+                .emits_changed_const()
+                .get(|_, _| Ok(vec!["UserDefined".to_string(), "Alphabetical".to_string()]));
+            let active = active_playlist.clone();
+            b.property("ActivePlaylist")
+                .emits_changed_false()
+                .get(move |_, _| {
+                    Ok(match active.lock().unwrap().clone() {
+                        Some(playlist) => (true, playlist),
+                        None => (
+                            false,
+                            (dbus::Path::new("/").unwrap(), String::new(), String::new()),
+                        ),
+                    })
+                });
+        });
+
     let spotifyd_ctrls_interface: IfaceToken<()> = cr.register("rs.spotifyd.Controls", |b| {
-        let local_spirc = spirc.clone();
-        b.method("VolumeUp", (), (), move |_, _, (): ()| {
-            local_spirc.volume_up();
+        for (name, command) in [
+            ("VolumeUp", ControlCommand::VolumeUp),
+            ("VolumeDown", ControlCommand::VolumeDown),
+            ("NextChapter", ControlCommand::NextChapter),
+            ("PreviousChapter", ControlCommand::PrevChapter),
+        ] {
+            let apply = apply_command.clone();
+            b.method(name, (), (), move |ctx, _, (): ()| {
+                apply(command, caller(ctx.message()))
+            });
+        }
+
+        // the chapters of the current episode as (start in microseconds, title)
+        let state = playback_state.clone();
+        b.property("Chapters").get(move |_, _| {
+            Ok(state
+                .read()
+                .unwrap()
+                .chapters()
+                .iter()
+                .map(|chapter| (chapter.start_ms as i64 * 1000, chapter.title.clone()))
+                .collect::<Vec<_>>())
+        });
+        // the index of the current chapter, or -1 if there is none
+        let state = playback_state.clone();
+        b.property("CurrentChapter").get(move |_, _| {
+            Ok(state
+                .read()
+                .unwrap()
+                .current_chapter()
+                .map_or(-1, |index| index as i32))
+        });
+
+        // the status, or "inactive" when another device took over
+        let state = playback_state.clone();
+        b.property("Activity")
+            .get(move |_, _| Ok(state.read().unwrap().activity().as_str().to_string()));
+
+        // keeps the playback with the current account and client
+        let lock = actions.do_not_disturb.clone();
+        let state = playback_state.clone();
+        b.method("Lock", (), (), move |_, _, (): ()| {
+            lock.lock(LockOwner::current(&state.read().unwrap()));
             Ok(())
         });
-        let local_spirc = spirc.clone();
-        b.method("VolumeDown", (), (), move |_, _, (): ()| {
-            local_spirc.volume_down();
+        let lock = actions.do_not_disturb.clone();
+        b.method("Unlock", (), (), move |_, _, (): ()| {
+            lock.unlock();
             Ok(())
         });
+        let lock = actions.do_not_disturb.clone();
+        b.property("Locked").get(move |_, _| Ok(lock.is_locked()));
+
+        // shows or hides the device in the Spotify apps, keeping the session
+        let discoverable = actions.discoverable.clone();
+        b.method(
+            "Discoverable",
+            ("discoverable",),
+            (),
+            move |_, _, (visible,): (bool,)| {
+                discoverable
+                    .set_discoverable(visible)
+                    .map_err(|e| MethodErr::failed(&e))
+            },
+        );
+
+        // removes the stored credentials of the user and ends their session
+        let (cache_layout, encrypted_credentials, use_keyring, forget_user) = (
+            actions.cache_layout.clone(),
+            actions.encrypted_credentials.clone(),
+            actions.use_keyring,
+            actions.forget_user.clone(),
+        );
+        b.method(
+            "ForgetUser",
+            ("username",),
+            (),
+            move |_, _, (username,): (String,)| {
+                let forgotten = cache::forget_user(
+                    cache_layout.as_ref(),
+                    encrypted_credentials.as_ref(),
+                    use_keyring,
+                    &username,
+                )
+                .map_err(|e| MethodErr::failed(&format!("{:#}", e)))?;
+                for removed in &forgotten.removed {
+                    info!("Removed {}", removed);
+                }
+                let _ = forget_user.send(username.clone());
+                if forgotten.is_empty() {
+                    return Err(MethodErr::failed(&format!(
+                        "no credentials of {} are stored",
+                        username
+                    )));
+                }
+                Ok(())
+            },
+        );
+
+        // the session of the profile replaces this one, along with the server
+        let profiles = actions.profiles.clone();
+        b.method(
+            "SwitchProfile",
+            ("name",),
+            (),
+            move |_, _, (name,): (String,)| {
+                profiles.switch(&name).map_err(|e| MethodErr::failed(&e))
+            },
+        );
+        let profiles = actions.profiles.clone();
+        b.property("Profile")
+            .get(move |_, _| Ok(profiles.active().unwrap_or_default()));
+        let profiles = actions.profiles.clone();
+        b.property("Profiles").get(move |_, _| Ok(profiles.names()));
+
+        // pauses the playback after the seconds, fading out over the last minute
+        let sleep_timer = actions.sleep_timer.clone();
+        b.method(
+            "Sleep",
+            ("seconds",),
+            (),
+            move |_, _, (seconds,): (u64,)| {
+                sleep_timer
+                    .set(std::time::Duration::from_secs(seconds))
+                    .map_err(|e| MethodErr::failed(&e))
+            },
+        );
+        let sleep_timer = actions.sleep_timer.clone();
+        b.method("CancelSleep", (), (), move |_, _, (): ()| {
+            sleep_timer.cancel();
+            Ok(())
+        });
+        // the seconds until the sleep timer runs out, or -1 if it isn't set
+        let sleep_timer = actions.sleep_timer.clone();
+        b.property("SleepRemaining").get(move |_, _| {
+            Ok(sleep_timer
+                .remaining()
+                .map_or(-1, |remaining| remaining.as_secs() as i64))
+        });
 
         let mv_device_name = device_name.clone();
         let sp_client = Arc::clone(&spotify_api_client);
@@ -564,7 +934,12 @@ async fn create_dbus_server(
 
     cr.insert(
         "/org/mpris/MediaPlayer2",
-        &[media_player2_interface, player_interface],
+        &[
+            media_player2_interface,
+            player_interface,
+            track_list_interface,
+            playlists_interface,
+        ],
         (),
     );
 
@@ -578,102 +953,81 @@ async fn create_dbus_server(
         }),
     );
 
-    // Store current playback state to be able to detect changes
-    let mut last_track_id = None;
-    let mut last_playback_status = None;
-    let mut last_volume = None;
+    // Store the last published playback state to be able to detect changes
+    let mut last_state = PlaybackState::default();
 
     loop {
-        let event = event_rx
+        let event = events
             .recv()
             .await
-            .expect("Changed track channel was unexpectedly closed");
-        let mut seeked_position_ms = None;
+            .expect("Event bus was unexpectedly closed");
 
-        // Update playback state from event
-        let (track_id, playback_status, player_volume) = match event {
-            PlayerEvent::VolumeSet { volume } => {
-                (last_track_id, last_playback_status, Some(volume))
-            }
-            PlayerEvent::Playing {
-                track_id,
-                position_ms,
-                ..
-            } => {
-                seeked_position_ms = Some(position_ms);
-                (Some(track_id), Some(PlaybackStatus::Playing), last_volume)
-            }
-            PlayerEvent::Stopped { .. } => {
-                (last_track_id, Some(PlaybackStatus::Stopped), last_volume)
-            }
-            PlayerEvent::Paused { .. } => {
-                (last_track_id, Some(PlaybackStatus::Paused), last_volume)
+        // the main loop has already applied the event to the shared state
+        let state = playback_state.read().unwrap().clone();
+        let mut changed_properties: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+
+        if last_state.volume != state.volume {
+            if let Some(volume) = state.volume {
+                changed_properties
+                    .insert("Volume".to_owned(), Variant(Box::new(mpris_volume(volume))));
             }
-            _ => continue,
-        };
+        }
 
-        // if playback_status, track_id or volume have changed, emit a PropertiesChanged signal
-        if last_playback_status != playback_status
-            || last_track_id != track_id
-            || last_volume != player_volume
-        {
-            let mut changed_properties: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
-
-            if last_volume != player_volume {
-                if let Some(player_volume) = player_volume {
-                    // convert u16 to float
-                    let mut vol_mpris = player_volume as f64;
-                    // max. vol = 1.0 according to mpris spec, round to two decimal places
-                    vol_mpris = (vol_mpris / 65535.0 * 100.0).round() / 100.0;
-                    changed_properties
-                        .insert("Volume".to_owned(), Variant(Box::new(vol_mpris.to_owned())));
-                }
-            } else {
-                if let Some(track_id) = track_id {
-                    let item = match track_id.audio_type {
-                        SpotifyAudioType::Track => {
-                            let track_id = TrackId::from_id(track_id.to_base62().unwrap()).unwrap();
-                            let track = spotify_api_client
-                                .track(track_id, None)
-                                .map(PlayableItem::Track);
-                            Some(track)
-                        }
-                        SpotifyAudioType::Podcast => {
-                            let id = EpisodeId::from_id(track_id.to_base62().unwrap()).unwrap();
-                            let episode = spotify_api_client
-                                .get_an_episode(id, None)
-                                .map(PlayableItem::Episode);
-                            Some(episode)
-                        }
-                        SpotifyAudioType::NonPlayable => None,
-                    };
+        if last_state.track != state.track {
+            if let Some(ref track) = state.track {
+                let item = match track.item_type.as_str() {
+                    "track" => TrackId::from_id(&track.track_id)
+                        .ok()
+                        .map(|id| spotify_api_client.track(id, None).map(PlayableItem::Track)),
+                    "episode" => EpisodeId::from_id(&track.track_id).ok().map(|id| {
+                        spotify_api_client
+                            .get_an_episode(id, None)
+                            .map(PlayableItem::Episode)
+                    }),
+                    _ => None,
+                };
 
-                    if let Some(item) = item {
-                        match item {
-                            Ok(item) => {
-                                let mut m: HashMap<String, Variant<Box<dyn RefArg>>> =
-                                    HashMap::new();
-                                insert_metadata(&mut m, item);
-
-                                changed_properties
-                                    .insert("Metadata".to_owned(), Variant(Box::new(m)));
-                            }
-                            Err(e) => info!("Couldn't fetch metadata from spotify: {:?}", e),
-                        }
+                let mut m: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+                match item {
+                    Some(Ok(item)) => insert_metadata(&mut m, item),
+                    Some(Err(e)) => info!("Couldn't fetch metadata from spotify: {:?}", e),
+                    // without a Spotify id, there is nothing to look up
+                    None => {
+                        m.insert(
+                            "mpris:trackid".to_string(),
+                            Variant(Box::new(dbus::Path::new(NO_TRACK).unwrap())),
+                        );
+                        m.insert(
+                            "xesam:title".to_string(),
+                            Variant(Box::new(track.name.clone())),
+                        );
                     }
                 }
-                if let Some(playback_status) = playback_status {
-                    changed_properties.insert(
-                        "PlaybackStatus".to_owned(),
-                        Variant(Box::new(match playback_status {
-                            PlaybackStatus::Playing => "Playing".to_owned(),
-                            PlaybackStatus::Paused => "Paused".to_owned(),
-                            PlaybackStatus::Stopped => "Stopped".to_owned(),
-                        })),
-                    );
+                if !m.is_empty() {
+                    changed_properties.insert("Metadata".to_owned(), Variant(Box::new(m)));
                 }
             }
+        }
+
+        if last_state.status != state.status {
+            changed_properties.insert(
+                "PlaybackStatus".to_owned(),
+                Variant(Box::new(state.status.as_str().to_owned())),
+            );
+        }
+
+        if last_state.shuffle != state.shuffle {
+            changed_properties.insert("Shuffle".to_owned(), Variant(Box::new(state.shuffle)));
+        }
+
+        if last_state.repeat != state.repeat {
+            changed_properties.insert(
+                "LoopStatus".to_owned(),
+                Variant(Box::new(loop_status(&state).to_owned())),
+            );
+        }
 
+        if !changed_properties.is_empty() {
             let msg = dbus::nonblock::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged {
                 interface_name: "org.mpris.MediaPlayer2.Player".to_owned(),
                 changed_properties,
@@ -681,23 +1035,69 @@ async fn create_dbus_server(
             };
             conn.send(msg.to_emit_message(&dbus::Path::new("/org/mpris/MediaPlayer2").unwrap()))
                 .unwrap();
+        }
 
-            last_playback_status = playback_status;
-            last_track_id = track_id;
-            last_volume = player_volume;
+        // the queue moves along with the track, and may have been changed
+        // while paused
+        if last_state.track != state.track || last_state.status != state.status {
+            if let Some(items) = fetch_track_list(&spotify_api_client) {
+                let mut list = track_list.lock().unwrap();
+                if track_ids(&list) != track_ids(&items) {
+                    conn.send(track_list_replaced(&items)).unwrap();
+                    *list = items;
+                }
+            }
         }
 
         // if position in track has changed emit a Seeked signal
-        if let Some(position_ms) = seeked_position_ms {
+        if let SpotifydEvent::Playing { .. } | SpotifydEvent::Seeked { .. } = event {
             let msg = dbus::message::Message::signal(
                 &dbus::Path::new("/org/mpris/MediaPlayer2").unwrap(),
                 &dbus::strings::Interface::new("org.mpris.MediaPlayer2.Player").unwrap(),
                 &dbus::strings::Member::new("Seeked").unwrap(),
             )
             // position should be in microseconds
-            .append1(position_ms as i64 * 1000);
+            .append1(state.position_ms() as i64 * 1000);
             conn.send(msg).unwrap();
         }
+
+        last_state = state;
+    }
+}
+
+/// The client that called the method, by its unique bus name, for the audit
+/// log.
+fn caller(message: &dbus::Message) -> CommandClient {
+    CommandClient {
+        name: message.sender().map(|sender| sender.to_string()),
+        ip: None,
+    }
+}
+
+/// Converts librespot's volume to the range used by MPRIS (0.0 to 1.0),
+/// rounded to two decimal places.
+fn mpris_volume(volume: u16) -> f64 {
+    (volume as f64 / 65535.0 * 100.0).round() / 100.0
+}
+
+/// The MPRIS loop status of the playback. Spotify Connect only knows whether
+/// the context repeats, so a loop status set to `Track` reads back as
+/// `Playlist`.
+fn loop_status(state: &PlaybackState) -> &'static str {
+    if state.repeat {
+        "Playlist"
+    } else {
+        "None"
+    }
+}
+
+/// Whether the MPRIS loop status repeats. Spotify Connect can only repeat the
+/// whole context, which is the closest to repeating the track as well.
+fn parse_loop_status(status: &str) -> Option<bool> {
+    match status {
+        "None" => Some(false),
+        "Track" | "Playlist" => Some(true),
+        _ => None,
     }
 }
 
@@ -723,6 +1123,74 @@ fn get_device_id(
     }
 }
 
+/// A playlist as listed by MPRIS: its path, name and icon.
+type MprisPlaylist = (dbus::Path<'static>, String, String);
+
+/// The playlists of the user, in their order.
+fn fetch_playlists(
+    sp_client: &AuthCodeSpotify,
+) -> Result<Vec<(PlaylistId<'static>, MprisPlaylist)>, MethodErr> {
+    sp_client
+        .current_user_playlists()
+        .map(|playlist| {
+            let playlist = playlist?;
+            let path = uri_to_object_path(playlist.id.uri());
+            let icon = playlist
+                .images
+                .into_iter()
+                .max_by_key(|i| i.width.unwrap_or(0))
+                .map(|i| i.url)
+                .unwrap_or_default();
+            Ok((playlist.id, (path, playlist.name, icon)))
+        })
+        .collect::<Result<_, ClientError>>()
+        .map_err(|e| MethodErr::failed(&format!("Couldn't fetch the playlists: {}", e)))
+}
+
+/// The path MPRIS uses when there is no current track.
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+/// The current track followed by the upcoming ones of the queue, as far as
+/// the Web API returns them.
+fn fetch_track_list(sp_client: &AuthCodeSpotify) -> Option<Vec<PlayableItem>> {
+    match sp_client.current_user_queue() {
+        Ok(queue) => Some(
+            queue
+                .currently_playing
+                .into_iter()
+                .chain(queue.queue)
+                .collect(),
+        ),
+        Err(e) => {
+            info!("Couldn't fetch the queue from spotify: {:?}", e);
+            None
+        }
+    }
+}
+
+fn item_path(item: &PlayableItem) -> Option<dbus::Path<'static>> {
+    item.id().map(|id| uri_to_object_path(id.uri()))
+}
+
+fn track_ids(items: &[PlayableItem]) -> Vec<dbus::Path<'static>> {
+    items.iter().filter_map(item_path).collect()
+}
+
+/// The signal announcing the items of the queue, once they changed.
+fn track_list_replaced(items: &[PlayableItem]) -> dbus::Message {
+    let tracks = track_ids(items);
+    let current_track = tracks
+        .first()
+        .cloned()
+        .unwrap_or_else(|| dbus::Path::new(NO_TRACK).unwrap());
+    dbus::message::Message::signal(
+        &dbus::Path::new("/org/mpris/MediaPlayer2").unwrap(),
+        &dbus::strings::Interface::new("org.mpris.MediaPlayer2.TrackList").unwrap(),
+        &dbus::strings::Member::new("TrackListReplaced").unwrap(),
+    )
+    .append2(tracks, current_track)
+}
+
 fn uri_to_object_path(uri: String) -> dbus::Path<'static> {
     let mut path = String::with_capacity(uri.len() + 1);
     for element in uri.split(':') {
@@ -788,7 +1256,10 @@ fn insert_metadata(m: &mut HashMap<String, Variant<Box<dyn RefArg>>>, item: Play
 
     m.insert(
         "mpris:trackid".to_string(),
-        Variant(Box::new(item.id.unwrap_or_default())),
+        Variant(Box::new(
+            item.id
+                .unwrap_or_else(|| dbus::Path::new(NO_TRACK).unwrap()),
+        )),
     );
 
     m.insert(
@@ -0,0 +1,81 @@
+use log::info;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::watch;
+
+/// Whether the device is shown in the Spotify apps via the discovery, which
+/// can be changed while running. Hiding it stops the zeroconf responder
+/// without ending the current session, e.g. once the accounts of the
+/// household are attached.
+///
+/// There's nothing to show with configured credentials, as the discovery
+/// isn't enabled then, nor once switched to a profile.
+#[derive(Debug)]
+pub struct Discoverable {
+    available: AtomicBool,
+    visible: watch::Sender<bool>,
+}
+
+impl Discoverable {
+    pub(crate) fn new(available: bool) -> Self {
+        Self {
+            available: AtomicBool::new(available),
+            visible: watch::channel(available).0,
+        }
+    }
+
+    pub fn is_discoverable(&self) -> bool {
+        *self.visible.borrow()
+    }
+
+    /// Shows or hides the device, failing when the discovery isn't enabled.
+    pub fn set_discoverable(&self, discoverable: bool) -> Result<(), &'static str> {
+        if !self.available.load(Ordering::Relaxed) {
+            return Err("the discovery isn't enabled, as spotifyd connects with credentials");
+        }
+        let changed = self.visible.send_if_modified(|visible| {
+            let changed = *visible != discoverable;
+            *visible = discoverable;
+            changed
+        });
+        if changed {
+            match discoverable {
+                true => info!("Showing the device via the discovery"),
+                false => info!("Hiding the device from the discovery"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells that the discovery has been stopped for good, as the account of
+    /// a profile connects instead.
+    pub(crate) fn set_unavailable(&self) {
+        self.available.store(false, Ordering::Relaxed);
+        self.visible.send_replace(false);
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.visible.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discoverable() {
+        let discoverable = Discoverable::new(true);
+        let mut visible = discoverable.subscribe();
+        assert!(discoverable.is_discoverable());
+
+        discoverable.set_discoverable(false).unwrap();
+        assert!(visible.has_changed().unwrap());
+        assert!(!*visible.borrow_and_update());
+        discoverable.set_discoverable(false).unwrap();
+        assert!(!visible.has_changed().unwrap());
+
+        let configured = Discoverable::new(false);
+        assert!(!configured.is_discoverable());
+        assert!(configured.set_discoverable(true).is_err());
+    }
+}
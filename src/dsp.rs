@@ -0,0 +1,431 @@
+#[cfg(feature = "convolution")]
+use crate::convolution::{Convolver, Filter};
+#[cfg(feature = "ladspa")]
+use crate::ladspa::{Instance, Plugin};
+use crate::{config::DspStage, equalizer::Equalizer};
+use librespot_playback::{
+    audio_backend::{Sink, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+    NUM_CHANNELS, SAMPLE_RATE,
+};
+use log::error;
+use std::{
+    f64::consts::PI,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+fn db_to_factor(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+/// A stage of the DSP chain, processing interleaved stereo samples in place.
+trait Stage: Send {
+    fn process(&mut self, samples: &mut [f64]);
+}
+
+struct Gain {
+    factor: f64,
+}
+
+impl Stage for Gain {
+    fn process(&mut self, samples: &mut [f64]) {
+        for sample in samples {
+            *sample *= self.factor;
+        }
+    }
+}
+
+/// A peak limiter, lowering the gain right away when a frame would exceed the
+/// threshold and raising it back exponentially.
+struct Limiter {
+    threshold: f64,
+    /// How much of the way back to the wanted gain is made each frame.
+    release: f64,
+    gain: f64,
+}
+
+impl Limiter {
+    fn new(threshold_db: f64, release_ms: f64) -> Self {
+        let release_frames = (release_ms / 1000.0 * SAMPLE_RATE as f64).max(1.0);
+        Self {
+            threshold: db_to_factor(threshold_db),
+            release: 1.0 - (-1.0 / release_frames).exp(),
+            gain: 1.0,
+        }
+    }
+}
+
+impl Stage for Limiter {
+    fn process(&mut self, samples: &mut [f64]) {
+        for frame in samples.chunks_mut(NUM_CHANNELS as usize) {
+            let peak = frame
+                .iter()
+                .fold(0f64, |peak, sample| peak.max(sample.abs()));
+            let wanted = if peak > self.threshold {
+                self.threshold / peak
+            } else {
+                1.0
+            };
+            if wanted < self.gain {
+                self.gain = wanted;
+            } else {
+                self.gain += (wanted - self.gain) * self.release;
+            }
+            for sample in frame {
+                *sample *= self.gain;
+            }
+        }
+    }
+}
+
+/// The coefficients of a first order low-pass, or of a high-pass's pole, at
+/// the cutoff frequency.
+fn pole(cutoff_hz: f64) -> f64 {
+    (-2.0 * PI * cutoff_hz / SAMPLE_RATE as f64).exp()
+}
+
+/// The filter of Bauer's stereophonic-to-binaural DSP (bs2b), feeding the
+/// low frequencies of each channel attenuated into the other one, like
+/// speakers heard by both ears, so that hard-panned recordings are less
+/// tiring on headphones.
+struct Crossfeed {
+    enabled: Arc<AtomicBool>,
+    a0_lo: f64,
+    b1_lo: f64,
+    a0_hi: f64,
+    a1_hi: f64,
+    b1_hi: f64,
+    gain: f64,
+    /// The previous input and filter outputs of each channel.
+    input: [f64; 2],
+    lo: [f64; 2],
+    hi: [f64; 2],
+}
+
+impl Crossfeed {
+    fn new(level_db: f64, cutoff_hz: f64, enabled: Arc<AtomicBool>) -> Self {
+        let gain_lo_db = level_db * -5.0 / 6.0 - 3.0;
+        let gain_hi_db = level_db / 6.0 - 3.0;
+        let gain_lo = db_to_factor(gain_lo_db);
+        let gain_hi = 1.0 - db_to_factor(gain_hi_db);
+        let cutoff_hi = cutoff_hz * 2f64.powf((gain_lo_db - 20.0 * gain_hi.log10()) / 12.0);
+
+        let x = pole(cutoff_hz);
+        let y = pole(cutoff_hi);
+        Self {
+            enabled,
+            a0_lo: gain_lo * (1.0 - x),
+            b1_lo: x,
+            a0_hi: 1.0 - gain_hi * (1.0 - y),
+            a1_hi: -y,
+            b1_hi: y,
+            gain: 1.0 / (1.0 - gain_hi + gain_lo),
+            input: [0.0; 2],
+            lo: [0.0; 2],
+            hi: [0.0; 2],
+        }
+    }
+}
+
+impl Stage for Crossfeed {
+    fn process(&mut self, samples: &mut [f64]) {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(2) {
+            for channel in 0..2 {
+                let input = frame[channel];
+                self.lo[channel] = self.a0_lo * input + self.b1_lo * self.lo[channel];
+                self.hi[channel] = self.a0_hi * input
+                    + self.a1_hi * self.input[channel]
+                    + self.b1_hi * self.hi[channel];
+                self.input[channel] = input;
+            }
+            frame[0] = (self.hi[0] + self.lo[1]) * self.gain;
+            frame[1] = (self.hi[1] + self.lo[0]) * self.gain;
+        }
+    }
+}
+
+/// The switches of the stages that can be toggled while playing, shared by
+/// the sinks and the control APIs.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DspSwitches {
+    /// Whether the crossfeed is on, if the chain has one.
+    crossfeed: Option<Arc<AtomicBool>>,
+    /// Whether the heavy stages are bypassed, e.g. while the SoC throttles.
+    reduced: Arc<AtomicBool>,
+}
+
+impl DspSwitches {
+    pub(crate) fn new(stages: &[DspStage]) -> Self {
+        let has_crossfeed = stages
+            .iter()
+            .any(|stage| matches!(stage, DspStage::Crossfeed { .. }));
+        Self {
+            crossfeed: has_crossfeed.then(|| Arc::new(AtomicBool::new(true))),
+            reduced: Default::default(),
+        }
+    }
+
+    pub(crate) fn crossfeed(&self) -> Option<bool> {
+        self.crossfeed
+            .as_ref()
+            .map(|enabled| enabled.load(Ordering::Relaxed))
+    }
+
+    /// Turns the crossfeed on or off, returning whether the chain has one.
+    pub(crate) fn set_crossfeed(&self, on: bool) -> bool {
+        let Some(ref enabled) = self.crossfeed else {
+            return false;
+        };
+        enabled.store(on, Ordering::Relaxed);
+        true
+    }
+
+    pub(crate) fn is_reduced(&self) -> bool {
+        self.reduced.load(Ordering::Relaxed)
+    }
+
+    /// Bypasses the heavy stages, or brings them back.
+    #[cfg_attr(not(target_os = "linux"), allow(unused))]
+    pub(crate) fn set_reduced(&self, reduced: bool) {
+        self.reduced.store(reduced, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "convolution")]
+impl Stage for Convolver {
+    fn process(&mut self, samples: &mut [f64]) {
+        Convolver::process(self, samples)
+    }
+}
+
+impl Stage for Equalizer {
+    fn process(&mut self, samples: &mut [f64]) {
+        Equalizer::process(self, samples)
+    }
+}
+
+#[cfg(feature = "ladspa")]
+impl Stage for Instance {
+    fn process(&mut self, samples: &mut [f64]) {
+        Instance::process(self, samples)
+    }
+}
+
+/// What a stage loads from its files, once for all the sinks.
+#[derive(Clone)]
+enum Loaded {
+    #[cfg(feature = "convolution")]
+    Filter(Arc<Filter>),
+    #[cfg(feature = "ladspa")]
+    Plugin(Arc<Plugin>),
+}
+
+/// The stages of the DSP chain, with the impulse responses and the plugins
+/// they need loaded.
+#[derive(Clone, Default)]
+pub(crate) struct DspChain {
+    stages: Vec<DspStage>,
+    loaded: Vec<Option<Loaded>>,
+}
+
+impl DspChain {
+    /// Loads the files of the stages, skipping the ones that fail to load
+    /// so that a bad impulse response or plugin doesn't keep spotifyd from
+    /// playing.
+    pub(crate) fn load(stages: Vec<DspStage>) -> Self {
+        let mut chain = Self::default();
+        for stage in stages {
+            let loaded = match stage {
+                #[cfg(feature = "convolution")]
+                DspStage::Convolution {
+                    ref impulse_response,
+                } => Filter::load(impulse_response)
+                    .map(|filter| Some(Loaded::Filter(Arc::new(filter)))),
+                #[cfg(feature = "ladspa")]
+                DspStage::Ladspa {
+                    ref plugin,
+                    ref label,
+                    ref controls,
+                } => Plugin::load(plugin, label, controls)
+                    .map(|plugin| Some(Loaded::Plugin(Arc::new(plugin)))),
+                _ => Ok(None),
+            };
+            match loaded {
+                Ok(loaded) => {
+                    chain.stages.push(stage);
+                    chain.loaded.push(loaded);
+                }
+                Err(e) => error!("Skipping the DSP stage {:?}: {}", stage, e),
+            }
+        }
+        chain
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+}
+
+fn build(
+    stage: &DspStage,
+    loaded: Option<&Loaded>,
+    switches: &DspSwitches,
+) -> Result<Box<dyn Stage>, String> {
+    Ok(match (stage, loaded) {
+        (&DspStage::Gain { db }, _) => Box::new(Gain {
+            factor: db_to_factor(db),
+        }),
+        (
+            &DspStage::Limiter {
+                threshold_db,
+                release_ms,
+            },
+            _,
+        ) => Box::new(Limiter::new(threshold_db, release_ms)),
+        (
+            &DspStage::Crossfeed {
+                level_db,
+                cutoff_hz,
+            },
+            _,
+        ) => Box::new(Crossfeed::new(
+            level_db,
+            cutoff_hz,
+            switches.crossfeed.clone().unwrap_or_default(),
+        )),
+        (DspStage::Equalizer { preset, bands }, _) => Box::new(Equalizer::new(*preset, bands)?),
+        #[cfg(feature = "convolution")]
+        (DspStage::Convolution { .. }, Some(Loaded::Filter(filter))) => {
+            Box::new(Convolver::new(filter.clone()))
+        }
+        #[cfg(feature = "ladspa")]
+        (DspStage::Ladspa { .. }, Some(Loaded::Plugin(plugin))) => {
+            Box::new(Instance::new(plugin.clone())?)
+        }
+        // the convolution and ladspa stages are removed from the config
+        // without their features
+        (stage, _) => unreachable!("{:?} isn't loaded", stage),
+    })
+}
+
+/// Whether the stage is heavy on the CPU, so that it's bypassed when the
+/// load is reduced.
+fn is_heavy(stage: &DspStage) -> bool {
+    match stage {
+        DspStage::Equalizer { .. } | DspStage::Convolution { .. } | DspStage::Ladspa { .. } => true,
+        _ => false,
+    }
+}
+
+/// A sink passing the samples through the configured stages, in order,
+/// before writing them to the inner sink.
+pub(crate) struct DspSink {
+    inner: Box<dyn Sink>,
+    /// The stages, with whether they are heavy.
+    stages: Vec<(Box<dyn Stage>, bool)>,
+    switches: DspSwitches,
+}
+
+impl DspSink {
+    pub(crate) fn new(inner: Box<dyn Sink>, chain: &DspChain, switches: &DspSwitches) -> Self {
+        let stages = chain
+            .stages
+            .iter()
+            .zip(&chain.loaded)
+            .filter_map(|(stage, loaded)| {
+                build(stage, loaded.as_ref(), switches)
+                    .map(|built| (built, is_heavy(stage)))
+                    .map_err(|e| error!("Skipping the DSP stage {:?}: {}", stage, e))
+                    .ok()
+            })
+            .collect();
+        Self {
+            inner,
+            stages,
+            switches: switches.clone(),
+        }
+    }
+}
+
+impl Sink for DspSink {
+    fn start(&mut self) -> SinkResult<()> {
+        self.inner.start()
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        self.inner.stop()
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        match packet {
+            AudioPacket::Samples(mut samples) => {
+                let reduced = self.switches.is_reduced();
+                for (stage, heavy) in &mut self.stages {
+                    if !(reduced && *heavy) {
+                        stage.process(&mut samples);
+                    }
+                }
+                self.inner.write(AudioPacket::Samples(samples), converter)
+            }
+            // passed through undecoded, there are no samples to process
+            packet => self.inner.write(packet, converter),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain() {
+        let switches = DspSwitches::new(&[]);
+        let mut gain = build(&DspStage::Gain { db: -6.0 }, None, &switches).unwrap();
+        let mut samples = vec![1.0, -0.5];
+        gain.process(&mut samples);
+        assert!((samples[0] - 0.501).abs() < 0.001);
+        assert!((samples[1] + 0.251).abs() < 0.001);
+
+        let mut limiter = build(
+            &DspStage::Limiter {
+                threshold_db: -6.0,
+                release_ms: 100.0,
+            },
+            None,
+            &switches,
+        )
+        .unwrap();
+        let mut samples = vec![0.2, 0.2, 1.0, -1.0, 0.2, 0.2];
+        limiter.process(&mut samples);
+        assert_eq!(samples[..2], [0.2, 0.2]);
+        assert!((samples[2] - 0.501).abs() < 0.001);
+        assert!((samples[3] + 0.501).abs() < 0.001);
+        // released slowly
+        assert!(samples[4] < 0.11);
+
+        let stages = [DspStage::Crossfeed {
+            level_db: 4.5,
+            cutoff_hz: 700.0,
+        }];
+        let switches = DspSwitches::new(&stages);
+        let mut crossfeed = build(&stages[0], None, &switches).unwrap();
+        let mut samples = [1.0, 0.0].repeat(10_000);
+        crossfeed.process(&mut samples);
+        let (left, right) = (samples[19_998], samples[19_999]);
+        assert!(right > 0.2 && right < left);
+        assert!((left + right - 1.0).abs() < 0.001);
+
+        assert!(switches.set_crossfeed(false));
+        let mut samples = [1.0, 0.0];
+        crossfeed.process(&mut samples);
+        assert_eq!(samples, [1.0, 0.0]);
+        assert!(!DspSwitches::default().set_crossfeed(true));
+    }
+}
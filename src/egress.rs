@@ -0,0 +1,126 @@
+use crate::{
+    control::{ControlHandle, PlaybackControl},
+    events::{EventBus, SpotifydEvent},
+    state::{PlaybackStatus, SharedPlaybackState},
+};
+use log::{error, info, warn};
+use std::{fs, time::Duration};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The flags of an interface that is up and has a carrier.
+const IFF_UP: u32 = 0x1;
+const IFF_RUNNING: u32 = 0x40;
+/// The flag of a usable route.
+const RTF_UP: u32 = 0x1;
+
+fn parse_flags(flags: &str) -> Option<u32> {
+    u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).ok()
+}
+
+/// Whether the flags of `/sys/class/net/<interface>/flags` describe an
+/// interface that is up.
+fn is_running(flags: &str) -> bool {
+    let running = IFF_UP | IFF_RUNNING;
+    parse_flags(flags).map_or(false, |flags| flags & running == running)
+}
+
+/// Whether a route of `/proc/net/route` or `/proc/net/ipv6_route` goes via
+/// the interface.
+fn has_route(routes: &str, ipv6_routes: &str, interface: &str) -> bool {
+    let is_up = |flags: &str| parse_flags(flags).map_or(false, |flags| flags & RTF_UP != 0);
+    // the interface and the flags are the first and fourth columns of the
+    // IPv4 routes, which have a header
+    let ipv4 = routes.lines().skip(1).any(|line| {
+        let columns: Vec<_> = line.split_whitespace().collect();
+        columns.len() > 3 && columns[0] == interface && is_up(columns[3])
+    });
+    // and the last and ninth of the IPv6 ones
+    let ipv6 = ipv6_routes.lines().any(|line| {
+        let columns: Vec<_> = line.split_whitespace().collect();
+        columns.len() == 10 && columns[9] == interface && is_up(columns[8])
+    });
+    ipv4 || ipv6
+}
+
+fn is_available(interface: &str) -> bool {
+    let read = |path: &str| fs::read_to_string(path).unwrap_or_default();
+    is_running(&read(&format!("/sys/class/net/{}/flags", interface)))
+        && has_route(
+            &read("/proc/net/route"),
+            &read("/proc/net/ipv6_route"),
+            interface,
+        )
+}
+
+/// Pauses the playback while the interface the connections are bound to is
+/// down or has lost its routes, e.g. when a WireGuard tunnel is torn down, so
+/// that librespot doesn't buffer into a connection that goes nowhere.
+///
+/// The playback resumes once the route returns, unless it has been resumed or
+/// stopped in the meantime.
+pub(crate) async fn watch_egress(
+    interface: String,
+    control: ControlHandle,
+    playback_state: SharedPlaybackState,
+    event_bus: EventBus,
+) {
+    let mut available = true;
+    let mut paused = false;
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        if is_available(&interface) == available {
+            continue;
+        }
+        available = !available;
+
+        if !available {
+            warn!("Lost the route via {}, pausing the playback", interface);
+            paused = playback_state.read().unwrap().status == PlaybackStatus::Playing;
+            if paused {
+                if let Err(e) = control.pause() {
+                    error!("Failed to pause the playback: {}", e);
+                    paused = false;
+                }
+            }
+            event_bus.publish(SpotifydEvent::EgressLost {
+                interface: interface.clone(),
+            });
+        } else {
+            info!("The route via {} is back", interface);
+            event_bus.publish(SpotifydEvent::EgressRestored {
+                interface: interface.clone(),
+            });
+            let still_paused = playback_state.read().unwrap().status == PlaybackStatus::Paused;
+            if paused && still_paused {
+                if let Err(e) = control.play() {
+                    error!("Failed to resume the playback: {}", e);
+                }
+            }
+            paused = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes() {
+        let routes =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+             eth0\t00000000\t0101A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0\n\
+             wg0\t0000000A\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n";
+        let ipv6_routes = "fd000000000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001     wg1\n";
+        assert!(has_route(routes, ipv6_routes, "wg0"));
+        assert!(has_route(routes, ipv6_routes, "wg1"));
+        assert!(!has_route(routes, ipv6_routes, "tun0"));
+        assert!(!has_route("", "", "wg0"));
+
+        assert!(is_running("0x1043\n"));
+        assert!(!is_running("0x1002\n"));
+        assert!(!is_running(""));
+    }
+}
@@ -0,0 +1,164 @@
+#[cfg(feature = "cache_encryption")]
+use librespot_core::Error;
+use librespot_core::{authentication::Credentials, session::Session};
+#[cfg(feature = "cache_encryption")]
+use log::{debug, warn};
+use std::{fmt, path::PathBuf};
+
+/// The credentials of the cache, encrypted with a key derived from the
+/// configured secret, for when the storage can be read by others.
+///
+/// librespot can only store them unencrypted, so spotifyd stores them
+/// itself. Without the `cache_encryption` feature, nothing is stored.
+#[derive(Clone)]
+pub struct EncryptedCredentials {
+    path: PathBuf,
+    #[cfg_attr(not(feature = "cache_encryption"), allow(unused))]
+    secret: String,
+}
+
+impl fmt::Debug for EncryptedCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EncryptedCredentials")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptedCredentials {
+    pub(crate) fn new(path: PathBuf, secret: String) -> Self {
+        Self { path, secret }
+    }
+
+    #[cfg(feature = "cache_encryption")]
+    pub(crate) fn load(&self) -> Option<Credentials> {
+        let data = std::fs::read(&self.path).ok()?;
+        let credentials = decrypt(&self.secret, &data)
+            .and_then(|plaintext| serde_json::from_slice(&plaintext).map_err(Error::data_loss));
+        match credentials {
+            Ok(credentials) => Some(credentials),
+            Err(e) => {
+                warn!("Failed to decrypt {}: {}", self.path.display(), e);
+                None
+            }
+        }
+    }
+
+    #[cfg(not(feature = "cache_encryption"))]
+    pub(crate) fn load(&self) -> Option<Credentials> {
+        None
+    }
+
+    /// Stores the reusable credentials of the connected session.
+    #[cfg(feature = "cache_encryption")]
+    pub(crate) fn save(&self, session: &Session) {
+        use librespot_protocol::authentication::AuthenticationType;
+
+        let credentials = Credentials {
+            username: Some(session.username()),
+            auth_type: AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS,
+            auth_data: session.auth_data(),
+        };
+        let plaintext = serde_json::to_vec(&credentials).unwrap();
+        match std::fs::write(&self.path, encrypt(&self.secret, &plaintext)) {
+            Ok(()) => debug!("Stored the encrypted credentials"),
+            Err(e) => warn!("Failed to write {}: {}", self.path.display(), e),
+        }
+    }
+
+    #[cfg(not(feature = "cache_encryption"))]
+    pub(crate) fn save(&self, _session: &Session) {}
+}
+
+#[cfg(feature = "cache_encryption")]
+const SALT_LEN: usize = 16;
+#[cfg(feature = "cache_encryption")]
+const IV_LEN: usize = 16;
+#[cfg(feature = "cache_encryption")]
+const TAG_LEN: usize = 32;
+#[cfg(feature = "cache_encryption")]
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+#[cfg(feature = "cache_encryption")]
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+#[cfg(feature = "cache_encryption")]
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+/// Derives the keys for the encryption and the authentication from the
+/// secret.
+#[cfg(feature = "cache_encryption")]
+fn derive_keys(secret: &str, salt: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut keys = [0u8; 64];
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(secret.as_bytes(), salt, PBKDF2_ROUNDS, &mut keys);
+    let mut encryption_key = [0u8; 32];
+    let mut mac_key = [0u8; 32];
+    encryption_key.copy_from_slice(&keys[..32]);
+    mac_key.copy_from_slice(&keys[32..]);
+    (encryption_key, mac_key)
+}
+
+/// Encrypts the data with AES-256-CTR and authenticates it with
+/// HMAC-SHA256. The result is the salt, the IV, the ciphertext and the tag.
+#[cfg(feature = "cache_encryption")]
+fn encrypt(secret: &str, plaintext: &[u8]) -> Vec<u8> {
+    use aes::cipher::{KeyIvInit, StreamCipher};
+    use hmac::Mac;
+    use rand::RngCore;
+
+    let mut header = [0u8; SALT_LEN + IV_LEN];
+    rand::thread_rng().fill_bytes(&mut header);
+    let (salt, iv) = header.split_at(SALT_LEN);
+    let (encryption_key, mac_key) = derive_keys(secret, salt);
+
+    let mut data = header.to_vec();
+    data.extend_from_slice(plaintext);
+    Aes256Ctr::new(&encryption_key.into(), iv.into()).apply_keystream(&mut data[header.len()..]);
+
+    let mut mac = HmacSha256::new_from_slice(&mac_key).unwrap();
+    mac.update(&data);
+    data.extend_from_slice(&mac.finalize().into_bytes());
+    data
+}
+
+#[cfg(feature = "cache_encryption")]
+fn decrypt(secret: &str, data: &[u8]) -> Result<Vec<u8>, Error> {
+    use aes::cipher::{KeyIvInit, StreamCipher};
+    use hmac::Mac;
+
+    if data.len() < SALT_LEN + IV_LEN + TAG_LEN {
+        return Err(Error::data_loss("the file is truncated"));
+    }
+    let (data, tag) = data.split_at(data.len() - TAG_LEN);
+    let (salt, rest) = data.split_at(SALT_LEN);
+    let (iv, ciphertext) = rest.split_at(IV_LEN);
+    let (encryption_key, mac_key) = derive_keys(secret, salt);
+
+    let mut mac = HmacSha256::new_from_slice(&mac_key).unwrap();
+    mac.update(data);
+    mac.verify_slice(tag)
+        .map_err(|_| Error::permission_denied("wrong cache_secret or corrupted file"))?;
+
+    let mut plaintext = ciphertext.to_vec();
+    Aes256Ctr::new(&encryption_key.into(), iv.into()).apply_keystream(&mut plaintext);
+    Ok(plaintext)
+}
+
+#[cfg(all(test, feature = "cache_encryption"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encryption_roundtrip() {
+        let data = encrypt("correct horse", b"{\"username\":\"alice\"}");
+        assert_eq!(
+            decrypt("correct horse", &data).unwrap(),
+            b"{\"username\":\"alice\"}"
+        );
+        assert!(decrypt("battery staple", &data).is_err());
+
+        let mut tampered = data.clone();
+        tampered[SALT_LEN + IV_LEN] ^= 1;
+        assert!(decrypt("correct horse", &tampered).is_err());
+        assert!(decrypt("correct horse", &data[..10]).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use crate::config::{EqBand, EqFilter, EqPreset};
+use librespot_playback::{NUM_CHANNELS, SAMPLE_RATE};
+use std::f64::consts::PI;
+
+/// The bands of a preset.
+fn preset_bands(preset: EqPreset) -> Vec<EqBand> {
+    let band = |filter, freq_hz, gain_db, q| EqBand {
+        filter,
+        freq_hz,
+        gain_db,
+        q,
+    };
+    match preset {
+        EqPreset::BassBoost => vec![band(EqFilter::LowShelf, 100.0, 6.0, 0.707)],
+        EqPreset::SmallSpeakers => vec![
+            band(EqFilter::LowShelf, 60.0, -6.0, 0.707),
+            band(EqFilter::Peak, 150.0, 4.0, 1.0),
+            band(EqFilter::HighShelf, 8000.0, 2.0, 0.707),
+        ],
+        EqPreset::Loudness => vec![
+            band(EqFilter::LowShelf, 100.0, 6.0, 0.707),
+            band(EqFilter::HighShelf, 10000.0, 4.0, 0.707),
+        ],
+        EqPreset::Vocal => vec![
+            band(EqFilter::LowShelf, 150.0, -2.0, 0.707),
+            band(EqFilter::Peak, 2500.0, 3.0, 1.0),
+        ],
+    }
+}
+
+/// A second order filter of a band, after the Audio EQ Cookbook of Robert
+/// Bristow-Johnson, with its coefficients divided by `a0`.
+#[derive(Clone, Copy, Debug)]
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+impl Biquad {
+    fn new(band: &EqBand) -> Result<Self, String> {
+        let nyquist = SAMPLE_RATE as f64 / 2.0;
+        let audible = band.freq_hz > 0.0 && band.freq_hz < nyquist;
+        if !audible {
+            return Err(format!(
+                "the frequency {} Hz isn't between 0 and {} Hz",
+                band.freq_hz, nyquist
+            ));
+        }
+        if band.q.is_nan() || band.q <= 0.0 {
+            return Err(format!("the q {} isn't above 0", band.q));
+        }
+        let a = 10f64.powf(band.gain_db / 40.0);
+        let w0 = 2.0 * PI * band.freq_hz / SAMPLE_RATE as f64;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * band.q);
+        let shelf = 2.0 * a.sqrt() * alpha;
+        let [b0, b1, b2, a0, a1, a2] = match band.filter {
+            EqFilter::Peak => [
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ],
+            EqFilter::LowShelf => [
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ],
+            EqFilter::HighShelf => [
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ],
+        };
+        Ok(Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        })
+    }
+}
+
+/// A multi-band equalizer, running the filters of the bands one after the
+/// other on each channel.
+pub(crate) struct Equalizer {
+    filters: Vec<Biquad>,
+    /// The state of each filter for each channel, in transposed direct
+    /// form II.
+    state: Vec<[[f64; 2]; NUM_CHANNELS as usize]>,
+}
+
+impl Equalizer {
+    pub(crate) fn new(preset: Option<EqPreset>, bands: &[EqBand]) -> Result<Self, String> {
+        let filters = preset
+            .map(preset_bands)
+            .unwrap_or_default()
+            .iter()
+            .chain(bands)
+            .map(Biquad::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            state: vec![Default::default(); filters.len()],
+            filters,
+        })
+    }
+
+    pub(crate) fn process(&mut self, samples: &mut [f64]) {
+        for frame in samples.chunks_exact_mut(NUM_CHANNELS as usize) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                for (filter, state) in self.filters.iter().zip(&mut self.state) {
+                    let [z1, z2] = &mut state[channel];
+                    let input = *sample;
+                    *sample = filter.b0 * input + *z1;
+                    *z1 = filter.b1 * input - filter.a1 * *sample + *z2;
+                    *z2 = filter.b2 * input - filter.a2 * *sample;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The gain of the filter in decibels at 0 Hz, or at the Nyquist
+    /// frequency.
+    fn gain_db(filter: &Biquad, nyquist: bool) -> f64 {
+        let z = if nyquist { -1.0 } else { 1.0 };
+        let gain = (filter.b0 + filter.b1 * z + filter.b2) / (1.0 + filter.a1 * z + filter.a2);
+        20.0 * gain.abs().log10()
+    }
+
+    #[test]
+    fn test_bands() {
+        let band = |filter, gain_db| EqBand {
+            filter,
+            freq_hz: 1000.0,
+            gain_db,
+            q: 0.707,
+        };
+        let low_shelf = Biquad::new(&band(EqFilter::LowShelf, 6.0)).unwrap();
+        assert!((gain_db(&low_shelf, false) - 6.0).abs() < 0.01);
+        assert!(gain_db(&low_shelf, true).abs() < 0.01);
+        let high_shelf = Biquad::new(&band(EqFilter::HighShelf, -6.0)).unwrap();
+        assert!(gain_db(&high_shelf, false).abs() < 0.01);
+        assert!((gain_db(&high_shelf, true) + 6.0).abs() < 0.01);
+        let peak = Biquad::new(&band(EqFilter::Peak, 6.0)).unwrap();
+        assert!(gain_db(&peak, false).abs() < 0.01);
+        assert!(Biquad::new(&EqBand {
+            freq_hz: 30000.0,
+            ..band(EqFilter::Peak, 6.0)
+        })
+        .is_err());
+
+        // a constant signal settles at the gain of the low shelf
+        let mut equalizer = Equalizer::new(None, &[band(EqFilter::LowShelf, 6.0)]).unwrap();
+        let mut samples = vec![0.5; 2 * 4096];
+        equalizer.process(&mut samples);
+        assert!((samples[samples.len() - 1] - 0.5 * 1.995).abs() < 0.01);
+    }
+}
@@ -6,6 +6,12 @@ use std::{
 #[derive(Clone, Debug)]
 pub struct ParseError(String);
 
+impl ParseError {
+    pub(crate) fn new(msg: impl Into<String>) -> Self {
+        Self(msg.into())
+    }
+}
+
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "failed to parse config entry: {}", self.0)
@@ -37,7 +43,7 @@ impl Error {
 
     pub(crate) fn subprocess_with_err<E>(shell: &str, cmd: &str, e: E) -> Self
     where
-        E: std::error::Error + 'static,
+        E: std::error::Error + Send + Sync + 'static,
     {
         Self {
             kind: ErrorKind::Subprocess {
@@ -48,6 +54,21 @@ impl Error {
         }
     }
 
+    /// Whether the error was caused by the system running out of memory or
+    /// processes, in which case trying again later might succeed.
+    pub(crate) fn is_resource_exhaustion(&self) -> bool {
+        match &self.kind {
+            ErrorKind::Subprocess {
+                msg: Message::Error(e),
+                ..
+            } => e
+                .downcast_ref::<std::io::Error>()
+                .and_then(std::io::Error::raw_os_error)
+                .map_or(false, |code| code == libc::ENOMEM || code == libc::EAGAIN),
+            _ => false,
+        }
+    }
+
     pub(crate) fn subprocess_with_str(shell: &str, cmd: &str, s: &str) -> Self {
         Self {
             kind: ErrorKind::Subprocess {
@@ -113,5 +134,5 @@ impl Display for ErrorKind {
 pub(crate) enum Message {
     None,
     String(String),
-    Error(Box<dyn std::error::Error + 'static>),
+    Error(Box<dyn std::error::Error + Send + Sync + 'static>),
 }
@@ -0,0 +1,170 @@
+use crate::events::{EventSubscriber, SpotifydEvent};
+use log::{debug, info, warn};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// The longest line that is written, so that the lines are written to a pipe
+/// at once and never interleave with or tear at the lines of other writers.
+#[cfg(target_os = "linux")]
+const MAX_LINE_LEN: usize = libc::PIPE_BUF;
+/// The minimum `PIPE_BUF` of POSIX.
+#[cfg(not(target_os = "linux"))]
+const MAX_LINE_LEN: usize = 512;
+
+/// An append-only log of the events, one JSON object per line.
+///
+/// The log may be a regular file or a named pipe. A pipe is opened without
+/// blocking, so events are dropped while no reader is connected, and it is
+/// reopened once the reader disappears. Each line is written with a single
+/// write, an event that can't be written at once is dropped.
+struct EventLog {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl EventLog {
+    fn new(path: PathBuf) -> Self {
+        Self { path, file: None }
+    }
+
+    fn open(path: &Path) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            // opening a pipe for writing would block until there is a reader
+            options.custom_flags(libc::O_NONBLOCK);
+        }
+        options.open(path)
+    }
+
+    fn write(&mut self, event: &SpotifydEvent) {
+        if self.file.is_none() {
+            match Self::open(&self.path) {
+                Ok(file) => {
+                    info!("Writing events to {}", self.path.display());
+                    self.file = Some(file);
+                }
+                Err(e) if is_missing_reader(&e) => {
+                    debug!(
+                        "No reader on {}, dropping {} event",
+                        self.path.display(),
+                        event.name()
+                    );
+                    return;
+                }
+                Err(e) => {
+                    warn!("Failed to open {}: {}", self.path.display(), e);
+                    return;
+                }
+            }
+        }
+        let file = self.file.as_mut().unwrap();
+
+        let mut line = serde_json::to_vec(event).unwrap();
+        line.push(b'\n');
+        if line.len() > MAX_LINE_LEN {
+            warn!(
+                "The {} event is longer than {} bytes, dropping it",
+                event.name(),
+                MAX_LINE_LEN
+            );
+            return;
+        }
+        match file.write(&line) {
+            Ok(written) if written == line.len() => (),
+            // the rest isn't written, a second write could interleave with
+            // the lines of other writers
+            Ok(written) => warn!(
+                "Wrote only {} of {} bytes of the {} event to {}",
+                written,
+                line.len(),
+                event.name(),
+                self.path.display()
+            ),
+            // the pipe is full, the reader can't keep up
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                debug!(
+                    "{} is full, dropping {} event",
+                    self.path.display(),
+                    event.name()
+                );
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::BrokenPipe {
+                    info!("The reader of {} disappeared", self.path.display());
+                } else {
+                    warn!("Failed to write to {}: {}", self.path.display(), e);
+                }
+                // reopened for the next event
+                self.file = None;
+            }
+        }
+    }
+}
+
+/// Whether opening the pipe failed because nobody is reading from it.
+#[cfg(unix)]
+fn is_missing_reader(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENXIO)
+}
+
+#[cfg(not(unix))]
+fn is_missing_reader(_e: &io::Error) -> bool {
+    false
+}
+
+/// Writes every event received by the subscriber to the event log at the path.
+pub(crate) async fn write_event_log(path: PathBuf, mut events: EventSubscriber) {
+    let mut log = EventLog::new(path);
+    while let Some(event) = events.recv().await {
+        log.write(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("spotifyd-event-log-{}", std::process::id()));
+        fs::write(&path, "{}\n").unwrap();
+
+        let mut log = EventLog::new(path.clone());
+        log.write(&SpotifydEvent::VolumeChanged { volume: 100 });
+        log.write(&SpotifydEvent::ShuffleChanged { shuffle: true });
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            content,
+            "{}\n\
+             {\"event\":\"volume_changed\",\"volume\":100}\n\
+             {\"event\":\"shuffle_changed\",\"shuffle\":true}\n"
+        );
+    }
+
+    #[test]
+    fn test_drops_too_long_events() {
+        let path =
+            std::env::temp_dir().join(format!("spotifyd-event-log-long-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut log = EventLog::new(path.clone());
+        log.write(&SpotifydEvent::BlockedSkipped {
+            track_id: "4uLU6hMCjMI75M1A2tKUQC".to_string(),
+            blocked_uri: "x".repeat(MAX_LINE_LEN),
+        });
+        log.write(&SpotifydEvent::VolumeChanged { volume: 100 });
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(content, "{\"event\":\"volume_changed\",\"volume\":100}\n");
+    }
+}
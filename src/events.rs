@@ -0,0 +1,878 @@
+use crate::state::Activity;
+use librespot_core::spotify_id::SpotifyId;
+use librespot_metadata::audio::{AudioItem, UniqueFields};
+use librespot_playback::player::PlayerEvent;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    Notify,
+};
+
+/// The number of events kept for subscribers that connect late.
+pub const REPLAY_BUFFER_SIZE: usize = 32;
+
+/// The number of events a subscriber can fall behind before it misses events,
+/// unless it subscribed with [`EventBus::subscribe_queued`].
+const BUS_CAPACITY: usize = 64;
+
+/// The number of events queued for a subscriber of
+/// [`EventBus::subscribe_queued`] before the oldest ones are dropped.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// How often a queue that drops events is warned about at most.
+const QUEUE_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A player event, as published on the [`EventBus`].
+///
+/// In contrast to librespot's `PlayerEvent`, this owns all of its data and
+/// can be serialized. The serialized names of the events are the same as the
+/// values of `PLAYER_EVENT` passed to the `onevent` hook.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SpotifydEvent {
+    PlayRequestIdChanged {
+        play_request_id: u64,
+    },
+    #[serde(rename = "stop")]
+    Stopped {
+        play_request_id: u64,
+        track_id: String,
+    },
+    #[serde(rename = "load")]
+    Loading {
+        play_request_id: u64,
+        track_id: String,
+        position_ms: u32,
+    },
+    Preloading {
+        track_id: String,
+    },
+    #[serde(rename = "play")]
+    Playing {
+        play_request_id: u64,
+        track_id: String,
+        position_ms: u32,
+    },
+    #[serde(rename = "pause")]
+    Paused {
+        play_request_id: u64,
+        track_id: String,
+        position_ms: u32,
+    },
+    #[serde(rename = "preload")]
+    TimeToPreloadNextTrack {
+        play_request_id: u64,
+        track_id: String,
+    },
+    #[serde(rename = "endoftrack")]
+    EndOfTrack {
+        play_request_id: u64,
+        track_id: String,
+    },
+    Unavailable {
+        play_request_id: u64,
+        track_id: String,
+    },
+    /// An unavailable track has been skipped by spotifyd.
+    UnavailableSkipped {
+        play_request_id: u64,
+        track_id: String,
+    },
+    /// A track on the blocklist has been skipped by spotifyd.
+    BlockedSkipped {
+        track_id: String,
+        /// The entry of the blocklist that matched, the URI of the track or
+        /// one of its artists.
+        blocked_uri: String,
+    },
+    VolumeChanged {
+        volume: u16,
+    },
+    PositionCorrection {
+        play_request_id: u64,
+        track_id: String,
+        position_ms: u32,
+    },
+    Seeked {
+        play_request_id: u64,
+        track_id: String,
+        position_ms: u32,
+    },
+    TrackChanged(TrackInfo),
+    SessionConnected {
+        connection_id: String,
+        user_name: String,
+        /// The address of the client that connected the session via the
+        /// discovery, if it's known.
+        client_ip: Option<IpAddr>,
+    },
+    SessionDisconnected {
+        connection_id: String,
+        user_name: String,
+    },
+    SessionClientChanged {
+        client_id: String,
+        client_name: String,
+        client_brand_name: String,
+        client_model_name: String,
+        /// The address of the client that connected the session via the
+        /// discovery, if it's known.
+        client_ip: Option<IpAddr>,
+    },
+    ShuffleChanged {
+        shuffle: bool,
+    },
+    RepeatChanged {
+        repeat: bool,
+    },
+    AutoPlayChanged {
+        auto_play: bool,
+    },
+    FilterExplicitContentChanged {
+        filter: bool,
+    },
+    /// The interface the connections are bound to has gone down or lost its
+    /// routes, and the playback has been paused.
+    EgressLost {
+        interface: String,
+    },
+    /// The route via the interface the connections are bound to is back.
+    EgressRestored {
+        interface: String,
+    },
+    /// Another device took over the playback from this one.
+    TakenOver {
+        /// The name of the device, if the Web API reports it.
+        device_name: Option<String>,
+    },
+    /// The lock refused another account's connection via the discovery, or
+    /// paused the playback another client started.
+    TakeoverRefused {
+        /// The account that tried to connect, if it came via the discovery.
+        user_name: Option<String>,
+        /// The client that took control, if it was one of the same account.
+        client_name: Option<String>,
+    },
+    /// The device has been paused, stopped or without a session for longer
+    /// than the idle timeout of that activity.
+    Idle {
+        activity: Activity,
+        idle_ms: u64,
+    },
+    /// The playback reached the end of the context after the track, and
+    /// nothing else will play, unlike a stop by a client.
+    QueueEnded {
+        track_id: String,
+    },
+}
+
+/// The metadata of a track or episode.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackInfo {
+    pub track_id: String,
+    pub uri: String,
+    pub name: String,
+    pub duration_ms: u32,
+    pub is_explicit: bool,
+    /// The urls of the cover images.
+    pub covers: Vec<String>,
+    /// Either `track` or `episode`.
+    pub item_type: String,
+    /// For episodes, the name of the show.
+    pub artists: Vec<String>,
+    /// The URIs of the artists, in the order of `artists`. Empty for episodes.
+    #[serde(default)]
+    pub artist_uris: Vec<String>,
+    pub album_artists: Vec<String>,
+    /// For episodes, the name of the show.
+    pub album: String,
+    /// For episodes, the chapters listed in the description.
+    #[serde(default)]
+    pub chapters: Vec<Chapter>,
+}
+
+/// A chapter of an episode.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chapter {
+    pub start_ms: u32,
+    pub title: String,
+}
+
+/// Parses a timestamp like `1:02:03` or `02:03` into milliseconds.
+fn parse_timestamp(s: &str) -> Option<u32> {
+    let mut parts = s.split(':').rev();
+    let seconds: u32 = parts.next()?.parse().ok()?;
+    let minutes: u32 = parts.next()?.parse().ok()?;
+    let hours: u32 = parts.next().map_or(Some(0), |h| h.parse().ok())?;
+    if parts.next().is_some() || seconds >= 60 || (hours > 0 && minutes >= 60) {
+        return None;
+    }
+    Some((hours * 3600 + minutes * 60 + seconds) * 1000)
+}
+
+/// Parses the chapters from an episode description, where podcasts commonly
+/// list them as lines like `(12:34) Title` or `12:34 - Title`.
+///
+/// Descriptions that don't list at least two chapters in order are treated
+/// as having no chapters, as their timestamps are likely something else.
+pub(crate) fn parse_chapters(description: &str) -> Vec<Chapter> {
+    let mut chapters: Vec<Chapter> = Vec::new();
+    for line in description.lines() {
+        let line = line.trim().trim_start_matches(|c| c == '(' || c == '[');
+        let end = line
+            .find(|c: char| !c.is_ascii_digit() && c != ':')
+            .unwrap_or(line.len());
+        let Some(start_ms) = parse_timestamp(&line[..end]) else {
+            continue;
+        };
+        let title = line[end..]
+            .trim_start_matches(|c: char| {
+                c.is_whitespace() || matches!(c, ')' | ']' | '-' | '\u{2013}' | '\u{2014}' | ':')
+            })
+            .trim();
+        if chapters
+            .last()
+            .map_or(false, |last| last.start_ms >= start_ms)
+        {
+            return Vec::new();
+        }
+        chapters.push(Chapter {
+            start_ms,
+            title: title.to_string(),
+        });
+    }
+    if chapters.len() < 2 {
+        return Vec::new();
+    }
+    chapters
+}
+
+impl SpotifydEvent {
+    /// The name of the event, as passed to hooks in `PLAYER_EVENT`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SpotifydEvent::PlayRequestIdChanged { .. } => "play_request_id_changed",
+            SpotifydEvent::Stopped { .. } => "stop",
+            SpotifydEvent::Loading { .. } => "load",
+            SpotifydEvent::Preloading { .. } => "preloading",
+            SpotifydEvent::Playing { .. } => "play",
+            SpotifydEvent::Paused { .. } => "pause",
+            SpotifydEvent::TimeToPreloadNextTrack { .. } => "preload",
+            SpotifydEvent::EndOfTrack { .. } => "endoftrack",
+            SpotifydEvent::Unavailable { .. } => "unavailable",
+            SpotifydEvent::UnavailableSkipped { .. } => "unavailable_skipped",
+            SpotifydEvent::BlockedSkipped { .. } => "blocked_skipped",
+            SpotifydEvent::VolumeChanged { .. } => "volume_changed",
+            SpotifydEvent::PositionCorrection { .. } => "position_correction",
+            SpotifydEvent::Seeked { .. } => "seeked",
+            SpotifydEvent::TrackChanged(_) => "track_changed",
+            SpotifydEvent::SessionConnected { .. } => "session_connected",
+            SpotifydEvent::SessionDisconnected { .. } => "session_disconnected",
+            SpotifydEvent::SessionClientChanged { .. } => "session_client_changed",
+            SpotifydEvent::ShuffleChanged { .. } => "shuffle_changed",
+            SpotifydEvent::RepeatChanged { .. } => "repeat_changed",
+            SpotifydEvent::AutoPlayChanged { .. } => "auto_play_changed",
+            SpotifydEvent::FilterExplicitContentChanged { .. } => "filter_explicit_content_changed",
+            SpotifydEvent::EgressLost { .. } => "egress_lost",
+            SpotifydEvent::EgressRestored { .. } => "egress_restored",
+            SpotifydEvent::TakenOver { .. } => "taken_over",
+            SpotifydEvent::TakeoverRefused { .. } => "takeover_refused",
+            SpotifydEvent::Idle { .. } => "idle",
+            SpotifydEvent::QueueEnded { .. } => "queue_ended",
+        }
+    }
+
+    /// The id of the track the event refers to, if any.
+    pub fn track_id(&self) -> Option<&str> {
+        match self {
+            SpotifydEvent::Stopped { track_id, .. }
+            | SpotifydEvent::Loading { track_id, .. }
+            | SpotifydEvent::Preloading { track_id }
+            | SpotifydEvent::Playing { track_id, .. }
+            | SpotifydEvent::Paused { track_id, .. }
+            | SpotifydEvent::TimeToPreloadNextTrack { track_id, .. }
+            | SpotifydEvent::EndOfTrack { track_id, .. }
+            | SpotifydEvent::Unavailable { track_id, .. }
+            | SpotifydEvent::UnavailableSkipped { track_id, .. }
+            | SpotifydEvent::BlockedSkipped { track_id, .. }
+            | SpotifydEvent::QueueEnded { track_id }
+            | SpotifydEvent::PositionCorrection { track_id, .. }
+            | SpotifydEvent::Seeked { track_id, .. } => Some(track_id),
+            SpotifydEvent::TrackChanged(info) => Some(&info.track_id),
+            _ => None,
+        }
+    }
+
+    /// The structured fields attached to the messages logged while the event
+    /// is handled, see [`logging::with_event_fields`](crate::logging).
+    pub(crate) fn log_fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![("EVENT", self.name().to_string())];
+        if let Some(track_id) = self.track_id() {
+            fields.push(("TRACK_ID", track_id.to_string()));
+        }
+        fields
+    }
+
+    /// The piece of state the event describes, if any.
+    fn state_kind(&self) -> Option<&'static str> {
+        match self {
+            SpotifydEvent::Playing { .. }
+            | SpotifydEvent::Paused { .. }
+            | SpotifydEvent::Stopped { .. } => Some("status"),
+            SpotifydEvent::TrackChanged(_) => Some("track"),
+            SpotifydEvent::Seeked { .. } | SpotifydEvent::PositionCorrection { .. } => {
+                Some("position")
+            }
+            SpotifydEvent::VolumeChanged { .. } => Some("volume"),
+            SpotifydEvent::ShuffleChanged { .. } => Some("shuffle"),
+            SpotifydEvent::RepeatChanged { .. } => Some("repeat"),
+            SpotifydEvent::AutoPlayChanged { .. } => Some("autoplay"),
+            SpotifydEvent::FilterExplicitContentChanged { .. } => Some("filter_explicit_content"),
+            SpotifydEvent::SessionClientChanged { .. } => Some("client"),
+            _ => None,
+        }
+    }
+}
+
+fn base62(track_id: SpotifyId) -> String {
+    track_id.to_base62().unwrap_or_default()
+}
+
+impl From<AudioItem> for TrackInfo {
+    fn from(audio_item: AudioItem) -> Self {
+        let mut info = TrackInfo {
+            track_id: base62(audio_item.track_id),
+            uri: audio_item.uri,
+            name: audio_item.name,
+            duration_ms: audio_item.duration_ms,
+            is_explicit: audio_item.is_explicit,
+            covers: audio_item.covers.into_iter().map(|c| c.url).collect(),
+            ..Default::default()
+        };
+        match audio_item.unique_fields {
+            UniqueFields::Track {
+                artists,
+                album,
+                album_artists,
+                ..
+            } => {
+                info.item_type = "track".to_string();
+                info.artist_uris = artists
+                    .0
+                    .iter()
+                    .filter_map(|a| a.id.to_uri().ok())
+                    .collect();
+                info.artists = artists.0.into_iter().map(|a| a.name).collect();
+                info.album_artists = album_artists;
+                info.album = album;
+            }
+            UniqueFields::Episode {
+                show_name,
+                description,
+                ..
+            } => {
+                info.item_type = "episode".to_string();
+                info.chapters = parse_chapters(&description);
+                info.artists = vec![show_name.clone()];
+                info.album = show_name;
+            }
+        }
+        info
+    }
+}
+
+impl From<PlayerEvent> for SpotifydEvent {
+    fn from(event: PlayerEvent) -> Self {
+        match event {
+            PlayerEvent::PlayRequestIdChanged { play_request_id } => {
+                SpotifydEvent::PlayRequestIdChanged { play_request_id }
+            }
+            PlayerEvent::Stopped {
+                play_request_id,
+                track_id,
+            } => SpotifydEvent::Stopped {
+                play_request_id,
+                track_id: base62(track_id),
+            },
+            PlayerEvent::Loading {
+                play_request_id,
+                track_id,
+                position_ms,
+            } => SpotifydEvent::Loading {
+                play_request_id,
+                track_id: base62(track_id),
+                position_ms,
+            },
+            PlayerEvent::Preloading { track_id } => SpotifydEvent::Preloading {
+                track_id: base62(track_id),
+            },
+            PlayerEvent::Playing {
+                play_request_id,
+                track_id,
+                position_ms,
+            } => SpotifydEvent::Playing {
+                play_request_id,
+                track_id: base62(track_id),
+                position_ms,
+            },
+            PlayerEvent::Paused {
+                play_request_id,
+                track_id,
+                position_ms,
+            } => SpotifydEvent::Paused {
+                play_request_id,
+                track_id: base62(track_id),
+                position_ms,
+            },
+            PlayerEvent::TimeToPreloadNextTrack {
+                play_request_id,
+                track_id,
+            } => SpotifydEvent::TimeToPreloadNextTrack {
+                play_request_id,
+                track_id: base62(track_id),
+            },
+            PlayerEvent::EndOfTrack {
+                play_request_id,
+                track_id,
+            } => SpotifydEvent::EndOfTrack {
+                play_request_id,
+                track_id: base62(track_id),
+            },
+            PlayerEvent::Unavailable {
+                play_request_id,
+                track_id,
+            } => SpotifydEvent::Unavailable {
+                play_request_id,
+                track_id: base62(track_id),
+            },
+            PlayerEvent::VolumeChanged { volume } => SpotifydEvent::VolumeChanged { volume },
+            PlayerEvent::PositionCorrection {
+                play_request_id,
+                track_id,
+                position_ms,
+            } => SpotifydEvent::PositionCorrection {
+                play_request_id,
+                track_id: base62(track_id),
+                position_ms,
+            },
+            PlayerEvent::Seeked {
+                play_request_id,
+                track_id,
+                position_ms,
+            } => SpotifydEvent::Seeked {
+                play_request_id,
+                track_id: base62(track_id),
+                position_ms,
+            },
+            PlayerEvent::TrackChanged { audio_item } => {
+                SpotifydEvent::TrackChanged((*audio_item).into())
+            }
+            PlayerEvent::SessionConnected {
+                connection_id,
+                user_name,
+            } => SpotifydEvent::SessionConnected {
+                connection_id,
+                user_name,
+                client_ip: None,
+            },
+            PlayerEvent::SessionDisconnected {
+                connection_id,
+                user_name,
+            } => SpotifydEvent::SessionDisconnected {
+                connection_id,
+                user_name,
+            },
+            PlayerEvent::SessionClientChanged {
+                client_id,
+                client_name,
+                client_brand_name,
+                client_model_name,
+            } => SpotifydEvent::SessionClientChanged {
+                client_id,
+                client_name,
+                client_brand_name,
+                client_model_name,
+                client_ip: None,
+            },
+            PlayerEvent::ShuffleChanged { shuffle } => SpotifydEvent::ShuffleChanged { shuffle },
+            PlayerEvent::RepeatChanged { repeat } => SpotifydEvent::RepeatChanged { repeat },
+            PlayerEvent::AutoPlayChanged { auto_play } => {
+                SpotifydEvent::AutoPlayChanged { auto_play }
+            }
+            PlayerEvent::FilterExplicitContentChanged { filter } => {
+                SpotifydEvent::FilterExplicitContentChanged { filter }
+            }
+        }
+    }
+}
+
+/// Keeps the most recent events, so that subscribers connecting after the
+/// playback started can catch up on the current state.
+///
+/// Events describing a piece of state (e.g. the volume) replace older events
+/// describing the same state, so that the buffer isn't flooded by them.
+pub(crate) struct EventReplayBuffer {
+    events: VecDeque<SpotifydEvent>,
+    capacity: usize,
+}
+
+impl EventReplayBuffer {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub(crate) fn push(&mut self, event: SpotifydEvent) {
+        if let Some(kind) = event.state_kind() {
+            self.events.retain(|e| e.state_kind() != Some(kind));
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &SpotifydEvent> {
+        self.events.iter()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.events.clear();
+    }
+}
+
+/// Distributes the events to all subscribers, replaying the recent events to
+/// each new subscriber.
+///
+/// The bus can be cloned, so that integrations can subscribe on their own.
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<SpotifydEvent>,
+    replay: Arc<Mutex<EventReplayBuffer>>,
+    /// The queues of the subscribers that must not miss events.
+    queues: Arc<Mutex<Vec<QueueSender>>>,
+}
+
+impl EventBus {
+    pub fn new(replay_size: usize) -> Self {
+        let (tx, _) = broadcast::channel(BUS_CAPACITY);
+        Self {
+            tx,
+            replay: Arc::new(Mutex::new(EventReplayBuffer::new(replay_size))),
+            queues: Default::default(),
+        }
+    }
+
+    pub fn subscribe(&self) -> EventSubscriber {
+        // holding the lock ensures that no event is missed or received twice
+        let replay = self.replay.lock().unwrap();
+        EventSubscriber {
+            replay: replay.iter().cloned().collect(),
+            rx: Receiver::Broadcast(self.tx.subscribe()),
+        }
+    }
+
+    /// Subscribes with a queue of its own, so that the subscriber receives
+    /// every event while it falls behind, e.g. while a hook runs. Once the
+    /// queue is full, the oldest events are dropped, so that a subscriber
+    /// that is stuck, e.g. on an endpoint that is down, doesn't grow it
+    /// without bounds.
+    pub fn subscribe_queued(&self) -> EventSubscriber {
+        let replay = self.replay.lock().unwrap();
+        let queue = Arc::new(EventQueue::default());
+        let tx = QueueSender(queue.clone());
+        for event in replay.iter() {
+            tx.send(event.clone());
+        }
+        self.queues.lock().unwrap().push(tx);
+        EventSubscriber {
+            replay: VecDeque::new(),
+            rx: Receiver::Queue(queue),
+        }
+    }
+
+    pub fn publish(&self, event: SpotifydEvent) {
+        let mut replay = self.replay.lock().unwrap();
+        // fails only if there are no subscribers, which is fine
+        let _ = self.tx.send(event.clone());
+        // the queues of the dropped subscribers are closed
+        self.queues
+            .lock()
+            .unwrap()
+            .retain(|queue| queue.send(event.clone()));
+        replay.push(event);
+    }
+
+    /// Forgets the recent events, e.g. because they belong to a previous session.
+    pub(crate) fn clear_replay(&self) {
+        self.replay.lock().unwrap().clear();
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(REPLAY_BUFFER_SIZE)
+    }
+}
+
+/// The events queued for one subscriber.
+#[derive(Default)]
+struct EventQueue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct QueueState {
+    events: VecDeque<SpotifydEvent>,
+    /// Whether the bus has been dropped.
+    closed: bool,
+    /// Whether the subscriber has been dropped.
+    unsubscribed: bool,
+    /// The events dropped since the last warning.
+    dropped: usize,
+    last_warning: Option<Instant>,
+}
+
+/// The bus' end of a queue, which closes it once the bus is dropped.
+struct QueueSender(Arc<EventQueue>);
+
+impl QueueSender {
+    /// Queues the event, dropping the oldest one if the queue is full.
+    /// Returns whether the subscriber is still there.
+    fn send(&self, event: SpotifydEvent) -> bool {
+        let mut state = self.0.state.lock().unwrap();
+        if state.unsubscribed {
+            return false;
+        }
+        if state.events.len() == QUEUE_CAPACITY {
+            state.events.pop_front();
+            state.dropped += 1;
+            let warn_now = state
+                .last_warning
+                .map_or(true, |last| last.elapsed() >= QUEUE_WARNING_INTERVAL);
+            if warn_now {
+                warn!(
+                    "An event subscriber fell behind, dropped the {} oldest events",
+                    state.dropped
+                );
+                state.dropped = 0;
+                state.last_warning = Some(Instant::now());
+            }
+        }
+        state.events.push_back(event);
+        drop(state);
+        self.0.notify.notify_one();
+        true
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().closed = true;
+        self.0.notify.notify_one();
+    }
+}
+
+impl EventQueue {
+    async fn recv(&self) -> Option<SpotifydEvent> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(event) = state.events.pop_front() {
+                    return Some(event);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            // a notification sent in between is kept until this waits for it
+            self.notify.notified().await;
+        }
+    }
+}
+
+enum Receiver {
+    Broadcast(broadcast::Receiver<SpotifydEvent>),
+    Queue(Arc<EventQueue>),
+}
+
+/// Receives the events published on an [`EventBus`].
+pub struct EventSubscriber {
+    replay: VecDeque<SpotifydEvent>,
+    rx: Receiver,
+}
+
+impl EventSubscriber {
+    /// Returns the next event, or `None` if the bus has been dropped.
+    ///
+    /// If the subscriber fell behind, the missed events are skipped, unless
+    /// it has a queue of its own.
+    pub async fn recv(&mut self) -> Option<SpotifydEvent> {
+        if let Some(event) = self.replay.pop_front() {
+            return Some(event);
+        }
+        let rx = match self.rx {
+            Receiver::Broadcast(ref mut rx) => rx,
+            Receiver::Queue(ref queue) => return queue.recv().await,
+        };
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("An event subscriber fell behind, skipped {} events", missed)
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for EventSubscriber {
+    fn drop(&mut self) {
+        if let Receiver::Queue(ref queue) = self.rx {
+            let mut state = queue.state.lock().unwrap();
+            state.unsubscribed = true;
+            state.events.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chapters() {
+        let description = "Our guest talks about everything.\n\
+            (00:00) Intro\n\
+            05:12 - The first topic\n\
+            1:02:03 Outro\n\
+            Follow us at 9:30 every Monday";
+        assert_eq!(
+            parse_chapters(description),
+            vec![
+                Chapter {
+                    start_ms: 0,
+                    title: "Intro".to_string()
+                },
+                Chapter {
+                    start_ms: 312_000,
+                    title: "The first topic".to_string()
+                },
+                Chapter {
+                    start_ms: 3_723_000,
+                    title: "Outro".to_string()
+                },
+            ]
+        );
+
+        assert!(parse_chapters("Recorded at 10:30 in Berlin").is_empty());
+        assert!(parse_chapters("10:00 Later\n05:00 Earlier").is_empty());
+    }
+
+    #[test]
+    fn test_replay_keeps_latest_state() {
+        let mut buffer = EventReplayBuffer::new(2);
+        buffer.push(SpotifydEvent::VolumeChanged { volume: 1 });
+        buffer.push(SpotifydEvent::ShuffleChanged { shuffle: true });
+        buffer.push(SpotifydEvent::VolumeChanged { volume: 2 });
+
+        let events: Vec<_> = buffer.iter().cloned().collect();
+        assert_eq!(
+            events,
+            vec![
+                SpotifydEvent::ShuffleChanged { shuffle: true },
+                SpotifydEvent::VolumeChanged { volume: 2 },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_late_subscriber_receives_replay() {
+        let bus = EventBus::new(REPLAY_BUFFER_SIZE);
+        bus.publish(SpotifydEvent::VolumeChanged { volume: 42 });
+
+        let mut subscriber = bus.subscribe();
+        bus.publish(SpotifydEvent::ShuffleChanged { shuffle: true });
+
+        assert_eq!(
+            subscriber.recv().await,
+            Some(SpotifydEvent::VolumeChanged { volume: 42 })
+        );
+        assert_eq!(
+            subscriber.recv().await,
+            Some(SpotifydEvent::ShuffleChanged { shuffle: true })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queued_subscriber_receives_every_event() {
+        let bus = EventBus::new(REPLAY_BUFFER_SIZE);
+        let mut slow = bus.subscribe_queued();
+        let mut lossy = bus.subscribe();
+        for volume in 0..(2 * BUS_CAPACITY as u16) {
+            bus.publish(SpotifydEvent::VolumeChanged { volume });
+        }
+        drop(bus);
+
+        for volume in 0..(2 * BUS_CAPACITY as u16) {
+            assert_eq!(
+                slow.recv().await,
+                Some(SpotifydEvent::VolumeChanged { volume })
+            );
+        }
+        assert_eq!(slow.recv().await, None);
+        assert_ne!(
+            lossy.recv().await,
+            Some(SpotifydEvent::VolumeChanged { volume: 0 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queued_subscriber_drops_oldest_events() {
+        let bus = EventBus::new(0);
+        let mut stuck = bus.subscribe_queued();
+        let published = QUEUE_CAPACITY as u16 + 10;
+        for volume in 0..published {
+            bus.publish(SpotifydEvent::VolumeChanged { volume });
+        }
+        drop(bus);
+
+        for volume in 10..published {
+            assert_eq!(
+                stuck.recv().await,
+                Some(SpotifydEvent::VolumeChanged { volume })
+            );
+        }
+        assert_eq!(stuck.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_queued_subscriber_is_removed() {
+        let bus = EventBus::new(0);
+        drop(bus.subscribe_queued());
+        bus.publish(SpotifydEvent::VolumeChanged { volume: 0 });
+        assert!(bus.queues.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_serialized_name_matches_hook_name() {
+        let event = SpotifydEvent::EndOfTrack {
+            play_request_id: 1,
+            track_id: "4uLU6hMCjMI75M1A2tKUQC".to_string(),
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], event.name());
+        assert_eq!(json["track_id"], "4uLU6hMCjMI75M1A2tKUQC");
+    }
+}
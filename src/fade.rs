@@ -0,0 +1,159 @@
+use librespot_playback::{
+    audio_backend::{Sink, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+    NUM_CHANNELS, SAMPLE_RATE,
+};
+use std::{f64::consts::FRAC_PI_2, time::Duration};
+
+const CHANNELS: usize = NUM_CHANNELS as usize;
+
+fn samples(duration: Duration) -> usize {
+    (duration.as_millis() as u64 * SAMPLE_RATE as u64 / 1000) as usize * CHANNELS
+}
+
+/// Ramps the volume of the samples, which start `offset` samples into a ramp
+/// of `len` samples, up or down along a quarter sine.
+fn ramp(samples: &mut [f64], offset: usize, len: usize, up: bool) {
+    for (i, frame) in samples.chunks_exact_mut(CHANNELS).enumerate() {
+        let position = offset + i * CHANNELS;
+        if position >= len {
+            break;
+        }
+        let (sin, cos) = (position as f64 / len as f64 * FRAC_PI_2).sin_cos();
+        let gain = if up { sin } else { cos };
+        frame.iter_mut().for_each(|sample| *sample *= gain);
+    }
+}
+
+/// A sink fading the audio in when the playback starts, and out when it
+/// pauses or stops.
+///
+/// librespot only stops the sink once the playback has stopped, so the last
+/// samples are held back to be faded out then. This delays the audio by the
+/// fade out.
+pub(crate) struct FadeSink {
+    inner: Box<dyn Sink>,
+    fade_in: usize,
+    fade_out: usize,
+    /// How many samples have been faded in since the start.
+    faded_in: usize,
+    /// The last samples, written once newer ones come in.
+    held: Vec<f64>,
+}
+
+impl FadeSink {
+    pub(crate) fn new(inner: Box<dyn Sink>, fade_in: Duration, fade_out: Duration) -> Self {
+        Self {
+            inner,
+            fade_in: samples(fade_in),
+            fade_out: samples(fade_out),
+            faded_in: 0,
+            held: Vec::new(),
+        }
+    }
+
+    /// Fades the samples in, if the playback just started, and returns the
+    /// ones no longer held back.
+    fn process(&mut self, mut samples: Vec<f64>) -> Vec<f64> {
+        if self.faded_in < self.fade_in {
+            ramp(&mut samples, self.faded_in, self.fade_in, true);
+            self.faded_in = (self.faded_in + samples.len()).min(self.fade_in);
+        }
+        self.held.extend(samples);
+        let released = self.held.len().saturating_sub(self.fade_out);
+        self.held.drain(..released).collect()
+    }
+
+    /// The held back samples, faded out.
+    fn fade_out(&mut self) -> Vec<f64> {
+        let mut held = std::mem::take(&mut self.held);
+        // less audio has been held back if the playback barely started
+        let len = held.len();
+        ramp(&mut held, 0, len, false);
+        held
+    }
+}
+
+impl Sink for FadeSink {
+    fn start(&mut self) -> SinkResult<()> {
+        self.faded_in = 0;
+        self.held.clear();
+        self.inner.start()
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        let samples = self.fade_out();
+        if !samples.is_empty() {
+            let mut converter = Converter::new(None);
+            self.inner
+                .write(AudioPacket::Samples(samples), &mut converter)?;
+        }
+        self.inner.stop()
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        match packet {
+            AudioPacket::Samples(samples) => {
+                let samples = self.process(samples);
+                if samples.is_empty() {
+                    return Ok(());
+                }
+                self.inner.write(AudioPacket::Samples(samples), converter)
+            }
+            // passed through undecoded, there are no samples to fade
+            packet => {
+                let held = std::mem::take(&mut self.held);
+                if !held.is_empty() {
+                    self.inner.write(AudioPacket::Samples(held), converter)?;
+                }
+                self.inner.write(packet, converter)
+            }
+        }
+    }
+}
+
+impl Drop for FadeSink {
+    fn drop(&mut self) {
+        // the session ended without stopping the playback
+        if !self.held.is_empty() {
+            let _ = self.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Discard;
+
+    impl Sink for Discard {
+        fn write(&mut self, _: AudioPacket, _: &mut Converter) -> SinkResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_fades() {
+        let ten_ms = samples(Duration::from_millis(10));
+        let mut sink = FadeSink::new(
+            Box::new(Discard),
+            Duration::from_millis(10),
+            Duration::from_millis(10),
+        );
+
+        let written = sink.process(vec![1.0; 4 * ten_ms]);
+        // the start fades in, the last samples are held back
+        assert_eq!(written.len(), 3 * ten_ms);
+        assert_eq!(written[0], 0.0);
+        assert!(written[ten_ms / 2] > 0.5 && written[ten_ms / 2] < 1.0);
+        assert_eq!(written[ten_ms], 1.0);
+
+        let faded_out = sink.fade_out();
+        assert_eq!(faded_out.len(), ten_ms);
+        assert_eq!(faded_out[0], 1.0);
+        assert!(faded_out[ten_ms - 1] < 0.01);
+        assert!(sink.fade_out().is_empty());
+    }
+}
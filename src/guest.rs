@@ -0,0 +1,115 @@
+use crate::config::GuestWifi;
+use qrcode::{render::svg, QrCode};
+
+/// Escapes the characters with a meaning in the fields of a Wi-Fi QR code.
+fn escape_wifi(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        if matches!(c, '\\' | ';' | ',' | ':' | '"') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The content of a QR code joining the network, as understood by the
+/// cameras of Android and iOS.
+fn wifi_payload(wifi: &GuestWifi) -> String {
+    match wifi.password {
+        Some(ref password) => format!(
+            "WIFI:T:WPA;S:{};P:{};;",
+            escape_wifi(&wifi.ssid),
+            escape_wifi(password)
+        ),
+        None => format!("WIFI:T:nopass;S:{};;", escape_wifi(&wifi.ssid)),
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The page telling guests how to play on this device: a QR code joining the
+/// Wi-Fi network if one is configured, or else one with the name of the
+/// device, and the name to pick in the Spotify app.
+pub(crate) fn page(device_name: &str, wifi: Option<&GuestWifi>) -> Result<String, String> {
+    let payload = match wifi {
+        Some(wifi) => wifi_payload(wifi),
+        None => format!("Play on {} with Spotify Connect", device_name),
+    };
+    let code = QrCode::new(payload.as_bytes()).map_err(|e| e.to_string())?;
+    let image = code
+        .render::<svg::Color>()
+        .min_dimensions(320, 320)
+        .dark_color(svg::Color("#121212"))
+        .light_color(svg::Color("#ffffff"))
+        .build();
+    let name = escape_html(device_name);
+    let steps = match wifi {
+        Some(wifi) => format!(
+            "Scan the code to join <b>{}</b>, then open Spotify and pick <b>{}</b> from the devices.",
+            escape_html(&wifi.ssid),
+            name
+        ),
+        None => format!("Open Spotify and pick <b>{}</b> from the devices.", name),
+    };
+    Ok(format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Play on {name}</title>
+<style>
+  body {{ margin: 0; min-height: 100vh; display: flex; align-items: center; justify-content: center; background: #121212; color: #fff; font-family: sans-serif; text-align: center; }}
+  main {{ max-width: 480px; padding: 16px; }}
+  h1 {{ font-size: 2em; }}
+  svg {{ width: 100%; height: auto; border-radius: 8px; }}
+  p {{ color: #b3b3b3; font-size: 1.2em; }}
+  b {{ color: #fff; }}
+</style>
+</head>
+<body>
+<main>
+  <h1>{name}</h1>
+  {image}
+  <p>{steps}</p>
+</main>
+</body>
+</html>
+"#,
+        name = name,
+        image = image,
+        steps = steps,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wifi_payload() {
+        let wifi = GuestWifi {
+            ssid: "Guests; 2.4GHz".to_string(),
+            password: Some("p:a\\ss".to_string()),
+        };
+        assert_eq!(
+            wifi_payload(&wifi),
+            r"WIFI:T:WPA;S:Guests\; 2.4GHz;P:p\:a\\ss;;"
+        );
+        let open = GuestWifi {
+            ssid: "Cafe".to_string(),
+            password: None,
+        };
+        assert_eq!(wifi_payload(&open), "WIFI:T:nopass;S:Cafe;;");
+
+        let page = page("<Living room>", Some(&wifi)).unwrap();
+        assert!(page.contains("&lt;Living room&gt;"));
+        assert!(page.contains("<svg"));
+    }
+}
@@ -0,0 +1,163 @@
+use crate::events::TrackInfo;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A track that has been played, as stored in the history file.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Play {
+    uri: String,
+    /// When the track started, in seconds since the epoch.
+    played_at: u64,
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The tracks played within the duplicate window, to skip tracks that are
+/// repeated too soon.
+///
+/// If a path is given, the plays are appended to that file as JSON lines, so
+/// that they are remembered across restarts. Plays older than the window are
+/// dropped from the file when it is loaded.
+#[derive(Debug)]
+pub(crate) struct PlayHistory {
+    path: Option<PathBuf>,
+    window: Duration,
+    plays: VecDeque<Play>,
+}
+
+impl PlayHistory {
+    pub(crate) fn load(path: Option<PathBuf>, window: Duration) -> Self {
+        let mut history = Self {
+            path,
+            window,
+            plays: VecDeque::new(),
+        };
+        let Some(path) = history.path.clone() else {
+            return history;
+        };
+        match File::open(&path) {
+            Ok(file) => {
+                history.plays = BufReader::new(file)
+                    .lines()
+                    .map_while(Result::ok)
+                    .filter_map(|line| serde_json::from_str(&line).ok())
+                    .collect();
+                history.prune(SystemTime::now());
+                if let Err(e) = history.rewrite() {
+                    warn!("Failed to write {}: {}", path.display(), e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => warn!("Failed to read {}: {}", path.display(), e),
+        }
+        history
+    }
+
+    fn rewrite(&self) -> io::Result<()> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+        let mut content = String::new();
+        for play in &self.plays {
+            content.push_str(&serde_json::to_string(play).unwrap());
+            content.push('\n');
+        }
+        fs::write(path, content)
+    }
+
+    fn prune(&mut self, now: SystemTime) {
+        let oldest = unix_time(now).saturating_sub(self.window.as_secs());
+        self.plays.retain(|play| play.played_at >= oldest);
+    }
+
+    fn record(&mut self, uri: &str, now: SystemTime) {
+        let play = Play {
+            uri: uri.to_string(),
+            played_at: unix_time(now),
+        };
+        if let Some(ref path) = self.path {
+            let line = serde_json::to_string(&play).unwrap();
+            let result = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", line));
+            if let Err(e) = result {
+                warn!("Failed to write to {}: {}", path.display(), e);
+            }
+        }
+        self.plays.push_back(play);
+    }
+
+    fn check_at(&mut self, track: &TrackInfo, now: SystemTime) -> Option<Duration> {
+        // episodes are resumed, not repeated
+        if track.item_type != "track" {
+            return None;
+        }
+        self.prune(now);
+        let last = self.plays.iter().rev().find(|play| play.uri == track.uri);
+        if let Some(play) = last {
+            return Some(Duration::from_secs(
+                unix_time(now).saturating_sub(play.played_at),
+            ));
+        }
+        self.record(&track.uri, now);
+        None
+    }
+
+    /// Returns how long ago the track was played, if that was within the
+    /// window. Otherwise, the track is recorded as played now.
+    pub(crate) fn check(&mut self, track: &TrackInfo) -> Option<Duration> {
+        self.check_at(track, SystemTime::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicates_within_window() {
+        let hour = Duration::from_secs(3600);
+        let mut history = PlayHistory::load(None, 4 * hour);
+        let track = |uri: &str| TrackInfo {
+            uri: uri.to_string(),
+            item_type: "track".to_string(),
+            ..Default::default()
+        };
+        let start = UNIX_EPOCH + 1000 * hour;
+
+        assert_eq!(history.check_at(&track("spotify:track:a"), start), None);
+        assert_eq!(
+            history.check_at(&track("spotify:track:b"), start + hour),
+            None
+        );
+        assert_eq!(
+            history.check_at(&track("spotify:track:a"), start + 2 * hour),
+            Some(2 * hour)
+        );
+        // the skipped repetition doesn't extend the window
+        assert_eq!(
+            history.check_at(&track("spotify:track:a"), start + 5 * hour),
+            None
+        );
+
+        let episode = TrackInfo {
+            item_type: "episode".to_string(),
+            ..track("spotify:episode:c")
+        };
+        assert_eq!(history.check_at(&episode, start), None);
+        assert_eq!(history.check_at(&episode, start), None);
+    }
+}
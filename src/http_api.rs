@@ -0,0 +1,649 @@
+use crate::{
+    audit::{CommandClient, SharedAuditLog},
+    config::{GuestWifi, HttpScope, HttpToken, HumanDuration, MeteredMode, VolumeLevel},
+    control::{ControlCommand, ControlHandle},
+    data_usage::{DataUsage, Usage},
+    dsp::DspSwitches,
+    events::{EventBus, EventSubscriber},
+    lock::{DoNotDisturb, LockOwner},
+    metered::Metered,
+    rate_limit::RateLimiter,
+    sleep_timer::SleepTimer,
+    state::{HistoryReport, SharedPlaybackState, StatusReport},
+};
+use futures::{SinkExt, StreamExt};
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderValue},
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    upgrade::Upgraded,
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::{debug, error, info};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tokio_tungstenite::{
+    tungstenite::{
+        self,
+        handshake::derive_accept_key,
+        protocol::{Role, WebSocketConfig},
+        Message,
+    },
+    WebSocketStream,
+};
+
+/// How many requests a client may make at once, and how many per second
+/// after that. Enough for a dashboard polling the status every second.
+const RATE_BURST: u32 = 20;
+const RATE_PER_SECOND: u32 = 5;
+/// The largest body a request may have, far more than any endpoint needs.
+const MAX_BODY_SIZE: usize = 16 * 1024;
+/// How long a client may take to send the body of a request.
+const BODY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many connections are served at the same time.
+const MAX_CONNECTIONS: usize = 16;
+
+/// The page of the web UI, served at `/` and controlling the playback through
+/// this API.
+#[cfg(feature = "web_ui")]
+const WEB_UI: &str = include_str!("web_ui.html");
+
+/// The scope the endpoint at the path requires, if there is one.
+fn required_scope(method: &Method, path: &str) -> Option<HttpScope> {
+    match (method, path) {
+        (
+            &Method::GET,
+            "/status" | "/history" | "/settings" | "/metrics" | "/events" | "/sleep",
+        ) => Some(HttpScope::Read),
+        (&Method::GET, "/guest") if cfg!(feature = "web_ui") => Some(HttpScope::Read),
+        (
+            &Method::POST,
+            "/play" | "/pause" | "/play-pause" | "/next" | "/previous" | "/seek" | "/volume"
+            | "/sleep",
+        )
+        | (&Method::DELETE, "/sleep") => Some(HttpScope::Control),
+        (&Method::GET, "/audit")
+        | (&Method::POST, "/settings" | "/lock" | "/unlock" | "/shutdown") => {
+            Some(HttpScope::Admin)
+        }
+        _ => None,
+    }
+}
+
+/// The token of the request, from its `Authorization: Bearer` header or its
+/// `token` query parameter.
+fn request_token<B>(request: &Request<B>) -> Option<&str> {
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let Some(token) = bearer {
+        return Some(token.trim());
+    }
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+}
+
+/// Compares the tokens in a time that doesn't depend on where they differ.
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The configured token of the request, if it grants the scope.
+fn authorize<'a>(
+    tokens: &'a [HttpToken],
+    token: Option<&str>,
+    scope: HttpScope,
+) -> Result<&'a HttpToken, StatusCode> {
+    let token = token.ok_or(StatusCode::UNAUTHORIZED)?;
+    let token = tokens
+        .iter()
+        .find(|configured| tokens_match(&configured.token, token))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if token.scope >= scope {
+        Ok(token)
+    } else {
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Seek {
+    position_ms: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct Volume {
+    /// The volume, between 0 and 100, or in decibels like `"-12dB"`.
+    volume: VolumeLevel,
+}
+
+#[derive(Debug, Deserialize)]
+struct Sleep {
+    /// How long until the playback pauses, like `"30m"`.
+    duration: HumanDuration,
+}
+
+/// The settings that can be changed while running.
+#[derive(Debug, Deserialize, Serialize)]
+struct Settings {
+    metered: Option<MeteredMode>,
+    preload_tracks: Option<usize>,
+    /// Whether the crossfeed stage of the DSP chain is on, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crossfeed: Option<bool>,
+}
+
+fn json<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(value).unwrap()))
+        .unwrap()
+}
+
+fn error(status: StatusCode, message: impl ToString) -> Response<Body> {
+    json(status, &serde_json::json!({ "error": message.to_string() }))
+}
+
+fn no_content() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, Response<Body>> {
+    serde_json::from_slice(body)
+        .map_err(|e| error(StatusCode::BAD_REQUEST, format!("invalid body: {}", e)))
+}
+
+/// The counts of the requests that were rejected, by the reason.
+#[derive(Debug, Default)]
+struct Rejections {
+    too_many_connections: AtomicU64,
+    rate_limited: AtomicU64,
+    /// Requests with a missing or unknown token, or one without the scope.
+    unauthorized: AtomicU64,
+    too_large: AtomicU64,
+}
+
+impl Rejections {
+    fn count(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RejectionCounts {
+    too_many_connections: u64,
+    rate_limited: u64,
+    unauthorized: u64,
+    too_large: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Metrics {
+    connections: usize,
+    rejected: RejectionCounts,
+    data_usage: Usage,
+}
+
+/// What keeps misbehaving clients from degrading the playback, shared by all
+/// the connections.
+#[derive(Debug)]
+struct Limits {
+    rate_limiter: RateLimiter,
+    connections: AtomicUsize,
+    rejections: Rejections,
+}
+
+impl Limits {
+    fn metrics(&self, data_usage: Usage) -> Metrics {
+        let count = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Metrics {
+            connections: self.connections.load(Ordering::Relaxed),
+            rejected: RejectionCounts {
+                too_many_connections: count(&self.rejections.too_many_connections),
+                rate_limited: count(&self.rejections.rate_limited),
+                unauthorized: count(&self.rejections.unauthorized),
+                too_large: count(&self.rejections.too_large),
+            },
+            data_usage,
+        }
+    }
+}
+
+/// A connection of a client, counted while it's open.
+struct Connection {
+    client: IpAddr,
+    limits: Arc<Limits>,
+    /// Whether the connection was opened below `MAX_CONNECTIONS`. Otherwise,
+    /// its requests are rejected and it's closed.
+    admitted: bool,
+}
+
+impl Connection {
+    fn open(client: IpAddr, limits: Arc<Limits>) -> Self {
+        let admitted = limits.connections.fetch_add(1, Ordering::Relaxed) < MAX_CONNECTIONS;
+        Self {
+            client,
+            limits,
+            admitted,
+        }
+    }
+
+    /// Takes a request of the client from the limits, or returns the response
+    /// rejecting it.
+    fn admit(&self) -> Result<(), Response<Body>> {
+        let rejections = &self.limits.rejections;
+        if !self.admitted {
+            Rejections::count(&rejections.too_many_connections);
+            debug!(
+                "Rejected a request of {}: too many connections",
+                self.client
+            );
+            let mut response = error(StatusCode::SERVICE_UNAVAILABLE, "too many connections");
+            response
+                .headers_mut()
+                .insert(header::CONNECTION, HeaderValue::from_static("close"));
+            return Err(response);
+        }
+        if !self.limits.rate_limiter.allow(self.client) {
+            Rejections::count(&rejections.rate_limited);
+            debug!("Rejected a request of {}: too many requests", self.client);
+            let mut response = error(StatusCode::TOO_MANY_REQUESTS, "too many requests");
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+            return Err(response);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.limits.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Reads the body, as long as it's no larger than `MAX_BODY_SIZE`.
+async fn read_body(mut body: Body) -> Result<Vec<u8>, Response<Body>> {
+    let too_large = || error(StatusCode::PAYLOAD_TOO_LARGE, "the body is too large");
+    if body.size_hint().lower() > MAX_BODY_SIZE as u64 {
+        return Err(too_large());
+    }
+    let read = async {
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| error(StatusCode::BAD_REQUEST, e))?;
+            if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+                return Err(too_large());
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    };
+    tokio::time::timeout(BODY_TIMEOUT, read)
+        .await
+        .unwrap_or_else(|_| Err(error(StatusCode::REQUEST_TIMEOUT, "the body took too long")))
+}
+
+/// Sends every event as a JSON text message, until the client closes the
+/// WebSocket.
+async fn stream_events(
+    mut socket: WebSocketStream<Upgraded>,
+    mut events: EventSubscriber,
+) -> Result<(), tungstenite::Error> {
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let Some(event) = event else {
+                    return socket.close(None).await;
+                };
+                let message = Message::Text(serde_json::to_string(&event).unwrap());
+                socket.send(message).await?;
+            }
+            // pings are answered while reading, anything else is ignored
+            message = socket.next() => match message {
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(e),
+            },
+        }
+    }
+}
+
+/// The state the HTTP API reads and the handles it controls the daemon with.
+#[derive(Clone)]
+pub(crate) struct HttpApi {
+    pub(crate) tokens: Arc<Vec<HttpToken>>,
+    pub(crate) control: ControlHandle,
+    pub(crate) playback_state: SharedPlaybackState,
+    pub(crate) audit_log: SharedAuditLog,
+    pub(crate) do_not_disturb: Arc<DoNotDisturb>,
+    pub(crate) metered: Arc<Metered>,
+    pub(crate) data_usage: Arc<DataUsage>,
+    pub(crate) preload_tracks: Arc<AtomicUsize>,
+    pub(crate) dsp_switches: DspSwitches,
+    pub(crate) shutdown_request: Arc<Notify>,
+    pub(crate) event_bus: EventBus,
+    #[cfg_attr(not(feature = "web_ui"), allow(unused))]
+    pub(crate) device_name: String,
+    #[cfg_attr(not(feature = "web_ui"), allow(unused))]
+    pub(crate) guest_wifi: Option<GuestWifi>,
+    pub(crate) sleep_timer: Arc<SleepTimer>,
+}
+
+impl HttpApi {
+    /// Sends the command, recorded in the audit log as sent by the client.
+    fn send(&self, command: ControlCommand, client: CommandClient) -> Response<Body> {
+        match self.control.for_client(client).send(command) {
+            Ok(()) => no_content(),
+            Err(e) => error(StatusCode::SERVICE_UNAVAILABLE, e),
+        }
+    }
+
+    fn settings(&self) -> Settings {
+        Settings {
+            metered: Some(self.metered.mode()),
+            preload_tracks: Some(self.preload_tracks.load(Ordering::Relaxed)),
+            crossfeed: self.dsp_switches.crossfeed(),
+        }
+    }
+
+    /// Upgrades the request to a WebSocket streaming the events, which counts
+    /// as a connection until it's closed.
+    fn events(&self, mut request: Request<Body>, connection: Arc<Connection>) -> Response<Body> {
+        let headers = request.headers();
+        let upgrade = headers
+            .get(header::UPGRADE)
+            .and_then(|value| value.to_str().ok())
+            .map_or(false, |value| value.eq_ignore_ascii_case("websocket"));
+        let Some(key) = headers.get(header::SEC_WEBSOCKET_KEY).filter(|_| upgrade) else {
+            return error(StatusCode::UPGRADE_REQUIRED, "expected a WebSocket upgrade");
+        };
+        let accept = derive_accept_key(key.as_bytes());
+
+        let events = self.event_bus.subscribe();
+        let upgraded = hyper::upgrade::on(&mut request);
+        tokio::spawn(async move {
+            let upgraded = match upgraded.await {
+                Ok(upgraded) => upgraded,
+                Err(e) => {
+                    debug!("Failed to upgrade to a WebSocket: {}", e);
+                    return;
+                }
+            };
+            let config = WebSocketConfig {
+                max_message_size: Some(MAX_BODY_SIZE),
+                max_frame_size: Some(MAX_BODY_SIZE),
+                ..Default::default()
+            };
+            let socket =
+                WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(config)).await;
+            if let Err(e) = stream_events(socket, events).await {
+                debug!("The WebSocket of {} failed: {}", connection.client, e);
+            }
+        });
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(header::SEC_WEBSOCKET_ACCEPT, accept)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    async fn handle(self, request: Request<Body>, connection: Arc<Connection>) -> Response<Body> {
+        if let Err(response) = connection.admit() {
+            return response;
+        }
+        let rejections = &connection.limits.rejections;
+        let method = request.method().clone();
+        let path = request.uri().path().to_string();
+        // the page itself holds nothing, it asks for a token like other clients
+        #[cfg(feature = "web_ui")]
+        if method == Method::GET && path == "/" {
+            return Response::builder()
+                .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Body::from(WEB_UI))
+                .unwrap();
+        }
+        let Some(scope) = required_scope(&method, &path) else {
+            return error(StatusCode::NOT_FOUND, "no such endpoint");
+        };
+        let token = match authorize(&self.tokens, request_token(&request), scope) {
+            Ok(token) => token.name.clone(),
+            Err(status) => {
+                Rejections::count(&rejections.unauthorized);
+                return error(status, "the token doesn't grant access to this endpoint");
+            }
+        };
+        if path == "/events" {
+            return self.events(request, connection);
+        }
+        let body = match read_body(request.into_body()).await {
+            Ok(body) => body,
+            Err(response) => {
+                if response.status() == StatusCode::PAYLOAD_TOO_LARGE {
+                    Rejections::count(&rejections.too_large);
+                }
+                return response;
+            }
+        };
+
+        let command = match path.as_str() {
+            "/status" => {
+                let state = self.playback_state.read().unwrap();
+                let status = StatusReport::new(&state, self.do_not_disturb.is_locked());
+                return json(StatusCode::OK, &status);
+            }
+            "/history" => {
+                let state = self.playback_state.read().unwrap();
+                return json(StatusCode::OK, &HistoryReport::new(&state));
+            }
+            #[cfg(feature = "web_ui")]
+            "/guest" => {
+                return match crate::guest::page(&self.device_name, self.guest_wifi.as_ref()) {
+                    Ok(page) => Response::builder()
+                        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                        .body(Body::from(page))
+                        .unwrap(),
+                    Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e),
+                };
+            }
+            "/metrics" => {
+                return json(
+                    StatusCode::OK,
+                    &connection.limits.metrics(self.data_usage.usage()),
+                );
+            }
+            "/settings" if method == Method::GET => {
+                return json(StatusCode::OK, &self.settings());
+            }
+            "/settings" => {
+                let settings: Settings = match parse(&body) {
+                    Ok(settings) => settings,
+                    Err(response) => return response,
+                };
+                if let Some(crossfeed) = settings.crossfeed {
+                    if !self.dsp_switches.set_crossfeed(crossfeed) {
+                        return error(
+                            StatusCode::BAD_REQUEST,
+                            "the dsp chain has no crossfeed stage",
+                        );
+                    }
+                }
+                if let Some(mode) = settings.metered {
+                    self.metered.set_mode(mode);
+                }
+                if let Some(preload_tracks) = settings.preload_tracks {
+                    self.preload_tracks.store(preload_tracks, Ordering::Relaxed);
+                }
+                return json(StatusCode::OK, &self.settings());
+            }
+            "/audit" => {
+                let audit_log = self.audit_log.lock().unwrap();
+                return json(StatusCode::OK, &audit_log.recent().collect::<Vec<_>>());
+            }
+            "/lock" => {
+                let owner = LockOwner::current(&self.playback_state.read().unwrap());
+                self.do_not_disturb.lock(owner);
+                return no_content();
+            }
+            "/unlock" => {
+                self.do_not_disturb.unlock();
+                return no_content();
+            }
+            "/sleep" if method == Method::GET => {
+                return json(StatusCode::OK, &self.sleep_timer.report());
+            }
+            "/sleep" if method == Method::DELETE => {
+                self.sleep_timer.cancel();
+                return no_content();
+            }
+            "/sleep" => {
+                let sleep: Sleep = match parse(&body) {
+                    Ok(sleep) => sleep,
+                    Err(response) => return response,
+                };
+                return match self.sleep_timer.set(sleep.duration.0) {
+                    Ok(()) => no_content(),
+                    Err(e) => error(StatusCode::BAD_REQUEST, e),
+                };
+            }
+            "/shutdown" => {
+                info!(
+                    "Shutting down as requested via the HTTP API by {}",
+                    token.as_deref().unwrap_or("a token")
+                );
+                self.shutdown_request.notify_one();
+                return no_content();
+            }
+            "/play" => ControlCommand::Play,
+            "/pause" => ControlCommand::Pause,
+            "/play-pause" => ControlCommand::PlayPause,
+            "/next" => ControlCommand::Next,
+            "/previous" => ControlCommand::Prev,
+            "/seek" => match parse::<Seek>(&body) {
+                Ok(seek) => ControlCommand::Seek(seek.position_ms),
+                Err(response) => return response,
+            },
+            "/volume" => match parse::<Volume>(&body) {
+                Ok(volume) => ControlCommand::SetVolume(volume.volume.volume()),
+                Err(response) => return response,
+            },
+            _ => unreachable!(),
+        };
+        let client = CommandClient {
+            name: token,
+            ip: Some(connection.client),
+        };
+        self.send(command, client)
+    }
+}
+
+/// Serves the HTTP API on the address until the program shuts down.
+pub(crate) async fn serve(address: SocketAddr, api: HttpApi) {
+    let limits = Arc::new(Limits {
+        rate_limiter: RateLimiter::new(RATE_BURST, RATE_PER_SECOND),
+        connections: AtomicUsize::new(0),
+        rejections: Default::default(),
+    });
+    let make_service = make_service_fn(move |stream: &AddrStream| {
+        let api = api.clone();
+        let connection = Arc::new(Connection::open(stream.remote_addr().ip(), limits.clone()));
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let api = api.clone();
+                let connection = connection.clone();
+                async move { Ok::<_, Infallible>(api.handle(request, connection).await) }
+            }))
+        }
+    });
+    let server = match Server::try_bind(&address) {
+        Ok(builder) => builder.serve(make_service),
+        Err(e) => {
+            error!("Failed to serve the HTTP API on {}: {}", address, e);
+            return;
+        }
+    };
+    info!("Serving the HTTP API on {}", address);
+    if let Err(e) = server.await {
+        error!("The HTTP API failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scopes() {
+        let tokens = [
+            HttpToken {
+                token: "panel".to_string(),
+                scope: HttpScope::Read,
+                name: Some("wall panel".to_string()),
+            },
+            HttpToken {
+                token: "root".to_string(),
+                scope: HttpScope::Admin,
+                name: None,
+            },
+        ];
+        let scope = |method: Method, path: &str| required_scope(&method, path).unwrap();
+
+        let status = scope(Method::GET, "/status");
+        assert!(authorize(&tokens, Some("panel"), status).is_ok());
+        let events = scope(Method::GET, "/events");
+        assert!(authorize(&tokens, Some("panel"), events).is_ok());
+        let pause = scope(Method::POST, "/pause");
+        assert_eq!(
+            authorize(&tokens, Some("panel"), pause).unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+        assert!(authorize(&tokens, Some("root"), pause).is_ok());
+        let settings = scope(Method::POST, "/settings");
+        assert!(authorize(&tokens, Some("root"), settings).is_ok());
+        assert_eq!(
+            authorize(&tokens, Some("roots"), status).unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            authorize(&tokens, None, status).unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(required_scope(&Method::GET, "/pause"), None);
+
+        let request = Request::get("/status?volume=1&token=panel")
+            .body(())
+            .unwrap();
+        assert_eq!(request_token(&request), Some("panel"));
+        let request = Request::get("/status")
+            .header(header::AUTHORIZATION, "Bearer root")
+            .body(())
+            .unwrap();
+        assert_eq!(request_token(&request), Some("root"));
+    }
+}
@@ -0,0 +1,141 @@
+use crate::{
+    config::IdleTimeouts,
+    events::{EventBus, EventSubscriber, SpotifydEvent},
+    state::{Activity, PlaybackState},
+};
+use futures::future;
+use log::info;
+use std::time::Instant;
+
+/// Follows the activity of the device through the events, and when it has
+/// lasted for its timeout, which is once per activity.
+struct IdleTracker {
+    timeouts: IdleTimeouts,
+    state: PlaybackState,
+    activity: Activity,
+    since: Instant,
+    reported: bool,
+}
+
+impl IdleTracker {
+    fn new(timeouts: IdleTimeouts, now: Instant) -> Self {
+        let state = PlaybackState::default();
+        Self {
+            timeouts,
+            activity: state.activity(),
+            state,
+            since: now,
+            reported: false,
+        }
+    }
+
+    fn observe(&mut self, event: &SpotifydEvent, now: Instant) {
+        self.state.update(event);
+        let activity = self.state.activity();
+        if activity != self.activity {
+            self.activity = activity;
+            self.since = now;
+            self.reported = false;
+        }
+    }
+
+    /// When the current activity counts as idle, unless it already has.
+    fn deadline(&self) -> Option<Instant> {
+        if self.reported {
+            return None;
+        }
+        let timeout = match self.activity {
+            Activity::Playing => None,
+            Activity::Paused => self.timeouts.paused,
+            Activity::Stopped => self.timeouts.stopped,
+            Activity::Inactive => self.timeouts.inactive,
+        };
+        timeout.map(|timeout| self.since + timeout)
+    }
+
+    fn idle(&mut self, now: Instant) -> SpotifydEvent {
+        self.reported = true;
+        SpotifydEvent::Idle {
+            activity: self.activity,
+            idle_ms: now.duration_since(self.since).as_millis() as u64,
+        }
+    }
+}
+
+/// Publishes an idle event once the device has been paused, stopped or
+/// without a session for the timeout of that activity, e.g. for a script
+/// turning off an amplifier, which the next play event turns on again.
+pub(crate) async fn watch_idle(
+    timeouts: IdleTimeouts,
+    event_bus: EventBus,
+    mut events: EventSubscriber,
+) {
+    let mut tracker = IdleTracker::new(timeouts, Instant::now());
+    loop {
+        let deadline = tracker.deadline();
+        let timeout = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => tracker.observe(&event, Instant::now()),
+                None => return,
+            },
+            _ = timeout => {
+                let event = tracker.idle(Instant::now());
+                info!("The device is idle: {}", tracker.activity.as_str());
+                event_bus.publish(event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_idle_tracker() {
+        let minutes = |minutes| Duration::from_secs(minutes * 60);
+        let timeouts = IdleTimeouts {
+            paused: Some(minutes(10)),
+            stopped: Some(minutes(1)),
+            inactive: None,
+        };
+        let start = Instant::now();
+        let mut tracker = IdleTracker::new(timeouts, start);
+        assert_eq!(tracker.deadline(), None);
+
+        tracker.observe(
+            &SpotifydEvent::SessionConnected {
+                connection_id: "connection".to_string(),
+                user_name: "user".to_string(),
+                client_ip: None,
+            },
+            start,
+        );
+        assert_eq!(tracker.deadline(), Some(start + minutes(1)));
+
+        let paused = SpotifydEvent::Paused {
+            play_request_id: 1,
+            track_id: "4uLU6hMCjMI75M1A2tKUQC".to_string(),
+            position_ms: 0,
+        };
+        tracker.observe(&paused, start + minutes(2));
+        assert_eq!(tracker.deadline(), Some(start + minutes(12)));
+        assert_eq!(
+            tracker.idle(start + minutes(12)),
+            SpotifydEvent::Idle {
+                activity: Activity::Paused,
+                idle_ms: 600_000,
+            }
+        );
+        // reported once, until the activity changes
+        tracker.observe(&paused, start + minutes(13));
+        assert_eq!(tracker.deadline(), None);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::config::JackConfig;
+use jack::{
+    AsyncClient, AudioOut, Client, ClientOptions, ClientStatus, Control, NotificationHandler, Port,
+    PortFlags, ProcessHandler, ProcessScope,
+};
+use librespot_playback::{
+    audio_backend::{Sink, SinkError, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+    SAMPLE_RATE,
+};
+use log::{info, warn};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// How many frames are buffered between the player and JACK.
+const BUFFER_FRAMES: usize = 8192;
+/// How long the player waits for JACK to take frames from a full buffer.
+const FULL_DELAY: Duration = Duration::from_millis(5);
+const PORT_NAMES: [&str; 2] = ["out_L", "out_R"];
+/// The type of the audio ports of JACK.
+const AUDIO_TYPE: &str = "32 bit float mono audio";
+
+/// The connections from the outputs to the ports, alternating between the
+/// left and the right output, or from both to a single port.
+fn connections<'a>(outputs: &'a [String; 2], ports: &'a [String]) -> Vec<(&'a str, &'a str)> {
+    match ports {
+        [port] => outputs
+            .iter()
+            .map(|output| (&output[..], &port[..]))
+            .collect(),
+        _ => ports
+            .iter()
+            .enumerate()
+            .map(|(i, port)| (&outputs[i % 2][..], &port[..]))
+            .collect(),
+    }
+}
+
+struct Process {
+    outputs: [Port<AudioOut>; 2],
+    frames: Receiver<[f32; 2]>,
+}
+
+impl ProcessHandler for Process {
+    fn process(&mut self, _: &Client, scope: &ProcessScope) -> Control {
+        let [left, right] = &mut self.outputs;
+        let (left, right) = (left.as_mut_slice(scope), right.as_mut_slice(scope));
+        for (left, right) in left.iter_mut().zip(right) {
+            // silence while paused or when the player lags behind
+            [*left, *right] = self.frames.try_recv().unwrap_or_default();
+        }
+        Control::Continue
+    }
+}
+
+struct Notifications {
+    shut_down: Arc<AtomicBool>,
+}
+
+impl NotificationHandler for Notifications {
+    unsafe fn shutdown(&mut self, _: ClientStatus, reason: &str) {
+        warn!("The JACK server shut down: {}", reason);
+        self.shut_down.store(true, Ordering::Relaxed);
+    }
+}
+
+struct Connection {
+    _client: AsyncClient<Notifications, Process>,
+    frames: SyncSender<[f32; 2]>,
+    shut_down: Arc<AtomicBool>,
+}
+
+/// A sink playing to JACK as a client with a port for each channel, which
+/// are connected to the configured ports, or to the physical playback ports.
+///
+/// The client stays active as long as the sink, so that connections made in
+/// a patchbay aren't lost while paused.
+pub(crate) struct JackSink {
+    config: JackConfig,
+    connection: Option<Connection>,
+}
+
+impl JackSink {
+    pub(crate) fn new(config: JackConfig) -> Self {
+        Self {
+            config,
+            connection: None,
+        }
+    }
+
+    fn connect(&self) -> Result<Connection, jack::Error> {
+        let (client, _) = Client::new(&self.config.client_name, ClientOptions::NO_START_SERVER)?;
+        if client.sample_rate() != SAMPLE_RATE as usize {
+            warn!(
+                "JACK runs at {} Hz, but the audio is played at {} Hz",
+                client.sample_rate(),
+                SAMPLE_RATE
+            );
+        }
+        let outputs = [
+            client.register_port(PORT_NAMES[0], AudioOut::default())?,
+            client.register_port(PORT_NAMES[1], AudioOut::default())?,
+        ];
+        let names = [outputs[0].name()?, outputs[1].name()?];
+        let ports = self.config.ports.clone().unwrap_or_else(|| {
+            client.ports(
+                None,
+                Some(AUDIO_TYPE),
+                PortFlags::IS_INPUT | PortFlags::IS_PHYSICAL,
+            )
+        });
+
+        let (sender, frames) = mpsc::sync_channel(BUFFER_FRAMES);
+        let shut_down = Arc::new(AtomicBool::new(false));
+        let notifications = Notifications {
+            shut_down: shut_down.clone(),
+        };
+        let client = client.activate_async(notifications, Process { outputs, frames })?;
+        for (output, port) in connections(&names, &ports) {
+            match client.as_client().connect_ports_by_name(output, port) {
+                Ok(()) => info!("Connected {} to {}", output, port),
+                Err(e) => warn!("Failed to connect {} to {}: {}", output, port, e),
+            }
+        }
+        Ok(Connection {
+            _client: client,
+            frames: sender,
+            shut_down,
+        })
+    }
+}
+
+/// Sends the interleaved samples to JACK, waiting while the buffer is full,
+/// and returns whether the server is still there.
+fn send(connection: &Connection, samples: &[f32]) -> bool {
+    for frame in samples.chunks_exact(2) {
+        let mut frame = [frame[0], frame[1]];
+        loop {
+            match connection.frames.try_send(frame) {
+                Ok(()) => break,
+                Err(TrySendError::Full(rejected))
+                    if !connection.shut_down.load(Ordering::Relaxed) =>
+                {
+                    frame = rejected;
+                    thread::sleep(FULL_DELAY);
+                }
+                Err(_) => return false,
+            }
+        }
+    }
+    true
+}
+
+impl Sink for JackSink {
+    fn start(&mut self) -> SinkResult<()> {
+        if self.connection.is_none() {
+            let connection = self
+                .connect()
+                .map_err(|e| SinkError::ConnectionRefused(e.to_string()))?;
+            self.connection = Some(connection);
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        let samples = packet
+            .samples()
+            .map_err(|e| SinkError::OnWrite(e.to_string()))?;
+        let samples = converter.f64_to_f32(samples);
+        let connection = self
+            .connection
+            .as_ref()
+            .ok_or_else(|| SinkError::NotConnected("the sink isn't started".to_string()))?;
+        if !send(connection, &samples) {
+            // connected again when the player starts the sink
+            self.connection = None;
+            return Err(SinkError::NotConnected(
+                "the JACK server shut down".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connections() {
+        let outputs = ["spotifyd:out_L".to_string(), "spotifyd:out_R".to_string()];
+        let ports = |ports: &[&str]| {
+            ports
+                .iter()
+                .map(|port| port.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            connections(
+                &outputs,
+                &ports(&["system:playback_1", "system:playback_2"])
+            ),
+            [
+                ("spotifyd:out_L", "system:playback_1"),
+                ("spotifyd:out_R", "system:playback_2")
+            ]
+        );
+        assert_eq!(
+            connections(&outputs, &ports(&["mono:in"])),
+            [("spotifyd:out_L", "mono:in"), ("spotifyd:out_R", "mono:in")]
+        );
+        assert!(connections(&outputs, &[]).is_empty());
+    }
+}
@@ -0,0 +1,334 @@
+use libloading::Library;
+use librespot_playback::{NUM_CHANNELS, SAMPLE_RATE};
+use std::{
+    collections::HashMap,
+    env,
+    ffi::CStr,
+    os::raw::{c_char, c_int, c_ulong, c_void},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Where the plugins are looked up when `LADSPA_PATH` isn't set.
+const DEFAULT_PATH: &str = "/usr/lib/ladspa:/usr/local/lib/ladspa";
+/// How many frames are passed to a plugin at once.
+const MAX_FRAMES: usize = 4096;
+
+const PORT_INPUT: c_int = 0x1;
+const PORT_CONTROL: c_int = 0x4;
+const PORT_AUDIO: c_int = 0x8;
+
+const HINT_BOUNDED_BELOW: c_int = 0x1;
+const HINT_BOUNDED_ABOVE: c_int = 0x2;
+const HINT_SAMPLE_RATE: c_int = 0x8;
+const HINT_LOGARITHMIC: c_int = 0x10;
+const HINT_DEFAULT_MASK: c_int = 0x3c0;
+
+type Handle = *mut c_void;
+
+#[repr(C)]
+struct RangeHint {
+    descriptor: c_int,
+    lower_bound: f32,
+    upper_bound: f32,
+}
+
+/// The `LADSPA_Descriptor` of ladspa.h.
+#[allow(dead_code)]
+#[repr(C)]
+struct Descriptor {
+    unique_id: c_ulong,
+    label: *const c_char,
+    properties: c_int,
+    name: *const c_char,
+    maker: *const c_char,
+    copyright: *const c_char,
+    port_count: c_ulong,
+    port_descriptors: *const c_int,
+    port_names: *const *const c_char,
+    port_range_hints: *const RangeHint,
+    implementation_data: *mut c_void,
+    instantiate: unsafe extern "C" fn(*const Descriptor, c_ulong) -> Handle,
+    connect_port: unsafe extern "C" fn(Handle, c_ulong, *mut f32),
+    activate: Option<unsafe extern "C" fn(Handle)>,
+    run: unsafe extern "C" fn(Handle, c_ulong),
+    run_adding: Option<unsafe extern "C" fn(Handle, c_ulong)>,
+    set_run_adding_gain: Option<unsafe extern "C" fn(Handle, f32)>,
+    deactivate: Option<unsafe extern "C" fn(Handle)>,
+    cleanup: unsafe extern "C" fn(Handle),
+}
+
+/// The default value of a control, from the hints of its port.
+fn default_value(hint: &RangeHint) -> f32 {
+    let scale = if hint.descriptor & HINT_SAMPLE_RATE != 0 {
+        SAMPLE_RATE as f32
+    } else {
+        1.0
+    };
+    let (lower, upper) = (hint.lower_bound * scale, hint.upper_bound * scale);
+    let between = |weight: f32| {
+        if hint.descriptor & HINT_LOGARITHMIC != 0 && lower > 0.0 && upper > 0.0 {
+            (lower.ln() * (1.0 - weight) + upper.ln() * weight).exp()
+        } else {
+            lower * (1.0 - weight) + upper * weight
+        }
+    };
+    match hint.descriptor & HINT_DEFAULT_MASK {
+        0x40 => lower,
+        0x80 => between(0.25),
+        0xc0 => between(0.5),
+        0x100 => between(0.75),
+        0x140 => upper,
+        0x200 => 0.0,
+        0x240 => 1.0,
+        0x280 => 100.0,
+        0x2c0 => 440.0,
+        _ if hint.descriptor & HINT_BOUNDED_BELOW != 0 => lower,
+        _ if hint.descriptor & HINT_BOUNDED_ABOVE != 0 => upper.min(0.0),
+        _ => 0.0,
+    }
+}
+
+/// The file of the plugin, a path, or a name looked up in `LADSPA_PATH`.
+fn find(plugin: &Path) -> Result<PathBuf, String> {
+    if plugin.components().count() > 1 {
+        return Ok(plugin.to_path_buf());
+    }
+    let file = if plugin.extension().is_some() {
+        plugin.to_path_buf()
+    } else {
+        plugin.with_extension("so")
+    };
+    let search_path = env::var("LADSPA_PATH").unwrap_or_else(|_| DEFAULT_PATH.to_string());
+    env::split_paths(&search_path)
+        .map(|dir| dir.join(&file))
+        .find(|path| path.is_file())
+        .ok_or_else(|| format!("{} isn't in {}", file.display(), search_path))
+}
+
+unsafe fn string(s: *const c_char) -> String {
+    if s.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(s).to_string_lossy().into_owned()
+    }
+}
+
+/// A plugin of a LADSPA library, with the values of its controls.
+pub(crate) struct Plugin {
+    descriptor: *const Descriptor,
+    audio_inputs: Vec<c_ulong>,
+    audio_outputs: Vec<c_ulong>,
+    /// The value of each control port, by the index of the port.
+    controls: Vec<f32>,
+    /// Unloaded last, once nothing points into it anymore.
+    _library: Library,
+}
+
+// the descriptor is immutable, and lives as long as the library
+unsafe impl Send for Plugin {}
+unsafe impl Sync for Plugin {}
+
+impl Plugin {
+    pub(crate) fn load(
+        plugin: &Path,
+        label: &str,
+        controls: &HashMap<String, f32>,
+    ) -> Result<Self, String> {
+        let path = find(plugin)?;
+        let fail = |e: String| format!("failed to load {}: {}", path.display(), e);
+        unsafe {
+            let library = Library::new(&path).map_err(|e| fail(e.to_string()))?;
+            let descriptor_at = *library
+                .get::<unsafe extern "C" fn(c_ulong) -> *const Descriptor>(b"ladspa_descriptor\0")
+                .map_err(|e| fail(e.to_string()))?;
+            let mut labels = Vec::new();
+            let descriptor = loop {
+                let descriptor = descriptor_at(labels.len() as c_ulong);
+                if descriptor.is_null() {
+                    return Err(fail(format!(
+                        "there's no plugin {:?}, only {:?}",
+                        label, labels
+                    )));
+                }
+                let name = string((*descriptor).label);
+                if name == label {
+                    break descriptor;
+                }
+                labels.push(name);
+            };
+
+            let mut audio_inputs = Vec::new();
+            let mut audio_outputs = Vec::new();
+            let mut values = vec![0.0; (*descriptor).port_count as usize];
+            let mut names = Vec::new();
+            for port in 0..(*descriptor).port_count {
+                let kind = *(*descriptor).port_descriptors.add(port as usize);
+                let is_input = kind & PORT_INPUT != 0;
+                if kind & PORT_AUDIO != 0 {
+                    if is_input {
+                        audio_inputs.push(port);
+                    } else {
+                        audio_outputs.push(port);
+                    }
+                } else if kind & PORT_CONTROL != 0 && is_input {
+                    let name = string(*(*descriptor).port_names.add(port as usize));
+                    let hint = &*(*descriptor).port_range_hints.add(port as usize);
+                    values[port as usize] = controls
+                        .get(&name)
+                        .copied()
+                        .unwrap_or_else(|| default_value(hint));
+                    names.push(name);
+                }
+            }
+            if let Some(unknown) = controls.keys().find(|name| !names.contains(name)) {
+                return Err(fail(format!(
+                    "{} has no control {:?}, only {:?}",
+                    label, unknown, names
+                )));
+            }
+            let channels = audio_inputs.len();
+            if (channels != 1 && channels != NUM_CHANNELS as usize)
+                || audio_outputs.len() != channels
+            {
+                return Err(fail(format!(
+                    "{} has {} inputs and {} outputs, only mono and stereo plugins are supported",
+                    label,
+                    channels,
+                    audio_outputs.len()
+                )));
+            }
+            Ok(Self {
+                descriptor,
+                audio_inputs,
+                audio_outputs,
+                controls: values,
+                _library: library,
+            })
+        }
+    }
+}
+
+/// Runs a plugin on the interleaved stereo audio, with an instance for each
+/// channel if it's a mono one.
+pub(crate) struct Instance {
+    handles: Vec<Handle>,
+    /// The ports of the handles point into these, which aren't resized.
+    controls: Vec<Vec<f32>>,
+    inputs: Vec<Vec<f32>>,
+    outputs: Vec<Vec<f32>>,
+    plugin: Arc<Plugin>,
+}
+
+// the handles are only used by the thread running the sink
+unsafe impl Send for Instance {}
+
+impl Instance {
+    pub(crate) fn new(plugin: Arc<Plugin>) -> Result<Self, String> {
+        let channels = NUM_CHANNELS as usize;
+        let mut instance = Self {
+            handles: Vec::new(),
+            controls: Vec::new(),
+            inputs: vec![vec![0.0; MAX_FRAMES]; channels],
+            outputs: vec![vec![0.0; MAX_FRAMES]; channels],
+            plugin,
+        };
+        let per_handle = instance.plugin.audio_inputs.len();
+        unsafe {
+            let descriptor = &*instance.plugin.descriptor;
+            for first in (0..channels).step_by(per_handle) {
+                let handle = (descriptor.instantiate)(descriptor, SAMPLE_RATE as c_ulong);
+                if handle.is_null() {
+                    return Err(format!(
+                        "failed to instantiate {}",
+                        string(descriptor.label)
+                    ));
+                }
+                // dropped with the instance from now on
+                instance.handles.push(handle);
+                instance.controls.push(instance.plugin.controls.clone());
+                let controls = instance.controls.last_mut().unwrap();
+                for port in 0..descriptor.port_count {
+                    (descriptor.connect_port)(handle, port, &mut controls[port as usize]);
+                }
+                for (i, (&input, &output)) in instance
+                    .plugin
+                    .audio_inputs
+                    .iter()
+                    .zip(&instance.plugin.audio_outputs)
+                    .enumerate()
+                {
+                    let (input_buffer, output_buffer) = (
+                        &mut instance.inputs[first + i],
+                        &mut instance.outputs[first + i],
+                    );
+                    (descriptor.connect_port)(handle, input, input_buffer.as_mut_ptr());
+                    (descriptor.connect_port)(handle, output, output_buffer.as_mut_ptr());
+                }
+                if let Some(activate) = descriptor.activate {
+                    activate(handle);
+                }
+            }
+        }
+        Ok(instance)
+    }
+
+    pub(crate) fn process(&mut self, samples: &mut [f64]) {
+        let channels = self.inputs.len();
+        for chunk in samples.chunks_mut(MAX_FRAMES * channels) {
+            let frames = chunk.len() / channels;
+            for (i, sample) in chunk.iter().enumerate() {
+                self.inputs[i % channels][i / channels] = *sample as f32;
+            }
+            unsafe {
+                let descriptor = &*self.plugin.descriptor;
+                for &handle in &self.handles {
+                    (descriptor.run)(handle, frames as c_ulong);
+                }
+            }
+            for (i, sample) in chunk.iter_mut().enumerate() {
+                *sample = self.outputs[i % channels][i / channels] as f64;
+            }
+        }
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        unsafe {
+            let descriptor = &*self.plugin.descriptor;
+            for handle in self.handles.drain(..) {
+                if let Some(deactivate) = descriptor.deactivate {
+                    deactivate(handle);
+                }
+                (descriptor.cleanup)(handle);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_value() {
+        let hint = |descriptor, lower_bound, upper_bound| RangeHint {
+            descriptor,
+            lower_bound,
+            upper_bound,
+        };
+        let bounded = HINT_BOUNDED_BELOW | HINT_BOUNDED_ABOVE;
+        assert_eq!(default_value(&hint(bounded | 0xc0, -10.0, 10.0)), 0.0);
+        assert_eq!(default_value(&hint(bounded | 0x140, -10.0, 10.0)), 10.0);
+        let frequency = bounded | HINT_LOGARITHMIC | 0xc0;
+        assert!((default_value(&hint(frequency, 10.0, 1000.0)) - 100.0).abs() < 0.01);
+        let relative = bounded | HINT_SAMPLE_RATE | 0x40;
+        assert_eq!(
+            default_value(&hint(relative, 0.5, 1.0)),
+            SAMPLE_RATE as f32 / 2.0
+        );
+        assert_eq!(default_value(&hint(0x240, 0.0, 0.0)), 1.0);
+        assert_eq!(default_value(&hint(HINT_BOUNDED_BELOW, 2.0, 0.0)), 2.0);
+    }
+}
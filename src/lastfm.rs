@@ -0,0 +1,198 @@
+use crate::{
+    config::LastfmConfig,
+    scrobble::{Listen, ScrobbleError, Scrobbler},
+};
+use log::{info, warn};
+use md5::{Digest, Md5};
+use std::{fs, io, path::PathBuf, sync::Mutex, time::Duration};
+
+const API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The error codes of Last.fm after which the request may succeed later:
+/// the service is offline, temporarily unavailable, or rate limited.
+const TRANSIENT_ERRORS: [u64; 3] = [11, 16, 29];
+/// The error code of an invalid session key, which is requested again.
+const INVALID_SESSION: u64 = 9;
+
+/// The signature of the call, the MD5 of its parameters, sorted by their
+/// name, followed by the secret.
+fn signature(params: &[(&str, String)], secret: &str) -> String {
+    let mut sorted: Vec<_> = params.iter().collect();
+    sorted.sort_by_key(|(name, _)| *name);
+    let mut hasher = Md5::new();
+    for (name, value) in sorted {
+        hasher.update(name.as_bytes());
+        hasher.update(value.as_bytes());
+    }
+    hasher.update(secret.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Submits the listens to a Last.fm account.
+///
+/// The session key of the account is requested with its password once, and
+/// stored in the session file if one is given, so that the password can be
+/// removed from the config afterwards.
+pub(crate) struct LastFm {
+    agent: ureq::Agent,
+    config: LastfmConfig,
+    session_path: Option<PathBuf>,
+    session_key: Mutex<Option<String>>,
+}
+
+impl LastFm {
+    pub(crate) fn new(config: LastfmConfig, session_path: Option<PathBuf>) -> Self {
+        let session_key = match session_path.as_ref().map(fs::read_to_string) {
+            Some(Ok(key)) => Some(key.trim().to_string()),
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => {
+                warn!("Failed to read the Last.fm session: {}", e);
+                None
+            }
+            _ => None,
+        };
+        Self {
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+            config,
+            session_path,
+            session_key: Mutex::new(session_key),
+        }
+    }
+
+    fn call(
+        &self,
+        method: &str,
+        mut params: Vec<(&str, String)>,
+    ) -> Result<serde_json::Value, ScrobbleError> {
+        params.push(("method", method.to_string()));
+        params.push(("api_key", self.config.api_key.clone()));
+        let api_sig = signature(&params, &self.config.api_secret);
+        params.push(("api_sig", api_sig));
+        params.push(("format", "json".to_string()));
+        let form: Vec<_> = params
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+
+        let (response, status) = match self.agent.post(API_URL).send_form(&form) {
+            Ok(response) => (response, 200),
+            Err(ureq::Error::Status(status, response)) => (response, status),
+            Err(e) => return Err(ScrobbleError::Transient(e.to_string())),
+        };
+        let body = response
+            .into_string()
+            .map_err(|e| ScrobbleError::Transient(e.to_string()))?;
+        let body: serde_json::Value = serde_json::from_str(&body).map_err(|e| {
+            ScrobbleError::Transient(format!("invalid response ({}): {}", status, e))
+        })?;
+        let Some(code) = body["error"].as_u64() else {
+            return Ok(body);
+        };
+        let message = format!(
+            "{} ({})",
+            body["message"].as_str().unwrap_or("unknown error"),
+            code
+        );
+        if code == INVALID_SESSION {
+            *self.session_key.lock().unwrap() = None;
+            return Err(ScrobbleError::Transient(message));
+        }
+        if TRANSIENT_ERRORS.contains(&code) {
+            Err(ScrobbleError::Transient(message))
+        } else {
+            Err(ScrobbleError::Rejected(message))
+        }
+    }
+
+    fn session_key(&self) -> Result<String, ScrobbleError> {
+        if let Some(ref key) = *self.session_key.lock().unwrap() {
+            return Ok(key.clone());
+        }
+        let Some(ref password) = self.config.password else {
+            return Err(ScrobbleError::Rejected(
+                "no Last.fm session is stored, and no password is configured".to_string(),
+            ));
+        };
+        let params = vec![
+            ("username", self.config.username.clone()),
+            ("password", password.clone()),
+        ];
+        let response = self.call("auth.getMobileSession", params)?;
+        let key = response["session"]["key"]
+            .as_str()
+            .ok_or_else(|| ScrobbleError::Transient("no session key in the response".to_string()))?
+            .to_string();
+        info!("Logged in to Last.fm as {}", self.config.username);
+        if let Some(ref path) = self.session_path {
+            if let Err(e) = fs::write(path, &key) {
+                warn!("Failed to store the Last.fm session: {}", e);
+            }
+        }
+        *self.session_key.lock().unwrap() = Some(key.clone());
+        Ok(key)
+    }
+}
+
+impl Scrobbler for LastFm {
+    fn name(&self) -> &'static str {
+        "Last.fm"
+    }
+
+    fn now_playing(&self, listen: &Listen) -> Result<(), ScrobbleError> {
+        let mut params = vec![
+            ("sk", self.session_key()?),
+            ("artist", listen.artist.clone()),
+            ("track", listen.track.clone()),
+            ("album", listen.album.clone()),
+            ("duration", (listen.duration_ms / 1000).to_string()),
+        ];
+        if let Some(ref album_artist) = listen.album_artist {
+            params.push(("albumArtist", album_artist.clone()));
+        }
+        self.call("track.updateNowPlaying", params).map(drop)
+    }
+
+    fn scrobble(&self, listens: &[Listen]) -> Result<(), ScrobbleError> {
+        // the parameters of each listen are numbered
+        let names: Vec<_> = (0..listens.len())
+            .map(|i| {
+                [
+                    format!("artist[{}]", i),
+                    format!("track[{}]", i),
+                    format!("album[{}]", i),
+                    format!("duration[{}]", i),
+                    format!("timestamp[{}]", i),
+                ]
+            })
+            .collect();
+        let mut params = vec![("sk", self.session_key()?)];
+        for (listen, names) in listens.iter().zip(&names) {
+            params.push((names[0].as_str(), listen.artist.clone()));
+            params.push((names[1].as_str(), listen.track.clone()));
+            params.push((names[2].as_str(), listen.album.clone()));
+            params.push((names[3].as_str(), (listen.duration_ms / 1000).to_string()));
+            params.push((names[4].as_str(), listen.started_at.to_string()));
+        }
+        self.call("track.scrobble", params).map(drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        let params = [
+            ("method", "auth.getMobileSession".to_string()),
+            ("api_key", "key".to_string()),
+            ("username", "user".to_string()),
+            ("password", "pass".to_string()),
+        ];
+        // md5("api_keykeymethodauth.getMobileSessionpasswordpassusernameusersecret")
+        assert_eq!(
+            signature(&params, "secret"),
+            "9c54f6cf8fc68a3826368902af94331e"
+        );
+    }
+}
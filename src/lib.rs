@@ -0,0 +1,129 @@
+//! The spotifyd daemon as a library, for projects that want to embed a
+//! Spotify Connect receiver instead of running `spotifyd` next to them.
+//!
+//! The library and the `spotifyd` binary are built from the same crate, there
+//! is no separate `spotifyd-core` crate: depending on `spotifyd` brings in the
+//! dependencies of the daemon, trimmed down through its cargo features as for
+//! the binary.
+//!
+//! ```no_run
+//! use spotifyd::{config::CliConfig, setup};
+//! use structopt::StructOpt;
+//!
+//! # async fn example() {
+//! let mut cli_config = CliConfig::from_args();
+//! cli_config.load_config_file_values().unwrap();
+//!
+//! let mut main_loop = setup::initial_state(spotifyd::config::get_internal_config(cli_config));
+//! let mut events = main_loop.subscribe();
+//! let state = main_loop.playback_state();
+//! tokio::spawn(async move {
+//!     while let Some(event) = events.recv().await {
+//!         println!("{:?}: {:?}", event, state.read().unwrap().status);
+//!     }
+//! });
+//! main_loop.run().await;
+//! # }
+//! ```
+
+#[cfg(feature = "alsa_backend")]
+mod alsa_mixer;
+pub mod audio_sink;
+pub mod audit;
+mod bind_proxy;
+mod blocklist;
+pub mod cache;
+mod cache_layout;
+#[cfg(feature = "camilladsp")]
+mod camilladsp;
+pub mod config;
+pub mod config_check;
+mod context_end;
+pub mod control;
+#[cfg(unix)]
+pub mod control_socket;
+#[cfg(feature = "convolution")]
+mod convolution;
+mod crossfade;
+#[cfg(feature = "web_api")]
+pub mod ctl;
+pub mod data_usage;
+#[cfg(feature = "dbus_mpris")]
+mod dbus_mpris;
+pub mod discoverable;
+mod dsp;
+#[cfg(target_os = "linux")]
+mod egress;
+mod encryption;
+mod equalizer;
+mod error;
+mod event_log;
+pub mod events;
+mod fade;
+#[cfg(feature = "web_ui")]
+mod guest;
+mod history;
+#[cfg(feature = "http_api")]
+mod http_api;
+mod idle;
+#[cfg(feature = "jack_backend")]
+mod jack_sink;
+#[cfg(feature = "ladspa")]
+mod ladspa;
+#[cfg(feature = "lastfm")]
+mod lastfm;
+#[cfg(feature = "listenbrainz")]
+mod listenbrainz;
+pub mod lock;
+pub mod logging;
+pub mod main_loop;
+pub mod man;
+pub mod metered;
+#[cfg(feature = "web_api")]
+mod mirror;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod multi_sink;
+mod no_mixer;
+#[cfg(feature = "oauth")]
+pub mod oauth;
+mod party;
+#[cfg(target_os = "linux")]
+mod peer;
+#[cfg(feature = "pipewire_backend")]
+mod pipewire_sink;
+#[cfg(feature = "web_api")]
+mod preload;
+mod process;
+pub mod profiles;
+#[cfg(feature = "http_api")]
+mod rate_limit;
+#[cfg(feature = "web_api")]
+mod reconnect;
+pub mod record;
+mod resume;
+#[cfg(feature = "web_api")]
+mod schedule;
+#[cfg(any(feature = "lastfm", feature = "listenbrainz"))]
+mod scrobble;
+mod sd_notify;
+#[cfg(feature = "web_api")]
+pub mod search;
+#[cfg(feature = "dbus_keyring")]
+pub mod secrets;
+pub mod setup;
+mod show_rules;
+pub mod simulate;
+pub mod sleep_timer;
+mod startup;
+pub mod state;
+mod telemetry;
+#[cfg(target_os = "linux")]
+mod thermal;
+mod utils;
+pub mod version;
+mod warm_sink;
+#[cfg(feature = "web_api")]
+mod web_api;
+#[cfg(feature = "webhook")]
+mod webhook;
@@ -0,0 +1,147 @@
+use crate::{
+    config::ListenbrainzConfig,
+    scrobble::{Listen, ScrobbleError, Scrobbler},
+};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const DEFAULT_API_URL: &str = "https://api.listenbrainz.org";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The metadata of the listen, without MusicBrainz IDs, which ListenBrainz
+/// looks up by itself.
+fn track_metadata(listen: &Listen) -> Value {
+    let mut additional_info = json!({
+        "duration_ms": listen.duration_ms,
+        "media_player": "spotifyd",
+        "submission_client": "spotifyd",
+        "submission_client_version": env!("CARGO_PKG_VERSION"),
+        "music_service": "spotify.com",
+    });
+    if let Some(id) = listen.uri.strip_prefix("spotify:track:") {
+        additional_info["spotify_id"] = json!(format!("https://open.spotify.com/track/{}", id));
+        additional_info["origin_url"] = additional_info["spotify_id"].clone();
+    }
+    if let Some(ref album_artist) = listen.album_artist {
+        additional_info["release_artist_name"] = json!(album_artist);
+    }
+    json!({
+        "artist_name": listen.artist,
+        "track_name": listen.track,
+        "release_name": listen.album,
+        "additional_info": additional_info,
+    })
+}
+
+/// The body submitting the listens, or the track playing now.
+fn submission(listens: &[Listen], playing_now: bool) -> Value {
+    let listen_type = if playing_now {
+        "playing_now"
+    } else if listens.len() == 1 {
+        "single"
+    } else {
+        "import"
+    };
+    let payload: Vec<_> = listens
+        .iter()
+        .map(|listen| {
+            let mut payload = json!({ "track_metadata": track_metadata(listen) });
+            if !playing_now {
+                payload["listened_at"] = json!(listen.started_at);
+            }
+            payload
+        })
+        .collect();
+    json!({ "listen_type": listen_type, "payload": payload })
+}
+
+/// Submits the listens to a ListenBrainz account, on listenbrainz.org or a
+/// self-hosted server.
+pub(crate) struct ListenBrainz {
+    agent: ureq::Agent,
+    url: String,
+    token: String,
+}
+
+impl ListenBrainz {
+    pub(crate) fn new(config: ListenbrainzConfig) -> Self {
+        let api_url = config.api_url.as_deref().unwrap_or(DEFAULT_API_URL);
+        Self {
+            agent: ureq::AgentBuilder::new().timeout(TIMEOUT).build(),
+            url: format!("{}/1/submit-listens", api_url.trim_end_matches('/')),
+            token: config.token,
+        }
+    }
+
+    fn submit(&self, body: Value) -> Result<(), ScrobbleError> {
+        let result = self
+            .agent
+            .post(&self.url)
+            .set("Authorization", &format!("Token {}", self.token))
+            .set("Content-Type", "application/json")
+            .send_string(&body.to_string());
+        match result {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(status, response)) => {
+                let message = response
+                    .into_string()
+                    .ok()
+                    .and_then(|body| serde_json::from_str::<Value>(&body).ok())
+                    .and_then(|body| body["error"].as_str().map(str::to_string))
+                    .unwrap_or_default();
+                let message = format!("{} {}", status, message);
+                if status == 429 || status >= 500 {
+                    Err(ScrobbleError::Transient(message))
+                } else {
+                    Err(ScrobbleError::Rejected(message))
+                }
+            }
+            Err(e) => Err(ScrobbleError::Transient(e.to_string())),
+        }
+    }
+}
+
+impl Scrobbler for ListenBrainz {
+    fn name(&self) -> &'static str {
+        "ListenBrainz"
+    }
+
+    fn now_playing(&self, listen: &Listen) -> Result<(), ScrobbleError> {
+        self.submit(submission(std::slice::from_ref(listen), true))
+    }
+
+    fn scrobble(&self, listens: &[Listen]) -> Result<(), ScrobbleError> {
+        self.submit(submission(listens, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_submission() {
+        let listen = Listen {
+            artist: "Artist".to_string(),
+            track: "Song".to_string(),
+            album: "Album".to_string(),
+            album_artist: None,
+            duration_ms: 200_000,
+            started_at: 1_700_000_000,
+            uri: "spotify:track:abc".to_string(),
+        };
+        let body = submission(&[listen.clone()], false);
+        assert_eq!(body["listen_type"], "single");
+        assert_eq!(body["payload"][0]["listened_at"], 1_700_000_000);
+        let metadata = &body["payload"][0]["track_metadata"];
+        assert_eq!(metadata["track_name"], "Song");
+        assert_eq!(
+            metadata["additional_info"]["spotify_id"],
+            "https://open.spotify.com/track/abc"
+        );
+
+        let body = submission(&[listen], true);
+        assert_eq!(body["listen_type"], "playing_now");
+        assert!(body["payload"][0].get("listened_at").is_none());
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{
+    control::ControlCommand,
+    events::SpotifydEvent,
+    state::{PlaybackState, PlaybackStatus},
+};
+use log::info;
+use std::sync::Mutex;
+
+/// Who the playback is kept with while the lock is taken.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LockOwner {
+    /// The account of the current session.
+    pub user_name: Option<String>,
+    /// The Spotify Connect client in control. If none was when the lock was
+    /// taken, the first one to take control becomes the owner.
+    pub client_name: Option<String>,
+}
+
+impl LockOwner {
+    /// The account and the client holding the playback now.
+    pub(crate) fn current(state: &PlaybackState) -> Self {
+        Self {
+            user_name: state.user_name.clone(),
+            client_name: state.controller.clone(),
+        }
+    }
+}
+
+/// The "do not disturb" lock, which keeps the playback with the account and
+/// the client holding it while it's taken.
+///
+/// The connections of other accounts via the discovery are refused. The
+/// clients of the same account can't be kept from taking control, librespot
+/// applies their commands before any event is emitted, so the playback is
+/// paused again whenever one of them starts it.
+#[derive(Debug, Default)]
+pub struct DoNotDisturb {
+    owner: Mutex<Option<LockOwner>>,
+}
+
+impl DoNotDisturb {
+    pub fn lock(&self, owner: LockOwner) {
+        info!(
+            "Locked the playback to {}",
+            owner
+                .client_name
+                .as_deref()
+                .unwrap_or("the current account")
+        );
+        *self.owner.lock().unwrap() = Some(owner);
+    }
+
+    pub fn unlock(&self) {
+        if self.owner.lock().unwrap().take().is_some() {
+            info!("Unlocked the playback");
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.owner.lock().unwrap().is_some()
+    }
+
+    /// Whether a new session of the account may replace the current one.
+    pub(crate) fn admits(&self, user_name: Option<&str>) -> bool {
+        match *self.owner.lock().unwrap() {
+            Some(ref owner) => owner.user_name.is_none() || owner.user_name.as_deref() == user_name,
+            None => true,
+        }
+    }
+
+    /// Returns the command that undoes what a client other than the owner
+    /// did while locked, after the event has been applied to the playback
+    /// state.
+    pub(crate) fn refuse(
+        &self,
+        event: &SpotifydEvent,
+        state: &PlaybackState,
+    ) -> Option<ControlCommand> {
+        if !matches!(
+            event,
+            SpotifydEvent::SessionClientChanged { .. } | SpotifydEvent::Playing { .. }
+        ) {
+            return None;
+        }
+        let mut owner = self.owner.lock().unwrap();
+        let owner = owner.as_mut()?;
+        let controller = state.controller.as_deref()?;
+        let Some(ref client_name) = owner.client_name else {
+            owner.client_name = Some(controller.to_string());
+            return None;
+        };
+        if client_name.eq_ignore_ascii_case(controller) {
+            return None;
+        }
+        (state.status == PlaybackStatus::Playing).then_some(ControlCommand::Pause)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_changed(client_name: &str) -> SpotifydEvent {
+        SpotifydEvent::SessionClientChanged {
+            client_id: "id".to_string(),
+            client_name: client_name.to_string(),
+            client_brand_name: String::new(),
+            client_model_name: String::new(),
+            client_ip: None,
+        }
+    }
+
+    #[test]
+    fn test_refuses_other_clients() {
+        let lock = DoNotDisturb::default();
+        let mut state = PlaybackState::default();
+        let mut emit = |event: SpotifydEvent| {
+            state.update(&event);
+            lock.refuse(&event, &state)
+        };
+
+        assert_eq!(emit(client_changed("Phone")), None);
+        let playing = SpotifydEvent::Playing {
+            play_request_id: 1,
+            track_id: "track".to_string(),
+            position_ms: 0,
+        };
+        assert_eq!(emit(playing), None);
+
+        lock.lock(LockOwner {
+            user_name: Some("host".to_string()),
+            client_name: None,
+        });
+        assert_eq!(emit(client_changed("Laptop")), None);
+        assert_eq!(emit(client_changed("Phone")), Some(ControlCommand::Pause));
+        assert_eq!(emit(client_changed("laptop")), None);
+        assert!(lock.admits(Some("host")));
+        assert!(!lock.admits(Some("guest")));
+
+        lock.unlock();
+        assert_eq!(emit(client_changed("Phone")), None);
+        assert!(lock.admits(Some("guest")));
+    }
+}
@@ -0,0 +1,820 @@
+use crate::error::ParseError;
+#[cfg(unix)]
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::{self, Context};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    fs,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, RwLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub(crate) static LOG_TARGET_VALUES: &[&str] = &[
+    "stdout",
+    "syslog",
+    #[cfg(target_os = "linux")]
+    "journald",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    Terminal,
+    Syslog,
+    #[cfg(target_os = "linux")]
+    Journald,
+}
+
+impl FromStr for LogTarget {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdout" => Ok(LogTarget::Terminal),
+            "syslog" => Ok(LogTarget::Syslog),
+            #[cfg(target_os = "linux")]
+            "journald" => Ok(LogTarget::Journald),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// The text that replaces sensitive data in log messages.
+const PLACEHOLDER: &str = "<redacted>";
+
+/// Query parameters and `key=value` pairs whose value is replaced.
+const SENSITIVE_KEYS: &[&str] = &[
+    "access_token",
+    "auth",
+    "authorization",
+    "code",
+    "key",
+    "password",
+    "refresh_token",
+    "secret",
+    "token",
+];
+
+/// Tokens and authentication blobs are long, while Spotify ids (22 chars)
+/// and device ids (40 chars) are shorter than this.
+const MIN_TOKEN_LEN: usize = 48;
+
+/// How many debug messages are kept for every subsystem.
+const DEBUG_BUFFER_LEN: usize = 200;
+/// How many errors of a subsystem within `ERROR_WINDOW` escalate its log level.
+const ERROR_THRESHOLD: usize = 3;
+const ERROR_WINDOW: Duration = Duration::from_secs(60);
+/// How long the debug messages of an escalated subsystem are logged.
+const ESCALATION_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+static REDACTION_ENABLED: AtomicBool = AtomicBool::new(true);
+static ADAPTIVE_ENABLED: AtomicBool = AtomicBool::new(false);
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+static ESCALATION: Mutex<Option<Escalation>> = Mutex::new(None);
+static MODULE_LEVELS: RwLock<Vec<(String, LevelFilter)>> = RwLock::new(Vec::new());
+
+thread_local! {
+    static EVENT_FIELDS: RefCell<Vec<(&'static str, String)>> = RefCell::new(Vec::new());
+}
+
+/// Enables or disables the redaction of sensitive data in log messages.
+///
+/// Redaction is enabled by default, so that messages logged before the
+/// config has been loaded are redacted, too.
+pub fn set_redaction(enabled: bool) {
+    REDACTION_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Runs `f` with the given structured fields attached to every message that is
+/// logged meanwhile. The fields are only visible when logging to journald.
+pub(crate) fn with_event_fields<T>(
+    fields: Vec<(&'static str, String)>,
+    f: impl FnOnce() -> T,
+) -> T {
+    let previous = EVENT_FIELDS.with(|current| current.replace(fields));
+    let result = f();
+    EVENT_FIELDS.with(|current| current.replace(previous));
+    result
+}
+
+/// Enables the adaptive log level: the debug messages of the subsystems of
+/// spotifyd and librespot are kept in a rolling buffer, and after repeated
+/// errors of a subsystem, its debug messages are logged for a while. The
+/// buffered messages are written to a file in `dump_dir` then, or logged if
+/// there is none.
+pub fn set_adaptive_logging(dump_dir: Option<PathBuf>) {
+    *ESCALATION.lock().unwrap() = Some(Escalation::new(dump_dir));
+    ADAPTIVE_ENABLED.store(true, Ordering::Relaxed);
+    if log::max_level() < LevelFilter::Debug {
+        log::set_max_level(LevelFilter::Debug);
+    }
+}
+
+/// Sets the log levels of modules, e.g. `librespot_playback` or
+/// `spotifyd::main_loop`, replacing the ones set before. The level of the most
+/// specific module applies, `--verbose` only applies to the other modules.
+pub fn set_module_levels(levels: Vec<(String, LevelFilter)>) {
+    if let Some(max) = levels.iter().map(|(_, level)| *level).max() {
+        if log::max_level() < max {
+            log::set_max_level(max);
+        }
+    }
+    *MODULE_LEVELS.write().unwrap() = levels;
+}
+
+/// The level of the most specific module in `levels` that `target` is part of.
+fn module_level(levels: &[(String, LevelFilter)], target: &str) -> Option<LevelFilter> {
+    levels
+        .iter()
+        .filter(|(module, _)| {
+            target
+                .strip_prefix(module.as_str())
+                .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+        })
+        .max_by_key(|(module, _)| module.len())
+        .map(|(_, level)| *level)
+}
+
+/// The subsystem a message belongs to, e.g. `librespot_playback::audio_backend`
+/// for messages of `librespot_playback::audio_backend::alsa`.
+fn subsystem(target: &str) -> &str {
+    match target.match_indices("::").nth(1) {
+        Some((end, _)) => &target[..end],
+        None => target,
+    }
+}
+
+/// Whether the debug messages of `target` are handled by the adaptive log
+/// level. Those of the other crates, like the HTTP and TLS stacks, are many
+/// and rarely help, so they aren't even formatted.
+fn is_adaptive(target: &str) -> bool {
+    let krate = target.split("::").next().unwrap_or_default();
+    krate == "spotifyd" || krate.starts_with("librespot")
+}
+
+/// Tracks the errors and recent debug messages of the subsystems.
+struct Escalation {
+    dump_dir: Option<PathBuf>,
+    buffers: HashMap<String, VecDeque<String>>,
+    errors: HashMap<String, VecDeque<Instant>>,
+    escalated_until: HashMap<String, Instant>,
+}
+
+impl Escalation {
+    fn new(dump_dir: Option<PathBuf>) -> Self {
+        Self {
+            dump_dir,
+            buffers: HashMap::new(),
+            errors: HashMap::new(),
+            escalated_until: HashMap::new(),
+        }
+    }
+
+    fn is_escalated(&self, subsystem: &str, now: Instant) -> bool {
+        self.escalated_until
+            .get(subsystem)
+            .map_or(false, |until| now < *until)
+    }
+
+    fn buffer(&mut self, subsystem: &str, line: String) {
+        let buffer = self.buffers.entry(subsystem.to_string()).or_default();
+        if buffer.len() == DEBUG_BUFFER_LEN {
+            buffer.pop_front();
+        }
+        buffer.push_back(line);
+    }
+
+    /// Counts an error of the subsystem. Returns the buffered debug messages
+    /// if this escalates the subsystem.
+    fn error(&mut self, subsystem: &str, now: Instant) -> Option<Vec<String>> {
+        if self.is_escalated(subsystem, now) {
+            return None;
+        }
+        let errors = self.errors.entry(subsystem.to_string()).or_default();
+        while errors
+            .front()
+            .map_or(false, |t| now.duration_since(*t) > ERROR_WINDOW)
+        {
+            errors.pop_front();
+        }
+        errors.push_back(now);
+        if errors.len() < ERROR_THRESHOLD {
+            return None;
+        }
+        errors.clear();
+        self.escalated_until
+            .insert(subsystem.to_string(), now + ESCALATION_PERIOD);
+        let buffer = self.buffers.remove(subsystem).unwrap_or_default();
+        Some(buffer.into())
+    }
+}
+
+/// Writes the buffered debug messages of an escalated subsystem to a file in
+/// the dump directory, or logs them via `inner`.
+fn dump_debug_buffer(
+    inner: &dyn Log,
+    subsystem: &str,
+    lines: Vec<String>,
+    dump_dir: Option<PathBuf>,
+) {
+    let notice = |message: String| {
+        inner.log(
+            &Record::builder()
+                .level(Level::Warn)
+                .target("spotifyd::logging")
+                .args(format_args!("{}", message))
+                .build(),
+        )
+    };
+    if let Some(dir) = dump_dir {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = dir.join(format!(
+            "debug-{}-{}.log",
+            subsystem.replace("::", "-"),
+            timestamp
+        ));
+        let result = fs::create_dir_all(&dir).and_then(|()| fs::write(&path, lines.join("\n")));
+        match result {
+            Ok(()) => {
+                return notice(format!(
+                    "Repeated errors of {}, logging its debug messages for {} minutes, earlier ones were written to {}",
+                    subsystem,
+                    ESCALATION_PERIOD.as_secs() / 60,
+                    path.display()
+                ))
+            }
+            Err(e) => notice(format!("Failed to write {}: {}", path.display(), e)),
+        }
+    }
+    notice(format!(
+        "Repeated errors of {}, logging its debug messages for {} minutes, starting with the last {}",
+        subsystem,
+        ESCALATION_PERIOD.as_secs() / 60,
+        lines.len()
+    ));
+    for line in lines {
+        notice(line);
+    }
+}
+
+/// Forwards the messages up to `level` to `inner`, and handles the debug
+/// messages of the adaptive log level.
+struct AdaptiveLogger {
+    inner: Box<dyn Log>,
+    level: LevelFilter,
+    /// The levels of modules that apply unless others are configured.
+    default_levels: Vec<(String, LevelFilter)>,
+}
+
+impl AdaptiveLogger {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        module_level(&MODULE_LEVELS.read().unwrap(), target)
+            .or_else(|| module_level(&self.default_levels, target))
+            .unwrap_or(self.level)
+    }
+}
+
+impl Log for AdaptiveLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+            || (metadata.level() == Level::Debug
+                && ADAPTIVE_ENABLED.load(Ordering::Relaxed)
+                && is_adaptive(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        let level = self.level_for(record.target());
+        let shown = record.level() <= level;
+        if shown {
+            self.inner.log(record);
+        }
+        // errors are counted and debug messages are buffered, unless the
+        // debug messages are shown anyway
+        let adaptive = match record.level() {
+            Level::Error => level < LevelFilter::Debug,
+            Level::Debug => !shown,
+            _ => false,
+        };
+        if !adaptive || !ADAPTIVE_ENABLED.load(Ordering::Relaxed) || !is_adaptive(record.target()) {
+            return;
+        }
+
+        // the message is formatted before locking, so that the other threads
+        // don't wait for it
+        let line = (record.level() == Level::Debug).then(|| {
+            let line = format!("{} {}: {}", record.level(), record.target(), record.args());
+            if REDACTION_ENABLED.load(Ordering::Relaxed) {
+                redact(&line).into_owned()
+            } else {
+                line
+            }
+        });
+        let subsystem = subsystem(record.target());
+        let now = Instant::now();
+        let mut guard = ESCALATION.lock().unwrap();
+        let Some(escalation) = guard.as_mut() else {
+            return;
+        };
+        match record.level() {
+            Level::Debug if escalation.is_escalated(subsystem, now) => {
+                drop(guard);
+                self.inner.log(record)
+            }
+            Level::Debug => escalation.buffer(subsystem, line.unwrap_or_default()),
+            Level::Error => {
+                if let Some(lines) = escalation.error(subsystem, now) {
+                    let dump_dir = escalation.dump_dir.clone();
+                    drop(guard);
+                    dump_debug_buffer(self.inner.as_ref(), subsystem, lines, dump_dir);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// Registers a value (e.g. a username) that should never show up in the logs.
+pub fn register_secret(secret: &str) {
+    let secret = secret.trim();
+    // very short values would redact large parts of unrelated messages
+    if secret.len() < 3 {
+        return;
+    }
+    let mut secrets = SECRETS.write().unwrap();
+    if !secrets.iter().any(|s| s == secret) {
+        secrets.push(secret.to_string());
+    }
+}
+
+pub fn setup_logger(log_target: LogTarget, verbose: bool) -> eyre::Result<()> {
+    let log_level = if verbose {
+        LevelFilter::Trace
+    } else {
+        LevelFilter::Info
+    };
+
+    let logger = fern::Dispatch::new()
+        .format(|out, message, _record| {
+            if REDACTION_ENABLED.load(Ordering::Relaxed) {
+                let message = message.to_string();
+                out.finish(format_args!("{}", redact(&message)))
+            } else {
+                out.finish(*message)
+            }
+        })
+        // the adaptive logger decides which messages are shown
+        .level(LevelFilter::Trace);
+
+    let mut default_levels = Vec::new();
+    if cfg!(feature = "dbus_mpris") && !verbose {
+        default_levels.push(("rspotify_http".to_string(), LevelFilter::Warn));
+    }
+
+    let logger = match log_target {
+        LogTarget::Terminal => logger.chain(std::io::stdout()),
+        #[cfg(unix)]
+        LogTarget::Syslog => {
+            let log_format = syslog::Formatter3164 {
+                facility: syslog::Facility::LOG_DAEMON,
+                hostname: None,
+                process: "spotifyd".to_owned(),
+                pid: 0,
+            };
+            logger.chain(
+                syslog::unix(log_format)
+                    .map_err(|e| eyre!("Couldn't connect to syslog instance: {}", e))?,
+            )
+        }
+        #[cfg(target_os = "windows")]
+        LogTarget::Syslog => {
+            let dirs = directories::BaseDirs::new().unwrap();
+            let mut log_file = dirs.data_local_dir().to_path_buf();
+            log_file.push("spotifyd");
+            log_file.push(".spotifyd.log");
+
+            if let Some(p) = log_file.parent() {
+                fs::create_dir_all(p)?
+            };
+            logger.chain(
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(log_file)
+                    .expect("Couldn't initialize logger"),
+            )
+        }
+        #[cfg(target_os = "linux")]
+        LogTarget::Journald => {
+            logger.chain(Box::new(journald::JournaldLogger::connect()?) as Box<dyn log::Log>)
+        }
+    };
+
+    let (_, inner) = logger.into_log();
+    log::set_boxed_logger(Box::new(AdaptiveLogger {
+        inner,
+        level: log_level,
+        default_levels,
+    }))
+    .wrap_err("Couldn't initialize logger")?;
+    log::set_max_level(log_level);
+    Ok(())
+}
+
+/// A logger speaking the [native journal protocol], which allows attaching
+/// structured fields like `TRACK_ID` to the messages.
+///
+/// [native journal protocol]: https://systemd.io/JOURNAL_NATIVE_PROTOCOL/
+#[cfg(target_os = "linux")]
+mod journald {
+    use super::EVENT_FIELDS;
+    use color_eyre::eyre::{self, Context};
+    use log::{Level, Metadata, Record};
+    use std::{
+        fs::File,
+        io::{self, Write},
+        mem,
+        os::unix::{
+            io::{AsRawFd, FromRawFd, RawFd},
+            net::UnixDatagram,
+        },
+        ptr,
+    };
+
+    const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+    pub(super) struct JournaldLogger {
+        socket: UnixDatagram,
+    }
+
+    impl JournaldLogger {
+        pub(super) fn connect() -> eyre::Result<Self> {
+            let socket = UnixDatagram::unbound()?;
+            socket
+                .connect(JOURNALD_SOCKET)
+                .wrap_err("Couldn't connect to journald")?;
+            Ok(Self { socket })
+        }
+    }
+
+    impl log::Log for JournaldLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            let mut payload = Vec::new();
+            append_field(&mut payload, "PRIORITY", priority(record.level()));
+            append_field(&mut payload, "SYSLOG_IDENTIFIER", "spotifyd");
+            append_field(&mut payload, "MESSAGE", &record.args().to_string());
+            if let Some(module) = record.module_path() {
+                append_field(&mut payload, "CODE_MODULE", module);
+            }
+            EVENT_FIELDS.with(|fields| {
+                for (key, value) in fields.borrow().iter() {
+                    append_field(&mut payload, key, value);
+                }
+            });
+            // There is no sensible way to report a failure to log a message.
+            let _ = send(&self.socket, &payload);
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Sends the payload in a datagram, or in a sealed memfd if it's too large
+    /// for one, like the clients of systemd do.
+    pub(super) fn send(socket: &UnixDatagram, payload: &[u8]) -> io::Result<()> {
+        match socket.send(payload) {
+            Err(e) if e.raw_os_error() == Some(libc::EMSGSIZE) => send_memfd(socket, payload),
+            result => result.map(drop),
+        }
+    }
+
+    fn send_memfd(socket: &UnixDatagram, payload: &[u8]) -> io::Result<()> {
+        let fd = unsafe {
+            libc::memfd_create(
+                b"spotifyd-journal\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // closes the memfd once it has been sent
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(payload)?;
+        // journald only accepts sealed memfds
+        let seals =
+            libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+        if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // an empty datagram passing the memfd
+        let fd_len = mem::size_of::<RawFd>() as u32;
+        let space = unsafe { libc::CMSG_SPACE(fd_len) } as usize;
+        // u64s, for the alignment of the control message header
+        let mut control = vec![0u64; (space + 7) / 8];
+        let mut message: libc::msghdr = unsafe { mem::zeroed() };
+        message.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        message.msg_controllen = space as _;
+        let sent = unsafe {
+            let header = libc::CMSG_FIRSTHDR(&message);
+            (*header).cmsg_level = libc::SOL_SOCKET;
+            (*header).cmsg_type = libc::SCM_RIGHTS;
+            (*header).cmsg_len = libc::CMSG_LEN(fd_len) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(header) as *mut RawFd, fd);
+            libc::sendmsg(socket.as_raw_fd(), &message, libc::MSG_NOSIGNAL)
+        };
+        if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn priority(level: Level) -> &'static str {
+        match level {
+            Level::Error => "3",
+            Level::Warn => "4",
+            Level::Info => "6",
+            Level::Debug | Level::Trace => "7",
+        }
+    }
+
+    fn append_field(payload: &mut Vec<u8>, key: &str, value: &str) {
+        payload.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            // multi-line values are sent with an explicit length
+            payload.push(b'\n');
+            payload.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            payload.push(b'=');
+        }
+        payload.extend_from_slice(value.as_bytes());
+        payload.push(b'\n');
+    }
+}
+
+/// Replaces registered secrets, access tokens, sensitive query parameters and
+/// IP addresses in `message`.
+fn redact(message: &str) -> Cow<'_, str> {
+    let mut message = Cow::Borrowed(message);
+
+    for secret in SECRETS.read().unwrap().iter() {
+        if message.contains(secret.as_str()) {
+            message = Cow::Owned(message.replace(secret.as_str(), PLACEHOLDER));
+        }
+    }
+
+    let mut redacted = String::with_capacity(message.len());
+    let mut changed = false;
+    let mut rest = message.as_ref();
+    while !rest.is_empty() {
+        let word_len = rest.find(is_delimiter).unwrap_or(rest.len());
+        let (word, tail) = rest.split_at(word_len);
+        match redact_word(word) {
+            Some(replacement) => {
+                redacted.push_str(&replacement);
+                changed = true;
+            }
+            None => redacted.push_str(word),
+        }
+        let delimiter_len = tail.find(|c| !is_delimiter(c)).unwrap_or(tail.len());
+        let (delimiters, tail) = tail.split_at(delimiter_len);
+        redacted.push_str(delimiters);
+        rest = tail;
+    }
+
+    if changed {
+        Cow::Owned(redacted)
+    } else {
+        message
+    }
+}
+
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "\"'`,;()[]{}<>".contains(c)
+}
+
+fn redact_word(word: &str) -> Option<String> {
+    if word.is_empty() {
+        return None;
+    }
+    if is_ip_address(word) {
+        return Some(PLACEHOLDER.to_string());
+    }
+    if is_token(word) {
+        return Some(PLACEHOLDER.to_string());
+    }
+    if !word.contains('=') {
+        return None;
+    }
+
+    // query strings (`?access_token=...&foo=bar`) and plain `password=...` pairs
+    let mut changed = false;
+    let redacted: String = word
+        .split_inclusive(|c| c == '?' || c == '&')
+        .map(|part| {
+            let (pair, separator) = match part.char_indices().last() {
+                Some((i, c)) if c == '?' || c == '&' => (&part[..i], &part[i..]),
+                _ => (part, ""),
+            };
+            match pair.split_once('=') {
+                Some((key, value)) if !value.is_empty() && is_sensitive_key(key) => {
+                    changed = true;
+                    format!("{}={}{}", key, PLACEHOLDER, separator)
+                }
+                _ => part.to_string(),
+            }
+        })
+        .collect();
+
+    changed.then_some(redacted)
+}
+
+fn is_sensitive_key(key: &str) -> bool {
+    // only look at the last path segment, e.g. of `/callback?code=...`
+    let key = key.rsplit('/').next().unwrap_or(key).to_ascii_lowercase();
+    SENSITIVE_KEYS.contains(&key.as_str())
+}
+
+fn is_token(word: &str) -> bool {
+    word.len() >= MIN_TOKEN_LEN
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_+/=.".contains(c))
+        && word.chars().any(|c| c.is_ascii_digit())
+        && word.chars().any(|c| c.is_ascii_alphabetic())
+}
+
+fn is_ip_address(word: &str) -> bool {
+    let word = word.trim_end_matches(|c| c == '.' || c == ':');
+    is_ipv4(word) || is_ipv4(strip_port(word)) || is_ipv6(word)
+}
+
+fn strip_port(word: &str) -> &str {
+    match word.rsplit_once(':') {
+        Some((host, port)) if !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => word,
+    }
+}
+
+fn is_ipv4(word: &str) -> bool {
+    let octets: Vec<_> = word.split('.').collect();
+    octets.len() == 4
+        && octets
+            .iter()
+            .all(|o| !o.is_empty() && o.len() <= 3 && o.parse::<u8>().is_ok())
+}
+
+fn is_ipv6(word: &str) -> bool {
+    // `[::1]:57621` style addresses contain a port after the brackets
+    let word = match word.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or(rest),
+        None => word,
+    };
+    let colons = word.matches(':').count();
+    // timestamps like 12:30:45 contain neither hex letters nor `::`
+    (2..=7).contains(&colons)
+        && word.chars().all(|c| c.is_ascii_hexdigit() || c == ':')
+        && (word.contains("::") || word.chars().any(|c| c.is_ascii_alphabetic()))
+        && word.split(':').all(|group| group.len() <= 4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escalation_after_repeated_errors() {
+        assert_eq!(
+            subsystem("librespot_playback::audio_backend::alsa"),
+            "librespot_playback::audio_backend"
+        );
+        assert_eq!(subsystem("spotifyd::main_loop"), "spotifyd::main_loop");
+
+        let backend = "librespot_playback::audio_backend";
+        let start = Instant::now();
+        let mut escalation = Escalation::new(None);
+        for i in 0..DEBUG_BUFFER_LEN + 1 {
+            escalation.buffer(backend, format!("message {}", i));
+        }
+        assert_eq!(escalation.error(backend, start), None);
+        // errors outside the window don't count
+        assert_eq!(escalation.error(backend, start + 2 * ERROR_WINDOW), None);
+        assert_eq!(
+            escalation.error(backend, start + 2 * ERROR_WINDOW + Duration::from_secs(1)),
+            None
+        );
+        assert!(!escalation.is_escalated(backend, start + 2 * ERROR_WINDOW));
+
+        let now = start + 2 * ERROR_WINDOW + Duration::from_secs(2);
+        let lines = escalation.error(backend, now).unwrap();
+        assert_eq!(lines.len(), DEBUG_BUFFER_LEN);
+        assert_eq!(lines[0], "message 1");
+        assert!(escalation.is_escalated(backend, now));
+        assert!(!escalation.is_escalated("spotifyd::main_loop", now));
+        assert!(!escalation.is_escalated(backend, now + ESCALATION_PERIOD));
+    }
+
+    #[test]
+    fn test_adaptive_targets() {
+        assert!(is_adaptive("spotifyd::main_loop"));
+        assert!(is_adaptive("librespot_playback::audio_backend::alsa"));
+        assert!(is_adaptive("spotifyd"));
+        assert!(!is_adaptive("rustls::client::hs"));
+        assert!(!is_adaptive("hyper::proto::h1::conn"));
+        assert!(!is_adaptive("spotifyd_extra"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_journald_sends_large_messages_in_memfd() {
+        use std::os::unix::net::UnixDatagram;
+
+        let (client, server) = UnixDatagram::pair().unwrap();
+        journald::send(&client, b"MESSAGE=short\n").unwrap();
+        let mut buffer = [0; 64];
+        assert_eq!(server.recv(&mut buffer).unwrap(), 14);
+
+        // too large for a datagram, so it's passed in a memfd with an empty one
+        let mut payload = b"MESSAGE=".to_vec();
+        payload.resize(16 * 1024 * 1024, b'x');
+        payload.push(b'\n');
+        journald::send(&client, &payload).unwrap();
+        assert_eq!(server.recv(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_module_levels() {
+        let levels = vec![
+            ("librespot_playback".to_string(), LevelFilter::Warn),
+            (
+                "librespot_playback::audio_backend".to_string(),
+                LevelFilter::Debug,
+            ),
+        ];
+        assert_eq!(
+            module_level(&levels, "librespot_playback::player"),
+            Some(LevelFilter::Warn)
+        );
+        assert_eq!(
+            module_level(&levels, "librespot_playback::audio_backend::alsa"),
+            Some(LevelFilter::Debug)
+        );
+        assert_eq!(module_level(&levels, "librespot_playback_extra"), None);
+        assert_eq!(module_level(&levels, "spotifyd::main_loop"), None);
+    }
+
+    #[test]
+    fn test_redact_ip_addresses() {
+        assert_eq!(
+            redact("connecting to 192.168.1.10:4070 and [fe80::1]:80"),
+            "connecting to <redacted> and [<redacted>]:80"
+        );
+        assert_eq!(redact("now playing at 12:30:45"), "now playing at 12:30:45");
+        assert_eq!(redact("version 0.3.5"), "version 0.3.5");
+    }
+
+    #[test]
+    fn test_redact_tokens() {
+        let token = "BQDk3h4Xy7e9ZWpqJ2Ym1u4Y0eC7vI8nB2lKxW5rQz3f6dA9sL1oT4hN8gU2iP7c";
+        assert_eq!(
+            redact(&format!("Authorization: Bearer {}", token)),
+            "Authorization: Bearer <redacted>"
+        );
+        assert_eq!(
+            redact("GET https://example.org/cb?code=abc123&state=xyz"),
+            "GET https://example.org/cb?code=<redacted>&state=xyz"
+        );
+        assert_eq!(
+            redact("track spotify:track:4uLU6hMCjMI75M1A2tKUQC"),
+            "track spotify:track:4uLU6hMCjMI75M1A2tKUQC"
+        );
+    }
+
+    #[test]
+    fn test_redact_registered_secrets() {
+        register_secret("secret_user");
+        assert_eq!(
+            redact("Authenticated as \"secret_user\" !"),
+            "Authenticated as \"<redacted>\" !"
+        );
+    }
+}
@@ -1,6 +1,3 @@
-use crate::config::CliConfig;
-#[cfg(unix)]
-use color_eyre::eyre::eyre;
 use color_eyre::{
     eyre::{self, Context},
     Help, SectionExt,
@@ -8,84 +5,38 @@ use color_eyre::{
 #[cfg(unix)]
 use daemonize::Daemonize;
 #[cfg(unix)]
-use log::error;
-use log::{info, trace, LevelFilter};
+use log::{error, warn};
+use log::{info, trace};
 #[cfg(target_os = "openbsd")]
 use pledge::pledge;
-#[cfg(windows)]
-use std::fs;
+#[cfg(unix)]
+use spotifyd::control_socket;
+#[cfg(unix)]
+use spotifyd::metered::Metered;
+#[cfg(feature = "oauth")]
+use spotifyd::oauth;
+#[cfg(feature = "dbus_keyring")]
+use spotifyd::secrets;
+use spotifyd::{
+    cache,
+    config::{self, CliConfig, Command, ConfigAction, StatsAction},
+    config_check, data_usage,
+    logging::{self, setup_logger, LogTarget},
+    man, record, setup, simulate, version,
+};
+#[cfg(feature = "web_api")]
+use spotifyd::{ctl, search};
+#[cfg(unix)]
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 use structopt::StructOpt;
 use tokio::runtime::Runtime;
 
-#[cfg(feature = "alsa_backend")]
-mod alsa_mixer;
-mod config;
-#[cfg(feature = "dbus_mpris")]
-mod dbus_mpris;
-mod error;
-mod main_loop;
-mod no_mixer;
-mod process;
-mod setup;
-mod utils;
-
-enum LogTarget {
-    Terminal,
-    Syslog,
-}
-
-fn setup_logger(log_target: LogTarget, verbose: bool) -> eyre::Result<()> {
-    let log_level = if verbose {
-        LevelFilter::Trace
-    } else {
-        LevelFilter::Info
-    };
-
-    let mut logger = fern::Dispatch::new().level(log_level);
-
-    if cfg!(feature = "dbus_mpris") && !verbose {
-        logger = logger.level_for("rspotify_http", LevelFilter::Warn);
-    }
-
-    let logger = match log_target {
-        LogTarget::Terminal => logger.chain(std::io::stdout()),
-        #[cfg(unix)]
-        LogTarget::Syslog => {
-            let log_format = syslog::Formatter3164 {
-                facility: syslog::Facility::LOG_DAEMON,
-                hostname: None,
-                process: "spotifyd".to_owned(),
-                pid: 0,
-            };
-            logger.chain(
-                syslog::unix(log_format)
-                    .map_err(|e| eyre!("Couldn't connect to syslog instance: {}", e))?,
-            )
-        }
-        #[cfg(target_os = "windows")]
-        LogTarget::Syslog => {
-            let dirs = directories::BaseDirs::new().unwrap();
-            let mut log_file = dirs.data_local_dir().to_path_buf();
-            log_file.push("spotifyd");
-            log_file.push(".spotifyd.log");
-
-            if let Some(p) = log_file.parent() {
-                fs::create_dir_all(p)?
-            };
-            logger.chain(
-                fs::OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .truncate(true)
-                    .open(log_file)
-                    .expect("Couldn't initialize logger"),
-            )
-        }
-    };
-
-    logger.apply().wrap_err("Couldn't initialize logger")
-}
-
 fn main() -> eyre::Result<()> {
     // Start with superset of all potentially required promises.
     // Drop later after CLI arguments and configuration files were parsed.
@@ -100,9 +51,27 @@ fn main() -> eyre::Result<()> {
 
     let mut cli_config: CliConfig = CliConfig::from_args();
 
-    let is_daemon = !cli_config.no_daemon;
+    if cli_config.version {
+        version::print(cli_config.json);
+        return Ok(());
+    }
+    match cli_config.command {
+        Some(Command::Completions(ref args)) => {
+            man::print_completions(args.shell);
+            return Ok(());
+        }
+        Some(Command::Man) => {
+            print!("{}", man::man_page());
+            return Ok(());
+        }
+        _ => (),
+    }
+
+    let is_daemon = !cli_config.no_daemon && !cli_config.simulate && cli_config.command.is_none();
 
-    let log_target = if is_daemon {
+    let log_target = if let Some(log_target) = cli_config.log_target {
+        log_target
+    } else if is_daemon {
         #[cfg(unix)]
         {
             LogTarget::Syslog
@@ -128,6 +97,11 @@ fn main() -> eyre::Result<()> {
 
     setup_logger(log_target, cli_config.verbose)?;
 
+    // the config file is parsed by the check itself, to report its errors
+    if let Some(Command::Config(ConfigAction::Check)) = cli_config.command {
+        return config_check::run(cli_config);
+    }
+
     cli_config
         .load_config_file_values()
         .wrap_err("could not load the config file")
@@ -140,9 +114,97 @@ fn main() -> eyre::Result<()> {
         })?;
     trace!("{:?}", &cli_config);
 
+    let cli_config_simulate = cli_config.simulate;
+    let cli_config_verbose = cli_config.verbose;
+    let command = cli_config.command.take();
+
     // Returns the old SpotifydConfig struct used within the rest of the daemon.
     let internal_config = config::get_internal_config(cli_config);
 
+    logging::set_redaction(internal_config.log_redaction);
+    logging::set_module_levels(internal_config.log_levels.clone());
+    if internal_config.adaptive_logging && !cli_config_verbose {
+        logging::set_adaptive_logging(internal_config.debug_dumps.clone());
+    }
+    for secret in [&internal_config.username, &internal_config.password]
+        .into_iter()
+        .flatten()
+    {
+        logging::register_secret(secret);
+    }
+
+    match command {
+        Some(Command::SimulateEvent(args)) => {
+            let runtime = Runtime::new().unwrap();
+            return runtime.block_on(simulate::simulate_event(&internal_config, &args));
+        }
+        Some(Command::Replay(args)) => {
+            let runtime = Runtime::new().unwrap();
+            return runtime.block_on(record::replay(&internal_config, &args));
+        }
+        #[cfg(feature = "web_api")]
+        Some(Command::Ctl(args)) => {
+            let runtime = Runtime::new().unwrap();
+            return runtime.block_on(ctl::run(&internal_config, &args));
+        }
+        #[cfg(not(feature = "web_api"))]
+        Some(Command::Ctl(_)) => {
+            eyre::bail!("spotifyd ctl requires the web_api feature");
+        }
+        #[cfg(feature = "web_api")]
+        Some(Command::Search(args)) => {
+            let runtime = Runtime::new().unwrap();
+            return runtime.block_on(search::run(&internal_config, &args));
+        }
+        #[cfg(not(feature = "web_api"))]
+        Some(Command::Search(_)) => {
+            eyre::bail!("spotifyd search requires the web_api feature");
+        }
+        #[cfg(unix)]
+        Some(Command::Local(action)) => {
+            let runtime = Runtime::new().unwrap();
+            return runtime.block_on(control_socket::run(&internal_config, &action));
+        }
+        #[cfg(not(unix))]
+        Some(Command::Local(_)) => {
+            eyre::bail!("spotifyd local requires a unix system");
+        }
+        Some(Command::Cache(action)) => {
+            let runtime = Runtime::new().unwrap();
+            return runtime.block_on(cache::run(&internal_config, &action));
+        }
+        #[cfg(feature = "oauth")]
+        Some(Command::Authenticate) => {
+            let runtime = Runtime::new().unwrap();
+            return runtime.block_on(oauth::authenticate(&internal_config));
+        }
+        #[cfg(not(feature = "oauth"))]
+        Some(Command::Authenticate) => {
+            eyre::bail!("spotifyd authenticate requires the oauth feature");
+        }
+        #[cfg(feature = "dbus_keyring")]
+        Some(Command::Keyring(action)) => {
+            return secrets::run(&internal_config, &action);
+        }
+        #[cfg(not(feature = "dbus_keyring"))]
+        Some(Command::Keyring(_)) => {
+            eyre::bail!("spotifyd keyring requires the dbus_keyring feature");
+        }
+        Some(Command::Stats(StatsAction::Data)) => {
+            return data_usage::print_stats(&internal_config);
+        }
+        Some(Command::Config(_) | Command::Completions(_) | Command::Man) => {
+            unreachable!("handled before the config is loaded")
+        }
+        None => (),
+    }
+
+    if cli_config_simulate {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(simulate::run(internal_config));
+        return Ok(());
+    }
+
     if is_daemon {
         info!("Daemonizing running instance");
 
@@ -195,8 +257,8 @@ fn main() -> eyre::Result<()> {
         // PortAudio, sio_open(3)  ("[rwc]path unix inet audio")
         // > after sndio(7) cookie  "audio"
 
-        // --on-song-change-hook aka. "onevent", run via --shell aka. "shell"
-        if internal_config.onevent.is_some() {
+        // --on-song-change-hook aka. "onevent" and the event_hooks, run via --shell aka. "shell"
+        if !internal_config.hooks.is_empty() {
             pledge(
                 "stdio rpath wpath cpath inet mcast unix dns proc exec audio",
                 None,
@@ -207,11 +269,47 @@ fn main() -> eyre::Result<()> {
         }
     }
 
+    #[cfg(unix)]
+    let config_file = internal_config.config_file.clone();
     let runtime = Runtime::new().unwrap();
     runtime.block_on(async {
         let mut initial_state = setup::initial_state(internal_config);
+        #[cfg(unix)]
+        if let Some(path) = config_file {
+            tokio::spawn(reload_on_hangup(
+                path,
+                initial_state.preload_tracks(),
+                initial_state.metered(),
+            ));
+        }
         initial_state.run().await;
     });
 
     Ok(())
 }
+
+/// Applies the settings of the config file that can be changed at runtime
+/// again whenever SIGHUP is received.
+#[cfg(unix)]
+async fn reload_on_hangup(path: PathBuf, preload_tracks: Arc<AtomicUsize>, metered: Arc<Metered>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match config::reload(&path) {
+            Ok(reloaded) => {
+                info!("Reloaded the config from {}", path.display());
+                logging::set_module_levels(reloaded.log_levels);
+                preload_tracks.store(reloaded.preload_tracks, Ordering::Relaxed);
+                metered.set_mode(reloaded.metered);
+            }
+            Err(e) => warn!("Failed to reload the config from {}: {}", path.display(), e),
+        }
+    }
+}
@@ -1,13 +1,57 @@
-use crate::config::DBusType;
-#[cfg(feature = "dbus_mpris")]
-use crate::dbus_mpris::DbusServer;
-use crate::process::spawn_program_on_event;
-use futures::{
-    self,
-    future::{self, Fuse, FusedFuture},
-    stream::Peekable,
-    Future, FutureExt, StreamExt,
+use crate::audit::{apply_audited, CommandClient, CommandSource, ConnectCommands, SharedAuditLog};
+use crate::bind_proxy::{self, OutgoingBind};
+use crate::blocklist::Blocklist;
+use crate::cache::{record_lookup, CacheHits};
+use crate::cache_layout::CacheLayout;
+use crate::config::{
+    ContextEnd, DBusType, EventHooks, GuestWifi, HookOptions, HttpToken, IdleTimeouts,
+    LastfmConfig, ListenbrainzConfig, MprisQuit, PartyMode, RadioSeed, ScheduledPlaylist, ShowRule,
+    Takeover, VolumeStep,
+};
+#[cfg(feature = "web_api")]
+use crate::context_end::start_radio;
+use crate::context_end::ContextEndDetector;
+use crate::control::{
+    display_volume, ControlCommand, ControlHandle, ControlReceiver, PlaybackControl,
 };
+use crate::crossfade::{CrossfadeSink, PlayerEventSlot};
+use crate::data_usage::{DataUsage, UsageSink};
+#[cfg(feature = "dbus_mpris")]
+use crate::dbus_mpris::{DbusServer, MprisActions};
+use crate::discoverable::Discoverable;
+use crate::dsp::{DspChain, DspSink, DspSwitches};
+use crate::encryption::EncryptedCredentials;
+use crate::event_log::write_event_log;
+use crate::events::{EventBus, EventSubscriber, SpotifydEvent};
+use crate::fade::FadeSink;
+use crate::history::PlayHistory;
+use crate::lock::DoNotDisturb;
+use crate::logging;
+use crate::metered::Metered;
+#[cfg(feature = "web_api")]
+use crate::mirror::run_mirror;
+use crate::party;
+#[cfg(feature = "web_api")]
+use crate::preload::preload_upcoming;
+use crate::process::run_hooks;
+use crate::profiles::Profiles;
+#[cfg(feature = "web_api")]
+use crate::reconnect::{resume_interrupted, Interruption};
+use crate::record::record_events;
+use crate::resume::resume_positions;
+#[cfg(feature = "web_api")]
+use crate::schedule::run_schedule;
+use crate::sd_notify::{Notifier, Watchdog};
+use crate::show_rules::apply_show_rules;
+use crate::simulate::MockPlayer;
+use crate::sleep_timer::{run_sleep_timer, SleepSink, SleepTimer};
+use crate::startup::StartupTimer;
+use crate::state::SharedPlaybackState;
+use crate::telemetry::{self, PlaybackSpans};
+use crate::warm_sink::WarmSink;
+#[cfg(feature = "web_api")]
+use crate::web_api::{self, RemoteControl};
+use futures::{self, future, stream::Peekable, Future, StreamExt};
 use librespot_connect::{config::ConnectConfig, spirc::Spirc};
 use librespot_core::{
     authentication::Credentials,
@@ -19,100 +63,1025 @@ use librespot_core::{
 use librespot_discovery::Discovery;
 use librespot_playback::{
     audio_backend::Sink,
-    config::{AudioFormat, PlayerConfig},
+    config::{AudioFormat, Bitrate, PlayerConfig},
     mixer::Mixer,
-    player::Player,
+    player::{Player, PlayerEvent},
 };
-use log::error;
+use log::{debug, error, info, warn};
+use std::fs::File;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{
+    mpsc::{UnboundedReceiver, UnboundedSender},
+    watch, Notify,
+};
+use url::Url;
+
+/// How long after another device took over the playback the Web API is asked
+/// for its name.
+#[cfg(feature = "web_api")]
+const TAKEOVER_LOOKUP_DELAY: Duration = Duration::from_secs(2);
+
+/// Opens a sink of the audio backend on the device.
+pub type SinkBuilder = Arc<dyn Fn(Option<String>, AudioFormat) -> Box<dyn Sink> + Send + Sync>;
 
 pub struct AudioSetup {
     pub mixer: Box<dyn FnMut() -> Arc<dyn Mixer>>,
-    pub backend: fn(Option<String>, AudioFormat) -> Box<dyn Sink>,
+    pub backend: SinkBuilder,
     pub audio_device: Option<String>,
     pub audio_format: AudioFormat,
+    /// Whether the audio device is opened ahead of the playback.
+    pub warmup: bool,
+    /// How long consecutive tracks overlap, if they are crossfaded.
+    pub crossfade: Option<Duration>,
+    /// How long the audio fades in on start and out on pause or stop.
+    pub fade_in: Duration,
+    pub fade_out: Duration,
+    pub(crate) dsp: DspChain,
 }
 
 pub struct SpotifydState {
     pub cache: Option<Cache>,
     pub device_name: String,
-    pub player_event_program: Option<String>,
+    pub event_hooks: EventHooks,
 }
 
-pub(crate) enum CredentialsProvider {
-    Discovery(Peekable<Discovery>),
-    SpotifyCredentials(Credentials),
+/// Launches the discovery, whenever the device is shown again.
+pub(crate) type LaunchDiscovery = Box<dyn Fn() -> Result<Discovery, Error> + Send>;
+
+/// The discovery, which only runs while the device is discoverable.
+pub(crate) struct DiscoveryProvider {
+    stream: Option<Peekable<Discovery>>,
+    launch: LaunchDiscovery,
+    visible: watch::Receiver<bool>,
+    /// The cached credentials of the user who connected last, which are used
+    /// once instead of waiting for a client.
+    last_user: Option<Credentials>,
+    reattaching: bool,
 }
 
-impl From<Discovery> for CredentialsProvider {
-    fn from(stream: Discovery) -> Self {
-        CredentialsProvider::Discovery(stream.peekable())
+impl DiscoveryProvider {
+    pub(crate) fn new(
+        stream: Discovery,
+        launch: LaunchDiscovery,
+        visible: watch::Receiver<bool>,
+        last_user: Option<Credentials>,
+    ) -> Self {
+        Self {
+            stream: Some(stream.peekable()),
+            launch,
+            visible,
+            last_user,
+            reattaching: false,
+        }
+    }
+
+    /// Starts or stops the discovery, if the device has been shown or hidden.
+    fn follow_visibility(&mut self) {
+        let visible = *self.visible.borrow_and_update();
+        match (visible, self.stream.is_some()) {
+            (true, false) => match (self.launch)() {
+                Ok(stream) => {
+                    info!("Started the discovery");
+                    self.stream = Some(stream.peekable());
+                }
+                Err(err) => error!("failed to enable discovery: {err}"),
+            },
+            (false, true) => {
+                // the session connected via the discovery is kept
+                info!("Stopped the discovery");
+                self.stream = None;
+            }
+            _ => (),
+        }
     }
+
+    /// Waits until a client sends its credentials, while discoverable.
+    async fn ready(&mut self) {
+        loop {
+            self.follow_visibility();
+            let (stream, visible) = (&mut self.stream, &mut self.visible);
+            let changed = async move {
+                if visible.changed().await.is_err() {
+                    future::pending::<()>().await
+                }
+            };
+            match stream {
+                Some(stream) => tokio::select! {
+                    peeked = Pin::new(stream).peek() => {
+                        if peeked.is_some() {
+                            return;
+                        }
+                        future::pending::<()>().await
+                    }
+                    _ = changed => (),
+                },
+                None => changed.await,
+            }
+        }
+    }
+}
+
+pub(crate) enum CredentialsProvider {
+    Discovery(DiscoveryProvider),
+    SpotifyCredentials(Credentials),
+    /// The events come from a [`MockPlayer`], nothing connects to Spotify.
+    Simulated,
 }
 
 impl CredentialsProvider {
     async fn get_credentials(&mut self) -> Credentials {
         match self {
-            CredentialsProvider::Discovery(stream) => stream.next().await.unwrap(),
+            CredentialsProvider::Discovery(discovery) => {
+                discovery.reattaching = discovery.last_user.is_some();
+                if let Some(credentials) = discovery.last_user.take() {
+                    return credentials;
+                }
+                discovery.ready().await;
+                let stream = discovery.stream.as_mut().unwrap();
+                stream.next().await.unwrap()
+            }
             CredentialsProvider::SpotifyCredentials(creds) => creds.clone(),
+            CredentialsProvider::Simulated => future::pending().await,
         }
     }
 
+    /// Whether the credentials are the cached ones of the user who connected
+    /// last, rather than sent by a client.
+    fn reattaching(&self) -> bool {
+        matches!(self, CredentialsProvider::Discovery(discovery) if discovery.reattaching)
+    }
+
     // wait for an incoming connection if the underlying provider is a discovery stream
     async fn incoming_connection(&mut self) {
         match self {
-            CredentialsProvider::Discovery(stream) => {
-                let peeked = Pin::new(stream).peek().await;
-                if peeked.is_none() {
-                    future::pending().await
+            CredentialsProvider::Discovery(discovery) => discovery.ready().await,
+            _ => future::pending().await,
+        }
+    }
+
+    /// Drops the incoming connection if the lock doesn't admit its account.
+    /// Returns `Some` with the account, if known, when it was refused.
+    async fn refuse_incoming(&mut self, do_not_disturb: &DoNotDisturb) -> Option<Option<String>> {
+        let CredentialsProvider::Discovery(discovery) = self else {
+            return None;
+        };
+        let stream = discovery.stream.as_mut()?;
+        let user_name = Pin::new(&mut *stream)
+            .peek()
+            .await
+            .and_then(|credentials| credentials.username.clone());
+        if do_not_disturb.admits(user_name.as_deref()) {
+            return None;
+        }
+        stream.next().await;
+        Some(user_name)
+    }
+}
+
+/// Resolves once the program should shut down, on Ctrl-C or, on unix, on the
+/// SIGTERM sent by service managers like systemd or procd.
+pub(crate) async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => (),
+                    _ = terminate.recv() => (),
                 }
+                return;
             }
-            _ => future::pending().await,
+            Err(err) => warn!("failed to listen for SIGTERM: {}", err),
         }
     }
+    let _ = tokio::signal::ctrl_c().await;
 }
 
-pub(crate) struct MainLoop {
+/// The daemon itself: waits for credentials, connects to Spotify and plays
+/// back whatever is requested through Spotify Connect.
+pub struct MainLoop {
     pub(crate) audio_setup: AudioSetup,
     pub(crate) spotifyd_state: SpotifydState,
     pub(crate) player_config: PlayerConfig,
     pub(crate) session_config: SessionConfig,
+    pub(crate) cache_layout: Option<CacheLayout>,
     pub(crate) has_volume_ctrl: bool,
     pub(crate) initial_volume: Option<u16>,
+    pub(crate) volume_step: Option<VolumeStep>,
     pub(crate) shell: String,
+    pub(crate) hook_options: HookOptions,
     pub(crate) device_type: DeviceType,
     #[cfg_attr(not(feature = "dbus_mpris"), allow(unused))]
     pub(crate) use_mpris: bool,
     #[cfg_attr(not(feature = "dbus_mpris"), allow(unused))]
     pub(crate) dbus_type: DBusType,
+    #[cfg_attr(not(feature = "dbus_mpris"), allow(unused))]
+    pub(crate) mpris_raise_cmd: Option<String>,
+    #[cfg_attr(not(feature = "dbus_mpris"), allow(unused))]
+    pub(crate) mpris_quit: MprisQuit,
+    pub(crate) takeover: Takeover,
+    /// Shuts the daemon down when notified, as an MPRIS client may ask it to.
+    pub(crate) shutdown_request: Arc<Notify>,
+    /// Keeps the playback with its current owner while taken.
+    pub(crate) do_not_disturb: Arc<DoNotDisturb>,
+    /// Whether the device is shown via the discovery.
+    pub(crate) discoverable: Arc<Discoverable>,
+    /// The accounts that can be switched to, and the one asked for.
+    pub(crate) profiles: Arc<Profiles>,
+    pub(crate) profile_switch: watch::Receiver<Option<String>>,
+    /// Pauses the playback when it runs out.
+    pub(crate) sleep_timer: Arc<SleepTimer>,
+    /// The users whose credentials were forgotten while running, whose
+    /// session ends.
+    pub(crate) forget_user_tx: UnboundedSender<String>,
+    pub(crate) forget_user_rx: UnboundedReceiver<String>,
     pub(crate) credentials_provider: CredentialsProvider,
+    pub(crate) event_bus: EventBus,
+    pub(crate) playback_state: SharedPlaybackState,
+    #[cfg_attr(not(feature = "otlp"), allow(unused))]
+    pub(crate) otlp_endpoint: Option<String>,
+    #[cfg_attr(not(feature = "mqtt"), allow(unused))]
+    pub(crate) mqtt: Option<(Url, String)>,
+    #[cfg_attr(not(feature = "webhook"), allow(unused))]
+    pub(crate) webhook_url: Option<Url>,
+    #[cfg_attr(not(feature = "http_api"), allow(unused))]
+    pub(crate) http_api: Option<(SocketAddr, Vec<HttpToken>)>,
+    #[cfg_attr(not(feature = "web_ui"), allow(unused))]
+    pub(crate) guest_wifi: Option<GuestWifi>,
+    #[cfg_attr(not(feature = "camilladsp"), allow(unused))]
+    pub(crate) camilladsp_address: Option<String>,
+    /// The volume of the CamillaDSP mixers.
+    #[cfg_attr(not(feature = "camilladsp"), allow(unused))]
+    pub(crate) camilladsp_volume: Option<watch::Receiver<u16>>,
+    #[cfg_attr(not(feature = "lastfm"), allow(unused))]
+    pub(crate) lastfm: Option<LastfmConfig>,
+    #[cfg_attr(not(feature = "listenbrainz"), allow(unused))]
+    pub(crate) listenbrainz: Option<ListenbrainzConfig>,
+    #[cfg_attr(not(any(feature = "lastfm", feature = "listenbrainz")), allow(unused))]
+    pub(crate) scrobble_dir: Option<PathBuf>,
+    #[cfg_attr(not(unix), allow(unused))]
+    pub(crate) control_socket: Option<PathBuf>,
+    pub(crate) audit_log: SharedAuditLog,
+    pub(crate) startup_timer: StartupTimer,
+    /// Tells systemd when spotifyd is ready, if it started it.
+    pub(crate) notifier: Notifier,
+    pub(crate) control_tx: UnboundedSender<(ControlCommand, CommandSource, CommandClient)>,
+    pub(crate) control_rx: ControlReceiver,
+    pub(crate) record_events: Option<PathBuf>,
+    pub(crate) event_log: Option<PathBuf>,
+    pub(crate) encrypted_credentials: Option<EncryptedCredentials>,
+    /// Stores the credentials in the keyring once connected.
+    pub(crate) use_keyring: bool,
+    /// Remembers the user who connected via the discovery, to reattach them
+    /// on the next start.
+    pub(crate) reattach_last_user: bool,
+    /// The port of the discovery, if known, to look up the clients by.
+    #[cfg_attr(not(target_os = "linux"), allow(unused))]
+    pub(crate) discovery_port: Option<u16>,
+    pub(crate) blocklist: Option<Blocklist>,
+    pub(crate) unavailable_skip_delay: Duration,
+    pub(crate) play_history: Option<PlayHistory>,
+    pub(crate) resume_min_duration: Option<Duration>,
+    pub(crate) idle_timeouts: IdleTimeouts,
+    pub(crate) resume_positions: Option<PathBuf>,
+    pub(crate) show_rules: Vec<ShowRule>,
+    #[cfg_attr(not(feature = "web_api"), allow(unused))]
+    pub(crate) playlist_schedule: Vec<ScheduledPlaylist>,
+    pub(crate) context_end: Option<ContextEnd>,
+    #[cfg_attr(not(feature = "web_api"), allow(unused))]
+    pub(crate) radio_seed: RadioSeed,
+    #[cfg_attr(not(feature = "web_api"), allow(unused))]
+    pub(crate) resume_after_reconnect: bool,
+    pub(crate) party_mode: Option<PartyMode>,
+    #[cfg_attr(not(feature = "web_api"), allow(unused))]
+    pub(crate) mirror_mode: bool,
+    #[cfg_attr(not(feature = "web_api"), allow(unused))]
+    pub(crate) mirror_interval: Duration,
+    #[cfg_attr(not(feature = "web_api"), allow(unused))]
+    pub(crate) preload_tracks: Arc<AtomicUsize>,
+    pub(crate) metered: Arc<Metered>,
+    pub(crate) data_usage: Arc<DataUsage>,
+    pub(crate) cache_hits: Arc<CacheHits>,
+    pub(crate) dsp_switches: DspSwitches,
+    #[cfg_attr(not(target_os = "linux"), allow(unused))]
+    pub(crate) reduce_dsp_when_throttled: bool,
+    pub(crate) outgoing_bind: Option<OutgoingBind>,
 }
 
 impl MainLoop {
-    async fn get_session(&mut self, credentials: Credentials) -> Result<Session, Error> {
+    /// Subscribes to the events, starting with the recent events of the
+    /// current session.
+    pub fn subscribe(&self) -> EventSubscriber {
+        self.event_bus.subscribe()
+    }
+
+    pub fn event_bus(&self) -> EventBus {
+        self.event_bus.clone()
+    }
+
+    pub fn playback_state(&self) -> SharedPlaybackState {
+        self.playback_state.clone()
+    }
+
+    /// The commands that recently changed the playback.
+    pub fn audit_log(&self) -> SharedAuditLog {
+        self.audit_log.clone()
+    }
+
+    /// The number of upcoming tracks that are preloaded into the cache, which
+    /// can be changed while running, e.g. on metered connections.
+    pub fn preload_tracks(&self) -> Arc<AtomicUsize> {
+        self.preload_tracks.clone()
+    }
+
+    /// Whether the connection is treated as metered, which can be changed
+    /// while running.
+    pub fn metered(&self) -> Arc<Metered> {
+        self.metered.clone()
+    }
+
+    /// The "do not disturb" lock, which can be taken and released while
+    /// running.
+    pub fn do_not_disturb(&self) -> Arc<DoNotDisturb> {
+        self.do_not_disturb.clone()
+    }
+
+    /// Whether the device is shown via the discovery, which can be changed
+    /// while running.
+    pub fn discoverable(&self) -> Arc<Discoverable> {
+        self.discoverable.clone()
+    }
+
+    /// The profiles of the config, which can be switched between while
+    /// running.
+    pub fn profiles(&self) -> Arc<Profiles> {
+        self.profiles.clone()
+    }
+
+    /// The sleep timer, which can be set and cancelled while running.
+    pub fn sleep_timer(&self) -> Arc<SleepTimer> {
+        self.sleep_timer.clone()
+    }
+
+    pub fn control_handle(&self) -> ControlHandle {
+        ControlHandle::new(self.control_tx.clone(), CommandSource::Api)
+    }
+
+    #[cfg(feature = "dbus_mpris")]
+    fn mpris_actions(&self) -> MprisActions {
+        MprisActions {
+            shell: self.shell.clone(),
+            raise_cmd: self.mpris_raise_cmd.clone(),
+            quit: self.mpris_quit,
+            shutdown_request: self.shutdown_request.clone(),
+            do_not_disturb: self.do_not_disturb.clone(),
+            discoverable: self.discoverable.clone(),
+            profiles: self.profiles.clone(),
+            cache_layout: self.cache_layout.clone(),
+            encrypted_credentials: self.encrypted_credentials.clone(),
+            use_keyring: self.use_keyring,
+            forget_user: self.forget_user_tx.clone(),
+            sleep_timer: self.sleep_timer.clone(),
+            volume_step: self.volume_step,
+        }
+    }
+
+    /// A control handle for the commands spotifyd sends on its own.
+    fn internal_control_handle(&self) -> ControlHandle {
+        ControlHandle::new(self.control_tx.clone(), CommandSource::Spotifyd)
+    }
+
+    /// Switches to the account of the profile asked for, which the next
+    /// session connects with. Returns whether it did, so that the current
+    /// session ends.
+    fn switch_profile(&mut self) -> bool {
+        let Some(name) = self.profile_switch.borrow_and_update().clone() else {
+            return false;
+        };
+        let profiles = self.profiles.clone();
+        let Some(profile) = profiles.get(&name) else {
+            return false;
+        };
+        let username = Some(profile.username.clone());
+        let cache = profile
+            .cache_layout
+            .as_ref()
+            .and_then(|layout| layout.cache(username.as_deref()));
+        let Some(credentials) = crate::setup::account_credentials(
+            &cache,
+            &profile.encrypted_credentials,
+            &username,
+            profile.password.clone(),
+            self.use_keyring,
+        ) else {
+            error!("The profile {} has no credentials to connect with", name);
+            return false;
+        };
+        self.spotifyd_state.device_name = profile.device_name.clone();
+        self.spotifyd_state.cache = cache;
+        self.cache_layout = profile.cache_layout.clone();
+        self.encrypted_credentials = profile.encrypted_credentials.clone();
+        self.credentials_provider = CredentialsProvider::SpotifyCredentials(credentials);
+        self.discoverable.set_unavailable();
+        true
+    }
+
+    fn new_session(&self) -> Session {
         let session_config = self.session_config.clone();
         let cache = self.spotifyd_state.cache.clone();
-        let session = Session::new(session_config, cache);
+        Session::new(session_config, cache)
+    }
 
-        session.connect(credentials, true).await.map(|()| session)
+    async fn connect_session(session: Session, credentials: Credentials) -> Result<Session, Error> {
+        let mut span = telemetry::Span::start("session_connect");
+        let result = session.connect(credentials, true).await;
+        if let Err(ref err) = result {
+            span.set_error(err);
+        }
+        result.map(|()| session)
+    }
+
+    /// Resolves the access point the session connects to, so that this can
+    /// happen while waiting for the credentials.
+    async fn resolve_access_point(session: &Session) {
+        let start = Instant::now();
+        match session.apresolver().resolve("accesspoint").await {
+            Ok(_) => debug!("Resolved the access point in {:?}", start.elapsed()),
+            Err(err) => debug!("Failed to resolve the access point in advance: {}", err),
+        }
+    }
+
+    /// The address of the client that sent the credentials via the
+    /// discovery, if it can be told.
+    fn discovery_client(&self) -> Option<IpAddr> {
+        let CredentialsProvider::Discovery(_) = self.credentials_provider else {
+            return None;
+        };
+        if self.credentials_provider.reattaching() {
+            return None;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(port) = self.discovery_port {
+            return crate::peer::discovery_client(port);
+        }
+        None
+    }
+
+    fn session_connected(&self, session: &Session, user_name: &str) {
+        logging::register_secret(user_name);
+        let mut state = self.playback_state.write().unwrap();
+        state.country = Some(session.country());
+        state.product = session.get_user_attribute("type");
+        info!(
+            "Logged in with a {} account from {}",
+            state.product.as_deref().unwrap_or("unknown"),
+            session.country()
+        );
+    }
+
+    /// Follows the playback of the account's active device instead of playing,
+    /// until the session ends. Returns whether the program should shut down.
+    #[cfg(feature = "web_api")]
+    async fn mirror(
+        &mut self,
+        session: Session,
+        mut shutdown: Pin<&mut impl Future<Output = ()>>,
+        watchdog: &mut Watchdog,
+    ) -> bool {
+        let client = match web_api::client(&session).await {
+            Ok(client) => Arc::new(client),
+            Err(err) => {
+                error!("failed to create the Web API client: {}", err);
+                return true;
+            }
+        };
+        self.startup_timer.finish("mirror");
+        self.notifier
+            .notify("READY=1\nSTATUS=Mirroring the active device");
+
+        self.event_bus.clear_replay();
+        self.playback_state.write().unwrap().reset();
+        self.session_connected(&session, &session.username());
+        let event = SpotifydEvent::SessionConnected {
+            connection_id: session.connection_id(),
+            user_name: session.username(),
+            client_ip: None,
+        };
+        self.playback_state.write().unwrap().update(&event);
+        self.event_bus.publish(event);
+
+        // the commands are sent to the active device
+        let control = Arc::new(RemoteControl::new(client.clone(), None, self.volume_step));
+
+        let mut dbus_server: Pin<Box<dyn Future<Output = ()>>> = Box::pin(future::pending());
+
+        #[cfg(feature = "dbus_mpris")]
+        if self.use_mpris {
+            dbus_server = Box::pin(DbusServer::new(
+                session.clone(),
+                control.clone(),
+                self.mpris_actions(),
+                self.spotifyd_state.device_name.clone(),
+                self.event_bus.clone(),
+                self.playback_state.clone(),
+                self.audit_log.clone(),
+                self.dbus_type,
+            ));
+        }
+
+        let mirror = run_mirror(
+            session,
+            client,
+            self.mirror_interval,
+            self.event_bus.clone(),
+            self.playback_state.clone(),
+        );
+        tokio::pin!(mirror);
+
+        loop {
+            tokio::select!(
+                _ = self.credentials_provider.incoming_connection() => return false,
+                Ok(()) = self.profile_switch.changed() => {
+                    if self.switch_profile() {
+                        return false;
+                    }
+                }
+                _ = &mut shutdown => return true,
+                // the session ended
+                _ = &mut mirror => return false,
+                _ = watchdog.ping() => (),
+                _ = &mut dbus_server => return true,
+                Some((command, source, client)) = self.control_rx.recv() => {
+                    let state = self.playback_state.read().unwrap().clone();
+                    let command = command.with_volume_step(self.volume_step, state.volume);
+                    // the Web API client is blocking
+                    let result = tokio::task::block_in_place(|| {
+                        apply_audited(&self.audit_log, source, client, command, &*control, &state)
+                    });
+                    if let Err(err) = result {
+                        error!("failed to apply {:?}: {}", command, err);
+                    }
+                }
+            )
+        }
+    }
+
+    /// Skips the unavailable track after the configured delay, unless the
+    /// playback has moved on by then.
+    fn skip_unavailable(&self, play_request_id: u64, track_id: String) {
+        let delay = self.unavailable_skip_delay;
+        match self.playback_state.read().unwrap().country {
+            // most tracks are unavailable because of licensing restrictions
+            Some(ref country) => warn!(
+                "Track {} is unavailable, possibly because it isn't licensed in the account's \
+                 country ({}), skipping it in {:?}",
+                track_id, country, delay
+            ),
+            None => warn!(
+                "Track {} is unavailable, skipping it in {:?}",
+                track_id, delay
+            ),
+        }
+
+        let control = self.internal_control_handle();
+        let playback_state = self.playback_state.clone();
+        let event_bus = self.event_bus.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if playback_state.read().unwrap().track_id.as_deref() != Some(&track_id) {
+                return;
+            }
+            if let Err(err) = control.next() {
+                error!("failed to skip unavailable track: {}", err);
+                return;
+            }
+            event_bus.publish(SpotifydEvent::UnavailableSkipped {
+                play_request_id,
+                track_id,
+            });
+        });
+    }
+
+    /// Announces that another device took over the playback, with its name
+    /// if the Web API reports it.
+    fn taken_over(&self, session: &Session) {
+        let event_bus = self.event_bus.clone();
+        #[cfg(feature = "web_api")]
+        {
+            let session = session.clone();
+            let own_name = self.spotifyd_state.device_name.clone();
+            tokio::spawn(async move {
+                // the Web API takes a moment to report the new active device
+                tokio::time::sleep(TAKEOVER_LOOKUP_DELAY).await;
+                let device_name = web_api::with_client(&session, move |client| {
+                    let devices = client.device().map_err(Error::unavailable)?;
+                    Ok(devices
+                        .into_iter()
+                        .find(|device| device.is_active && device.name != own_name)
+                        .map(|device| device.name))
+                })
+                .await
+                .unwrap_or_else(|err| {
+                    warn!("failed to look up the device that took over: {}", err);
+                    None
+                });
+                event_bus.publish(SpotifydEvent::TakenOver { device_name });
+            });
+        }
+        #[cfg(not(feature = "web_api"))]
+        {
+            let _ = session;
+            event_bus.publish(SpotifydEvent::TakenOver { device_name: None });
+        }
+    }
+
+    /// Skips the track right away, because it is on the blocklist.
+    fn skip_blocked(&self, track_id: String, blocked_uri: String) {
+        info!("Skipping {}, it is blocked by {}", track_id, blocked_uri);
+        if let Err(err) = self.internal_control_handle().next() {
+            error!("failed to skip blocked track: {}", err);
+            return;
+        }
+        self.event_bus.publish(SpotifydEvent::BlockedSkipped {
+            track_id,
+            blocked_uri,
+        });
+    }
+
+    /// Continues the playback as configured, once it reached the end of the
+    /// context after the track, or publishes the end of the queue if nothing
+    /// else will play.
+    fn continue_after_context(&self, session: &Session, spirc: &Spirc, track_id: String) {
+        match self.context_end {
+            None | Some(ContextEnd::Stop) => {
+                info!("Playback reached the end of the queue");
+                self.event_bus
+                    .publish(SpotifydEvent::QueueEnded { track_id });
+            }
+            Some(ContextEnd::Repeat) => {
+                info!("Playback reached the end of the context, repeating it");
+                // the first track of the context has already been loaded
+                if let Err(err) = spirc.play() {
+                    error!("failed to repeat the context: {}", err);
+                    self.event_bus
+                        .publish(SpotifydEvent::QueueEnded { track_id });
+                }
+            }
+            #[cfg(feature = "web_api")]
+            Some(ContextEnd::Radio) => {
+                tokio::spawn(start_radio(
+                    session.clone(),
+                    self.spotifyd_state.device_name.clone(),
+                    self.radio_seed.clone(),
+                    track_id,
+                    self.event_bus.clone(),
+                ));
+            }
+            #[cfg(not(feature = "web_api"))]
+            Some(ContextEnd::Radio) => {
+                let _ = session;
+                self.event_bus
+                    .publish(SpotifydEvent::QueueEnded { track_id });
+            }
+        }
+    }
+
+    /// The bitrate of new sessions, the lowest one on metered connections.
+    fn bitrate(&self) -> Bitrate {
+        if self.metered.is_metered() {
+            Bitrate::Bitrate96
+        } else {
+            self.player_config.bitrate
+        }
+    }
+
+    /// Preloads the tracks after the new one, if more than librespot's one
+    /// should be preloaded.
+    #[cfg(feature = "web_api")]
+    fn preload(&self, session: &Session) -> Pin<Box<dyn Future<Output = ()>>> {
+        let depth = self.preload_tracks.load(Ordering::Relaxed);
+        if depth < 2 || session.cache().is_none() || self.metered.is_metered() {
+            return Box::pin(future::pending());
+        }
+        Box::pin(preload_upcoming(session.clone(), depth, self.bitrate()))
     }
 
-    pub(crate) async fn run(&mut self) {
+    /// Starts the services following the events and serving the clients,
+    /// which run as long as spotifyd does.
+    fn spawn_services(&self) {
+        if !self.spotifyd_state.event_hooks.is_empty() {
+            tokio::spawn(run_hooks(
+                self.shell.clone(),
+                self.spotifyd_state.event_hooks.clone(),
+                self.hook_options.clone(),
+                self.event_bus.subscribe_queued(),
+            ));
+        }
+
+        if let Some(ref path) = self.record_events {
+            match File::create(path) {
+                Ok(file) => {
+                    tokio::spawn(record_events(file, self.event_bus.subscribe_queued()));
+                }
+                Err(e) => error!("Failed to create {}: {}", path.display(), e),
+            }
+        }
+
+        #[cfg(feature = "network_manager")]
+        crate::metered::watch_network_manager(self.metered.clone());
+
+        #[cfg(target_os = "linux")]
+        if self.reduce_dsp_when_throttled {
+            crate::thermal::watch_throttling(self.dsp_switches.clone());
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(interface) = self
+            .outgoing_bind
+            .as_ref()
+            .and_then(|b| b.interface.clone())
+        {
+            tokio::spawn(crate::egress::watch_egress(
+                interface,
+                self.internal_control_handle(),
+                self.playback_state.clone(),
+                self.event_bus.clone(),
+            ));
+        }
+
+        if !self.idle_timeouts.is_empty() {
+            tokio::spawn(crate::idle::watch_idle(
+                self.idle_timeouts.clone(),
+                self.event_bus.clone(),
+                self.event_bus.subscribe(),
+            ));
+        }
+
+        if let Some(ref path) = self.event_log {
+            tokio::spawn(write_event_log(
+                path.clone(),
+                self.event_bus.subscribe_queued(),
+            ));
+        }
+
+        #[cfg(feature = "mqtt")]
+        if let Some((ref broker, ref topic)) = self.mqtt {
+            tokio::spawn(crate::mqtt::publish_events(
+                broker.clone(),
+                topic.clone(),
+                self.event_bus.subscribe_queued(),
+            ));
+        }
+
+        #[cfg(feature = "webhook")]
+        if let Some(ref url) = self.webhook_url {
+            tokio::spawn(crate::webhook::deliver_events(
+                url.clone(),
+                self.event_bus.subscribe_queued(),
+            ));
+        }
+
+        #[cfg(feature = "camilladsp")]
+        if let Some(ref address) = self.camilladsp_address {
+            tokio::spawn(crate::camilladsp::sync(
+                address.clone(),
+                self.audio_setup.audio_format,
+                self.camilladsp_volume.clone(),
+            ));
+        }
+
+        #[cfg(feature = "lastfm")]
+        if let Some(ref lastfm) = self.lastfm {
+            let path = |name| self.scrobble_dir.as_ref().map(|dir| dir.join(name));
+            tokio::spawn(crate::scrobble::scrobble(
+                crate::lastfm::LastFm::new(lastfm.clone(), path("lastfm_session")),
+                path("lastfm_queue"),
+                self.playback_state.clone(),
+                self.event_bus.subscribe_queued(),
+            ));
+        }
+
+        #[cfg(feature = "listenbrainz")]
+        if let Some(ref listenbrainz) = self.listenbrainz {
+            tokio::spawn(crate::scrobble::scrobble(
+                crate::listenbrainz::ListenBrainz::new(listenbrainz.clone()),
+                self.scrobble_dir
+                    .as_ref()
+                    .map(|dir| dir.join("listenbrainz_queue")),
+                self.playback_state.clone(),
+                self.event_bus.subscribe_queued(),
+            ));
+        }
+
+        #[cfg(feature = "http_api")]
+        if let Some((address, ref tokens)) = self.http_api {
+            tokio::spawn(crate::http_api::serve(
+                address,
+                crate::http_api::HttpApi {
+                    tokens: Arc::new(tokens.clone()),
+                    control: ControlHandle::new(self.control_tx.clone(), CommandSource::Http),
+                    playback_state: self.playback_state.clone(),
+                    audit_log: self.audit_log.clone(),
+                    do_not_disturb: self.do_not_disturb.clone(),
+                    metered: self.metered.clone(),
+                    data_usage: self.data_usage.clone(),
+                    preload_tracks: self.preload_tracks.clone(),
+                    dsp_switches: self.dsp_switches.clone(),
+                    shutdown_request: self.shutdown_request.clone(),
+                    event_bus: self.event_bus.clone(),
+                    device_name: self.spotifyd_state.device_name.clone(),
+                    guest_wifi: self.guest_wifi.clone(),
+                    sleep_timer: self.sleep_timer.clone(),
+                },
+            ));
+        }
+
+        #[cfg(unix)]
+        if let Some(ref path) = self.control_socket {
+            tokio::spawn(crate::control_socket::serve(
+                path.clone(),
+                ControlHandle::new(self.control_tx.clone(), CommandSource::Socket),
+                self.playback_state.clone(),
+                self.do_not_disturb.clone(),
+                self.discoverable.clone(),
+                self.profiles.clone(),
+                self.sleep_timer.clone(),
+                self.cache_hits.clone(),
+            ));
+        }
+
+        tokio::spawn(run_sleep_timer(
+            self.sleep_timer.clone(),
+            self.internal_control_handle(),
+            self.event_bus.subscribe(),
+        ));
+
+        if !self.show_rules.is_empty() {
+            tokio::spawn(apply_show_rules(
+                self.show_rules.clone(),
+                self.internal_control_handle(),
+                self.playback_state.clone(),
+                self.event_bus.subscribe(),
+            ));
+        }
+
+        if let Some(min_duration) = self.resume_min_duration {
+            tokio::spawn(resume_positions(
+                self.resume_positions.clone(),
+                min_duration,
+                self.internal_control_handle(),
+                self.playback_state.clone(),
+                self.event_bus.subscribe(),
+            ));
+        }
+    }
+
+    /// Wraps the sink of the audio backend in the stages of the audio pipeline
+    /// that are configured.
+    fn sink_pipeline(
+        &self,
+        bitrate: Bitrate,
+        crossfade_events: PlayerEventSlot,
+    ) -> impl FnOnce(Box<dyn Sink>) -> Box<dyn Sink> + Send + 'static {
+        let warmup = self.audio_setup.warmup;
+        let crossfade = self.audio_setup.crossfade;
+        let (fade_in, fade_out) = (self.audio_setup.fade_in, self.audio_setup.fade_out);
+        let dsp = self.audio_setup.dsp.clone();
+        let dsp_switches = self.dsp_switches.clone();
+        let sleep_timer = self.sleep_timer.clone();
+        let data_usage = self.data_usage.clone();
+        move |mut sink| {
+            sink = Box::new(UsageSink::new(sink, data_usage, bitrate));
+            if !dsp.is_empty() {
+                sink = Box::new(DspSink::new(sink, &dsp, &dsp_switches));
+            }
+            if let Some(crossfade) = crossfade {
+                sink = Box::new(CrossfadeSink::new(sink, crossfade, crossfade_events));
+            }
+            if !fade_in.is_zero() || !fade_out.is_zero() {
+                sink = Box::new(FadeSink::new(sink, fade_in, fade_out));
+            }
+            sink = Box::new(SleepSink::new(sink, sleep_timer));
+            if warmup {
+                Box::new(WarmSink::new(sink))
+            } else {
+                sink
+            }
+        }
+    }
+
+    /// Runs the services against the events of a simulated session instead
+    /// of connecting to Spotify, with the audio of the session written to the
+    /// sink through the configured audio pipeline. The services keep running
+    /// afterwards, for the clients to query the state the session left.
+    ///
+    /// The MPRIS interface isn't served, as it needs a session to get tokens
+    /// for the Web API.
+    pub fn simulate(&mut self, sink: Box<dyn Sink>, session: impl FnOnce(&mut MockPlayer)) {
+        self.spawn_services();
+        let sink = self.sink_pipeline(self.bitrate(), PlayerEventSlot::default())(sink);
+        let mut player =
+            MockPlayer::new(self.event_bus.clone(), self.playback_state.clone()).with_sink(sink);
+        session(&mut player);
+    }
+
+    /// Runs the daemon until it is interrupted or the session fails.
+    pub async fn run(&mut self) {
+        let shutdown_request = self.shutdown_request.clone();
         tokio::pin! {
-            let ctrl_c = tokio::signal::ctrl_c();
+            let shutdown = async move {
+                tokio::select! {
+                    _ = shutdown_signal() => (),
+                    _ = shutdown_request.notified() => info!("Shutting down as requested"),
+                }
+            };
+        }
+
+        if let Some(bind) = self.outgoing_bind.clone() {
+            match bind_proxy::start(bind).await {
+                Ok(url) => self.session_config.proxy = Some(url),
+                Err(e) => {
+                    // connecting unbound could use the wrong route
+                    error!("Failed to bind the connections to Spotify: {}", e);
+                    return;
+                }
+            }
+        }
+
+        #[cfg(feature = "otlp")]
+        if let Some(ref endpoint) = self.otlp_endpoint {
+            match telemetry::init(endpoint) {
+                Ok(()) => info!("Exporting spans to {}", endpoint),
+                Err(e) => error!("Failed to set up the export of spans: {}", e),
+            }
         }
 
+        self.spawn_services();
+
+        let mut watchdog = self.notifier.watchdog();
+        // the playback that the last session lost, to resume in the next one
+        #[cfg(feature = "web_api")]
+        let mut interrupted: Option<Interruption> = None;
+
         'mainloop: loop {
-            let credentials = self.credentials_provider.get_credentials().await;
+            let session = self.new_session();
+            if let CredentialsProvider::Discovery(_) = self.credentials_provider {
+                // there's nothing to connect to until a client connects
+                self.notifier
+                    .notify("READY=1\nSTATUS=Waiting for a Spotify Connect client");
+            }
+            let credentials_provider = &mut self.credentials_provider;
+            let waiting = watchdog.run(async {
+                tokio::join!(
+                    credentials_provider.get_credentials(),
+                    Self::resolve_access_point(&session),
+                )
+            });
+            let (credentials, ()) = tokio::select!(
+                result = waiting => result,
+                // the signal handlers are installed, so they have to be
+                // listened to while waiting for the next client as well
+                _ = &mut shutdown => break 'mainloop,
+                Ok(()) = self.profile_switch.changed() => {
+                    self.switch_profile();
+                    continue 'mainloop;
+                }
+            );
+            self.startup_timer.phase("credentials");
+            self.notifier.notify("STATUS=Connecting to Spotify");
+            let client_ip = self.discovery_client();
+            if let Some(client_ip) = client_ip {
+                info!("The credentials were sent by {}", client_ip);
+            }
+
+            // the cache of the user is only known now, so the access point
+            // has to be resolved again
+            let session = match self.cache_layout {
+                Some(ref layout) if layout.per_user() => {
+                    let cache = layout.cache(credentials.username.as_deref());
+                    Session::new(self.session_config.clone(), cache)
+                }
+                _ => session,
+            };
 
             let session = tokio::select!(
-                _ = &mut ctrl_c => {
+                _ = &mut shutdown => {
                     break 'mainloop;
                 }
-                session = self.get_session(credentials.clone()) => {
+                session = watchdog.run(Self::connect_session(session, credentials.clone())) => {
                     match session {
                         Ok(session) => session,
+                        // the cached credentials may have been revoked
+                        Err(err) if self.credentials_provider.reattaching() => {
+                            warn!("failed to reattach the last user, waiting for a client: {}", err);
+                            continue 'mainloop;
+                        }
                         Err(err) => {
                             error!("failed to connect to spotify: {}", err);
                             break 'mainloop;
@@ -121,17 +1090,56 @@ impl MainLoop {
                 }
             );
 
+            self.startup_timer.phase("connect");
+            if let Some(ref encrypted_credentials) = self.encrypted_credentials {
+                encrypted_credentials.save(&session);
+            }
+            #[cfg(feature = "dbus_keyring")]
+            if self.use_keyring {
+                crate::secrets::save_credentials(&session);
+            }
+            if let (Some(ref layout), CredentialsProvider::Discovery(_)) =
+                (&self.cache_layout, &self.credentials_provider)
+            {
+                if self.reattach_last_user {
+                    layout.remember_user(&session.username());
+                }
+            }
+
+            #[cfg(feature = "web_api")]
+            if self.mirror_mode {
+                if self.mirror(session, shutdown.as_mut(), &mut watchdog).await {
+                    break 'mainloop;
+                }
+                continue;
+            }
+
             let mixer = (self.audio_setup.mixer)();
-            let backend = self.audio_setup.backend;
+            let backend = self.audio_setup.backend.clone();
             let audio_device = self.audio_setup.audio_device.clone();
             let audio_format = self.audio_setup.audio_format;
+            let crossfade_events = PlayerEventSlot::default();
+            let bitrate = self.bitrate();
+            let pipeline = self.sink_pipeline(bitrate, crossfade_events.clone());
+            let player_config = PlayerConfig {
+                bitrate,
+                ..self.player_config.clone()
+            };
             let player = Player::new(
-                self.player_config.clone(),
+                player_config,
                 session.clone(),
                 mixer.get_soft_volume(),
-                move || (backend)(audio_device, audio_format),
+                move || pipeline((backend)(audio_device, audio_format)),
             );
             let mut event_channel = player.get_player_event_channel();
+            if self.audio_setup.crossfade.is_some() {
+                *crossfade_events.lock().unwrap() = Some(player.get_player_event_channel());
+            }
+
+            // events of the previous session don't describe the current state anymore
+            self.event_bus.clear_replay();
+            self.playback_state.write().unwrap().reset();
+            self.data_usage.start_session();
 
             let Ok((spirc, spirc_task)) = Spirc::new(
                 ConnectConfig {
@@ -153,6 +1161,22 @@ impl MainLoop {
             };
 
             tokio::pin!(spirc_task);
+            // the device is visible in the Spotify apps from now on
+            self.startup_timer.finish("spirc");
+            self.notifier.notify("READY=1\nSTATUS=Connected to Spotify");
+
+            #[cfg(feature = "web_api")]
+            if let Some(interruption) = interrupted.take() {
+                tokio::spawn(resume_interrupted(
+                    session.clone(),
+                    self.spotifyd_state.device_name.clone(),
+                    interruption,
+                ));
+            }
+
+            let mut context_end_detector = ContextEndDetector::default();
+            let mut connect_commands = ConnectCommands::default();
+            let mut playback_spans = PlaybackSpans::default();
 
             let shared_spirc = Arc::new(spirc);
 
@@ -160,33 +1184,92 @@ impl MainLoop {
             let mut dbus_server: Pin<Box<dyn Future<Output = ()>>> = Box::pin(future::pending());
 
             #[cfg(feature = "dbus_mpris")]
-            let mpris_event_tx = if self.use_mpris {
-                let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            if self.use_mpris {
                 dbus_server = Box::pin(DbusServer::new(
-                    session,
+                    session.clone(),
                     shared_spirc.clone(),
+                    self.mpris_actions(),
                     self.spotifyd_state.device_name.clone(),
-                    rx,
+                    self.event_bus.clone(),
+                    self.playback_state.clone(),
+                    self.audit_log.clone(),
                     self.dbus_type,
                 ));
-                Some(tx)
-            } else {
-                None
-            };
+            }
+
+            // the schedule only runs while the session does
+            let mut schedule: Pin<Box<dyn Future<Output = ()>>> = Box::pin(future::pending());
+
+            #[cfg(feature = "web_api")]
+            if !self.playlist_schedule.is_empty() {
+                schedule = Box::pin(run_schedule(
+                    self.playlist_schedule.clone(),
+                    session.clone(),
+                    self.spotifyd_state.device_name.clone(),
+                    self.playback_state.clone(),
+                ));
+            }
+
+            // the changes of guests are undone while the session runs
+            let mut party_guard: Pin<Box<dyn Future<Output = ()>>> = Box::pin(future::pending());
+
+            #[cfg(feature = "web_api")]
+            if let Some(ref party_mode) = self.party_mode {
+                party_guard = Box::pin(party::run_party_guard(
+                    party_mode.clone(),
+                    session.clone(),
+                    self.spotifyd_state.device_name.clone(),
+                    self.playback_state.clone(),
+                ));
+            }
+
+            // the users forgotten before belong to previous sessions
+            while self.forget_user_rx.try_recv().is_ok() {}
 
-            let mut running_event_program = Box::pin(Fuse::terminated());
+            // the preloading of the upcoming tracks restarts with every track
+            let mut preload: Pin<Box<dyn Future<Output = ()>>> = Box::pin(future::pending());
 
             loop {
                 tokio::select!(
                     // a new session has been started via the discovery stream
                     _ = self.credentials_provider.incoming_connection() => {
+                        let refused = self.credentials_provider.refuse_incoming(&self.do_not_disturb).await;
+                        if let Some(user_name) = refused {
+                            info!("Refused the connection of another account, the playback is locked");
+                            self.event_bus.publish(SpotifydEvent::TakeoverRefused {
+                                user_name,
+                                client_name: None,
+                            });
+                            continue;
+                        }
+                        if let Err(err) = shared_spirc.shutdown() {
+                            error!("failed to shutdown spirc: {}", err)
+                        }
+                        break;
+                    }
+                    // another account connects instead
+                    Ok(()) = self.profile_switch.changed() => {
+                        if !self.switch_profile() {
+                            continue;
+                        }
+                        if let Err(err) = shared_spirc.shutdown() {
+                            error!("failed to shutdown spirc: {}", err)
+                        }
+                        break;
+                    }
+                    // the user has to select the device in the app again
+                    Some(username) = self.forget_user_rx.recv() => {
+                        if username != session.username() {
+                            continue;
+                        }
+                        info!("Ending the session of {}, whose credentials were forgotten", username);
                         if let Err(err) = shared_spirc.shutdown() {
                             error!("failed to shutdown spirc: {}", err)
                         }
                         break;
                     }
                     // the program should shut down
-                    _ = &mut ctrl_c => {
+                    _ = &mut shutdown => {
                         if let Err(err) = shared_spirc.shutdown() {
                             error!("failed to shutdown spirc: {}", err)
                         }
@@ -194,6 +1277,10 @@ impl MainLoop {
                     }
                     // spirc was shut down by some external factor
                     _ = &mut spirc_task => {
+                        #[cfg(feature = "web_api")]
+                        if self.resume_after_reconnect {
+                            interrupted = Interruption::of(&self.playback_state.read().unwrap());
+                        }
                         break;
                     }
                     // dbus stopped unexpectedly
@@ -203,31 +1290,160 @@ impl MainLoop {
                         }
                         break 'mainloop;
                     }
-                    // a new player event is available and no program is running
-                    event = event_channel.recv(), if running_event_program.is_terminated() => {
+                    // the schedule has no more switches
+                    _ = &mut schedule => {
+                        schedule = Box::pin(future::pending());
+                    }
+                    // the guests can't be restricted
+                    _ = &mut party_guard => {
+                        party_guard = Box::pin(future::pending());
+                    }
+                    // the upcoming tracks have been preloaded
+                    _ = &mut preload => {
+                        preload = Box::pin(future::pending());
+                    }
+                    // systemd restarts spotifyd if this loop gets stuck
+                    _ = watchdog.ping() => (),
+                    // a new player event is available
+                    event = event_channel.recv() => {
                         let event = event.unwrap();
-                        #[cfg(feature = "dbus_mpris")]
-                        if let Some(ref tx) = mpris_event_tx {
-                            tx.send(event.clone()).unwrap();
+                        if let PlayerEvent::SessionConnected { ref user_name, .. } = event {
+                            self.session_connected(&session, user_name);
                         }
-                        if let Some(ref cmd) = self.spotifyd_state.player_event_program {
-                            match spawn_program_on_event(&self.shell, cmd, event) {
-                                Ok(child) => running_event_program = Box::pin(child.wait().fuse()),
-                                Err(e) => error!("{}", e),
+                        let mut event = SpotifydEvent::from(event);
+                        // messages logged meanwhile can be found by the event in the journal
+                        logging::with_event_fields(event.log_fields(), || {
+                            match event {
+                                SpotifydEvent::SessionConnected { client_ip: ref mut ip, ref user_name, .. } => {
+                                    *ip = client_ip;
+                                    self.audit_log.lock().unwrap().record_session(user_name, client_ip);
+                                }
+                                SpotifydEvent::SessionClientChanged { client_ip: ref mut ip, .. } => *ip = client_ip,
+                                _ => (),
                             }
-                        }
+                            if let SpotifydEvent::TrackChanged(ref mut info) = event {
+                                // the covers would be downloaded by the hooks and MPRIS clients
+                                if self.metered.is_metered() {
+                                    info.covers.clear();
+                                }
+                            }
+                            if let SpotifydEvent::Unavailable { play_request_id, ref track_id } = event {
+                                self.skip_unavailable(play_request_id, track_id.clone());
+                            }
+                            if let SpotifydEvent::Loading { ref track_id, .. } = event {
+                                tokio::spawn(record_lookup(session.clone(), track_id.clone(), self.cache_hits.clone()));
+                            }
+                            self.playback_state.write().unwrap().update(&event);
+                            if let Some(time_to_audio) = playback_spans.observe(&event) {
+                                let ms = time_to_audio.as_millis() as u32;
+                                debug!("The audio started {}ms after loading the track", ms);
+                                self.playback_state.write().unwrap().time_to_audio_ms = Some(ms);
+                            }
+                            if let Some(command) = connect_commands.observe(&event) {
+                                let client = self.playback_state.read().unwrap().controller.clone();
+                                self.audit_log.lock().unwrap().record_connect(client, client_ip, command);
+                            }
+                            if let Some(ref party_mode) = self.party_mode {
+                                let state = self.playback_state.read().unwrap();
+                                let enforced = party::cap_volume(party_mode, &event, &state);
+                                if let Some(command) = enforced {
+                                    info!(
+                                        "Limiting the volume set by a guest to {}",
+                                        display_volume(party_mode.max_volume)
+                                    );
+                                    let spirc = &*shared_spirc;
+                                    let (log, source) = (&self.audit_log, CommandSource::Spotifyd);
+                                    let client = CommandClient::default();
+                                    if let Err(err) =
+                                        apply_audited(log, source, client, command, spirc, &state)
+                                    {
+                                        error!("failed to limit the volume: {}", err);
+                                    }
+                                }
+                            }
+                            let refused = {
+                                let state = self.playback_state.read().unwrap();
+                                self.do_not_disturb.refuse(&event, &state).map(|command| {
+                                    info!("Pausing the playback started by another client, the playback is locked");
+                                    let spirc = &*shared_spirc;
+                                    let (log, source) = (&self.audit_log, CommandSource::Spotifyd);
+                                    let client = CommandClient::default();
+                                    if let Err(err) =
+                                        apply_audited(log, source, client, command, spirc, &state)
+                                    {
+                                        error!("failed to pause the playback: {}", err);
+                                    }
+                                    state.controller.clone()
+                                })
+                            };
+                            if let Some(track_id) = context_end_detector.observe(&event) {
+                                self.continue_after_context(&session, &shared_spirc, track_id);
+                            }
+                            let blocked = match (&event, &mut self.blocklist) {
+                                (SpotifydEvent::TrackChanged(info), Some(blocklist)) => blocklist
+                                    .blocks(info)
+                                    .map(|uri| (info.track_id.clone(), uri)),
+                                _ => None,
+                            };
+                            let duplicate = match (&event, &mut self.play_history) {
+                                (SpotifydEvent::TrackChanged(info), Some(history)) if blocked.is_none() => {
+                                    history.check(info).map(|ago| (info.track_id.clone(), ago))
+                                }
+                                _ => None,
+                            };
+                            #[cfg(feature = "web_api")]
+                            if matches!(event, SpotifydEvent::TrackChanged(_))
+                                && blocked.is_none()
+                                && duplicate.is_none()
+                            {
+                                preload = self.preload(&session);
+                            }
+                            let taken_over = matches!(event, SpotifydEvent::SessionDisconnected { .. });
+                            self.event_bus.publish(event);
+                            if let Some(client_name) = refused {
+                                self.event_bus.publish(SpotifydEvent::TakeoverRefused {
+                                    user_name: None,
+                                    client_name,
+                                });
+                            }
+                            if taken_over {
+                                self.taken_over(&session);
+                                if self.takeover == Takeover::Release {
+                                    info!("Another device took over, restarting the session to close the audio device");
+                                    if let Err(err) = shared_spirc.shutdown() {
+                                        error!("failed to shutdown spirc: {}", err)
+                                    }
+                                }
+                            }
+                            if let Some((track_id, blocked_uri)) = blocked {
+                                self.skip_blocked(track_id, blocked_uri);
+                            }
+                            if let Some((track_id, ago)) = duplicate {
+                                info!("Skipping {}, it has already been played {:?} ago", track_id, ago);
+                                if let Err(err) = self.internal_control_handle().next() {
+                                    error!("failed to skip repeated track: {}", err);
+                                }
+                            }
+                        });
                     }
-                    // a running program has finished
-                    result = &mut running_event_program, if !running_event_program.is_terminated() => {
-                        match result {
-                            // Exited without error...
-                            Ok(_) => (),
-                            // Exited with error...
-                            Err(e) => error!("{}", e),
+                    // a command was sent through a control handle
+                    Some((command, source, client)) = self.control_rx.recv() => {
+                        let state = self.playback_state.read().unwrap();
+                        let command = command.with_volume_step(self.volume_step, state.volume);
+                        let spirc = &*shared_spirc;
+                        let log = &self.audit_log;
+                        if let Err(err) = apply_audited(log, source, client, command, spirc, &state) {
+                            error!("failed to apply {:?}: {}", command, err);
                         }
                     }
                 )
             }
         }
+
+        self.notifier.notify("STOPPING=1");
+        self.data_usage.save();
+
+        #[cfg(feature = "otlp")]
+        telemetry::shutdown();
     }
 }
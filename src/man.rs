@@ -0,0 +1,91 @@
+use crate::config::CliConfig;
+use std::io;
+use structopt::{
+    clap::{App, AppSettings, ErrorKind, Shell},
+    StructOpt,
+};
+
+fn app() -> App<'static, 'static> {
+    CliConfig::clap().global_setting(AppSettings::ColorNever)
+}
+
+/// Prints the completions of the command line for the shell.
+pub fn print_completions(shell: Shell) {
+    app().gen_completions_to("spotifyd", shell, &mut io::stdout());
+}
+
+/// The help of the subcommand, as printed by `spotifyd <subcommand> --help`.
+fn subcommand_help(name: &str) -> Option<String> {
+    match app().get_matches_from_safe(["spotifyd", name, "--help"]) {
+        Err(e) if e.kind == ErrorKind::HelpDisplayed => Some(e.message),
+        _ => None,
+    }
+}
+
+/// The names of the subcommands, listed in the help.
+fn subcommands(help: &str) -> Vec<&str> {
+    help.lines()
+        .skip_while(|line| line.trim() != "SUBCOMMANDS:")
+        .skip(1)
+        .take_while(|line| !line.trim().is_empty())
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|name| *name != "help")
+        .collect()
+}
+
+/// Escapes the text for roff, keeping its lines as they are.
+fn roff(text: &str) -> String {
+    let mut escaped = String::new();
+    for line in text.trim_end().lines() {
+        let line = line.replace('\\', "\\e").replace('-', "\\-");
+        if line.starts_with('.') || line.starts_with('\'') {
+            escaped.push_str("\\&");
+        }
+        escaped.push_str(&line);
+        escaped.push('\n');
+    }
+    escaped
+}
+
+/// The man page, from the help of the command line and of its subcommands.
+pub fn man_page() -> String {
+    let mut help = Vec::new();
+    app().write_long_help(&mut help).unwrap();
+    let help = String::from_utf8_lossy(&help);
+
+    let mut page = format!(
+        ".TH SPOTIFYD 1 \"\" \"spotifyd {}\"\n.SH NAME\nspotifyd \\- A Spotify daemon\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    page.push_str(".SH DESCRIPTION\n.nf\n");
+    page.push_str(&roff(&help));
+    page.push_str(".fi\n.SH COMMANDS\n");
+    for name in subcommands(&help) {
+        let Some(help) = subcommand_help(name) else {
+            continue;
+        };
+        page.push_str(&format!(".SS spotifyd {}\n.nf\n", roff(name).trim_end()));
+        page.push_str(&roff(&help));
+        page.push_str(".fi\n");
+    }
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_man_page() {
+        assert_eq!(
+            roff(".hidden\n--verbose\\n"),
+            "\\&.hidden\n\\-\\-verbose\\en\n"
+        );
+        let page = man_page();
+        assert!(page.starts_with(".TH SPOTIFYD 1"));
+        assert!(page.contains("\\-\\-no\\-daemon"));
+        assert!(page.contains(".SS spotifyd simulate\\-event\n"));
+        assert!(page.contains(".SS spotifyd man\n"));
+        assert!(!page.contains(".SS spotifyd help\n"));
+    }
+}
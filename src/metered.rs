@@ -0,0 +1,152 @@
+use crate::config::MeteredMode;
+use log::info;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// Whether spotifyd saves data because the connection is metered, e.g.
+/// tethered via LTE. While metered, the sessions started afterwards stream at
+/// the lowest bitrate, no tracks are preloaded beyond librespot's next one,
+/// and the covers aren't passed on to the hooks and MPRIS clients, which
+/// would download them.
+///
+/// The mode can be changed while running. In the automatic mode, the metered
+/// flag of NetworkManager's primary connection is followed. Whatever the
+/// mode, data is saved once the monthly data cap has been reached.
+#[derive(Debug)]
+pub struct Metered {
+    mode: AtomicU8,
+    detected: AtomicBool,
+    capped: AtomicBool,
+}
+
+fn mode_from_u8(value: u8) -> MeteredMode {
+    match value {
+        1 => MeteredMode::On,
+        2 => MeteredMode::Auto,
+        _ => MeteredMode::Off,
+    }
+}
+
+impl Metered {
+    pub(crate) fn new(mode: MeteredMode) -> Self {
+        Self {
+            mode: AtomicU8::new(mode as u8),
+            detected: AtomicBool::new(false),
+            capped: AtomicBool::new(false),
+        }
+    }
+
+    pub fn mode(&self) -> MeteredMode {
+        mode_from_u8(self.mode.load(Ordering::Relaxed))
+    }
+
+    pub fn set_mode(&self, mode: MeteredMode) {
+        let previous = self.mode.swap(mode as u8, Ordering::Relaxed);
+        if previous != mode as u8 {
+            info!("Switched the metered connection mode to {:?}", mode);
+        }
+    }
+
+    pub fn is_metered(&self) -> bool {
+        if self.is_capped() {
+            return true;
+        }
+        match self.mode() {
+            MeteredMode::Off => false,
+            MeteredMode::On => true,
+            MeteredMode::Auto => self.detected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether the monthly data cap has been reached.
+    pub fn is_capped(&self) -> bool {
+        self.capped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_capped(&self, capped: bool) {
+        if self.capped.swap(capped, Ordering::Relaxed) != capped {
+            if capped {
+                info!("The monthly data cap has been reached, using less data");
+            } else {
+                info!("The monthly data cap has been reset");
+            }
+        }
+    }
+
+    #[cfg_attr(not(feature = "network_manager"), allow(unused))]
+    fn set_detected(&self, metered: bool) {
+        if self.detected.swap(metered, Ordering::Relaxed) != metered {
+            if metered {
+                info!("The connection is metered, using less data");
+            } else {
+                info!("The connection isn't metered anymore");
+            }
+        }
+    }
+}
+
+/// Follows the metered flag of NetworkManager in a thread of its own, while
+/// the mode is automatic.
+#[cfg(feature = "network_manager")]
+pub(crate) fn watch_network_manager(metered: std::sync::Arc<Metered>) {
+    use dbus::blocking::{stdintf::org_freedesktop_dbus::Properties, Connection};
+    use log::warn;
+    use std::{thread, time::Duration};
+
+    const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+    thread::spawn(move || {
+        let connection = match Connection::new_system() {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Failed to connect to the system bus: {}", e);
+                return;
+            }
+        };
+        let mut warned = false;
+        loop {
+            if metered.mode() != MeteredMode::Auto {
+                thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            let proxy = connection.with_proxy(
+                "org.freedesktop.NetworkManager",
+                "/org/freedesktop/NetworkManager",
+                Duration::from_secs(5),
+            );
+            // NMMetered: 1 is "yes" and 3 is "guess yes"
+            match proxy.get::<u32>("org.freedesktop.NetworkManager", "Metered") {
+                Ok(flag) => metered.set_detected(flag == 1 || flag == 3),
+                // NetworkManager isn't necessarily running
+                Err(e) if !warned => {
+                    warn!("Failed to get the metered flag of NetworkManager: {}", e);
+                    warned = true;
+                }
+                Err(_) => (),
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modes() {
+        let metered = Metered::new(MeteredMode::Auto);
+        assert!(!metered.is_metered());
+        metered.set_detected(true);
+        assert!(metered.is_metered());
+
+        metered.set_mode(MeteredMode::Off);
+        assert_eq!(metered.mode(), MeteredMode::Off);
+        assert!(!metered.is_metered());
+        metered.set_mode(MeteredMode::On);
+        assert!(metered.is_metered());
+
+        metered.set_mode(MeteredMode::Off);
+        metered.set_capped(true);
+        assert!(metered.is_metered());
+    }
+}
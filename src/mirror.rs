@@ -0,0 +1,283 @@
+use crate::{
+    events::{parse_chapters, EventBus, SpotifydEvent, TrackInfo},
+    state::SharedPlaybackState,
+    web_api,
+};
+use librespot_core::session::Session;
+use log::{error, warn};
+use rspotify::{
+    model::{AdditionalType, CurrentPlaybackContext, PlayableItem, RepeatState},
+    prelude::*,
+    AuthCodeSpotify,
+};
+use std::{sync::Arc, time::Duration};
+
+/// A position that differs more than this from the expected one is a seek.
+const SEEK_THRESHOLD_MS: i64 = 2000;
+
+/// The playback of the account's active device at one point in time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Snapshot {
+    track: Option<TrackInfo>,
+    playing: bool,
+    position_ms: u32,
+    shuffle: bool,
+    repeat: bool,
+    volume: Option<u16>,
+}
+
+impl From<CurrentPlaybackContext> for Snapshot {
+    fn from(playback: CurrentPlaybackContext) -> Self {
+        Snapshot {
+            track: playback.item.and_then(track_info),
+            playing: playback.is_playing,
+            position_ms: playback
+                .progress
+                .map_or(0, |progress| progress.num_milliseconds().max(0) as u32),
+            shuffle: playback.shuffle_state,
+            repeat: playback.repeat_state != RepeatState::Off,
+            volume: playback
+                .device
+                .volume_percent
+                .map(|percent| (percent.min(100) * 0xFFFF / 100) as u16),
+        }
+    }
+}
+
+/// The track of the active device, unless it's a local file, which has no
+/// Spotify id to tell it apart from other tracks or to look it up by.
+fn track_info(item: PlayableItem) -> Option<TrackInfo> {
+    Some(match item {
+        PlayableItem::Track(track) => TrackInfo {
+            track_id: track.id.as_ref()?.id().to_string(),
+            uri: track.id.as_ref()?.uri(),
+            name: track.name,
+            duration_ms: track.duration.num_milliseconds() as u32,
+            is_explicit: track.explicit,
+            covers: track.album.images.into_iter().map(|i| i.url).collect(),
+            item_type: "track".to_string(),
+            artist_uris: track
+                .artists
+                .iter()
+                .filter_map(|a| a.id.as_ref().map(|id| id.uri()))
+                .collect(),
+            artists: track.artists.into_iter().map(|a| a.name).collect(),
+            album_artists: track.album.artists.into_iter().map(|a| a.name).collect(),
+            album: track.album.name,
+            chapters: Vec::new(),
+        },
+        PlayableItem::Episode(episode) => TrackInfo {
+            track_id: episode.id.id().to_string(),
+            uri: episode.id.uri(),
+            name: episode.name,
+            duration_ms: episode.duration.num_milliseconds() as u32,
+            is_explicit: episode.explicit,
+            covers: episode.images.into_iter().map(|i| i.url).collect(),
+            item_type: "episode".to_string(),
+            artists: vec![episode.show.name.clone()],
+            artist_uris: Vec::new(),
+            album_artists: Vec::new(),
+            chapters: parse_chapters(&episode.description),
+            album: episode.show.name,
+        },
+    })
+}
+
+/// Turns the changes between the snapshots into events, as if the playback
+/// happened on this device.
+#[derive(Debug, Default)]
+struct Mirror {
+    last: Snapshot,
+    play_request_id: u64,
+}
+
+impl Mirror {
+    /// Returns the events for the snapshot taken `elapsed` after the last one.
+    fn update(&mut self, snapshot: Snapshot, elapsed: Duration) -> Vec<SpotifydEvent> {
+        let last = std::mem::replace(&mut self.last, snapshot);
+        let current = &self.last;
+        let mut events = Vec::new();
+
+        let last_id = last.track.as_ref().map(|track| track.track_id.clone());
+        match current.track {
+            None => {
+                if let Some(track_id) = last_id {
+                    events.push(SpotifydEvent::Stopped {
+                        play_request_id: self.play_request_id,
+                        track_id,
+                    });
+                }
+            }
+            Some(ref track) => {
+                let play_request_id = if last_id.as_ref() != Some(&track.track_id) {
+                    self.play_request_id += 1;
+                    events.push(SpotifydEvent::TrackChanged(track.clone()));
+                    None
+                } else {
+                    Some(self.play_request_id)
+                };
+
+                let track_id = track.track_id.clone();
+                let position_ms = current.position_ms;
+                let status_changed = current.playing != last.playing;
+                if play_request_id.is_none() || status_changed {
+                    let play_request_id = self.play_request_id;
+                    events.push(if current.playing {
+                        SpotifydEvent::Playing {
+                            play_request_id,
+                            track_id,
+                            position_ms,
+                        }
+                    } else {
+                        SpotifydEvent::Paused {
+                            play_request_id,
+                            track_id,
+                            position_ms,
+                        }
+                    });
+                } else {
+                    let expected = if last.playing {
+                        last.position_ms as i64 + elapsed.as_millis() as i64
+                    } else {
+                        last.position_ms as i64
+                    };
+                    if (position_ms as i64 - expected).abs() > SEEK_THRESHOLD_MS {
+                        events.push(SpotifydEvent::Seeked {
+                            play_request_id: self.play_request_id,
+                            track_id,
+                            position_ms,
+                        });
+                    }
+                }
+            }
+        }
+
+        if current.shuffle != last.shuffle {
+            events.push(SpotifydEvent::ShuffleChanged {
+                shuffle: current.shuffle,
+            });
+        }
+        if current.repeat != last.repeat {
+            events.push(SpotifydEvent::RepeatChanged {
+                repeat: current.repeat,
+            });
+        }
+        if let Some(volume) = current.volume {
+            if current.volume != last.volume {
+                events.push(SpotifydEvent::VolumeChanged { volume });
+            }
+        }
+        events
+    }
+}
+
+/// Mirrors the playback of the account's active device through the Web API
+/// into the playback state and the events, until the session ends.
+pub(crate) async fn run_mirror(
+    session: Session,
+    client: Arc<AuthCodeSpotify>,
+    interval: Duration,
+    event_bus: EventBus,
+    playback_state: SharedPlaybackState,
+) {
+    let mut mirror = Mirror::default();
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if session.is_invalid() {
+            return;
+        }
+        if let Err(err) = web_api::refresh_token(&session, &client).await {
+            warn!("Failed to renew the token of the Web API: {}", err);
+            continue;
+        }
+
+        let local_client = client.clone();
+        let playback = tokio::task::spawn_blocking(move || {
+            local_client.current_playback(
+                None,
+                Some([&AdditionalType::Track, &AdditionalType::Episode]),
+            )
+        })
+        .await
+        .unwrap();
+        let snapshot = match playback {
+            Ok(playback) => playback.map(Snapshot::from).unwrap_or_default(),
+            Err(err) => {
+                error!("Failed to fetch the current playback: {}", err);
+                continue;
+            }
+        };
+
+        for event in mirror.update(snapshot, interval) {
+            playback_state.write().unwrap().update(&event);
+            event_bus.publish(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(track_id: &str, playing: bool, position_ms: u32) -> Snapshot {
+        Snapshot {
+            track: Some(TrackInfo {
+                track_id: track_id.to_string(),
+                ..Default::default()
+            }),
+            playing,
+            position_ms,
+            volume: Some(0xFFFF),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_mirror_events() {
+        let interval = Duration::from_secs(5);
+        let mut mirror = Mirror::default();
+
+        let events = mirror.update(snapshot("a", true, 1000), interval);
+        assert!(matches!(events[0], SpotifydEvent::TrackChanged(_)));
+        assert_eq!(
+            events[1..],
+            [
+                SpotifydEvent::Playing {
+                    play_request_id: 1,
+                    track_id: "a".to_string(),
+                    position_ms: 1000,
+                },
+                SpotifydEvent::VolumeChanged { volume: 0xFFFF },
+            ]
+        );
+
+        // the track kept playing
+        assert!(mirror
+            .update(snapshot("a", true, 6000), interval)
+            .is_empty());
+        assert_eq!(
+            mirror.update(snapshot("a", true, 60_000), interval),
+            [SpotifydEvent::Seeked {
+                play_request_id: 1,
+                track_id: "a".to_string(),
+                position_ms: 60_000,
+            }]
+        );
+        assert_eq!(
+            mirror.update(snapshot("a", false, 61_000), interval),
+            [SpotifydEvent::Paused {
+                play_request_id: 1,
+                track_id: "a".to_string(),
+                position_ms: 61_000,
+            }]
+        );
+        assert_eq!(
+            mirror.update(Snapshot::default(), interval),
+            [SpotifydEvent::Stopped {
+                play_request_id: 1,
+                track_id: "a".to_string(),
+            }]
+        );
+    }
+}
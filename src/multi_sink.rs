@@ -0,0 +1,251 @@
+use librespot_playback::{
+    audio_backend::{Sink, SinkError, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+    dither::DithererBuilder,
+};
+use log::warn;
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// How long an output may take to carry out a command before it's left out.
+const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Opens the sink of an output, on the thread writing to it.
+pub(crate) type OpenOutput = Arc<dyn Fn() -> Box<dyn Sink> + Send + Sync>;
+
+enum Command {
+    Start,
+    Stop,
+    Write(AudioPacket),
+}
+
+/// The thread playing on an output, which reports the result of each
+/// command.
+struct Worker {
+    commands: mpsc::Sender<Command>,
+    results: mpsc::Receiver<SinkResult<()>>,
+}
+
+impl Worker {
+    fn spawn(open: OpenOutput, ditherer: Option<DithererBuilder>) -> Self {
+        let (commands, received) = mpsc::channel();
+        let (reported, results) = mpsc::channel();
+        thread::spawn(move || {
+            let mut sink = open();
+            let mut converter = Converter::new(ditherer);
+            for command in received {
+                let result = match command {
+                    Command::Start => sink.start(),
+                    Command::Stop => sink.stop(),
+                    Command::Write(packet) => sink.write(packet, &mut converter),
+                };
+                if reported.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { commands, results }
+    }
+}
+
+struct Output {
+    open: OpenOutput,
+    gain: f64,
+    /// The thread playing on the output, until it stalled.
+    worker: Option<Worker>,
+    /// Whether the output failed or stalled, until the next start.
+    failed: bool,
+}
+
+/// A sink playing the audio on several sinks at the same time, each with its
+/// own volume.
+///
+/// Each output is played on by a thread of its own, so that a slow output
+/// doesn't hold up the others. An output that fails, or takes too long, is
+/// left out until the playback starts again, as long as another one still
+/// plays. A stalled output is opened again then.
+pub(crate) struct MultiSink {
+    outputs: Vec<Output>,
+    ditherer: Option<DithererBuilder>,
+    stall_timeout: Duration,
+}
+
+impl MultiSink {
+    pub(crate) fn new(outputs: Vec<(OpenOutput, f64)>, ditherer: Option<DithererBuilder>) -> Self {
+        let outputs = outputs
+            .into_iter()
+            .map(|(open, gain)| Output {
+                worker: Some(Worker::spawn(open.clone(), ditherer)),
+                open,
+                gain,
+                failed: false,
+            })
+            .collect();
+        Self {
+            outputs,
+            ditherer,
+            stall_timeout: STALL_TIMEOUT,
+        }
+    }
+
+    /// Passes the command to the outputs that haven't failed, and waits for
+    /// them to carry it out. Returns the last error, if any.
+    fn dispatch(&mut self, action: &str, command: impl Fn(f64) -> Command) -> Option<SinkError> {
+        let mut dispatched = Vec::new();
+        for (i, output) in self.outputs.iter_mut().enumerate() {
+            let Some(ref worker) = output.worker else {
+                continue;
+            };
+            if !output.failed && worker.commands.send(command(output.gain)).is_ok() {
+                dispatched.push(i);
+            }
+        }
+        let mut error = None;
+        for i in dispatched {
+            let output = &mut self.outputs[i];
+            let worker = output.worker.as_ref().unwrap();
+            let e = match worker.results.recv_timeout(self.stall_timeout) {
+                Ok(Ok(())) => continue,
+                Ok(Err(e)) => {
+                    warn!("The output {} failed to {}: {}", i + 1, action, e);
+                    e
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    warn!("The output {} stalls, leaving it out", i + 1);
+                    output.worker = None;
+                    SinkError::OnWrite("the output stalls".to_string())
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    warn!("The output {} stopped playing", i + 1);
+                    output.worker = None;
+                    SinkError::NotConnected("the output stopped playing".to_string())
+                }
+            };
+            output.failed = true;
+            error = Some(e);
+        }
+        error
+    }
+
+    /// Fails with the last error if every output failed.
+    fn result(&self, error: Option<SinkError>) -> SinkResult<()> {
+        match error {
+            Some(e) if self.outputs.iter().all(|output| output.failed) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Sink for MultiSink {
+    fn start(&mut self) -> SinkResult<()> {
+        for output in &mut self.outputs {
+            output.failed = false;
+            if output.worker.is_none() {
+                output.worker = Some(Worker::spawn(output.open.clone(), self.ditherer));
+            }
+        }
+        let error = self.dispatch("start", |_| Command::Start);
+        self.result(error)
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        self.dispatch("stop", |_| Command::Stop);
+        Ok(())
+    }
+
+    fn write(&mut self, packet: AudioPacket, _: &mut Converter) -> SinkResult<()> {
+        let error = self.dispatch("play", |gain| {
+            Command::Write(match packet {
+                AudioPacket::Samples(ref samples) => {
+                    AudioPacket::Samples(samples.iter().map(|s| s * gain).collect())
+                }
+                // passed through undecoded, there are no samples to scale
+                AudioPacket::Raw(ref bytes) => AudioPacket::Raw(bytes.clone()),
+            })
+        });
+        self.result(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records the samples written to it, or fails to write, or takes longer
+    /// than the outputs may.
+    struct Recorder {
+        samples: Arc<Mutex<Vec<f64>>>,
+        fails: bool,
+        stalls: bool,
+    }
+
+    impl Sink for Recorder {
+        fn write(&mut self, packet: AudioPacket, _: &mut Converter) -> SinkResult<()> {
+            if self.fails {
+                return Err(SinkError::OnWrite("unplugged".to_string()));
+            }
+            if self.stalls {
+                thread::sleep(Duration::from_millis(500));
+            }
+            if let AudioPacket::Samples(samples) = packet {
+                self.samples.lock().unwrap().extend(samples);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let recorder = |fails, stalls| {
+            let samples = Arc::new(Mutex::new(Vec::new()));
+            let recorded = samples.clone();
+            let open: OpenOutput = Arc::new(move || {
+                Box::new(Recorder {
+                    samples: recorded.clone(),
+                    fails,
+                    stalls,
+                })
+            });
+            (samples, open)
+        };
+        let (full, full_sink) = recorder(false, false);
+        let (half, half_sink) = recorder(false, false);
+        let (_, failing_sink) = recorder(true, false);
+        let (stalled, stalling_sink) = recorder(false, true);
+        let mut sink = MultiSink::new(
+            vec![
+                (full_sink, 1.0),
+                (half_sink, 0.5),
+                (failing_sink, 1.0),
+                (stalling_sink, 1.0),
+            ],
+            None,
+        );
+        sink.stall_timeout = Duration::from_millis(100);
+        let mut converter = Converter::new(None);
+
+        let packet = || AudioPacket::Samples(vec![0.5, -0.5]);
+        assert!(sink.write(packet(), &mut converter).is_ok());
+        assert!(sink.write(packet(), &mut converter).is_ok());
+        assert_eq!(*full.lock().unwrap(), [0.5, -0.5, 0.5, -0.5]);
+        assert_eq!(*half.lock().unwrap(), [0.25, -0.25, 0.25, -0.25]);
+        // the stalled output only got the first packet, and is left out
+        assert!(sink.outputs[3].failed);
+        assert!(sink.outputs[3].worker.is_none());
+        thread::sleep(Duration::from_millis(600));
+        assert_eq!(*stalled.lock().unwrap(), [0.5, -0.5]);
+
+        // fails once every output has failed
+        let (_, failing_sink) = recorder(true, false);
+        let mut sink = MultiSink::new(vec![(failing_sink, 1.0)], None);
+        assert!(sink.write(packet(), &mut converter).is_err());
+    }
+}
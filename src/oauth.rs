@@ -0,0 +1,166 @@
+use crate::config::SpotifydConfig;
+use color_eyre::eyre::{self, eyre};
+use librespot_core::{
+    authentication::Credentials, cache::Cache, config::SessionConfig, session::Session,
+};
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+const DEVICE_AUTHORIZATION_URL: &str = "https://accounts.spotify.com/oauth2/device/authorize";
+const TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+const SCOPES: &str = "streaming user-read-playback-state user-modify-playback-state \
+    user-read-currently-playing playlist-read-private user-library-read";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The answer to the device authorization request, see RFC 8628.
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    #[serde(default = "default_interval")]
+    interval: u64,
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenError {
+    error: String,
+}
+
+/// What polling for the token results in, while the user hasn't decided yet.
+#[derive(Debug, PartialEq, Eq)]
+enum Poll {
+    Token(String),
+    Pending,
+    SlowDown,
+}
+
+fn poll_result(status: u16, body: &str) -> eyre::Result<Poll> {
+    if status == 200 {
+        let token: TokenResponse = serde_json::from_str(body)?;
+        return Ok(Poll::Token(token.access_token));
+    }
+    let error = serde_json::from_str::<TokenError>(body)
+        .map(|e| e.error)
+        .unwrap_or_else(|_| format!("status {}", status));
+    match error.as_str() {
+        "authorization_pending" => Ok(Poll::Pending),
+        "slow_down" => Ok(Poll::SlowDown),
+        "expired_token" => Err(eyre!("the code expired before it was entered")),
+        "access_denied" => Err(eyre!("the login was denied")),
+        _ => Err(eyre!("failed to get the token: {}", error)),
+    }
+}
+
+/// Runs the device authorization flow, printing the address and the code to
+/// enter there, and returns the access token once the user logged in.
+fn device_code_token(client_id: &str) -> eyre::Result<String> {
+    let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+    let authorization: DeviceAuthorization = agent
+        .post(DEVICE_AUTHORIZATION_URL)
+        .send_form(&[("client_id", client_id), ("scope", SCOPES)])
+        .map_err(|e| eyre!("failed to start the login: {}", e))?
+        .into_json()?;
+
+    match authorization.verification_uri_complete {
+        Some(ref uri) => println!("To log in, open {}", uri),
+        None => println!("To log in, open {}", authorization.verification_uri),
+    }
+    println!("and enter the code {}", authorization.user_code);
+
+    let expires_at = Instant::now() + Duration::from_secs(authorization.expires_in);
+    let mut interval = Duration::from_secs(authorization.interval);
+    while Instant::now() < expires_at {
+        std::thread::sleep(interval);
+        let form = [
+            ("client_id", client_id),
+            ("device_code", authorization.device_code.as_str()),
+            ("grant_type", DEVICE_CODE_GRANT),
+        ];
+        let (status, body) = match agent.post(TOKEN_URL).send_form(&form) {
+            Ok(response) => (response.status(), response.into_string()?),
+            Err(ureq::Error::Status(status, response)) => (status, response.into_string()?),
+            Err(e) => return Err(eyre!("failed to get the token: {}", e)),
+        };
+        match poll_result(status, &body)? {
+            Poll::Token(token) => return Ok(token),
+            Poll::Pending => (),
+            Poll::SlowDown => interval += Duration::from_secs(5),
+        }
+    }
+    Err(eyre!("the code expired before it was entered"))
+}
+
+/// Connects with the token, storing the returned credentials in the cache.
+async fn connect(
+    config: &SpotifydConfig,
+    cache: Option<Cache>,
+    token: &str,
+) -> eyre::Result<Session> {
+    let session = Session::new(config.session_config.clone(), cache);
+    session
+        .connect(Credentials::with_access_token(token), true)
+        .await
+        .map_err(|e| eyre!("failed to connect to spotify: {}", e))?;
+    Ok(session)
+}
+
+/// Logs in with the device authorization flow, and stores the reusable
+/// credentials that Spotify returns for the token in the cache, where the
+/// daemon finds them.
+pub async fn authenticate(config: &SpotifydConfig) -> eyre::Result<()> {
+    let Some(ref layout) = config.cache_layout else {
+        eyre::bail!("the credentials are stored in the cache, which requires a cache_path");
+    };
+    let client_id = SessionConfig::default().client_id;
+    let token = tokio::task::spawn_blocking(move || device_code_token(&client_id)).await??;
+
+    // the cache of the user is only known once connected
+    let cache = if layout.per_user() {
+        let username = connect(config, None, &token).await?.username();
+        layout.cache(Some(&username))
+    } else {
+        layout.cache(None)
+    };
+    let session = connect(config, cache, &token).await?;
+    if let Some(ref encrypted_credentials) = config.encrypted_credentials {
+        encrypted_credentials.save(&session);
+    }
+    println!("Logged in as {}", session.username());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poll() {
+        assert_eq!(
+            poll_result(200, r#"{"access_token": "token", "expires_in": 3600}"#).unwrap(),
+            Poll::Token("token".to_string())
+        );
+        assert_eq!(
+            poll_result(400, r#"{"error": "authorization_pending"}"#).unwrap(),
+            Poll::Pending
+        );
+        assert_eq!(
+            poll_result(400, r#"{"error": "slow_down"}"#).unwrap(),
+            Poll::SlowDown
+        );
+        assert!(poll_result(400, r#"{"error": "access_denied"}"#).is_err());
+        assert!(poll_result(500, "").is_err());
+    }
+}
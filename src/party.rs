@@ -0,0 +1,353 @@
+use crate::{
+    config::PartyMode, control::ControlCommand, events::SpotifydEvent, state::PlaybackState,
+};
+#[cfg(feature = "web_api")]
+use crate::{state::SharedPlaybackState, web_api};
+#[cfg(feature = "web_api")]
+use librespot_core::{session::Session, Error};
+#[cfg(feature = "web_api")]
+use log::{error, info, warn};
+#[cfg(feature = "web_api")]
+use rspotify::{
+    model::{offset::Offset, AdditionalType, PlayContextId, PlayableItem},
+    prelude::*,
+    AuthCodeSpotify,
+};
+#[cfg(feature = "web_api")]
+use std::{sync::Arc, time::Duration};
+
+/// How often the queue is checked for changes by guests.
+#[cfg(feature = "web_api")]
+const PARTY_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Returns the command that brings the volume a guest in control raised back
+/// down to the cap, after the event has been applied to the playback state.
+///
+/// The commands of the Spotify Connect clients are applied by librespot
+/// before any event is emitted, so they can only be corrected afterwards.
+pub(crate) fn cap_volume(
+    party_mode: &PartyMode,
+    event: &SpotifydEvent,
+    state: &PlaybackState,
+) -> Option<ControlCommand> {
+    if !matches!(
+        event,
+        SpotifydEvent::VolumeChanged { .. } | SpotifydEvent::SessionClientChanged { .. }
+    ) {
+        return None;
+    }
+    if !party_mode.is_guest(state.controller.as_deref()) {
+        return None;
+    }
+    let volume = state.volume?;
+    (volume > party_mode.max_volume).then_some(ControlCommand::SetVolume(party_mode.max_volume))
+}
+
+/// The playback of this device, as far as the guests could have changed it.
+#[cfg(feature = "web_api")]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Snapshot {
+    context_uri: Option<String>,
+    track_uri: Option<String>,
+    position_ms: u32,
+    shuffle: bool,
+    /// The URIs of the upcoming items, the queued ones followed by the ones
+    /// of the context, as far as the Web API returns them.
+    upcoming: Vec<String>,
+}
+
+/// How a change by a guest is undone.
+#[cfg(feature = "web_api")]
+#[derive(Debug, PartialEq, Eq)]
+enum Correction {
+    /// Plays the context the hosts chose again, where it was left.
+    RestoreContext {
+        context_uri: String,
+        track_uri: Option<String>,
+        position_ms: u32,
+    },
+    /// Queues the items removed by a guest again.
+    Requeue(Vec<String>),
+}
+
+/// Finds the changes of guests to the queue and the context.
+///
+/// Spotify Connect applies the commands of the clients before spotifyd learns
+/// about them, so the changes are undone afterwards: a guest may enqueue
+/// tracks, but a context the guest started is replaced by the previous one,
+/// and the items a guest removed from the queue are queued again.
+#[cfg(feature = "web_api")]
+#[derive(Default)]
+struct PartyGuard {
+    /// The playback last seen, which the changes of guests are undone to.
+    last: Option<Snapshot>,
+}
+
+#[cfg(feature = "web_api")]
+impl PartyGuard {
+    fn observe(&mut self, snapshot: Snapshot, guest: bool) -> Option<Correction> {
+        let last = match self.last.take() {
+            Some(last) if guest => last,
+            _ => {
+                self.last = Some(snapshot);
+                return None;
+            }
+        };
+
+        if snapshot.context_uri != last.context_uri {
+            let Some(context_uri) = last.context_uri.clone() else {
+                self.last = Some(snapshot);
+                return None;
+            };
+            let correction = Correction::RestoreContext {
+                context_uri,
+                track_uri: last.track_uri.clone(),
+                position_ms: last.position_ms,
+            };
+            // the context is undone to the one last seen
+            self.last = Some(last);
+            return Some(correction);
+        }
+
+        // toggling the shuffle reorders the upcoming items of the context, and
+        // after a jump to a track further away, they can't be compared
+        let track_index = snapshot
+            .track_uri
+            .as_ref()
+            .and_then(|track| last.upcoming.iter().position(|uri| uri == track));
+        let kept = match track_index {
+            _ if snapshot.shuffle != last.shuffle => &[][..],
+            // the items before the current one have been played or skipped
+            Some(index) => &last.upcoming[index + 1..],
+            None if snapshot.track_uri == last.track_uri => &last.upcoming[..],
+            None => &[][..],
+        };
+        let removed: Vec<String> = kept
+            .iter()
+            .filter(|uri| !snapshot.upcoming.contains(uri))
+            .cloned()
+            .collect();
+
+        let mut current = snapshot;
+        current.upcoming.extend(removed.iter().cloned());
+        self.last = Some(current);
+        (!removed.is_empty()).then_some(Correction::Requeue(removed))
+    }
+}
+
+/// Undoes the changes of guests to the queue and the context while party mode
+/// is on, until the session ends.
+#[cfg(feature = "web_api")]
+pub(crate) async fn run_party_guard(
+    party_mode: PartyMode,
+    session: Session,
+    device_name: String,
+    playback_state: SharedPlaybackState,
+) {
+    let client = match web_api::client(&session).await {
+        Ok(client) => Arc::new(client),
+        Err(err) => {
+            error!("Failed to restrict the queue to the guests: {}", err);
+            return;
+        }
+    };
+    let mut guard = PartyGuard::default();
+    let mut ticker = tokio::time::interval(PARTY_POLL_INTERVAL);
+    loop {
+        ticker.tick().await;
+        if session.is_invalid() {
+            return;
+        }
+        if let Err(err) = web_api::refresh_token(&session, &client).await {
+            warn!("Failed to renew the token of the Web API: {}", err);
+            continue;
+        }
+
+        let guest = party_mode.is_guest(playback_state.read().unwrap().controller.as_deref());
+        let (local_client, local_device_name) = (client.clone(), device_name.clone());
+        let snapshot =
+            tokio::task::spawn_blocking(move || fetch_snapshot(&local_client, &local_device_name))
+                .await
+                .unwrap();
+        let snapshot = match snapshot {
+            Ok(Some(snapshot)) => snapshot,
+            // another device is playing, there is nothing to guard
+            Ok(None) => {
+                guard = PartyGuard::default();
+                continue;
+            }
+            Err(err) => {
+                error!("Failed to fetch the queue: {}", err);
+                continue;
+            }
+        };
+
+        let Some(correction) = guard.observe(snapshot, guest) else {
+            continue;
+        };
+        let (local_client, local_device_name) = (client.clone(), device_name.clone());
+        let result = tokio::task::spawn_blocking(move || {
+            correct(&local_client, &local_device_name, correction)
+        })
+        .await
+        .unwrap();
+        if let Err(err) = result {
+            error!("Failed to undo the change of a guest: {}", err);
+        }
+    }
+}
+
+/// The playback and queue of this device, if it's the active one.
+#[cfg(feature = "web_api")]
+fn fetch_snapshot(client: &AuthCodeSpotify, device_name: &str) -> Result<Option<Snapshot>, Error> {
+    let playback = client
+        .current_playback(
+            None,
+            Some([&AdditionalType::Track, &AdditionalType::Episode]),
+        )
+        .map_err(Error::unavailable)?;
+    let Some(playback) = playback.filter(|playback| playback.device.name == device_name) else {
+        return Ok(None);
+    };
+    let queue = client.current_user_queue().map_err(Error::unavailable)?;
+    Ok(Some(Snapshot {
+        context_uri: playback.context.map(|context| context.uri),
+        track_uri: playback.item.as_ref().and_then(item_uri),
+        position_ms: playback
+            .progress
+            .map_or(0, |progress| progress.num_milliseconds().max(0) as u32),
+        shuffle: playback.shuffle_state,
+        upcoming: queue.queue.iter().filter_map(item_uri).collect(),
+    }))
+}
+
+#[cfg(feature = "web_api")]
+fn item_uri(item: &PlayableItem) -> Option<String> {
+    item.id().map(|id| id.uri())
+}
+
+#[cfg(feature = "web_api")]
+fn correct(
+    client: &AuthCodeSpotify,
+    device_name: &str,
+    correction: Correction,
+) -> Result<(), Error> {
+    let device_id = web_api::device_id(client, device_name)?;
+    match correction {
+        Correction::RestoreContext {
+            context_uri,
+            track_uri,
+            position_ms,
+        } => {
+            info!(
+                "Playing {} again, a guest started another context",
+                context_uri
+            );
+            let context = PlayContextId::from_uri(&context_uri).map_err(Error::invalid_argument)?;
+            let position = chrono::Duration::milliseconds(position_ms as i64);
+            client
+                .start_context_playback(
+                    context,
+                    Some(&device_id),
+                    track_uri.map(Offset::Uri),
+                    Some(position),
+                )
+                .map_err(Error::unavailable)
+        }
+        Correction::Requeue(uris) => {
+            info!("Queueing {} items again, a guest removed them", uris.len());
+            for uri in uris {
+                let web_api::SpotifyUri::Playable(id) =
+                    web_api::SpotifyUri::parse(&uri).map_err(Error::invalid_argument)?
+                else {
+                    continue;
+                };
+                client
+                    .add_item_to_queue(id, Some(&device_id))
+                    .map_err(Error::unavailable)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_changed(client_name: &str) -> SpotifydEvent {
+        SpotifydEvent::SessionClientChanged {
+            client_id: "id".to_string(),
+            client_name: client_name.to_string(),
+            client_brand_name: String::new(),
+            client_model_name: String::new(),
+            client_ip: None,
+        }
+    }
+
+    #[test]
+    fn test_caps_volume_of_guests() {
+        let party_mode = PartyMode {
+            max_volume: 30_000,
+            hosts: vec!["Bar Counter".to_string()],
+        };
+        let mut state = PlaybackState::default();
+        let mut emit = |event: SpotifydEvent| {
+            state.update(&event);
+            cap_volume(&party_mode, &event, &state)
+        };
+
+        assert_eq!(emit(SpotifydEvent::VolumeChanged { volume: 60_000 }), None);
+        assert_eq!(
+            emit(client_changed("Phone")),
+            Some(ControlCommand::SetVolume(30_000))
+        );
+        assert_eq!(emit(SpotifydEvent::VolumeChanged { volume: 20_000 }), None);
+        assert_eq!(emit(client_changed("bar counter")), None);
+        assert_eq!(emit(SpotifydEvent::VolumeChanged { volume: 60_000 }), None);
+    }
+
+    #[cfg(feature = "web_api")]
+    #[test]
+    fn test_undoes_changes_of_guests() {
+        let snapshot = |context: &str, track: &str, upcoming: &[&str]| Snapshot {
+            context_uri: Some(context.to_string()),
+            track_uri: Some(track.to_string()),
+            position_ms: 1000,
+            shuffle: false,
+            upcoming: upcoming.iter().map(|uri| uri.to_string()).collect(),
+        };
+        let mut guard = PartyGuard::default();
+
+        assert_eq!(
+            guard.observe(snapshot("album", "a", &["q", "b", "c"]), false),
+            None
+        );
+        // a guest enqueues a track and skips one
+        assert_eq!(
+            guard.observe(snapshot("album", "q", &["b", "g", "c"]), true),
+            None
+        );
+        // a guest starts another context
+        assert_eq!(
+            guard.observe(snapshot("playlist", "x", &["y"]), true),
+            Some(Correction::RestoreContext {
+                context_uri: "album".to_string(),
+                track_uri: Some("q".to_string()),
+                position_ms: 1000,
+            })
+        );
+        assert_eq!(
+            guard.observe(snapshot("album", "q", &["b", "g", "c"]), true),
+            None
+        );
+        // a guest clears the queue
+        assert_eq!(
+            guard.observe(snapshot("album", "q", &["c"]), true),
+            Some(Correction::Requeue(vec!["b".to_string(), "g".to_string()]))
+        );
+        // the hosts may do anything
+        assert_eq!(guard.observe(snapshot("playlist", "x", &[]), false), None);
+        assert_eq!(guard.observe(snapshot("playlist", "x", &[]), true), None);
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    convert::TryFrom,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener},
+};
+
+/// The TCP connections of the kernel, by their local and remote address.
+const TCP_TABLES: &[&str] = &["/proc/net/tcp", "/proc/net/tcp6"];
+
+/// The states of `/proc/net/tcp` a connection is in once it was accepted,
+/// including the ones after the client closed it.
+const CONNECTED_STATES: &[&str] = &["01", "06", "07", "08", "09", "0B"];
+
+/// Parses an address of `/proc/net/tcp`, like `0100007F:1F90`, whose words
+/// are in the byte order of the host.
+fn parse_address(address: &str) -> Option<(IpAddr, u16)> {
+    let (ip, port) = address.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut words = Vec::new();
+    for i in (0..ip.len()).step_by(8) {
+        let word = u32::from_str_radix(ip.get(i..i + 8)?, 16).ok()?;
+        words.extend(word.to_ne_bytes());
+    }
+    let ip = match words.len() {
+        4 => IpAddr::V4(Ipv4Addr::new(words[0], words[1], words[2], words[3])),
+        16 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&words[..]).ok()?);
+            // the IPv4 clients of a socket listening on both
+            match ip.to_ipv4_mapped() {
+                Some(ip) => IpAddr::V4(ip),
+                None => IpAddr::V6(ip),
+            }
+        }
+        _ => return None,
+    };
+    Some((ip, port))
+}
+
+/// The addresses of the clients connected to the port in the table.
+fn clients(table: &str, port: u16) -> Vec<IpAddr> {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let (local, remote, state) = (fields.get(1)?, fields.get(2)?, fields.get(3)?);
+            if !CONNECTED_STATES.contains(state) || parse_address(local)?.1 != port {
+                return None;
+            }
+            Some(parse_address(remote)?.0)
+        })
+        .collect()
+}
+
+/// The address of the client that just sent its credentials to the
+/// discovery on the port, from its connection, which the kernel keeps for a
+/// while after it was closed.
+///
+/// There's none if several clients connected lately, as it's unknown which
+/// one it was.
+pub(crate) fn discovery_client(port: u16) -> Option<IpAddr> {
+    let mut addresses: Vec<_> = TCP_TABLES
+        .iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .flat_map(|table| clients(&table, port))
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    match addresses[..] {
+        [address] => Some(address),
+        _ => None,
+    }
+}
+
+/// A port that is free to listen on, so that the port of the discovery is
+/// known when it isn't configured.
+pub(crate) fn free_port() -> Option<u16> {
+    let listener = TcpListener::bind("[::]:0")
+        .or_else(|_| TcpListener::bind("0.0.0.0:0"))
+        .ok()?;
+    Some(listener.local_addr().ok()?.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_clients() {
+        let local = u32::from_ne_bytes([192, 168, 1, 2]);
+        let phone = u32::from_ne_bytes([192, 168, 1, 20]);
+        let table = format!(
+            "  sl  local_address rem_address   st tx_queue rx_queue\n   \
+             0: {local:08X}:1F90 00000000:0000 0A 00000000:00000000\n   \
+             1: {local:08X}:1F90 {phone:08X}:D431 06 00000000:00000000\n   \
+             2: {local:08X}:0016 {local:08X}:9C40 01 00000000:00000000\n"
+        );
+        let phone_ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        assert_eq!(clients(&table, 0x1F90), [phone_ip]);
+        assert!(clients(&table, 0x1F91).is_empty());
+
+        let mapped = Ipv4Addr::new(192, 168, 1, 20).to_ipv6_mapped().octets();
+        let ip6: String = mapped
+            .chunks(4)
+            .map(|word| format!("{:08X}", u32::from_ne_bytes(word.try_into().unwrap())))
+            .collect();
+        assert_eq!(
+            parse_address(&format!("{}:1F90", ip6)),
+            Some((phone_ip, 0x1F90))
+        );
+        assert_eq!(parse_address("nonsense"), None);
+    }
+}
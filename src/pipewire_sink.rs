@@ -0,0 +1,313 @@
+use librespot_playback::{
+    audio_backend::{Sink, SinkError, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+    NUM_CHANNELS, SAMPLE_RATE,
+};
+use log::{debug, warn};
+use pipewire::{self as pw, properties::properties, spa, stream::StreamState};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// How many frames are buffered between the player and PipeWire.
+const BUFFER_FRAMES: usize = 8192;
+/// How long the player waits for PipeWire to take frames from a full buffer.
+const FULL_DELAY: Duration = Duration::from_millis(5);
+/// The size of an interleaved stereo frame of 32 bit floats.
+const FRAME_SIZE: usize = 8;
+
+/// The name of the node of the stream, from the name of the device, e.g.
+/// `spotifyd.living-room` for "Living Room".
+fn node_name(device_name: &str) -> String {
+    let name: String = device_name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("spotifyd.{}", name.trim_matches('-'))
+}
+
+/// Fills the buffer with the frames sent by the player, or with silence while
+/// paused or when the player lags behind, and returns the size filled.
+fn fill(frames: &Receiver<[f32; 2]>, buffer: &mut [u8]) -> usize {
+    let mut size = 0;
+    for bytes in buffer.chunks_exact_mut(FRAME_SIZE) {
+        let [left, right] = frames.try_recv().unwrap_or_default();
+        bytes[..4].copy_from_slice(&left.to_le_bytes());
+        bytes[4..].copy_from_slice(&right.to_le_bytes());
+        size += FRAME_SIZE;
+    }
+    size
+}
+
+/// The format of the stream, interleaved stereo 32 bit floats.
+fn format_param() -> Result<Vec<u8>, String> {
+    let mut info = spa::param::audio::AudioInfoRaw::new();
+    info.set_format(spa::param::audio::AudioFormat::F32LE);
+    info.set_rate(SAMPLE_RATE);
+    info.set_channels(NUM_CHANNELS as u32);
+    let mut position = [0; spa::param::audio::MAX_CHANNELS];
+    position[0] = spa::sys::SPA_AUDIO_CHANNEL_FL;
+    position[1] = spa::sys::SPA_AUDIO_CHANNEL_FR;
+    info.set_position(position);
+    let object = spa::pod::Object {
+        type_: spa::sys::SPA_TYPE_OBJECT_Format,
+        id: spa::sys::SPA_PARAM_EnumFormat,
+        properties: info.into(),
+    };
+    spa::pod::serialize::PodSerializer::serialize(
+        io::Cursor::new(Vec::new()),
+        &spa::pod::Value::Object(object),
+    )
+    .map(|(cursor, _)| cursor.into_inner())
+    .map_err(|e| format!("{:?}", e))
+}
+
+/// Plays the frames on a stream of the Music role until the connection is
+/// terminated, after reporting whether the stream could be connected.
+fn run(
+    device_name: &str,
+    target: Option<&str>,
+    frames: Receiver<[f32; 2]>,
+    failed: Arc<AtomicBool>,
+    terminate: pw::channel::Receiver<()>,
+    ready: &mpsc::Sender<Result<(), String>>,
+) -> Result<(), String> {
+    pw::init();
+    let mainloop = pw::main_loop::MainLoop::new(None).map_err(|e| e.to_string())?;
+    let context = pw::context::Context::new(&mainloop).map_err(|e| e.to_string())?;
+    let core = context.connect(None).map_err(|e| e.to_string())?;
+
+    let _terminate = terminate.attach(mainloop.loop_(), {
+        let mainloop = mainloop.clone();
+        move |()| mainloop.quit()
+    });
+    // e.g. when PipeWire restarts, the stream is connected again on the next start
+    let _core_listener = core
+        .add_listener_local()
+        .error({
+            let (mainloop, failed) = (mainloop.clone(), failed.clone());
+            move |_, _, _, message| {
+                warn!("Lost the connection to PipeWire: {}", message);
+                failed.store(true, Ordering::Relaxed);
+                mainloop.quit();
+            }
+        })
+        .register();
+
+    let mut properties = properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => "Playback",
+        *pw::keys::MEDIA_ROLE => "Music",
+        *pw::keys::NODE_NAME => node_name(device_name),
+        *pw::keys::NODE_DESCRIPTION => device_name,
+        *pw::keys::APP_NAME => "spotifyd",
+    };
+    if let Some(target) = target {
+        properties.insert(*pw::keys::TARGET_OBJECT, target);
+    }
+    let stream =
+        pw::stream::Stream::new(&core, device_name, properties).map_err(|e| e.to_string())?;
+    let _listener = stream
+        .add_local_listener_with_user_data(())
+        .state_changed({
+            let failed = failed.clone();
+            move |_, _, old, new| {
+                debug!("The PipeWire stream went from {:?} to {:?}", old, new);
+                if let StreamState::Error(e) = new {
+                    warn!("The PipeWire stream failed: {}", e);
+                    failed.store(true, Ordering::Relaxed);
+                }
+            }
+        })
+        .process(move |stream, _| {
+            let Some(mut buffer) = stream.dequeue_buffer() else {
+                return;
+            };
+            let data = &mut buffer.datas_mut()[0];
+            let size = data.data().map_or(0, |bytes| fill(&frames, bytes));
+            let chunk = data.chunk_mut();
+            *chunk.offset_mut() = 0;
+            *chunk.stride_mut() = FRAME_SIZE as i32;
+            *chunk.size_mut() = size as u32;
+        })
+        .register()
+        .map_err(|e| e.to_string())?;
+
+    let format = format_param()?;
+    let mut params = [spa::pod::Pod::from_bytes(&format).ok_or("invalid format")?];
+    // the session manager links the stream to the default sink, or the
+    // target, and moves it when they change
+    stream
+        .connect(
+            spa::utils::Direction::Output,
+            None,
+            pw::stream::StreamFlags::AUTOCONNECT
+                | pw::stream::StreamFlags::MAP_BUFFERS
+                | pw::stream::StreamFlags::RT_PROCESS,
+            &mut params,
+        )
+        .map_err(|e| e.to_string())?;
+    let _ = ready.send(Ok(()));
+    mainloop.run();
+    Ok(())
+}
+
+struct Connection {
+    frames: SyncSender<[f32; 2]>,
+    failed: Arc<AtomicBool>,
+    terminate: pw::channel::Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let _ = self.terminate.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A sink playing to a PipeWire stream of the Music role named after the
+/// device, which desktops show with its own volume and let move between
+/// outputs, on the device if it's set, e.g. the `node.name` of a sink.
+pub(crate) struct PipeWireSink {
+    device_name: String,
+    target: Option<String>,
+    connection: Option<Connection>,
+}
+
+impl PipeWireSink {
+    pub(crate) fn new(device_name: String, target: Option<String>) -> Self {
+        Self {
+            device_name,
+            target,
+            connection: None,
+        }
+    }
+
+    fn connect(&self) -> Result<Connection, String> {
+        let (sender, frames) = mpsc::sync_channel(BUFFER_FRAMES);
+        let failed = Arc::new(AtomicBool::new(false));
+        let (terminate, terminated) = pw::channel::channel();
+        let (ready, connected) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("pipewire".to_string())
+            .spawn({
+                let (device_name, target) = (self.device_name.clone(), self.target.clone());
+                let failed = failed.clone();
+                move || {
+                    let result = run(
+                        &device_name,
+                        target.as_deref(),
+                        frames,
+                        failed.clone(),
+                        terminated,
+                        &ready,
+                    );
+                    if let Err(e) = result {
+                        failed.store(true, Ordering::Relaxed);
+                        let _ = ready.send(Err(e));
+                    }
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        let connection = Connection {
+            frames: sender,
+            failed,
+            terminate,
+            thread: Some(thread),
+        };
+        match connected.recv() {
+            Ok(Ok(())) => Ok(connection),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err("the PipeWire thread panicked".to_string()),
+        }
+    }
+}
+
+/// Sends the interleaved samples to PipeWire, waiting while the buffer is
+/// full, and returns whether the stream is still there.
+fn send(connection: &Connection, samples: &[f32]) -> bool {
+    for frame in samples.chunks_exact(2) {
+        let mut frame = [frame[0], frame[1]];
+        loop {
+            match connection.frames.try_send(frame) {
+                Ok(()) => break,
+                Err(TrySendError::Full(rejected)) if !connection.failed.load(Ordering::Relaxed) => {
+                    frame = rejected;
+                    thread::sleep(FULL_DELAY);
+                }
+                Err(_) => return false,
+            }
+        }
+    }
+    !connection.failed.load(Ordering::Relaxed)
+}
+
+impl Sink for PipeWireSink {
+    fn start(&mut self) -> SinkResult<()> {
+        if self.connection.is_none() {
+            let connection = self.connect().map_err(SinkError::ConnectionRefused)?;
+            self.connection = Some(connection);
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        let samples = packet
+            .samples()
+            .map_err(|e| SinkError::OnWrite(e.to_string()))?;
+        let samples = converter.f64_to_f32(samples);
+        let connection = self
+            .connection
+            .as_ref()
+            .ok_or_else(|| SinkError::NotConnected("the sink isn't started".to_string()))?;
+        if !send(connection, &samples) {
+            // connected again when the player starts the sink
+            self.connection = None;
+            return Err(SinkError::NotConnected(
+                "the PipeWire stream failed".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream() {
+        assert_eq!(node_name("Living Room"), "spotifyd.living-room");
+        assert_eq!(node_name("Spotifyd@host"), "spotifyd.spotifyd-host");
+
+        let (sender, frames) = mpsc::sync_channel(4);
+        sender.send([0.5, -0.5]).unwrap();
+        let mut buffer = [0xFF; 2 * FRAME_SIZE + 1];
+        assert_eq!(fill(&frames, &mut buffer), 2 * FRAME_SIZE);
+        assert_eq!(buffer[..4], 0.5f32.to_le_bytes());
+        assert_eq!(buffer[4..8], (-0.5f32).to_le_bytes());
+        // silence once the player has sent nothing more
+        assert_eq!(buffer[8..16], [0; 8]);
+    }
+}
@@ -0,0 +1,116 @@
+use crate::web_api;
+use librespot_audio::AudioFile;
+use librespot_core::{session::Session, spotify_id::SpotifyId, Error};
+use librespot_metadata::{audio::AudioFileFormat, Metadata, Track};
+use librespot_playback::config::Bitrate;
+use log::{debug, warn};
+use rspotify::{model::PlayableItem, prelude::*};
+use std::{io, time::Duration};
+
+/// How long a track plays before the upcoming ones are preloaded, so that
+/// they don't compete with the download of the current one.
+const PRELOAD_DELAY: Duration = Duration::from_secs(10);
+
+/// The formats of the audio files, the one of the bitrate first.
+fn formats(bitrate: Bitrate) -> [AudioFileFormat; 3] {
+    match bitrate {
+        Bitrate::Bitrate96 => [
+            AudioFileFormat::OGG_VORBIS_96,
+            AudioFileFormat::OGG_VORBIS_160,
+            AudioFileFormat::OGG_VORBIS_320,
+        ],
+        Bitrate::Bitrate160 => [
+            AudioFileFormat::OGG_VORBIS_160,
+            AudioFileFormat::OGG_VORBIS_96,
+            AudioFileFormat::OGG_VORBIS_320,
+        ],
+        Bitrate::Bitrate320 => [
+            AudioFileFormat::OGG_VORBIS_320,
+            AudioFileFormat::OGG_VORBIS_160,
+            AudioFileFormat::OGG_VORBIS_96,
+        ],
+    }
+}
+
+/// The bytes per second of the format, which decide how fast it's fetched.
+fn data_rate(format: AudioFileFormat) -> usize {
+    let kbps = match format {
+        AudioFileFormat::OGG_VORBIS_96 => 96,
+        AudioFileFormat::OGG_VORBIS_320 => 320,
+        _ => 160,
+    };
+    kbps * 1024 / 8
+}
+
+/// Downloads the audio of the upcoming tracks in the queue into the cache,
+/// once the current track has played for a while.
+///
+/// librespot only preloads the next track, shortly before the current one
+/// ends. The cached tracks still play when the network drops for a while.
+pub(crate) async fn preload_upcoming(session: Session, depth: usize, bitrate: Bitrate) {
+    tokio::time::sleep(PRELOAD_DELAY).await;
+    if let Err(e) = try_preload_upcoming(&session, depth, bitrate).await {
+        warn!("Failed to preload the upcoming tracks: {}", e);
+    }
+}
+
+async fn try_preload_upcoming(
+    session: &Session,
+    depth: usize,
+    bitrate: Bitrate,
+) -> Result<(), Error> {
+    let uris = web_api::with_client(session, move |client| {
+        let queue = client.current_user_queue().map_err(Error::unavailable)?;
+        Ok(queue
+            .queue
+            .into_iter()
+            .take(depth)
+            .filter_map(|item| match item {
+                PlayableItem::Track(track) => track.id.map(|id| id.uri()),
+                PlayableItem::Episode(_) => None,
+            })
+            .collect::<Vec<_>>())
+    })
+    .await?;
+
+    for uri in uris {
+        let id = SpotifyId::from_uri(&uri)?;
+        let track = Track::get(session, &id).await?;
+        let Some((format, file_id)) = formats(bitrate)
+            .into_iter()
+            .find_map(|format| Some((format, *track.files.get(&format)?)))
+        else {
+            continue;
+        };
+        let cached = session
+            .cache()
+            .map_or(false, |cache| cache.file(file_id).is_some());
+        if cached {
+            continue;
+        }
+
+        debug!("Preloading {}", uri);
+        let mut file = AudioFile::open(session, file_id, data_rate(format)).await?;
+        file.get_stream_loader_controller()?.set_stream_mode();
+        // librespot stores the file in the cache once it has been read
+        // completely
+        tokio::task::spawn_blocking(move || io::copy(&mut file, &mut io::sink()))
+            .await
+            .map_err(Error::internal)??;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(
+            formats(Bitrate::Bitrate320)[0],
+            AudioFileFormat::OGG_VORBIS_320
+        );
+        assert_eq!(data_rate(AudioFileFormat::OGG_VORBIS_160), 20 * 1024);
+    }
+}
@@ -1,7 +1,18 @@
-use crate::error::Error;
-use librespot_playback::player::PlayerEvent;
-use log::info;
-use std::{collections::HashMap, process::Stdio};
+use crate::{
+    config::{EventHooks, HookOptions},
+    control,
+    error::Error,
+    events::{EventSubscriber, SpotifydEvent},
+    logging,
+    state::PlaybackState,
+    telemetry::Span,
+};
+use log::{debug, error, info, warn};
+use std::{
+    collections::HashMap,
+    process::Stdio,
+    time::{Duration, Instant},
+};
 use tokio::{
     io::{self, AsyncWriteExt},
     process::{self, Command},
@@ -25,183 +36,440 @@ pub(crate) fn run_program(shell: &str, cmd: &str) -> Result<String, Error> {
     Ok(s)
 }
 
+/// The arguments of `systemd-run` that run a command in a transient scope
+/// with the given limits.
+fn scope_args(options: &HookOptions, user: bool) -> Vec<String> {
+    let mut args = vec![
+        "--scope".to_string(),
+        "--quiet".to_string(),
+        "--collect".to_string(),
+    ];
+    if user {
+        args.push("--user".to_string());
+    }
+    // in a scope, systemd-run switches to the user itself before running the hook
+    if let Some(uid) = options.uid {
+        args.push(format!("--uid={}", uid));
+    }
+    if let Some(gid) = options.gid {
+        args.push(format!("--gid={}", gid));
+    }
+    if let Some(memory_max) = &options.memory_max {
+        args.push("-p".to_string());
+        args.push(format!("MemoryMax={}", memory_max));
+    }
+    if let Some(cpu_quota) = &options.cpu_quota {
+        args.push("-p".to_string());
+        args.push(format!("CPUQuota={}", cpu_quota));
+    }
+    args.push("--".to_string());
+    args
+}
+
+/// A command running the shell directly, as the configured user and group.
+fn shell_command(shell: &str, options: &HookOptions) -> Command {
+    let mut command = Command::new(shell);
+    #[cfg(unix)]
+    {
+        new_process_group(&mut command);
+        if let Some(gid) = options.gid {
+            command.gid(gid);
+        }
+        if let Some(uid) = options.uid {
+            command.uid(uid);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = options;
+    command
+}
+
+/// Starts the subprocess in a process group of its own, so that it can be
+/// killed along with the processes it started.
+#[cfg(unix)]
+fn new_process_group(command: &mut Command) {
+    // setpgid is async-signal-safe, as required between fork and exec
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setpgid(0, 0) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
 /// Spawns provided command in a subprocess using the provided shell.
-fn spawn_program(shell: &str, cmd: &str, env: HashMap<&str, String>) -> Result<Child, Error> {
+///
+/// If resource limits are configured, the subprocess is started in a
+/// transient systemd scope enforcing them. Without systemd, the limits are
+/// ignored.
+fn spawn_program(
+    shell: &str,
+    cmd: &str,
+    options: &HookOptions,
+    env: HashMap<&str, String>,
+) -> Result<Child, Error> {
     info!(
         "Running {:?} using {:?} with environment variables {:?}",
         cmd, shell, env
     );
-    let inner = Command::new(shell)
-        .arg("-c")
-        .arg(cmd)
-        .envs(env.iter())
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|e| Error::subprocess_with_err(shell, cmd, e))?;
-    let child = Child::new(cmd.to_string(), inner, shell.to_string());
+    let spawn = |mut command: Command| {
+        command
+            .arg("-c")
+            .arg(cmd)
+            .envs(env.iter())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+    };
+
+    let inner = if cfg!(target_os = "linux") && options.has_limits() {
+        // a system instance of spotifyd may use the system manager, everyone
+        // else has to use their user manager
+        let user = unsafe { libc::geteuid() } != 0;
+        let mut command = Command::new("systemd-run");
+        command.args(scope_args(options, user)).arg(shell);
+        #[cfg(unix)]
+        new_process_group(&mut command);
+        match spawn(command) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!("systemd-run is not available, running the hook without resource limits");
+                spawn(shell_command(shell, options))
+            }
+            result => result,
+        }
+    } else {
+        spawn(shell_command(shell, options))
+    };
+    let inner = inner.map_err(|e| Error::subprocess_with_err(shell, cmd, e))?;
+    let mut child = Child::new(cmd.to_string(), inner, shell.to_string());
+    child.timeout = options.timeout;
+    child.quiet = options.quiet;
     Ok(child)
 }
 
 /// Spawns provided command in a subprocess using the provided shell.
 /// Various environment variables are included in the subprocess's environment
-/// depending on the `PlayerEvent` that was passed in.
+/// depending on the `SpotifydEvent` that was passed in and the state of the
+/// playback. The whole event is written to its stdin as a line of JSON.
 pub(crate) fn spawn_program_on_event(
     shell: &str,
     cmd: &str,
-    event: PlayerEvent,
+    options: &HookOptions,
+    event: &SpotifydEvent,
+    state: &PlaybackState,
 ) -> Result<Child, Error> {
+    let env = event_env(event, state);
+    let fields = event.log_fields();
+    let mut child = logging::with_event_fields(fields, || spawn_program(shell, cmd, options, env))?;
+    let mut json = serde_json::to_vec(event).unwrap();
+    json.push(b'\n');
+    child.write_stdin(json);
+    Ok(child)
+}
+
+/// The environment variables describing the event, as passed to hooks.
+///
+/// Track changes and the start of the playback also carry the shuffle, repeat
+/// and autoplay flags of the state, so that scripts displaying the playback
+/// don't have to follow the events changing them.
+pub(crate) fn event_env(
+    event: &SpotifydEvent,
+    state: &PlaybackState,
+) -> HashMap<&'static str, String> {
     let mut env = HashMap::new();
+    env.insert("PLAYER_EVENT", event.name().to_string());
+    env.insert("ACTIVITY", state.activity().as_str().to_string());
     match event {
-        PlayerEvent::PlayRequestIdChanged { play_request_id } => {
-            env.insert("PLAYER_EVENT", "play_request_id_changed".to_string());
+        SpotifydEvent::PlayRequestIdChanged { play_request_id } => {
             env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
         }
-        PlayerEvent::Stopped {
+        SpotifydEvent::Stopped {
             track_id,
             play_request_id,
-        } => {
-            env.insert("PLAYER_EVENT", "stop".to_string());
-            env.insert("TRACK_ID", track_id.to_base62().unwrap());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
         }
-        PlayerEvent::Loading {
+        | SpotifydEvent::TimeToPreloadNextTrack {
             track_id,
             play_request_id,
-            position_ms,
-        } => {
-            env.insert("PLAYER_EVENT", "load".to_string());
-            env.insert("TRACK_ID", track_id.to_base62().unwrap());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
-            env.insert("POSITION_MS", position_ms.to_string());
         }
-        PlayerEvent::Preloading { track_id } => {
-            env.insert("PLAYER_EVENT", "preloading".to_string());
-            env.insert("TRACK_ID", track_id.to_base62().unwrap());
+        | SpotifydEvent::EndOfTrack {
+            track_id,
+            play_request_id,
         }
-        PlayerEvent::Playing {
+        | SpotifydEvent::Unavailable {
+            play_request_id,
             track_id,
+        }
+        | SpotifydEvent::UnavailableSkipped {
             play_request_id,
-            position_ms,
+            track_id,
         } => {
-            env.insert("PLAYER_EVENT", "play".to_string());
-            env.insert("TRACK_ID", track_id.to_base62().unwrap());
+            env.insert("TRACK_ID", track_id.clone());
             env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
-            env.insert("POSITION_MS", position_ms.to_string());
         }
-        PlayerEvent::Paused {
+        SpotifydEvent::Loading {
             track_id,
             play_request_id,
             position_ms,
-        } => {
-            env.insert("PLAYER_EVENT", "pause".to_string());
-            env.insert("TRACK_ID", track_id.to_base62().unwrap());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
-            env.insert("POSITION_MS", position_ms.to_string());
         }
-        PlayerEvent::TimeToPreloadNextTrack {
+        | SpotifydEvent::Playing {
             track_id,
             play_request_id,
-        } => {
-            env.insert("PLAYER_EVENT", "preload".to_string());
-            env.insert("TRACK_ID", track_id.to_base62().unwrap());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
+            position_ms,
         }
-        PlayerEvent::EndOfTrack {
+        | SpotifydEvent::Paused {
             track_id,
             play_request_id,
-        } => {
-            env.insert("PLAYER_EVENT", "endoftrack".to_string());
-            env.insert("TRACK_ID", track_id.to_base62().unwrap());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
+            position_ms,
         }
-        PlayerEvent::Unavailable {
+        | SpotifydEvent::PositionCorrection {
             play_request_id,
             track_id,
-        } => {
-            env.insert("PLAYER_EVENT", "unavailable".to_string());
-            env.insert("TRACK_ID", track_id.to_base62().unwrap());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
-        }
-        PlayerEvent::VolumeChanged { volume } => {
-            env.insert("PLAYER_EVENT", "volume_changed".to_string());
-            env.insert("VOLUME", volume.to_string());
+            position_ms,
         }
-        PlayerEvent::PositionCorrection {
+        | SpotifydEvent::Seeked {
             play_request_id,
             track_id,
             position_ms,
         } => {
-            env.insert("PLAYER_EVENT", "position_correction".to_string());
-            env.insert("TRACK_ID", track_id.to_base62().unwrap());
+            env.insert("TRACK_ID", track_id.clone());
             env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
             env.insert("POSITION_MS", position_ms.to_string());
         }
-        PlayerEvent::Seeked {
-            play_request_id,
+        SpotifydEvent::Preloading { track_id } | SpotifydEvent::QueueEnded { track_id } => {
+            env.insert("TRACK_ID", track_id.clone());
+        }
+        SpotifydEvent::BlockedSkipped {
             track_id,
-            position_ms,
+            blocked_uri,
         } => {
-            env.insert("PLAYER_EVENT", "seeked".to_string());
-            env.insert("TRACK_ID", track_id.to_base62().unwrap());
-            env.insert("PLAY_REQUEST_ID", play_request_id.to_string());
-            env.insert("POSITION_MS", position_ms.to_string());
+            env.insert("TRACK_ID", track_id.clone());
+            env.insert("BLOCKED_URI", blocked_uri.clone());
+        }
+        SpotifydEvent::VolumeChanged { volume } => {
+            env.insert("VOLUME", volume.to_string());
+            env.insert("VOLUME_DB", format!("{:.1}", control::volume_db(*volume)));
         }
-        PlayerEvent::TrackChanged { audio_item } => {
-            // TODO: Do we want other audio_item fields?
-            env.insert("PLAYER_EVENT", "track_changed".to_string());
-            env.insert("TRACK_ID", audio_item.track_id.to_base62().unwrap());
+        SpotifydEvent::TrackChanged(info) => {
+            env.insert("TRACK_ID", info.track_id.clone());
+            env.insert("URI", info.uri.clone());
+            env.insert("NAME", info.name.clone());
+            env.insert("DURATION_MS", info.duration_ms.to_string());
+            env.insert("IS_EXPLICIT", info.is_explicit.to_string());
+            env.insert("COVERS", info.covers.join("\n"));
+            env.insert("ITEM_TYPE", info.item_type.clone());
+            env.insert("ARTISTS", info.artists.join("\n"));
+            env.insert("ALBUM_ARTISTS", info.album_artists.join("\n"));
+            env.insert("ALBUM", info.album.clone());
         }
-        PlayerEvent::SessionConnected {
+        SpotifydEvent::SessionConnected {
             connection_id,
             user_name,
+            client_ip,
         } => {
-            env.insert("PLAYER_EVENT", "session_connected".to_string());
-            env.insert("CONNECTION_ID", connection_id);
-            env.insert("USER_NAME", user_name);
+            env.insert("CONNECTION_ID", connection_id.clone());
+            env.insert("USER_NAME", user_name.clone());
+            if let Some(client_ip) = client_ip {
+                env.insert("CLIENT_IP", client_ip.to_string());
+            }
         }
-        PlayerEvent::SessionDisconnected {
+        SpotifydEvent::SessionDisconnected {
             connection_id,
             user_name,
         } => {
-            env.insert("PLAYER_EVENT", "session_disconnected".to_string());
-            env.insert("CONNECTION_ID", connection_id);
-            env.insert("USER_NAME", user_name);
+            env.insert("CONNECTION_ID", connection_id.clone());
+            env.insert("USER_NAME", user_name.clone());
         }
-        PlayerEvent::SessionClientChanged {
+        SpotifydEvent::SessionClientChanged {
             client_id,
             client_name,
             client_brand_name,
             client_model_name,
+            client_ip,
         } => {
-            env.insert("PLAYER_EVENT", "session_client_changed".to_string());
-            env.insert("CLIENT_ID", client_id);
-            env.insert("CLIENT_NAME", client_name);
-            env.insert("CLIENT_BRAND_NAME", client_brand_name);
-            env.insert("CLIENT_MODEL_NAME", client_model_name);
-        }
-        PlayerEvent::ShuffleChanged { shuffle } => {
-            env.insert("PLAYER_EVENT", "shuffle_changed".to_string());
+            env.insert("CLIENT_ID", client_id.clone());
+            env.insert("CLIENT_NAME", client_name.clone());
+            env.insert("CLIENT_BRAND_NAME", client_brand_name.clone());
+            env.insert("CLIENT_MODEL_NAME", client_model_name.clone());
+            if let Some(client_ip) = client_ip {
+                env.insert("CLIENT_IP", client_ip.to_string());
+            }
+        }
+        SpotifydEvent::ShuffleChanged { shuffle } => {
             env.insert("SHUFFLE", shuffle.to_string());
         }
-        PlayerEvent::RepeatChanged { repeat } => {
-            env.insert("PLAYER_EVENT", "repeat_changed".to_string());
+        SpotifydEvent::RepeatChanged { repeat } => {
             env.insert("REPEAT", repeat.to_string());
         }
-        PlayerEvent::AutoPlayChanged { auto_play } => {
-            env.insert("PLAYER_EVENT", "auto_play_changed".to_string());
+        SpotifydEvent::AutoPlayChanged { auto_play } => {
             env.insert("AUTO_PLAY", auto_play.to_string());
         }
-        PlayerEvent::FilterExplicitContentChanged { filter } => {
-            env.insert(
-                "PLAYER_EVENT",
-                "filter_explicit_content_changed".to_string(),
-            );
+        SpotifydEvent::FilterExplicitContentChanged { filter } => {
             env.insert("FILTER", filter.to_string());
         }
+        SpotifydEvent::EgressLost { interface } | SpotifydEvent::EgressRestored { interface } => {
+            env.insert("INTERFACE", interface.clone());
+        }
+        SpotifydEvent::TakenOver { device_name } => {
+            if let Some(device_name) = device_name {
+                env.insert("DEVICE_NAME", device_name.clone());
+            }
+        }
+        SpotifydEvent::TakeoverRefused {
+            user_name,
+            client_name,
+        } => {
+            if let Some(user_name) = user_name {
+                env.insert("USER_NAME", user_name.clone());
+            }
+            if let Some(client_name) = client_name {
+                env.insert("CLIENT_NAME", client_name.clone());
+            }
+        }
+        SpotifydEvent::Idle { idle_ms, .. } => {
+            env.insert("IDLE_MS", idle_ms.to_string());
+        }
+    }
+    if matches!(
+        event,
+        SpotifydEvent::TrackChanged(_) | SpotifydEvent::Playing { .. }
+    ) {
+        env.insert("SHUFFLE", state.shuffle.to_string());
+        env.insert("REPEAT", state.repeat.to_string());
+        env.insert("AUTO_PLAY", state.autoplay.to_string());
+    }
+    env
+}
+
+/// How often spawning a hook is retried when the system is out of resources.
+const SPAWN_RETRIES: u32 = 3;
+/// The delay before the first retry, doubled for every further retry.
+const SPAWN_BACKOFF: Duration = Duration::from_millis(250);
+/// For how long after spawning a hook failed for lack of resources the hooks
+/// of low priority events are skipped.
+const PRESSURE_PERIOD: Duration = Duration::from_secs(30);
+/// The minimum time between two warnings about skipped hooks.
+const WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Events that only update details of the playback, whose hooks are skipped
+/// when the system is low on resources.
+fn is_low_priority(event: &SpotifydEvent) -> bool {
+    matches!(
+        event,
+        SpotifydEvent::PlayRequestIdChanged { .. }
+            | SpotifydEvent::Preloading { .. }
+            | SpotifydEvent::TimeToPreloadNextTrack { .. }
+            | SpotifydEvent::VolumeChanged { .. }
+            | SpotifydEvent::PositionCorrection { .. }
+    )
+}
+
+/// Runs the hooks for one event after another, degrading gracefully when the
+/// system runs out of memory or processes (e.g. on a Pi Zero).
+struct HookRunner {
+    shell: String,
+    hooks: EventHooks,
+    options: HookOptions,
+    /// The state as of the event the hook runs for, which may lag behind the
+    /// one of the main loop.
+    state: PlaybackState,
+    last_exhaustion: Option<Instant>,
+    last_warning: Option<Instant>,
+    skipped: usize,
+}
+
+impl HookRunner {
+    fn new(shell: String, hooks: EventHooks, options: HookOptions) -> Self {
+        Self {
+            shell,
+            hooks,
+            options,
+            state: PlaybackState::default(),
+            last_exhaustion: None,
+            last_warning: None,
+            skipped: 0,
+        }
+    }
+
+    fn under_pressure(&self, now: Instant) -> bool {
+        self.last_exhaustion
+            .map_or(false, |t| now.duration_since(t) < PRESSURE_PERIOD)
+    }
+
+    async fn run(&mut self, event: &SpotifydEvent) {
+        self.state.update(event);
+        let Some(cmd) = self.hooks.command(event).map(str::to_owned) else {
+            return;
+        };
+        if is_low_priority(event) && self.under_pressure(Instant::now()) {
+            self.skip(event);
+            return;
+        }
+
+        let mut span = Span::start("hook");
+        span.set_attribute("event", event.name());
+        let mut backoff = SPAWN_BACKOFF;
+        for attempt in 0..=SPAWN_RETRIES {
+            match spawn_program_on_event(&self.shell, &cmd, &self.options, event, &self.state) {
+                Ok(child) => {
+                    if let Err(e) = child.wait().await {
+                        span.set_error(&e);
+                        error!("{}", e);
+                    }
+                    return;
+                }
+                Err(e) if e.is_resource_exhaustion() => {
+                    debug!("{} (attempt {})", e, attempt + 1);
+                }
+                Err(e) => {
+                    span.set_error(&e);
+                    error!("{}", e);
+                    return;
+                }
+            }
+            self.last_exhaustion = Some(Instant::now());
+            if is_low_priority(event) || attempt == SPAWN_RETRIES {
+                break;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        span.set_error(&"the system is low on resources");
+        self.skip(event);
+    }
+
+    fn skip(&mut self, event: &SpotifydEvent) {
+        self.skipped += 1;
+        debug!("Skipped the hook for {} event", event.name());
+
+        let now = Instant::now();
+        if self
+            .last_warning
+            .map_or(true, |t| now.duration_since(t) >= WARNING_INTERVAL)
+        {
+            warn!(
+                "The system is low on resources, skipped the hook for {} events",
+                self.skipped
+            );
+            self.last_warning = Some(now);
+            self.skipped = 0;
+        }
+    }
+}
+
+/// Runs the hook of every event received by the subscriber, one at a time.
+pub(crate) async fn run_hooks(
+    shell: String,
+    hooks: EventHooks,
+    options: HookOptions,
+    mut events: EventSubscriber,
+) {
+    let mut runner = HookRunner::new(shell, hooks, options);
+    while let Some(event) = events.recv().await {
+        runner.run(&event).await;
     }
-    spawn_program(shell, cmd, env)
 }
 
 /// Wraps `tokio::process::Child` so that when this `Child` exits:
@@ -210,26 +478,78 @@ pub(crate) fn spawn_program_on_event(
 /// * unsuccesfully: It returns an error that includes the contents it's stderr
 ///   as well as information on the command that was run and the shell that
 ///   invoked it.
+/// * not within the timeout: It kills the process group of the subprocess and
+///   returns an error.
 pub(crate) struct Child {
     cmd: String,
     child: process::Child,
     shell: String,
+    timeout: Option<Duration>,
+    /// Whether the output is dropped instead of written to stdout.
+    quiet: bool,
 }
 
 impl Child {
     pub(crate) fn new(cmd: String, child: process::Child, shell: String) -> Self {
-        Self { cmd, child, shell }
+        Self {
+            cmd,
+            child,
+            shell,
+            timeout: None,
+            quiet: false,
+        }
+    }
+
+    /// Writes the data to the stdin of the subprocess and closes it, in the
+    /// background, so that a subprocess that doesn't read it can't block.
+    pub(crate) fn write_stdin(&mut self, data: Vec<u8>) {
+        if let Some(mut stdin) = self.child.stdin.take() {
+            tokio::spawn(async move {
+                // the subprocess may exit without reading it
+                if let Err(e) = stdin.write_all(&data).await {
+                    debug!("Failed to write to the stdin of a hook: {}", e);
+                }
+            });
+        }
     }
 
     pub(crate) async fn wait(self) -> Result<(), Error> {
-        let Child { cmd, shell, child } = self;
+        let Child {
+            cmd,
+            shell,
+            child,
+            timeout,
+            quiet,
+        } = self;
 
-        let output = child
-            .wait_with_output()
-            .await
-            .map_err(|e| Error::subprocess_with_err(&shell, &cmd, e))?;
+        let pid = child.id();
+        let output = match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, child.wait_with_output()).await {
+                Ok(output) => output,
+                Err(_) => {
+                    // tokio reaps the killed subprocess in the background
+                    #[cfg(unix)]
+                    if let Some(pid) = pid {
+                        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+                    }
+                    #[cfg(not(unix))]
+                    let _ = pid;
+                    let msg = format!("killed it after it ran for {:?}", timeout);
+                    return Err(Error::subprocess_with_str(&shell, &cmd, &msg));
+                }
+            },
+            None => child.wait_with_output().await,
+        }
+        .map_err(|e| Error::subprocess_with_err(&shell, &cmd, e))?;
 
-        if output.status.success() {
+        if output.status.success() && quiet {
+            debug!(
+                "Dropped the output of {:?}: {}",
+                cmd,
+                String::from_utf8_lossy(&output.stdout).trim_end()
+            );
+            Ok(())
+        } else if output.status.success() {
             // If successful, write subprocess's stdout to main process's stdout...
             let mut stdout = io::stdout();
 
@@ -254,3 +574,62 @@ impl Child {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope_args() {
+        let options = HookOptions {
+            memory_max: Some("64M".to_string()),
+            uid: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(
+            scope_args(&options, true),
+            [
+                "--scope",
+                "--quiet",
+                "--collect",
+                "--user",
+                "--uid=1000",
+                "-p",
+                "MemoryMax=64M",
+                "--"
+            ]
+        );
+        assert!(!scope_args(&options, false).contains(&"--user".to_string()));
+    }
+
+    #[test]
+    fn test_pressure_expires() {
+        let mut runner = HookRunner::new(
+            "sh".to_string(),
+            EventHooks::default(),
+            HookOptions::default(),
+        );
+        let now = Instant::now();
+        assert!(!runner.under_pressure(now));
+
+        runner.last_exhaustion = Some(now);
+        assert!(runner.under_pressure(now + Duration::from_secs(1)));
+        assert!(!runner.under_pressure(now + PRESSURE_PERIOD));
+    }
+
+    #[test]
+    fn test_warnings_are_rate_limited() {
+        let mut runner = HookRunner::new(
+            "sh".to_string(),
+            EventHooks::default(),
+            HookOptions::default(),
+        );
+        let event = SpotifydEvent::VolumeChanged { volume: 1 };
+
+        runner.skip(&event);
+        assert_eq!(runner.skipped, 0);
+        runner.skip(&event);
+        runner.skip(&event);
+        assert_eq!(runner.skipped, 2);
+    }
+}
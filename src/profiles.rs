@@ -0,0 +1,114 @@
+use crate::{cache_layout::CacheLayout, encryption::EncryptedCredentials};
+use log::info;
+use std::fmt;
+use tokio::sync::watch;
+
+/// The account of a `[profile.<name>]` section, with its device name and
+/// cache.
+#[derive(Clone)]
+pub struct Profile {
+    pub name: String,
+    pub username: String,
+    pub(crate) password: Option<String>,
+    pub device_name: String,
+    pub(crate) cache_layout: Option<CacheLayout>,
+    pub(crate) encrypted_credentials: Option<EncryptedCredentials>,
+}
+
+impl fmt::Debug for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Profile")
+            .field("name", &self.name)
+            .field("device_name", &self.device_name)
+            .field("cache_layout", &self.cache_layout)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The profiles of the config, and the one spotifyd is switched to, which
+/// can be changed while running. The main loop ends the current session and
+/// connects with the account of the profile instead.
+#[derive(Debug)]
+pub struct Profiles {
+    profiles: Vec<Profile>,
+    active: watch::Sender<Option<String>>,
+}
+
+impl Profiles {
+    pub(crate) fn new(profiles: Vec<Profile>, active: Option<String>) -> Self {
+        Self {
+            profiles,
+            active: watch::channel(active).0,
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.profiles
+            .iter()
+            .map(|profile| profile.name.clone())
+            .collect()
+    }
+
+    /// The profile in use, if spotifyd was started with one or switched to
+    /// one.
+    pub fn active(&self) -> Option<String> {
+        self.active.borrow().clone()
+    }
+
+    /// Switches to the account of the profile, failing when there's none of
+    /// the name.
+    pub fn switch(&self, name: &str) -> Result<(), String> {
+        if self.get(name).is_none() {
+            return Err(format!("there's no profile {:?}", name));
+        }
+        let changed = self.active.send_if_modified(|active| {
+            let changed = active.as_deref() != Some(name);
+            *active = Some(name.to_string());
+            changed
+        });
+        if changed {
+            info!("Switching to the profile {}", name);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<Option<String>> {
+        self.active.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(name: &str) -> Profile {
+        Profile {
+            name: name.to_string(),
+            username: name.to_string(),
+            password: None,
+            device_name: format!("{}'s speaker", name),
+            cache_layout: None,
+            encrypted_credentials: None,
+        }
+    }
+
+    #[test]
+    fn test_switch() {
+        let profiles = Profiles::new(vec![profile("alice"), profile("bob")], None);
+        let mut active = profiles.subscribe();
+        assert_eq!(profiles.names(), ["alice", "bob"]);
+
+        profiles.switch("bob").unwrap();
+        assert!(active.has_changed().unwrap());
+        assert_eq!(active.borrow_and_update().as_deref(), Some("bob"));
+        profiles.switch("bob").unwrap();
+        assert!(!active.has_changed().unwrap());
+
+        assert!(profiles.switch("carol").is_err());
+        assert_eq!(profiles.active().as_deref(), Some("bob"));
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How many clients are remembered before the idle ones are forgotten.
+const MAX_CLIENTS: usize = 256;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket per client, holding up to `burst` requests and refilled by
+/// `per_second` requests every second.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    burst: f64,
+    per_second: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(burst: u32, per_second: u32) -> Self {
+        Self {
+            burst: burst as f64,
+            per_second: per_second as f64,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Takes a request of the client's bucket, returning whether one was left.
+    pub(crate) fn allow(&self, client: IpAddr) -> bool {
+        self.allow_at(client, Instant::now())
+    }
+
+    fn allow_at(&self, client: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS {
+            // a client idle for that long has a full bucket again anyway
+            let refill = Duration::from_secs_f64(self.burst / self.per_second);
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < refill);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = RateLimiter::new(3, 2);
+        let dashboard = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        let phone = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 21));
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.allow_at(dashboard, start));
+        }
+        assert!(!limiter.allow_at(dashboard, start));
+        assert!(limiter.allow_at(phone, start));
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.allow_at(dashboard, later));
+        assert!(!limiter.allow_at(dashboard, later));
+    }
+}
@@ -0,0 +1,93 @@
+use crate::{
+    state::{PlaybackState, PlaybackStatus},
+    web_api,
+};
+use librespot_core::{session::Session, Error};
+use log::{error, info};
+use rspotify::prelude::*;
+use std::time::Duration;
+
+/// How long the device takes to show up in the Web API after connecting.
+const DEVICE_LOOKUP_DELAY: Duration = Duration::from_secs(2);
+
+/// Where the playback was when the session was lost.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Interruption {
+    pub(crate) track_id: String,
+    pub(crate) position_ms: u32,
+}
+
+impl Interruption {
+    /// The interrupted playback, if the state was playing.
+    pub(crate) fn of(state: &PlaybackState) -> Option<Self> {
+        if state.status != PlaybackStatus::Playing {
+            return None;
+        }
+        Some(Self {
+            track_id: state.track_id.clone()?,
+            position_ms: state.position_ms(),
+        })
+    }
+}
+
+/// Resumes the interrupted playback on this device once the session
+/// reconnected, unless another device has started playing in the meantime.
+pub(crate) async fn resume_interrupted(
+    session: Session,
+    device_name: String,
+    interruption: Interruption,
+) {
+    tokio::time::sleep(DEVICE_LOOKUP_DELAY).await;
+    let result = web_api::with_client(&session, move |client| {
+        let playback = client
+            .current_playback(None, None::<Vec<_>>)
+            .map_err(Error::unavailable)?;
+        if let Some(playback) = playback.filter(|playback| playback.is_playing) {
+            if playback.device.name != device_name {
+                info!(
+                    "Not resuming the playback, {} is playing",
+                    playback.device.name
+                );
+                return Ok(());
+            }
+        }
+        info!(
+            "Resuming the playback of {} at {}ms",
+            interruption.track_id, interruption.position_ms
+        );
+        let device_id = web_api::device_id(&client, &device_name)?;
+        client
+            .transfer_playback(&device_id, Some(true))
+            .map_err(Error::unavailable)?;
+        client
+            .seek_track(
+                chrono::Duration::milliseconds(interruption.position_ms as i64),
+                Some(&device_id),
+            )
+            .map_err(Error::unavailable)
+    })
+    .await;
+    if let Err(e) = result {
+        error!("Failed to resume the playback after reconnecting: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interruption() {
+        let mut state = PlaybackState {
+            track_id: Some("4uLU6hMCjMI75M1A2tKUQC".to_string()),
+            ..Default::default()
+        };
+        state.status = PlaybackStatus::Paused;
+        assert_eq!(Interruption::of(&state), None);
+        state.status = PlaybackStatus::Playing;
+        let interruption = Interruption::of(&state).unwrap();
+        assert_eq!(interruption.track_id, "4uLU6hMCjMI75M1A2tKUQC");
+        state.track_id = None;
+        assert_eq!(Interruption::of(&state), None);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{
+    config::{ReplayArgs, SpotifydConfig},
+    error::ParseError,
+    events::{EventBus, EventSubscriber, SpotifydEvent},
+    process::run_hooks,
+};
+use color_eyre::eyre::{self, Context};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// An event of a recording, as stored in one line of the recording file.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct RecordedEvent {
+    /// The time since the recording started.
+    elapsed_ms: u64,
+    #[serde(flatten)]
+    event: SpotifydEvent,
+}
+
+/// Writes every event received by the subscriber as a JSON line to the file.
+pub(crate) async fn record_events(mut file: File, mut events: EventSubscriber) {
+    let start = Instant::now();
+    while let Some(event) = events.recv().await {
+        let recorded = RecordedEvent {
+            elapsed_ms: start.elapsed().as_millis() as u64,
+            event,
+        };
+        let line = serde_json::to_string(&recorded).unwrap();
+        if let Err(e) = writeln!(file, "{}", line) {
+            error!("Failed to record event, stopping the recording: {}", e);
+            return;
+        }
+    }
+}
+
+/// How much faster than recorded the events are replayed, e.g. `10x`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Speed(f64);
+
+impl FromStr for Speed {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let factor = s.strip_suffix('x').unwrap_or(s);
+        match factor.parse::<f64>() {
+            Ok(factor) if factor > 0.0 && factor.is_finite() => Ok(Speed(factor)),
+            _ => Err(ParseError::new(format!(
+                "{:?} is not a valid speed, use e.g. \"10x\"",
+                s
+            ))),
+        }
+    }
+}
+
+fn read_recording(file: File) -> eyre::Result<Vec<RecordedEvent>> {
+    let mut recording = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str(&line)
+            .wrap_err_with(|| format!("invalid event in line {}", number + 1))?;
+        recording.push(event);
+    }
+    Ok(recording)
+}
+
+/// Replays a recording against the configured hooks, keeping the recorded
+/// timing (scaled by the given speed) between the events.
+pub async fn replay(config: &SpotifydConfig, args: &ReplayArgs) -> eyre::Result<()> {
+    let file = File::open(&args.file)
+        .wrap_err_with(|| format!("could not open {}", args.file.display()))?;
+    let recording = read_recording(file)?;
+
+    let event_bus = EventBus::default();
+    let hooks = (!config.hooks.is_empty()).then(|| {
+        tokio::spawn(run_hooks(
+            config.shell.clone(),
+            config.hooks.clone(),
+            config.hook_options.clone(),
+            event_bus.subscribe_queued(),
+        ))
+    });
+
+    let start = Instant::now();
+    for recorded in recording {
+        let due = Duration::from_millis(recorded.elapsed_ms).div_f64(args.speed.0);
+        tokio::time::sleep_until((start + due).into()).await;
+        println!("{}", serde_json::to_string(&recorded.event).unwrap());
+        event_bus.publish(recorded.event);
+    }
+
+    // closes the event bus, so that the hooks finish
+    drop(event_bus);
+    if let Some(hooks) = hooks {
+        let _ = hooks.await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_speed() {
+        assert_eq!("10x".parse::<Speed>().unwrap(), Speed(10.0));
+        assert_eq!("0.5".parse::<Speed>().unwrap(), Speed(0.5));
+        assert!("0x".parse::<Speed>().is_err());
+        assert!("fast".parse::<Speed>().is_err());
+    }
+
+    #[test]
+    fn test_recorded_event_roundtrip() {
+        let recorded = RecordedEvent {
+            elapsed_ms: 1500,
+            event: SpotifydEvent::VolumeChanged { volume: 100 },
+        };
+        let line = serde_json::to_string(&recorded).unwrap();
+        assert_eq!(
+            line,
+            r#"{"elapsed_ms":1500,"event":"volume_changed","volume":100}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<RecordedEvent>(&line).unwrap(),
+            recorded
+        );
+    }
+}
@@ -0,0 +1,281 @@
+use crate::{
+    control::{ControlHandle, PlaybackControl},
+    events::{EventSubscriber, SpotifydEvent},
+    state::{PlaybackState, PlaybackStatus, SharedPlaybackState},
+};
+use log::{error, info, warn};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// How often the position of the current item is taken.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the positions are written to the file at most while playing.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// An item left closer than this to its end counts as finished.
+const END_MARGIN_MS: u32 = 30_000;
+/// Positions closer than this to the one the item starts at aren't worth a
+/// seek.
+const START_MARGIN_MS: u32 = 10_000;
+
+/// The positions at which long items were left, by their URI.
+///
+/// If a path is given, the positions are stored in that file as a JSON
+/// object, so that they are remembered across restarts.
+#[derive(Debug, Default)]
+struct Positions {
+    path: Option<PathBuf>,
+    positions: HashMap<String, u32>,
+    changed: bool,
+}
+
+impl Positions {
+    fn load(path: Option<PathBuf>) -> Self {
+        let positions = match path.as_ref().map(fs::read_to_string) {
+            Some(Ok(content)) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring the invalid resume positions: {}", e);
+                HashMap::new()
+            }),
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => {
+                warn!("Failed to read the resume positions: {}", e);
+                HashMap::new()
+            }
+            _ => HashMap::new(),
+        };
+        Self {
+            path,
+            positions,
+            changed: false,
+        }
+    }
+
+    fn get(&self, uri: &str) -> Option<u32> {
+        self.positions.get(uri).copied()
+    }
+
+    /// Remembers the position of the item, or forgets it once the item is
+    /// (nearly) finished.
+    fn set(&mut self, uri: &str, position_ms: u32, duration_ms: u32) {
+        if position_ms.saturating_add(END_MARGIN_MS) >= duration_ms {
+            self.changed |= self.positions.remove(uri).is_some();
+        } else if self.positions.insert(uri.to_string(), position_ms) != Some(position_ms) {
+            self.changed = true;
+        }
+    }
+
+    fn save(&mut self) {
+        let Some(ref path) = self.path else {
+            return;
+        };
+        if !self.changed {
+            return;
+        }
+        let content = serde_json::to_string(&self.positions).unwrap();
+        match fs::write(path, content) {
+            Ok(()) => self.changed = false,
+            Err(e) => warn!("Failed to write {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// A long item that is resumed where it was left.
+#[derive(Debug)]
+struct Item {
+    uri: String,
+    track_id: String,
+    duration_ms: u32,
+    /// Whether the item was sought to its remembered position, or didn't need
+    /// to be. Until then, its own position isn't remembered.
+    restored: bool,
+}
+
+struct Resumer {
+    positions: Positions,
+    min_duration: Duration,
+    control: ControlHandle,
+    item: Option<Item>,
+    last_save: Instant,
+}
+
+impl Resumer {
+    /// Remembers the position of the current item, and seeks it back to where
+    /// it was left once it plays.
+    fn update(&mut self, state: &PlaybackState) {
+        let Some(ref mut item) = self.item else {
+            return;
+        };
+        if state.track_id.as_deref() != Some(&item.track_id) {
+            return;
+        }
+        if item.restored {
+            self.positions
+                .set(&item.uri, state.position_ms(), item.duration_ms);
+            return;
+        }
+        if state.status != PlaybackStatus::Playing {
+            return;
+        }
+        item.restored = true;
+        let Some(position_ms) = self.positions.get(&item.uri) else {
+            return;
+        };
+        if position_ms > state.position_ms() + START_MARGIN_MS {
+            info!(
+                "Resuming {} at {}s",
+                item.uri,
+                Duration::from_millis(position_ms as u64).as_secs()
+            );
+            if let Err(e) = self.control.seek(position_ms) {
+                error!("Failed to resume {}: {}", item.uri, e);
+            }
+        }
+    }
+
+    fn handle(&mut self, event: &SpotifydEvent, playback_state: &SharedPlaybackState) {
+        match event {
+            SpotifydEvent::TrackChanged(info) => {
+                let long = Duration::from_millis(info.duration_ms as u64) >= self.min_duration;
+                self.item = long.then(|| Item {
+                    uri: info.uri.clone(),
+                    track_id: info.track_id.clone(),
+                    duration_ms: info.duration_ms,
+                    restored: false,
+                });
+                // the previous item was left
+                self.save();
+            }
+            SpotifydEvent::EndOfTrack { track_id, .. } => {
+                if self.item.as_ref().map(|item| &item.track_id) == Some(track_id) {
+                    let item = self.item.take().unwrap();
+                    self.positions
+                        .set(&item.uri, item.duration_ms, item.duration_ms);
+                }
+                self.save();
+                return;
+            }
+            _ => (),
+        }
+        self.update(&playback_state.read().unwrap());
+        if matches!(
+            event,
+            SpotifydEvent::Paused { .. } | SpotifydEvent::Stopped { .. }
+        ) {
+            self.save();
+        }
+    }
+
+    fn poll(&mut self, playback_state: &SharedPlaybackState) {
+        self.update(&playback_state.read().unwrap());
+        if self.last_save.elapsed() >= SAVE_INTERVAL {
+            self.save();
+        }
+    }
+
+    fn save(&mut self) {
+        self.positions.save();
+        self.last_save = Instant::now();
+    }
+}
+
+/// Remembers where the items at least `min_duration` long, like audiobooks,
+/// episodes and long mixes, were left, and seeks back to that position when
+/// they're played again.
+pub(crate) async fn resume_positions(
+    path: Option<PathBuf>,
+    min_duration: Duration,
+    control: ControlHandle,
+    playback_state: SharedPlaybackState,
+    mut events: EventSubscriber,
+) {
+    let mut resumer = Resumer {
+        positions: Positions::load(path),
+        min_duration,
+        control,
+        item: None,
+        last_save: Instant::now(),
+    };
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => resumer.handle(&event, &playback_state),
+                None => return,
+            },
+            _ = interval.tick() => resumer.poll(&playback_state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{audit::CommandSource, control::ControlCommand, events::TrackInfo};
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_resumes_long_items() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut positions = Positions::default();
+        positions.set("spotify:episode:long", 600_000, 3_600_000);
+        let mut resumer = Resumer {
+            positions,
+            min_duration: Duration::from_secs(1200),
+            control: ControlHandle::new(tx, CommandSource::Spotifyd),
+            item: None,
+            last_save: Instant::now(),
+        };
+        let playback_state = SharedPlaybackState::default();
+        let emit = |resumer: &mut Resumer, event: SpotifydEvent| {
+            playback_state.write().unwrap().update(&event);
+            resumer.handle(&event, &playback_state);
+        };
+
+        emit(
+            &mut resumer,
+            SpotifydEvent::TrackChanged(TrackInfo {
+                track_id: "long".to_string(),
+                uri: "spotify:episode:long".to_string(),
+                duration_ms: 3_600_000,
+                ..Default::default()
+            }),
+        );
+        emit(
+            &mut resumer,
+            SpotifydEvent::Playing {
+                play_request_id: 1,
+                track_id: "long".to_string(),
+                position_ms: 0,
+            },
+        );
+        assert_eq!(
+            rx.try_recv(),
+            Ok((
+                ControlCommand::Seek(600_000),
+                CommandSource::Spotifyd,
+                Default::default()
+            ))
+        );
+
+        emit(
+            &mut resumer,
+            SpotifydEvent::Paused {
+                play_request_id: 1,
+                track_id: "long".to_string(),
+                position_ms: 900_000,
+            },
+        );
+        assert_eq!(resumer.positions.get("spotify:episode:long"), Some(900_000));
+
+        emit(
+            &mut resumer,
+            SpotifydEvent::EndOfTrack {
+                play_request_id: 1,
+                track_id: "long".to_string(),
+            },
+        );
+        assert_eq!(resumer.positions.get("spotify:episode:long"), None);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{
+    config::ScheduledPlaylist,
+    state::{PlaybackStatus, SharedPlaybackState},
+    web_api,
+};
+use chrono::{Duration, Local, NaiveDateTime};
+use librespot_core::session::Session;
+use log::{error, info};
+
+/// Finds the next switch of the schedule strictly after the given time,
+/// wrapping around to the next day.
+fn next_switch(
+    schedule: &[ScheduledPlaylist],
+    after: NaiveDateTime,
+) -> Option<(NaiveDateTime, &ScheduledPlaylist)> {
+    schedule
+        .iter()
+        .map(|entry| {
+            let mut at = after.date().and_time(entry.at.0);
+            if at <= after {
+                at += Duration::days(1);
+            }
+            (at, entry)
+        })
+        .min_by_key(|(at, _)| *at)
+}
+
+/// Switches between the playlists of the schedule at their times of the day,
+/// as long as this device is playing.
+pub(crate) async fn run_schedule(
+    schedule: Vec<ScheduledPlaylist>,
+    session: Session,
+    device_name: String,
+    playback_state: SharedPlaybackState,
+) {
+    let mut after = Local::now().naive_local();
+    loop {
+        let Some((at, entry)) = next_switch(&schedule, after) else {
+            return;
+        };
+        let now = Local::now().naive_local();
+        tokio::time::sleep((at - now).to_std().unwrap_or_default()).await;
+        after = at;
+
+        // another device is playing, or the music has been stopped on purpose
+        if playback_state.read().unwrap().status != PlaybackStatus::Playing {
+            info!(
+                "Not switching to {}, this device isn't playing",
+                entry.playlist
+            );
+            continue;
+        }
+        info!("Switching to {} as scheduled", entry.playlist);
+        if let Err(e) = web_api::play_context(&session, &device_name, &entry.playlist).await {
+            error!("Failed to switch to {}: {}", entry.playlist, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TimeOfDay;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_next_switch() {
+        let entry = |at: &str, playlist: &str| ScheduledPlaylist {
+            at: at.parse::<TimeOfDay>().unwrap(),
+            playlist: playlist.to_string(),
+        };
+        let schedule = vec![
+            entry("08:00", "spotify:playlist:jazz"),
+            entry("14:00", "spotify:playlist:pop"),
+        ];
+        let day = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        let next = |h: u32, m: u32| {
+            let (at, entry) = next_switch(&schedule, day.and_hms_opt(h, m, 0).unwrap()).unwrap();
+            (at, entry.playlist.as_str())
+        };
+
+        assert_eq!(
+            next(9, 30),
+            (day.and_hms_opt(14, 0, 0).unwrap(), "spotify:playlist:pop")
+        );
+        // a switch happening right now is already over
+        assert_eq!(
+            next(14, 0),
+            (
+                day.succ_opt().unwrap().and_hms_opt(8, 0, 0).unwrap(),
+                "spotify:playlist:jazz"
+            )
+        );
+        assert!(next_switch(&[], day.and_hms_opt(0, 0, 0).unwrap()).is_none());
+    }
+}
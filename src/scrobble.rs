@@ -0,0 +1,403 @@
+use crate::{
+    events::{EventSubscriber, SpotifydEvent, TrackInfo},
+    state::{PlaybackState, PlaybackStatus, SharedPlaybackState},
+};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs, io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Tracks shorter than this aren't scrobbled.
+const MIN_DURATION: Duration = Duration::from_secs(30);
+/// A track is scrobbled once it has been listened to for half of its
+/// duration, or for this long.
+const MAX_LISTEN: Duration = Duration::from_secs(240);
+/// How often the listening time is checked, and the queue flushed.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// How long the submission waits after a failure before it's retried.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How many listens are kept while the service can't be reached.
+const MAX_QUEUED: usize = 1000;
+/// How many listens are submitted at once.
+const BATCH_SIZE: usize = 50;
+
+/// A track that has been listened to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Listen {
+    pub(crate) artist: String,
+    pub(crate) track: String,
+    pub(crate) album: String,
+    pub(crate) album_artist: Option<String>,
+    pub(crate) duration_ms: u32,
+    /// When the track started, in seconds since the epoch.
+    pub(crate) started_at: u64,
+    pub(crate) uri: String,
+}
+
+impl Listen {
+    fn new(info: &TrackInfo, started_at: SystemTime) -> Self {
+        Self {
+            artist: info.artists.first().cloned().unwrap_or_default(),
+            track: info.name.clone(),
+            album: info.album.clone(),
+            album_artist: info.album_artists.first().cloned(),
+            duration_ms: info.duration_ms,
+            started_at: started_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            uri: info.uri.clone(),
+        }
+    }
+}
+
+/// Why a submission failed.
+#[derive(Debug)]
+pub(crate) enum ScrobbleError {
+    /// The service may accept it later, e.g. because it can't be reached.
+    Transient(String),
+    /// The service won't ever accept it, e.g. because the account is invalid.
+    Rejected(String),
+}
+
+/// A service the listens are submitted to. The calls block.
+pub(crate) trait Scrobbler: Send + Sync + 'static {
+    /// The name of the service, for the logs.
+    fn name(&self) -> &'static str;
+
+    fn now_playing(&self, listen: &Listen) -> Result<(), ScrobbleError>;
+
+    fn scrobble(&self, listens: &[Listen]) -> Result<(), ScrobbleError>;
+}
+
+#[derive(Debug)]
+enum Update {
+    NowPlaying(Listen),
+    Scrobble(Listen),
+}
+
+/// The track being listened to, and for how long so far.
+#[derive(Debug)]
+struct Current {
+    listen: Listen,
+    track_id: String,
+    listened: Duration,
+    playing_since: Option<Instant>,
+    announced: bool,
+    scrobbled: bool,
+}
+
+impl Current {
+    fn listened(&self, now: Instant) -> Duration {
+        self.listened
+            + self
+                .playing_since
+                .map_or(Duration::ZERO, |since| now - since)
+    }
+
+    /// The scrobble of the track, once it has been listened to for long
+    /// enough. Seeking doesn't count, only the time spent playing.
+    fn check(&mut self, now: Instant) -> Option<Update> {
+        let duration = Duration::from_millis(self.listen.duration_ms as u64);
+        if self.scrobbled || duration < MIN_DURATION {
+            return None;
+        }
+        if self.listened(now) < (duration / 2).min(MAX_LISTEN) {
+            return None;
+        }
+        self.scrobbled = true;
+        Some(Update::Scrobble(self.listen.clone()))
+    }
+}
+
+/// Follows the playback to find out which tracks are listened to.
+#[derive(Debug, Default)]
+struct Tracker {
+    current: Option<Current>,
+}
+
+impl Tracker {
+    fn start(&mut self, info: &TrackInfo) {
+        // episodes aren't scrobbled
+        self.current = (info.item_type == "track").then(|| Current {
+            listen: Listen::new(info, SystemTime::now()),
+            track_id: info.track_id.clone(),
+            listened: Duration::ZERO,
+            playing_since: None,
+            announced: false,
+            scrobbled: false,
+        });
+    }
+
+    fn finish(&mut self, now: Instant) -> Option<Update> {
+        self.current.take()?.check(now)
+    }
+
+    /// Follows the state, after the event has been applied to it.
+    fn handle(
+        &mut self,
+        event: &SpotifydEvent,
+        state: &PlaybackState,
+        now: Instant,
+    ) -> Vec<Update> {
+        let mut updates = Vec::new();
+        match event {
+            SpotifydEvent::TrackChanged(info) => {
+                updates.extend(self.finish(now));
+                self.start(info);
+            }
+            SpotifydEvent::EndOfTrack { .. } => {
+                updates.extend(self.finish(now));
+                return updates;
+            }
+            // the same track played again, e.g. on repeat
+            SpotifydEvent::Playing { track_id, .. } if self.current.is_none() => {
+                if let Some(track) = state.track.as_ref().filter(|t| &t.track_id == track_id) {
+                    self.start(track);
+                }
+            }
+            _ => (),
+        }
+        let Some(ref mut current) = self.current else {
+            return updates;
+        };
+        let playing = state.status == PlaybackStatus::Playing
+            && state.track_id.as_deref() == Some(&current.track_id);
+        match (playing, current.playing_since) {
+            (true, None) => current.playing_since = Some(now),
+            (false, Some(since)) => {
+                current.listened += now - since;
+                current.playing_since = None;
+            }
+            _ => (),
+        }
+        if playing && !current.announced {
+            current.announced = true;
+            updates.push(Update::NowPlaying(current.listen.clone()));
+        }
+        updates.extend(current.check(now));
+        updates
+    }
+
+    fn poll(&mut self, now: Instant) -> Option<Update> {
+        self.current.as_mut()?.check(now)
+    }
+}
+
+/// The listens that haven't been submitted yet.
+///
+/// If a path is given, they are stored in that file as JSON, so that they
+/// are submitted after a restart.
+#[derive(Debug, Default)]
+struct Queue {
+    path: Option<PathBuf>,
+    listens: VecDeque<Listen>,
+}
+
+impl Queue {
+    fn load(path: Option<PathBuf>) -> Self {
+        let listens = match path.as_ref().map(fs::read_to_string) {
+            Some(Ok(content)) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring the invalid queued listens: {}", e);
+                VecDeque::new()
+            }),
+            Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => {
+                warn!("Failed to read the queued listens: {}", e);
+                VecDeque::new()
+            }
+            _ => VecDeque::new(),
+        };
+        Self { path, listens }
+    }
+
+    fn save(&self) {
+        let Some(ref path) = self.path else {
+            return;
+        };
+        let result = if self.listens.is_empty() {
+            fs::remove_file(path).or_else(|e| match e.kind() {
+                io::ErrorKind::NotFound => Ok(()),
+                _ => Err(e),
+            })
+        } else {
+            fs::write(path, serde_json::to_string(&self.listens).unwrap())
+        };
+        if let Err(e) = result {
+            warn!("Failed to write {}: {}", path.display(), e);
+        }
+    }
+
+    fn push(&mut self, listen: Listen) {
+        if self.listens.len() == MAX_QUEUED {
+            self.listens.pop_front();
+        }
+        self.listens.push_back(listen);
+        self.save();
+    }
+}
+
+struct Submitter<S> {
+    service: Arc<S>,
+    queue: Queue,
+    retry_at: Option<Instant>,
+}
+
+impl<S: Scrobbler> Submitter<S> {
+    async fn submit(&mut self, update: Update) {
+        match update {
+            Update::NowPlaying(listen) => {
+                let service = self.service.clone();
+                let result =
+                    tokio::task::spawn_blocking(move || service.now_playing(&listen)).await;
+                if let Ok(Err(e)) = result {
+                    debug!(
+                        "Failed to update the now playing track on {}: {:?}",
+                        self.service.name(),
+                        e
+                    );
+                }
+            }
+            Update::Scrobble(listen) => {
+                info!("Scrobbling {} to {}", listen.uri, self.service.name());
+                self.queue.push(listen);
+                self.flush().await;
+            }
+        }
+    }
+
+    /// Submits the queued listens, unless the last submission failed only
+    /// recently.
+    async fn flush(&mut self) {
+        if self
+            .retry_at
+            .map_or(false, |retry_at| Instant::now() < retry_at)
+        {
+            return;
+        }
+        while !self.queue.listens.is_empty() {
+            let count = self.queue.listens.len().min(BATCH_SIZE);
+            let batch: Vec<_> = self.queue.listens.iter().take(count).cloned().collect();
+            let service = self.service.clone();
+            let result = tokio::task::spawn_blocking(move || service.scrobble(&batch))
+                .await
+                .unwrap();
+            match result {
+                Ok(()) => self.retry_at = None,
+                Err(ScrobbleError::Transient(e)) => {
+                    debug!(
+                        "Failed to submit {} listens to {}, retrying later: {}",
+                        self.queue.listens.len(),
+                        self.service.name(),
+                        e
+                    );
+                    self.retry_at = Some(Instant::now() + RETRY_INTERVAL);
+                    return;
+                }
+                Err(ScrobbleError::Rejected(e)) => {
+                    warn!("{} rejected {} listens: {}", self.service.name(), count, e);
+                }
+            }
+            self.queue.listens.drain(..count);
+            self.queue.save();
+        }
+    }
+}
+
+/// Submits the tracks listened to the service, and the one playing as it
+/// starts. Listens that can't be submitted are kept in the queue file, if one
+/// is given, and retried.
+pub(crate) async fn scrobble<S: Scrobbler>(
+    service: S,
+    queue_path: Option<PathBuf>,
+    playback_state: SharedPlaybackState,
+    mut events: EventSubscriber,
+) {
+    let mut tracker = Tracker::default();
+    let mut submitter = Submitter {
+        service: Arc::new(service),
+        queue: Queue::load(queue_path),
+        retry_at: None,
+    };
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        let updates = tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => {
+                    let state = playback_state.read().unwrap();
+                    tracker.handle(&event, &state, Instant::now())
+                }
+                None => return,
+            },
+            _ = interval.tick() => {
+                submitter.flush().await;
+                tracker.poll(Instant::now()).into_iter().collect()
+            }
+        };
+        for update in updates {
+            submitter.submit(update).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrobbles_listened_tracks() {
+        let mut tracker = Tracker::default();
+        let mut state = PlaybackState::default();
+        let start = Instant::now();
+        let mut emit = |event: SpotifydEvent, seconds: u64| {
+            state.update(&event);
+            tracker.handle(&event, &state, start + Duration::from_secs(seconds))
+        };
+        let playing = |position_ms| SpotifydEvent::Playing {
+            play_request_id: 1,
+            track_id: "song".to_string(),
+            position_ms,
+        };
+
+        let info = TrackInfo {
+            track_id: "song".to_string(),
+            uri: "spotify:track:song".to_string(),
+            name: "Song".to_string(),
+            duration_ms: 200_000,
+            item_type: "track".to_string(),
+            artists: vec!["Artist".to_string()],
+            ..Default::default()
+        };
+        assert!(emit(SpotifydEvent::TrackChanged(info), 0).is_empty());
+        let updates = emit(playing(0), 0);
+        assert!(
+            matches!(updates[..], [Update::NowPlaying(ref listen)] if listen.artist == "Artist")
+        );
+
+        // seeking ahead doesn't count as listening
+        let seeked = SpotifydEvent::Seeked {
+            play_request_id: 1,
+            track_id: "song".to_string(),
+            position_ms: 150_000,
+        };
+        assert!(emit(seeked, 30).is_empty());
+        let paused = SpotifydEvent::Paused {
+            play_request_id: 1,
+            track_id: "song".to_string(),
+            position_ms: 160_000,
+        };
+        assert!(emit(paused, 40).is_empty());
+        let updates = emit(playing(160_000), 1000);
+        assert!(updates.is_empty());
+        assert!(tracker.poll(start + Duration::from_secs(1059)).is_none());
+        assert!(matches!(
+            tracker.poll(start + Duration::from_secs(1060)),
+            Some(Update::Scrobble(_))
+        ));
+        assert!(tracker.poll(start + Duration::from_secs(1100)).is_none());
+    }
+}
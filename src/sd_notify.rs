@@ -0,0 +1,225 @@
+//! The [notifications] with which spotifyd tells systemd, when started with
+//! `Type=notify`, that it's ready and that it's still alive.
+//!
+//! [notifications]: https://www.freedesktop.org/software/systemd/man/sd_notify.html
+
+use futures::future;
+use log::{debug, warn};
+use std::{future::Future, io, sync::Arc, time::Duration};
+use tokio::time::Interval;
+
+/// Sends the notifications to systemd if it listens to them, and does
+/// nothing otherwise.
+#[derive(Clone, Default)]
+pub(crate) struct Notifier(Option<Arc<Socket>>);
+
+impl Notifier {
+    /// The notifier of the socket systemd passed in `NOTIFY_SOCKET`.
+    pub(crate) fn from_env() -> Self {
+        Self(Socket::from_env().map(Arc::new))
+    }
+
+    /// Sends the state, e.g. `READY=1` or `STATUS=Connecting`.
+    pub(crate) fn notify(&self, state: &str) {
+        if let Some(ref socket) = self.0 {
+            if let Err(e) = socket.send(state) {
+                warn!("Failed to notify systemd: {}", e);
+            }
+        }
+    }
+
+    pub(crate) fn watchdog(&self) -> Watchdog {
+        let interval = self
+            .0
+            .as_ref()
+            .and_then(|socket| socket.watchdog_timeout())
+            .map(|timeout| {
+                debug!("Pinging the watchdog of systemd every {:?}", timeout / 2);
+                tokio::time::interval(timeout / 2)
+            });
+        Watchdog {
+            notifier: self.clone(),
+            interval,
+        }
+    }
+}
+
+/// Pings the watchdog of systemd at half its timeout, as long as the loop
+/// polling it runs, so that systemd restarts spotifyd when the loop is stuck.
+pub(crate) struct Watchdog {
+    notifier: Notifier,
+    interval: Option<Interval>,
+}
+
+impl Watchdog {
+    /// Waits for the time of the next ping and sends it. Never resolves
+    /// without a watchdog.
+    pub(crate) async fn ping(&mut self) {
+        match self.interval {
+            Some(ref mut interval) => {
+                interval.tick().await;
+                self.notifier.notify("WATCHDOG=1");
+            }
+            None => future::pending().await,
+        }
+    }
+
+    /// Awaits the future, pinging the watchdog meanwhile.
+    pub(crate) async fn run<F: Future>(&mut self, future: F) -> F::Output {
+        tokio::pin!(future);
+        loop {
+            tokio::select! {
+                output = &mut future => return output,
+                _ = self.ping() => (),
+            }
+        }
+    }
+}
+
+/// The timeout of the watchdog, if it's enabled for this process.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn watchdog_timeout(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = usec?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+#[cfg(target_os = "linux")]
+struct Socket {
+    socket: std::os::unix::net::UnixDatagram,
+    address: libc::sockaddr_un,
+    length: libc::socklen_t,
+    watchdog_timeout: Option<Duration>,
+}
+
+#[cfg(target_os = "linux")]
+impl Socket {
+    fn from_env() -> Option<Self> {
+        use std::env;
+
+        let path = env::var_os("NOTIFY_SOCKET")?;
+        let watchdog_timeout = watchdog_timeout(
+            env::var("WATCHDOG_USEC").ok().as_deref(),
+            env::var("WATCHDOG_PID").ok().as_deref(),
+        );
+        match Self::new(&path, watchdog_timeout) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                warn!("Can't notify systemd on {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    fn new(path: &std::ffi::OsStr, watchdog_timeout: Option<Duration>) -> io::Result<Self> {
+        use std::{mem, os::unix::ffi::OsStrExt};
+
+        let path = path.as_bytes();
+        // zeroed, the path is terminated by a nul
+        let mut address: libc::sockaddr_un = unsafe { mem::zeroed() };
+        address.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        if path.is_empty() || path.len() >= address.sun_path.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid socket address",
+            ));
+        }
+        for (dst, src) in address.sun_path.iter_mut().zip(path) {
+            *dst = *src as libc::c_char;
+        }
+        // a socket in the abstract namespace, which isn't terminated
+        let is_abstract = path[0] == b'@';
+        if is_abstract {
+            address.sun_path[0] = 0;
+        }
+        let length = mem::size_of::<libc::sa_family_t>() + path.len() + usize::from(!is_abstract);
+        Ok(Self {
+            socket: std::os::unix::net::UnixDatagram::unbound()?,
+            address,
+            length: length as libc::socklen_t,
+            watchdog_timeout,
+        })
+    }
+
+    fn watchdog_timeout(&self) -> Option<Duration> {
+        self.watchdog_timeout
+    }
+
+    fn send(&self, state: &str) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let sent = unsafe {
+            libc::sendto(
+                self.socket.as_raw_fd(),
+                state.as_ptr() as *const libc::c_void,
+                state.len(),
+                libc::MSG_NOSIGNAL,
+                &self.address as *const libc::sockaddr_un as *const libc::sockaddr,
+                self.length,
+            )
+        };
+        if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Only systemd sends notifications, which is only on Linux.
+#[cfg(not(target_os = "linux"))]
+enum Socket {}
+
+#[cfg(not(target_os = "linux"))]
+impl Socket {
+    fn from_env() -> Option<Self> {
+        None
+    }
+
+    fn watchdog_timeout(&self) -> Option<Duration> {
+        match *self {}
+    }
+
+    fn send(&self, _: &str) -> io::Result<()> {
+        match *self {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_timeout() {
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            watchdog_timeout(Some("30000000"), None),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            watchdog_timeout(Some("30000000"), Some(&pid)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(watchdog_timeout(Some("30000000"), Some("0")), None);
+        assert_eq!(watchdog_timeout(Some("0"), None), None);
+        assert_eq!(watchdog_timeout(None, None), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_notify() {
+        let path = std::env::temp_dir().join(format!("spotifyd-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier(Some(Arc::new(Socket::new(path.as_os_str(), None).unwrap())));
+        notifier.notify("READY=1");
+        let mut buffer = [0; 16];
+        let length = listener.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
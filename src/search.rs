@@ -0,0 +1,203 @@
+use crate::{
+    config::{SearchArgs, SearchType, SpotifydConfig},
+    ctl,
+    web_api::{self, SpotifyUri},
+};
+use color_eyre::eyre::{self, eyre};
+use librespot_core::Error;
+use rspotify::{
+    model::{Market, SearchResult, SearchType as ApiSearchType},
+    prelude::*,
+    AuthCodeSpotify,
+};
+use serde::Serialize;
+
+/// A result of the search, or an item of the library, as printed.
+#[derive(Debug, Serialize)]
+pub(crate) struct Hit {
+    pub(crate) name: String,
+    /// The artists of a track or an album, the owner of a playlist, or the
+    /// publisher of a show.
+    pub(crate) by: Option<String>,
+    pub(crate) uri: String,
+}
+
+impl Hit {
+    pub(crate) fn line(&self, number: usize) -> String {
+        match self.by {
+            Some(ref by) => format!("{:>3}. {} - {} ({})", number, self.name, by, self.uri),
+            None => format!("{:>3}. {} ({})", number, self.name, self.uri),
+        }
+    }
+}
+
+fn heading(search_type: SearchType) -> &'static str {
+    match search_type {
+        SearchType::Track => "Tracks",
+        SearchType::Album => "Albums",
+        SearchType::Artist => "Artists",
+        SearchType::Playlist => "Playlists",
+        SearchType::Show => "Shows",
+        SearchType::Episode => "Episodes",
+    }
+}
+
+pub(crate) fn join_names<'a>(names: impl Iterator<Item = &'a str>) -> Option<String> {
+    let names = names.collect::<Vec<_>>().join(", ");
+    (!names.is_empty()).then_some(names)
+}
+
+/// The results of one type, in the order of their relevance.
+fn search(
+    client: &AuthCodeSpotify,
+    query: &str,
+    search_type: SearchType,
+    limit: u32,
+) -> Result<Vec<Hit>, Error> {
+    let api_type = match search_type {
+        SearchType::Track => ApiSearchType::Track,
+        SearchType::Album => ApiSearchType::Album,
+        SearchType::Artist => ApiSearchType::Artist,
+        SearchType::Playlist => ApiSearchType::Playlist,
+        SearchType::Show => ApiSearchType::Show,
+        SearchType::Episode => ApiSearchType::Episode,
+    };
+    let result = client
+        .search(
+            query,
+            api_type,
+            Some(Market::FromToken),
+            None,
+            Some(limit),
+            None,
+        )
+        .map_err(Error::unavailable)?;
+
+    let hits = match result {
+        SearchResult::Tracks(page) => page
+            .items
+            .into_iter()
+            .filter_map(|track| {
+                Some(Hit {
+                    by: join_names(track.artists.iter().map(|a| a.name.as_str())),
+                    uri: track.id?.uri(),
+                    name: track.name,
+                })
+            })
+            .collect(),
+        SearchResult::Albums(page) => page
+            .items
+            .into_iter()
+            .filter_map(|album| {
+                Some(Hit {
+                    by: join_names(album.artists.iter().map(|a| a.name.as_str())),
+                    uri: album.id?.uri(),
+                    name: album.name,
+                })
+            })
+            .collect(),
+        SearchResult::Artists(page) => page
+            .items
+            .into_iter()
+            .map(|artist| Hit {
+                by: None,
+                uri: artist.id.uri(),
+                name: artist.name,
+            })
+            .collect(),
+        SearchResult::Playlists(page) => page
+            .items
+            .into_iter()
+            .map(|playlist| Hit {
+                by: playlist.owner.display_name,
+                uri: playlist.id.uri(),
+                name: playlist.name,
+            })
+            .collect(),
+        SearchResult::Shows(page) => page
+            .items
+            .into_iter()
+            .map(|show| Hit {
+                by: Some(show.publisher),
+                uri: show.id.uri(),
+                name: show.name,
+            })
+            .collect(),
+        SearchResult::Episodes(page) => page
+            .items
+            .into_iter()
+            .map(|episode| Hit {
+                by: None,
+                uri: episode.id.uri(),
+                name: episode.name,
+            })
+            .collect(),
+    };
+    Ok(hits)
+}
+
+/// Prints the results of each type, numbered throughout, and plays the one
+/// picked with `--play`.
+pub async fn run(config: &SpotifydConfig, args: &SearchArgs) -> eyre::Result<()> {
+    let client = ctl::connect(config).await?;
+    let query = args.query.clone();
+    let types = args.types.clone();
+    let limit = args.limit.clamp(1, 50);
+    let play = args.play;
+    let device = args
+        .device
+        .clone()
+        .unwrap_or_else(|| config.device_name.clone());
+
+    tokio::task::spawn_blocking(move || {
+        let mut hits = Vec::new();
+        for search_type in types {
+            let results = search(&client, &query, search_type, limit)?;
+            if results.is_empty() {
+                continue;
+            }
+            println!("{}", heading(search_type));
+            for hit in results {
+                println!("{}", hit.line(hits.len() + 1));
+                hits.push(hit);
+            }
+        }
+        if hits.is_empty() {
+            println!("Nothing found for {:?}", query);
+        }
+
+        let Some(number) = play else {
+            return Ok(());
+        };
+        let hit = number
+            .checked_sub(1)
+            .and_then(|index| hits.get(index))
+            .ok_or_else(|| Error::invalid_argument(format!("there is no result {}", number)))?;
+        let uri = SpotifyUri::parse(&hit.uri).map_err(Error::invalid_argument)?;
+        let device_id = web_api::device_id(&client, &device)?;
+        println!("Playing {} on {}", hit.name, device);
+        web_api::open_uri(&client, &device_id, uri).map_err(Error::unavailable)
+    })
+    .await
+    .unwrap()
+    .map_err(|e| eyre!("{}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hit_lines() {
+        let hit = Hit {
+            name: "Harder, Better, Faster, Stronger".to_string(),
+            by: join_names(["Daft Punk"].into_iter()),
+            uri: "spotify:track:5W3cjX2J3tjhG8zb6u0qHn".to_string(),
+        };
+        assert_eq!(
+            hit.line(1),
+            "  1. Harder, Better, Faster, Stronger - Daft Punk (spotify:track:5W3cjX2J3tjhG8zb6u0qHn)"
+        );
+        assert_eq!(join_names(std::iter::empty()), None);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::{
+    config::{KeyringAction, KeyringSecret, SpotifydConfig},
+    logging,
+};
+use color_eyre::eyre::{self, eyre};
+use keyring::{Entry, Error};
+use librespot_core::{authentication::Credentials, session::Session};
+use log::{debug, error, info, warn};
+use std::io;
+
+/// The service of the entries in the keyring.
+const SERVICE: &str = "spotifyd";
+
+/// The user of the entry of the secret. The password is stored for the
+/// username, as spotifyd has always looked it up.
+fn account(secret: KeyringSecret, username: Option<&str>) -> Option<String> {
+    match secret {
+        KeyringSecret::Password => username.map(str::to_string),
+        KeyringSecret::Credentials => username.map(|username| format!("{}/credentials", username)),
+        secret => Some(secret.to_string()),
+    }
+}
+
+fn entry(secret: KeyringSecret, username: Option<&str>) -> eyre::Result<Entry> {
+    let account = account(secret, username)
+        .ok_or_else(|| eyre!("the {} can't be looked up without a username", secret))?;
+    Ok(Entry::new(SERVICE, &account)?)
+}
+
+/// Looks up the secret, if it's in the keyring.
+pub(crate) fn get(secret: KeyringSecret, username: Option<&str>) -> Option<String> {
+    let entry = match entry(secret, username) {
+        Ok(entry) => entry,
+        Err(e) => {
+            warn!("Can't query the keyring: {}", e);
+            return None;
+        }
+    };
+    match entry.get_password() {
+        Ok(value) => Some(value),
+        Err(Error::NoEntry) => {
+            debug!("The keyring has no {}", secret);
+            None
+        }
+        Err(e) => {
+            error!("Keyring did not return the {}: {}", secret, e);
+            None
+        }
+    }
+}
+
+/// The reusable credentials stored for the user, once logged in.
+pub(crate) fn load_credentials(username: &str) -> Option<Credentials> {
+    let stored = get(KeyringSecret::Credentials, Some(username))?;
+    match serde_json::from_str(&stored) {
+        Ok(credentials) => Some(credentials),
+        Err(e) => {
+            warn!("Ignoring the invalid credentials in the keyring: {}", e);
+            None
+        }
+    }
+}
+
+/// Stores the reusable credentials of the connected session, instead of in
+/// the cache.
+pub(crate) fn save_credentials(session: &Session) {
+    use librespot_protocol::authentication::AuthenticationType;
+
+    let username = session.username();
+    let credentials = Credentials {
+        username: Some(username.clone()),
+        auth_type: AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS,
+        auth_data: session.auth_data(),
+    };
+    let stored = serde_json::to_string(&credentials).unwrap();
+    match entry(KeyringSecret::Credentials, Some(&username)).and_then(|entry| {
+        entry.set_password(&stored)?;
+        Ok(())
+    }) {
+        Ok(()) => debug!("Stored the credentials in the keyring"),
+        Err(e) => warn!("Failed to store the credentials in the keyring: {}", e),
+    }
+}
+
+/// Removes the credentials stored for the user. Returns whether there were
+/// any.
+pub(crate) fn delete_credentials(username: &str) -> eyre::Result<bool> {
+    match entry(KeyringSecret::Credentials, Some(username))?.delete_password() {
+        Ok(()) => Ok(true),
+        Err(Error::NoEntry) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Looks up the secret of a scrobbler, which is redacted from the logs.
+fn lookup(secret: KeyringSecret) -> Option<String> {
+    let stored = get(secret, None)?;
+    info!("Using the {} of the keyring", secret);
+    logging::register_secret(&stored);
+    Some(stored)
+}
+
+/// Fills in the secrets of the scrobblers that aren't in the config file
+/// from the keyring.
+pub(crate) fn fill(config: &mut SpotifydConfig) {
+    if let Some(ref mut lastfm) = config.lastfm {
+        if lastfm.api_secret.is_empty() {
+            lastfm.api_secret = lookup(KeyringSecret::LastfmApiSecret).unwrap_or_default();
+        }
+        if lastfm.password.is_none() {
+            lastfm.password = lookup(KeyringSecret::LastfmPassword);
+        }
+    }
+    if let Some(ref mut listenbrainz) = config.listenbrainz {
+        if listenbrainz.token.is_empty() {
+            listenbrainz.token = lookup(KeyringSecret::ListenbrainzToken).unwrap_or_default();
+        }
+    }
+}
+
+/// Stores the secret read from the standard input in the keyring, or
+/// removes it.
+pub fn run(config: &SpotifydConfig, action: &KeyringAction) -> eyre::Result<()> {
+    let username = config.username.as_deref();
+    match *action {
+        KeyringAction::Set {
+            secret: KeyringSecret::Credentials,
+        } => {
+            eyre::bail!("the credentials are stored once logged in with use_keyring enabled")
+        }
+        KeyringAction::Set { secret } => {
+            let entry = entry(secret, username)?;
+            eprintln!("Enter the {}:", secret);
+            let mut value = String::new();
+            io::stdin().read_line(&mut value)?;
+            let value = value.trim_end_matches(&['\r', '\n'][..]);
+            if value.is_empty() {
+                eyre::bail!("the {} is empty", secret);
+            }
+            entry.set_password(value)?;
+            println!("Stored the {} in the keyring", secret);
+        }
+        KeyringAction::Delete { secret } => match entry(secret, username)?.delete_password() {
+            Ok(()) => println!("Removed the {} from the keyring", secret),
+            Err(Error::NoEntry) => println!("The keyring has no {}", secret),
+            Err(e) => return Err(e.into()),
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secrets() {
+        assert_eq!(
+            account(KeyringSecret::Password, Some("alice")).as_deref(),
+            Some("alice")
+        );
+        assert_eq!(
+            account(KeyringSecret::Credentials, Some("alice")).as_deref(),
+            Some("alice/credentials")
+        );
+        assert_eq!(account(KeyringSecret::Credentials, None), None);
+        assert_eq!(
+            account(KeyringSecret::ListenbrainzToken, None).as_deref(),
+            Some("listenbrainz_token")
+        );
+    }
+}
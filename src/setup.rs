@@ -1,23 +1,64 @@
 #[cfg(feature = "alsa_backend")]
 use crate::alsa_mixer;
 use crate::{
+    audio_sink,
+    audit::AuditLog,
+    blocklist::Blocklist,
     config,
-    main_loop::{self, CredentialsProvider},
+    data_usage::DataUsage,
+    discoverable::Discoverable,
+    dsp::{DspChain, DspSwitches},
+    encryption::EncryptedCredentials,
+    events::{EventBus, REPLAY_BUFFER_SIZE},
+    history::PlayHistory,
+    main_loop::{self, CredentialsProvider, DiscoveryProvider},
+    metered::Metered,
+    multi_sink::{MultiSink, OpenOutput},
+    profiles::Profiles,
+    sd_notify::Notifier,
+    startup::StartupTimer,
 };
 #[cfg(feature = "dbus_keyring")]
-use keyring::Entry;
+use crate::{config::KeyringSecret, secrets};
 use librespot_core::{authentication::Credentials, cache::Cache, config::DeviceType};
 use librespot_playback::mixer::MixerConfig;
 use librespot_playback::{
     audio_backend::{Sink, BACKENDS},
     config::AudioFormat,
+    dither::DithererBuilder,
     mixer::{self, Mixer},
 };
 #[allow(unused_imports)] // cfg
 use log::{debug, error, info, warn};
-use std::{str::FromStr, sync::Arc, thread, time::Duration};
+use std::{
+    str::FromStr,
+    sync::{atomic::AtomicUsize, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tokio::sync::{mpsc, watch};
 
-pub(crate) fn initial_state(config: config::SpotifydConfig) -> main_loop::MainLoop {
+/// Prepares the main loop from the given config. This enables discovery, if
+/// no credentials are configured.
+pub fn initial_state(
+    #[allow(unused_mut)] // mut is needed behind the dbus_keyring flag.
+    mut config: config::SpotifydConfig,
+) -> main_loop::MainLoop {
+    let mut startup_timer = StartupTimer::new();
+    #[cfg(feature = "dbus_keyring")]
+    if config.use_keyring {
+        secrets::fill(&mut config);
+    }
+    let credentials = configured_credentials(&config);
+    let last_user = match credentials {
+        None if config.reattach_last_user => last_user_credentials(&config),
+        _ => None,
+    };
+    let camilladsp_volume = (config.volume_controller == config::VolumeController::CamillaDsp)
+        .then(|| {
+            let (sender, receiver) = watch::channel(u16::MAX);
+            (Arc::new(sender), receiver)
+        });
     let mixer = {
         match config.volume_controller {
             config::VolumeController::None => {
@@ -30,20 +71,34 @@ pub(crate) fn initial_state(config: config::SpotifydConfig) -> main_loop::MainLo
                 let audio_device = config.audio_device.clone();
                 let control_device = config.control_device.clone();
                 let mixer = config.mixer.clone();
+                let mixer_index = config.mixer_index;
+                let range_db = config.volume_range_db;
                 info!("Using alsa volume controller.");
                 let linear = matches!(
                     config.volume_controller,
                     config::VolumeController::AlsaLinear
                 );
                 Box::new(move || {
-                    Arc::new(alsa_mixer::AlsaMixer {
-                        device: control_device
-                            .clone()
-                            .or_else(|| audio_device.clone())
-                            .unwrap_or_else(|| "default".to_string()),
-                        mixer: mixer.clone().unwrap_or_else(|| "Master".to_string()),
-                        linear_scaling: linear,
-                    }) as Arc<dyn mixer::Mixer>
+                    let device = control_device.clone().unwrap_or_else(|| {
+                        audio_device
+                            .as_deref()
+                            .map_or_else(|| "default".to_string(), alsa_mixer::control_device)
+                    });
+                    Arc::new(alsa_mixer::AlsaMixer::new(
+                        device,
+                        mixer.clone(),
+                        mixer_index,
+                        linear,
+                        range_db,
+                    )) as Arc<dyn mixer::Mixer>
+                }) as Box<dyn FnMut() -> Arc<dyn Mixer>>
+            }
+            #[cfg(feature = "camilladsp")]
+            config::VolumeController::CamillaDsp => {
+                info!("Using CamillaDSP's volume controller.");
+                let volume = camilladsp_volume.as_ref().unwrap().0.clone();
+                Box::new(move || {
+                    Arc::new(crate::camilladsp::CamillaMixer::new(volume.clone())) as Arc<dyn Mixer>
                 }) as Box<dyn FnMut() -> Arc<dyn Mixer>>
             }
             _ => {
@@ -64,71 +119,108 @@ pub(crate) fn initial_state(config: config::SpotifydConfig) -> main_loop::MainLo
     let has_volume_ctrl = !matches!(config.volume_controller, config::VolumeController::None);
 
     let zeroconf_port = config.zeroconf_port.unwrap_or(0);
+    // the clients are looked up by the port of the discovery
+    #[cfg(target_os = "linux")]
+    let zeroconf_port = match zeroconf_port {
+        0 => crate::peer::free_port().unwrap_or(0),
+        port => port,
+    };
 
     let device_type: DeviceType = DeviceType::from_str(&config.device_type).unwrap_or_default();
 
-    let username = config.username;
-    #[allow(unused_mut)] // mut is needed behind the dbus_keyring flag.
-    let mut password = config.password;
-
-    #[cfg(feature = "dbus_keyring")]
-    if config.use_keyring {
-        match (&username, &password) {
-            (None, _) => warn!("Can't query the keyring without a username"),
-            (Some(_), Some(_)) => {
-                info!("Keyring is ignored, since you already configured a password")
-            }
-            (Some(username), None) => {
-                info!("Checking keyring for password");
-                let entry = Entry::new("spotifyd", username);
-                match entry.and_then(|e| e.get_password()) {
-                    Ok(retrieved_password) => password = Some(retrieved_password),
-                    Err(e) => error!("Keyring did not return any results: {e}"),
-                }
-            }
+    let discoverable = Arc::new(Discoverable::new(credentials.is_none()));
+    let credentials_provider = if config.simulate {
+        CredentialsProvider::Simulated
+    } else if let Some(credentials) = credentials {
+        CredentialsProvider::SpotifyCredentials(credentials)
+    } else {
+        info!("no usable credentials found, enabling discovery");
+        if config.mirror_mode {
+            // the device is shown in the Spotify apps, but doesn't play
+            warn!("mirror_mode is meant to be used with configured credentials");
         }
-    }
-
-    let credentials_provider =
-        if let Some(credentials) = get_credentials(&cache, &username, &password) {
-            CredentialsProvider::SpotifyCredentials(credentials)
-        } else {
-            info!("no usable credentials found, enabling discovery");
-            debug!(
-                "Using (device id, client_id) ('{}', '{}')",
-                session_config.device_id, session_config.client_id
-            );
-            const RETRY_MAX: u8 = 4;
-            let mut retry_counter = 0;
-            let mut backoff = Duration::from_secs(5);
-            let discovery_stream = loop {
-                match librespot_discovery::Discovery::builder(
-                    session_config.device_id.clone(),
-                    session_config.client_id.clone(),
-                )
-                .name(config.device_name.clone())
-                .device_type(device_type)
-                .port(zeroconf_port)
-                .launch()
-                {
-                    Ok(discovery_stream) => break discovery_stream,
-                    Err(err) => {
-                        error!("failed to enable discovery: {err}");
-                        if retry_counter >= RETRY_MAX {
-                            panic!("failed to enable discovery (and no credentials provided)");
-                        }
-                        info!("retrying discovery in {} seconds", backoff.as_secs());
-                        thread::sleep(backoff);
-                        retry_counter += 1;
-                        backoff *= 2;
-                        info!("trying to enable discovery (retry {retry_counter}/{RETRY_MAX})");
+        debug!(
+            "Using (device id, client_id) ('{}', '{}')",
+            session_config.device_id, session_config.client_id
+        );
+        let launch_discovery = {
+            let device_id = session_config.device_id.clone();
+            let client_id = session_config.client_id.clone();
+            let device_name = config.device_name.clone();
+            move || {
+                librespot_discovery::Discovery::builder(device_id.clone(), client_id.clone())
+                    .name(device_name.clone())
+                    .device_type(device_type)
+                    .port(zeroconf_port)
+                    .launch()
+            }
+        };
+        const RETRY_MAX: u8 = 4;
+        let mut retry_counter = 0;
+        let mut backoff = Duration::from_secs(5);
+        let discovery_stream = loop {
+            match launch_discovery() {
+                Ok(discovery_stream) => break discovery_stream,
+                Err(err) => {
+                    error!("failed to enable discovery: {err}");
+                    if retry_counter >= RETRY_MAX {
+                        panic!("failed to enable discovery (and no credentials provided)");
                     }
+                    info!("retrying discovery in {} seconds", backoff.as_secs());
+                    thread::sleep(backoff);
+                    retry_counter += 1;
+                    backoff *= 2;
+                    info!("trying to enable discovery (retry {retry_counter}/{RETRY_MAX})");
                 }
-            };
-            discovery_stream.into()
+            }
         };
+        // the device is visible in the Spotify apps from now on
+        startup_timer.phase("discovery");
+        if last_user.is_some() {
+            info!("Reattaching the user who connected last");
+        }
+        CredentialsProvider::Discovery(DiscoveryProvider::new(
+            discovery_stream,
+            Box::new(launch_discovery),
+            discoverable.subscribe(),
+            last_user,
+        ))
+    };
 
-    let backend = find_backend(backend.as_ref().map(String::as_ref));
+    #[cfg(feature = "backend_plugins")]
+    for path in &config.backend_plugins {
+        match audio_sink::plugin::load(path) {
+            Ok(name) => info!("Loaded the {} backend from {}", name, path.display()),
+            Err(e) => error!("{}", e),
+        }
+    }
+    let backend = if config.outputs.is_empty() {
+        find_backend(
+            backend.as_ref().map(String::as_ref),
+            config.jack,
+            &config.device_name,
+        )
+    } else {
+        find_outputs(
+            config.outputs,
+            backend.as_deref(),
+            config.jack,
+            &config.device_name,
+            player_config.ditherer,
+        )
+    };
+    startup_timer.phase("setup");
+    let (control_tx, control_rx) = mpsc::unbounded_channel();
+    let dsp_switches = DspSwitches::new(&config.dsp);
+    let dsp = DspChain::load(config.dsp);
+    let profiles = Arc::new(Profiles::new(config.profiles, config.profile));
+    let (forget_user_tx, forget_user_rx) = mpsc::unbounded_channel();
+    let metered = Arc::new(Metered::new(config.metered));
+    let data_usage = Arc::new(DataUsage::load(
+        config.data_usage,
+        config.data_cap,
+        metered.clone(),
+    ));
     main_loop::MainLoop {
         credentials_provider,
         audio_setup: main_loop::AudioSetup {
@@ -136,29 +228,162 @@ pub(crate) fn initial_state(config: config::SpotifydConfig) -> main_loop::MainLo
             backend,
             audio_device: config.audio_device,
             audio_format: config.audio_format,
+            warmup: config.audio_warmup,
+            crossfade: config.crossfade,
+            fade_in: config.fade_in,
+            fade_out: config.fade_out,
+            dsp,
         },
         spotifyd_state: main_loop::SpotifydState {
             cache,
             device_name: config.device_name,
-            player_event_program: config.onevent,
+            event_hooks: config.hooks,
         },
         player_config,
         session_config,
+        cache_layout: config.cache_layout,
         initial_volume: config.initial_volume,
+        volume_step: config.volume_step,
         has_volume_ctrl,
         shell: config.shell,
+        hook_options: config.hook_options,
         device_type,
         use_mpris: config.use_mpris,
         dbus_type: config.dbus_type,
+        mpris_raise_cmd: config.mpris_raise_cmd,
+        mpris_quit: config.mpris_quit,
+        takeover: config.takeover,
+        shutdown_request: Default::default(),
+        do_not_disturb: Default::default(),
+        discoverable,
+        profile_switch: profiles.subscribe(),
+        profiles,
+        sleep_timer: Default::default(),
+        forget_user_tx,
+        forget_user_rx,
+        event_bus: EventBus::new(REPLAY_BUFFER_SIZE),
+        playback_state: Default::default(),
+        otlp_endpoint: config.otlp_endpoint,
+        mqtt: config.mqtt,
+        webhook_url: config.webhook_url,
+        http_api: config.http_api,
+        guest_wifi: config.guest_wifi,
+        camilladsp_address: config.camilladsp_address,
+        camilladsp_volume: camilladsp_volume.map(|(_, volume)| volume),
+        lastfm: config.lastfm,
+        listenbrainz: config.listenbrainz,
+        scrobble_dir: config.scrobble_dir,
+        control_socket: config.control_socket,
+        startup_timer,
+        notifier: Notifier::from_env(),
+        audit_log: Arc::new(Mutex::new(AuditLog::new(config.audit_log))),
+        control_tx,
+        control_rx,
+        record_events: config.record_events,
+        event_log: config.event_log,
+        encrypted_credentials: config.encrypted_credentials,
+        use_keyring: config.use_keyring,
+        reattach_last_user: config.reattach_last_user,
+        discovery_port: (zeroconf_port != 0).then_some(zeroconf_port),
+        blocklist: config.blocklist.map(Blocklist::new),
+        unavailable_skip_delay: config.unavailable_skip_delay,
+        play_history: config
+            .duplicate_window
+            .map(|window| PlayHistory::load(config.play_history, window)),
+        resume_min_duration: config.resume_min_duration,
+        idle_timeouts: config.idle_timeouts,
+        resume_positions: config.resume_positions,
+        show_rules: config.show_rules,
+        playlist_schedule: config.playlist_schedule,
+        context_end: config.context_end,
+        radio_seed: config.radio_seed,
+        resume_after_reconnect: config.resume_after_reconnect,
+        party_mode: config.party_mode,
+        mirror_mode: config.mirror_mode,
+        mirror_interval: config.mirror_interval,
+        preload_tracks: Arc::new(AtomicUsize::new(config.preload_tracks)),
+        metered,
+        data_usage,
+        cache_hits: Default::default(),
+        dsp_switches,
+        reduce_dsp_when_throttled: config.reduce_dsp_when_throttled,
+        outgoing_bind: config.outgoing_bind,
+    }
+}
+
+/// The credentials cached for the configured user or given by the config,
+/// looking up the password in the keyring if enabled.
+pub(crate) fn configured_credentials(config: &config::SpotifydConfig) -> Option<Credentials> {
+    account_credentials(
+        &config.cache,
+        &config.encrypted_credentials,
+        &config.username,
+        config.password.clone(),
+        config.use_keyring,
+    )
+}
+
+/// The credentials cached for the user or given by the password, looking up
+/// the password in the keyring if enabled.
+#[cfg_attr(not(feature = "dbus_keyring"), allow(unused_variables))]
+pub(crate) fn account_credentials(
+    cache: &Option<Cache>,
+    encrypted_credentials: &Option<EncryptedCredentials>,
+    username: &Option<String>,
+    #[allow(unused_mut)] // mut is needed behind the dbus_keyring flag.
+    mut password: Option<String>,
+    use_keyring: bool,
+) -> Option<Credentials> {
+    #[cfg(feature = "dbus_keyring")]
+    if use_keyring {
+        // stored once logged in, like the cached ones
+        let stored = username.as_deref().and_then(secrets::load_credentials);
+        if stored.is_some() {
+            info!("Using the credentials of the keyring");
+            return stored;
+        }
+        match (username, &password) {
+            (None, _) => warn!("Can't query the keyring without a username"),
+            (Some(_), Some(_)) => {
+                info!("Keyring is ignored, since you already configured a password")
+            }
+            (Some(username), None) => {
+                info!("Checking keyring for password");
+                password = secrets::get(KeyringSecret::Password, Some(username));
+            }
+        }
     }
+
+    get_credentials(cache, encrypted_credentials, username, &password)
+}
+
+/// The cached credentials of the user who connected last via the discovery.
+fn last_user_credentials(config: &config::SpotifydConfig) -> Option<Credentials> {
+    let layout = config.cache_layout.as_ref()?;
+    // the keyring holds them instead of the cache
+    #[cfg(feature = "dbus_keyring")]
+    if config.use_keyring {
+        return secrets::load_credentials(&layout.last_user()?);
+    }
+    // they're saved whoever connected
+    let encrypted = config
+        .encrypted_credentials
+        .as_ref()
+        .and_then(EncryptedCredentials::load);
+    encrypted.or_else(|| layout.last_user_credentials())
 }
 
 fn get_credentials(
     cache: &Option<Cache>,
+    encrypted_credentials: &Option<EncryptedCredentials>,
     username: &Option<String>,
     password: &Option<String>,
 ) -> Option<Credentials> {
-    if let Some(credentials) = cache.as_ref().and_then(Cache::credentials) {
+    let cached = cache.as_ref().and_then(Cache::credentials);
+    let encrypted = encrypted_credentials
+        .as_ref()
+        .and_then(EncryptedCredentials::load);
+    for credentials in cached.into_iter().chain(encrypted) {
         if username == &credentials.username {
             return Some(credentials);
         }
@@ -170,14 +395,45 @@ fn get_credentials(
     ))
 }
 
-fn find_backend(name: Option<&str>) -> fn(Option<String>, AudioFormat) -> Box<dyn Sink> {
-    match name {
+#[cfg_attr(
+    not(all(feature = "jack_backend", feature = "pipewire_backend")),
+    allow(unused_variables)
+)]
+fn find_backend(
+    name: Option<&str>,
+    jack: config::JackConfig,
+    device_name: &str,
+) -> main_loop::SinkBuilder {
+    let backend = match name {
+        #[cfg(feature = "jack_backend")]
+        Some("jack") => {
+            // the sink is a JACK client, which has no device
+            return Arc::new(move |_: Option<String>, _: AudioFormat| {
+                Box::new(crate::jack_sink::JackSink::new(jack.clone())) as Box<dyn Sink>
+            });
+        }
+        #[cfg(feature = "pipewire_backend")]
+        Some("pipewire") => {
+            let device_name = device_name.to_string();
+            return Arc::new(move |device: Option<String>, _: AudioFormat| {
+                Box::new(crate::pipewire_sink::PipeWireSink::new(
+                    device_name.clone(),
+                    device,
+                )) as Box<dyn Sink>
+            });
+        }
         Some(name) => {
-            BACKENDS
-                .iter()
-                .find(|backend| name == backend.0)
-                .unwrap_or_else(|| panic!("Unknown backend: {}.", name))
-                .1
+            if let Some(factory) = audio_sink::registered_backend(name) {
+                return audio_sink::sink_builder(factory);
+            }
+            match BACKENDS.iter().find(|backend| name == backend.0) {
+                Some(&(_, backend)) => backend,
+                // a plugin that failed to load
+                None => {
+                    error!("Unknown backend {}, using the default one", name);
+                    return find_backend(None, jack, device_name);
+                }
+            }
         }
         None => {
             let &(name, back) = BACKENDS
@@ -186,5 +442,43 @@ fn find_backend(name: Option<&str>) -> fn(Option<String>, AudioFormat) -> Box<dy
             info!("No backend specified, defaulting to: {}.", name);
             back
         }
-    }
+    };
+    Arc::new(backend)
+}
+
+/// A sink builder playing on all of the outputs, with the backend
+/// of the config for the ones that don't set theirs.
+fn find_outputs(
+    outputs: Vec<config::AudioOutput>,
+    backend: Option<&str>,
+    jack: config::JackConfig,
+    device_name: &str,
+    ditherer: Option<DithererBuilder>,
+) -> main_loop::SinkBuilder {
+    let outputs: Vec<_> = outputs
+        .into_iter()
+        .map(|output| {
+            let name = output.backend.map(|backend| backend.to_string());
+            let name = name.as_deref().or(backend);
+            info!(
+                "Playing on the device {} of the {} backend",
+                output.device.as_deref().unwrap_or("default"),
+                name.unwrap_or("default"),
+            );
+            let builder = find_backend(name, jack.clone(), device_name);
+            let gain = output.volume.map_or(1.0, config::VolumeLevel::gain);
+            (builder, output.device, gain)
+        })
+        .collect();
+    Arc::new(move |_: Option<String>, format: AudioFormat| {
+        let sinks = outputs
+            .iter()
+            .map(|(builder, device, gain)| {
+                let (builder, device) = (builder.clone(), device.clone());
+                let open: OpenOutput = Arc::new(move || builder(device.clone(), format));
+                (open, *gain)
+            })
+            .collect();
+        Box::new(MultiSink::new(sinks, ditherer)) as Box<dyn Sink>
+    })
 }
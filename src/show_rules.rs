@@ -0,0 +1,174 @@
+use crate::{
+    config::ShowRule,
+    control::{ControlHandle, PlaybackControl},
+    events::{EventSubscriber, SpotifydEvent},
+    state::{PlaybackStatus, SharedPlaybackState},
+};
+use log::{error, info};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Applies the rules to the episodes of their shows, by skipping their intro
+/// and outro as they play.
+struct ShowRules {
+    rules: Vec<ShowRule>,
+    control: ControlHandle,
+    /// The episode the current rule applies to.
+    episode: Option<String>,
+    rule: Option<ShowRule>,
+    intro_skipped: bool,
+    /// When the outro of the current episode starts.
+    outro: Option<Instant>,
+}
+
+impl ShowRules {
+    fn find_rule(&self, show: &str) -> Option<&ShowRule> {
+        self.rules
+            .iter()
+            .find(|rule| rule.show.eq_ignore_ascii_case(show))
+    }
+
+    fn handle(&mut self, event: &SpotifydEvent, playback_state: &SharedPlaybackState) {
+        if let SpotifydEvent::TrackChanged(info) = event {
+            self.rule = match info.item_type.as_str() {
+                "episode" => self.find_rule(&info.album).cloned(),
+                _ => None,
+            };
+            self.episode = Some(info.track_id.clone());
+            self.intro_skipped = false;
+        }
+
+        let state = playback_state.read().unwrap();
+        let (Some(rule), true) = (&self.rule, state.track_id == self.episode) else {
+            self.outro = None;
+            return;
+        };
+        if state.status != PlaybackStatus::Playing {
+            self.outro = None;
+            return;
+        }
+
+        let position = Duration::from_millis(state.position_ms() as u64);
+        if let Some(skip_first) = rule.skip_first {
+            if !self.intro_skipped && position < skip_first.0 {
+                info!("Skipping the first {:?} of {}", skip_first.0, rule.show);
+                self.intro_skipped = true;
+                if let Err(e) = self.control.seek(skip_first.0.as_millis() as u32) {
+                    error!("Failed to skip the intro: {}", e);
+                }
+                // the outro is scheduled once the seek has happened
+                return;
+            }
+        }
+        self.outro = rule.skip_last.and_then(|skip_last| {
+            let duration = Duration::from_millis(state.duration_ms()? as u64);
+            let outro = duration.checked_sub(skip_last.0)?;
+            Some(Instant::now() + outro.checked_sub(position)?)
+        });
+    }
+
+    fn skip_outro(&mut self) {
+        self.outro = None;
+        if let Some(ref rule) = self.rule {
+            if let Some(skip_last) = rule.skip_last {
+                info!("Skipping the last {:?} of {}", skip_last.0, rule.show);
+            }
+        }
+        if let Err(e) = self.control.next() {
+            error!("Failed to skip the outro: {}", e);
+        }
+    }
+}
+
+/// Applies the rules to the episodes played, as announced by the events.
+pub(crate) async fn apply_show_rules(
+    rules: Vec<ShowRule>,
+    control: ControlHandle,
+    playback_state: SharedPlaybackState,
+    mut events: EventSubscriber,
+) {
+    let mut show_rules = ShowRules {
+        rules,
+        control,
+        episode: None,
+        rule: None,
+        intro_skipped: false,
+        outro: None,
+    };
+    loop {
+        let outro = show_rules.outro;
+        let sleep = tokio::time::sleep_until(outro.unwrap_or_else(Instant::now));
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => show_rules.handle(&event, &playback_state),
+                None => return,
+            },
+            _ = sleep, if outro.is_some() => show_rules.skip_outro(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audit::CommandSource, config::HumanDuration, control::ControlCommand, events::TrackInfo,
+    };
+    use tokio::sync::mpsc;
+
+    fn episode(show: &str) -> TrackInfo {
+        TrackInfo {
+            track_id: "episode".to_string(),
+            item_type: "episode".to_string(),
+            album: show.to_string(),
+            duration_ms: 600_000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_skips_intro_and_schedules_outro() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut show_rules = ShowRules {
+            rules: vec![ShowRule {
+                show: "The Show".to_string(),
+                skip_first: Some(HumanDuration(Duration::from_secs(30))),
+                skip_last: Some(HumanDuration(Duration::from_secs(60))),
+            }],
+            control: ControlHandle::new(tx, CommandSource::Spotifyd),
+            episode: None,
+            rule: None,
+            intro_skipped: false,
+            outro: None,
+        };
+        let playback_state = SharedPlaybackState::default();
+        let mut emit = |event: SpotifydEvent| {
+            playback_state.write().unwrap().update(&event);
+            show_rules.handle(&event, &playback_state);
+        };
+
+        emit(SpotifydEvent::TrackChanged(episode("the show")));
+        emit(SpotifydEvent::Playing {
+            play_request_id: 1,
+            track_id: "episode".to_string(),
+            position_ms: 0,
+        });
+        assert_eq!(
+            rx.try_recv(),
+            Ok((
+                ControlCommand::Seek(30_000),
+                CommandSource::Spotifyd,
+                Default::default()
+            ))
+        );
+
+        emit(SpotifydEvent::Seeked {
+            play_request_id: 1,
+            track_id: "episode".to_string(),
+            position_ms: 30_000,
+        });
+        assert!(rx.try_recv().is_err());
+        let outro = show_rules.outro.unwrap() - Instant::now();
+        assert!(outro <= Duration::from_secs(510) && outro > Duration::from_secs(509));
+    }
+}
@@ -0,0 +1,462 @@
+use crate::{
+    config::{SimulateEventArgs, SpotifydConfig},
+    events::{EventBus, SpotifydEvent, TrackInfo},
+    main_loop::shutdown_signal,
+    process::{event_env, spawn_program_on_event},
+    setup,
+    state::{Activity, PlaybackState, SharedPlaybackState},
+};
+use color_eyre::eyre::{self, eyre};
+use librespot_playback::{
+    audio_backend::{Sink, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+};
+use log::{error, info};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// The address of the simulated client, one reserved for documentation.
+const SIMULATED_CLIENT_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 10));
+
+/// The samples written to the sink when the playback starts, a second of
+/// silent stereo audio.
+const SIMULATED_SAMPLES: usize = 2 * 44_100;
+
+/// A stand-in for librespot's player, which publishes events without being
+/// connected to Spotify.
+pub struct MockPlayer {
+    event_bus: EventBus,
+    playback_state: SharedPlaybackState,
+    play_request_id: u64,
+    sink: Option<Box<dyn Sink>>,
+}
+
+impl MockPlayer {
+    pub fn new(event_bus: EventBus, playback_state: SharedPlaybackState) -> Self {
+        Self {
+            event_bus,
+            playback_state,
+            play_request_id: 0,
+            sink: None,
+        }
+    }
+
+    /// Plays silence to the sink while the simulated playback runs, the way
+    /// the player drives the sink of the audio backend.
+    pub fn with_sink(self, sink: Box<dyn Sink>) -> Self {
+        Self {
+            sink: Some(sink),
+            ..self
+        }
+    }
+
+    /// Publishes the event the same way the main loop does for real events.
+    pub fn emit(&mut self, event: SpotifydEvent) {
+        self.playback_state.write().unwrap().update(&event);
+        self.play_audio(&event);
+        self.event_bus.publish(event);
+    }
+
+    fn play_audio(&mut self, event: &SpotifydEvent) {
+        let Some(ref mut sink) = self.sink else {
+            return;
+        };
+        let result = match event {
+            SpotifydEvent::Playing { .. } => sink.start().and_then(|()| {
+                let samples = AudioPacket::Samples(vec![0.0; SIMULATED_SAMPLES]);
+                sink.write(samples, &mut Converter::new(None))
+            }),
+            SpotifydEvent::Paused { .. } | SpotifydEvent::Stopped { .. } => sink.stop(),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            error!("The simulated audio failed: {}", e);
+        }
+    }
+
+    /// Emits the events of loading the track and starting its playback.
+    pub fn play_track(&mut self, track: TrackInfo) {
+        self.play_request_id += 1;
+        let play_request_id = self.play_request_id;
+        let track_id = track.track_id.clone();
+
+        self.emit(SpotifydEvent::PlayRequestIdChanged { play_request_id });
+        self.emit(SpotifydEvent::Loading {
+            play_request_id,
+            track_id: track_id.clone(),
+            position_ms: 0,
+        });
+        self.emit(SpotifydEvent::TrackChanged(track));
+        self.emit(SpotifydEvent::Playing {
+            play_request_id,
+            track_id,
+            position_ms: 0,
+        });
+    }
+
+    /// Emits the events of the current track being paused at the given position.
+    pub fn pause(&mut self, position_ms: u32) {
+        if let Some(track_id) = self.current_track_id() {
+            self.emit(SpotifydEvent::Paused {
+                play_request_id: self.play_request_id,
+                track_id,
+                position_ms,
+            });
+        }
+    }
+
+    /// Emits the events of the current track playing until its end.
+    pub fn end_track(&mut self) {
+        if let Some(track_id) = self.current_track_id() {
+            self.emit(SpotifydEvent::EndOfTrack {
+                play_request_id: self.play_request_id,
+                track_id,
+            });
+        }
+    }
+
+    fn current_track_id(&self) -> Option<String> {
+        self.playback_state.read().unwrap().track_id.clone()
+    }
+}
+
+/// The track used by the simulated session.
+pub fn test_track() -> TrackInfo {
+    TrackInfo {
+        track_id: "4uLU6hMCjMI75M1A2tKUQC".to_string(),
+        uri: "spotify:track:4uLU6hMCjMI75M1A2tKUQC".to_string(),
+        name: "Test Track".to_string(),
+        duration_ms: 213_000,
+        is_explicit: false,
+        covers: Vec::new(),
+        item_type: "track".to_string(),
+        artists: vec!["Test Artist".to_string()],
+        artist_uris: vec!["spotify:artist:0OdUWJ0sBjDrqHygGUXeCF".to_string()],
+        album_artists: vec!["Test Artist".to_string()],
+        album: "Test Album".to_string(),
+        chapters: Vec::new(),
+    }
+}
+
+/// Emits the events of a short listening session: a client connects, plays
+/// a track, changes the volume, pauses and resumes, and the track ends.
+pub fn simulate_session(player: &mut MockPlayer) {
+    player.emit(SpotifydEvent::SessionConnected {
+        connection_id: "simulated".to_string(),
+        user_name: "simulated-user".to_string(),
+        client_ip: Some(SIMULATED_CLIENT_IP),
+    });
+    player.emit(SpotifydEvent::SessionClientChanged {
+        client_id: "simulated".to_string(),
+        client_name: "Simulated Client".to_string(),
+        client_brand_name: "spotifyd".to_string(),
+        client_model_name: "simulation".to_string(),
+        client_ip: Some(SIMULATED_CLIENT_IP),
+    });
+    player.play_track(test_track());
+    player.emit(SpotifydEvent::VolumeChanged { volume: 32768 });
+    player.pause(30_000);
+    player.emit(SpotifydEvent::Playing {
+        play_request_id: player.play_request_id,
+        track_id: test_track().track_id,
+        position_ms: 30_000,
+    });
+    player.end_track();
+    player.emit(SpotifydEvent::Stopped {
+        play_request_id: player.play_request_id,
+        track_id: test_track().track_id,
+    });
+}
+
+/// Runs the configured hooks and the other services of spotifyd, like the
+/// HTTP API and the control socket, against a simulated session instead of
+/// connecting to Spotify, so that hook scripts and clients can be tested.
+/// Keeps running until interrupted, printing the events.
+pub async fn run(config: SpotifydConfig) {
+    if config.hooks.is_empty() {
+        info!("No hooks configured, only printing the events");
+    }
+    let mut main_loop = setup::initial_state(config);
+    let mut printer = main_loop.subscribe();
+    main_loop.simulate(Box::<FakeSink>::default(), simulate_session);
+    info!("Simulated the session, press Ctrl-C to quit");
+
+    let print = async {
+        while let Some(event) = printer.recv().await {
+            println!("{}", serde_json::to_string(&event).unwrap());
+        }
+    };
+    tokio::select! {
+        _ = print => (),
+        _ = shutdown_signal() => (),
+    }
+}
+
+/// The events that can be simulated with `simulate-event`.
+///
+/// Besides the names passed to hooks, this accepts `change` and `start`, the
+/// names that older versions of spotifyd used for track changes and playback.
+pub(crate) static SIMULATED_EVENT_VALUES: &[&str] = &[
+    "change",
+    "start",
+    "play_request_id_changed",
+    "stop",
+    "load",
+    "preloading",
+    "play",
+    "pause",
+    "preload",
+    "endoftrack",
+    "unavailable",
+    "unavailable_skipped",
+    "blocked_skipped",
+    "volume_changed",
+    "position_correction",
+    "seeked",
+    "track_changed",
+    "session_connected",
+    "session_disconnected",
+    "session_client_changed",
+    "shuffle_changed",
+    "repeat_changed",
+    "auto_play_changed",
+    "filter_explicit_content_changed",
+    "egress_lost",
+    "egress_restored",
+    "taken_over",
+    "takeover_refused",
+    "idle",
+    "queue_ended",
+];
+
+/// Builds the event described by the arguments of `simulate-event`.
+pub fn synthetic_event(args: &SimulateEventArgs) -> SpotifydEvent {
+    let play_request_id = 1;
+    let track_id = args.track_id.clone();
+    let position_ms = args.position_ms;
+    let user_name = "simulated-user".to_string();
+    let connection_id = "simulated".to_string();
+
+    match args.event.as_str() {
+        "play_request_id_changed" => SpotifydEvent::PlayRequestIdChanged { play_request_id },
+        "stop" => SpotifydEvent::Stopped {
+            play_request_id,
+            track_id,
+        },
+        "load" => SpotifydEvent::Loading {
+            play_request_id,
+            track_id,
+            position_ms,
+        },
+        "preloading" => SpotifydEvent::Preloading { track_id },
+        "play" | "start" => SpotifydEvent::Playing {
+            play_request_id,
+            track_id,
+            position_ms,
+        },
+        "pause" => SpotifydEvent::Paused {
+            play_request_id,
+            track_id,
+            position_ms,
+        },
+        "preload" => SpotifydEvent::TimeToPreloadNextTrack {
+            play_request_id,
+            track_id,
+        },
+        "endoftrack" => SpotifydEvent::EndOfTrack {
+            play_request_id,
+            track_id,
+        },
+        "unavailable" => SpotifydEvent::Unavailable {
+            play_request_id,
+            track_id,
+        },
+        "unavailable_skipped" => SpotifydEvent::UnavailableSkipped {
+            play_request_id,
+            track_id,
+        },
+        "blocked_skipped" => SpotifydEvent::BlockedSkipped {
+            blocked_uri: format!("spotify:track:{}", track_id),
+            track_id,
+        },
+        "volume_changed" => SpotifydEvent::VolumeChanged {
+            volume: args.volume,
+        },
+        "position_correction" => SpotifydEvent::PositionCorrection {
+            play_request_id,
+            track_id,
+            position_ms,
+        },
+        "seeked" => SpotifydEvent::Seeked {
+            play_request_id,
+            track_id,
+            position_ms,
+        },
+        "track_changed" | "change" => {
+            let (artists, artist_uris) = if args.artists.is_empty() {
+                (test_track().artists, test_track().artist_uris)
+            } else {
+                (args.artists.clone(), Vec::new())
+            };
+            SpotifydEvent::TrackChanged(TrackInfo {
+                uri: format!("spotify:track:{}", track_id),
+                track_id,
+                name: args.track_name.clone(),
+                album_artists: artists.clone(),
+                artists,
+                artist_uris,
+                album: args.album.clone(),
+                ..test_track()
+            })
+        }
+        "session_connected" => SpotifydEvent::SessionConnected {
+            connection_id,
+            user_name,
+            client_ip: Some(SIMULATED_CLIENT_IP),
+        },
+        "session_disconnected" => SpotifydEvent::SessionDisconnected {
+            connection_id,
+            user_name,
+        },
+        "session_client_changed" => SpotifydEvent::SessionClientChanged {
+            client_id: connection_id,
+            client_name: "Simulated Client".to_string(),
+            client_brand_name: "spotifyd".to_string(),
+            client_model_name: "simulation".to_string(),
+            client_ip: Some(SIMULATED_CLIENT_IP),
+        },
+        "shuffle_changed" => SpotifydEvent::ShuffleChanged {
+            shuffle: args.enabled,
+        },
+        "repeat_changed" => SpotifydEvent::RepeatChanged {
+            repeat: args.enabled,
+        },
+        "auto_play_changed" => SpotifydEvent::AutoPlayChanged {
+            auto_play: args.enabled,
+        },
+        "filter_explicit_content_changed" => SpotifydEvent::FilterExplicitContentChanged {
+            filter: args.enabled,
+        },
+        "egress_lost" => SpotifydEvent::EgressLost {
+            interface: "wg0".to_string(),
+        },
+        "egress_restored" => SpotifydEvent::EgressRestored {
+            interface: "wg0".to_string(),
+        },
+        "taken_over" => SpotifydEvent::TakenOver {
+            device_name: Some("Kitchen".to_string()),
+        },
+        "takeover_refused" => SpotifydEvent::TakeoverRefused {
+            user_name: None,
+            client_name: Some("Simulated Client".to_string()),
+        },
+        "idle" => SpotifydEvent::Idle {
+            activity: Activity::Paused,
+            idle_ms: 600_000,
+        },
+        "queue_ended" => SpotifydEvent::QueueEnded { track_id },
+        _ => unreachable!(),
+    }
+}
+
+/// Runs the configured hook for the event described by the arguments, after
+/// printing the environment it is run with.
+pub async fn simulate_event(config: &SpotifydConfig, args: &SimulateEventArgs) -> eyre::Result<()> {
+    let event = synthetic_event(args);
+
+    let state = PlaybackState::default();
+    let mut env: Vec<_> = event_env(&event, &state).into_iter().collect();
+    env.sort();
+    println!("Environment:");
+    for (key, value) in env {
+        println!("  {}={:?}", key, value);
+    }
+
+    let Some(cmd) = config.hooks.command(&event) else {
+        println!("No hook configured for the event.");
+        return Ok(());
+    };
+    println!("Output of {:?}:", cmd);
+    spawn_program_on_event(&config.shell, cmd, &config.hook_options, &event, &state)
+        .map_err(|e| eyre!("{}", e))?
+        .wait()
+        .await
+        .map_err(|e| eyre!("{}", e))
+}
+
+/// An audio sink that discards the audio, while keeping track of what has
+/// been written to it.
+///
+/// Clones share their counters, so a clone can be kept to inspect the sink
+/// after it has been handed to the player.
+#[derive(Clone, Debug, Default)]
+pub struct FakeSink {
+    running: Arc<AtomicBool>,
+    samples: Arc<AtomicUsize>,
+}
+
+impl FakeSink {
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// The number of samples written since the sink has been created.
+    pub fn samples_written(&self) -> usize {
+        self.samples.load(Ordering::Relaxed)
+    }
+}
+
+impl Sink for FakeSink {
+    fn start(&mut self) -> SinkResult<()> {
+        self.running.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        self.running.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn write(&mut self, packet: AudioPacket, _converter: &mut Converter) -> SinkResult<()> {
+        let samples = packet.samples().map_or(0, |samples| samples.len());
+        self.samples.fetch_add(samples, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_synthetic_track_change() {
+        let args = SimulateEventArgs::from_iter([
+            "simulate-event",
+            "change",
+            "--track-name",
+            "Test",
+            "--artist",
+            "X",
+        ]);
+        let event = synthetic_event(&args);
+        assert_eq!(event.name(), "track_changed");
+
+        let state = PlaybackState {
+            shuffle: true,
+            ..Default::default()
+        };
+        let env = event_env(&event, &state);
+        assert_eq!(env["PLAYER_EVENT"], "track_changed");
+        assert_eq!(env["NAME"], "Test");
+        assert_eq!(env["ARTISTS"], "X");
+        assert_eq!(env["SHUFFLE"], "true");
+        assert_eq!(env["REPEAT"], "false");
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{
+    control::{ControlCommand, ControlHandle},
+    events::{EventSubscriber, SpotifydEvent},
+};
+use librespot_playback::{
+    audio_backend::{Sink, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+};
+use log::{error, info};
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{sync::watch, time::Instant};
+
+/// How long before the timer runs out the audio is faded down.
+const FADE_DURATION: Duration = Duration::from_secs(60);
+
+/// The sleep timer, which pauses the playback when it runs out, after fading
+/// the audio down over the last minute. It can be set, queried and cancelled
+/// while running.
+///
+/// The fade is applied to the samples by a [`SleepSink`] rather than through
+/// the volume, which is left as the clients set it.
+#[derive(Debug)]
+pub struct SleepTimer {
+    deadline: watch::Sender<Option<Instant>>,
+    /// Set once the timer ran out, keeping the audio silent until the
+    /// playback starts again.
+    silenced: AtomicBool,
+}
+
+/// When the sleep timer runs out, as reported to the clients.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct SleepTimerReport {
+    /// How long until the playback pauses, if the timer is set.
+    pub remaining_ms: Option<u64>,
+}
+
+impl Default for SleepTimer {
+    fn default() -> Self {
+        Self {
+            deadline: watch::channel(None).0,
+            silenced: AtomicBool::new(false),
+        }
+    }
+}
+
+impl SleepTimer {
+    /// Pauses the playback after the duration, replacing the time the timer
+    /// was set to before. Fails when the duration is too long to be reached.
+    pub fn set(&self, duration: Duration) -> Result<(), &'static str> {
+        let deadline = Instant::now()
+            .checked_add(duration)
+            .ok_or("the sleep timer can't be set this far ahead")?;
+        self.silenced.store(false, Ordering::Relaxed);
+        self.deadline.send_replace(Some(deadline));
+        info!("The playback pauses in {}s", duration.as_secs());
+        Ok(())
+    }
+
+    /// Cancels the timer, if it's set. Returns whether it was.
+    pub fn cancel(&self) -> bool {
+        self.silenced.store(false, Ordering::Relaxed);
+        let cancelled = self.clear();
+        if cancelled {
+            info!("Cancelled the sleep timer");
+        }
+        cancelled
+    }
+
+    fn clear(&self) -> bool {
+        self.deadline
+            .send_if_modified(|deadline| deadline.take().is_some())
+    }
+
+    /// How long until the playback pauses, if the timer is set.
+    pub fn remaining(&self) -> Option<Duration> {
+        let deadline = (*self.deadline.borrow())?;
+        Some(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn report(&self) -> SleepTimerReport {
+        SleepTimerReport {
+            remaining_ms: self
+                .remaining()
+                .map(|remaining| remaining.as_millis() as u64),
+        }
+    }
+
+    /// The gain of the audio: faded down over the last minute, and silent
+    /// once the timer ran out until the playback starts again.
+    fn gain(&self) -> f64 {
+        if self.silenced.load(Ordering::Relaxed) {
+            return 0.0;
+        }
+        self.remaining().map_or(1.0, fade_gain)
+    }
+}
+
+/// The gain while fading out, from full down to silence when the timer runs
+/// out.
+fn fade_gain(remaining: Duration) -> f64 {
+    (remaining.as_secs_f64() / FADE_DURATION.as_secs_f64()).min(1.0)
+}
+
+/// A sink fading the audio out as the sleep timer runs out.
+pub(crate) struct SleepSink {
+    inner: Box<dyn Sink>,
+    timer: Arc<SleepTimer>,
+}
+
+impl SleepSink {
+    pub(crate) fn new(inner: Box<dyn Sink>, timer: Arc<SleepTimer>) -> Self {
+        Self { inner, timer }
+    }
+}
+
+impl Sink for SleepSink {
+    fn start(&mut self) -> SinkResult<()> {
+        self.inner.start()
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        self.inner.stop()
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        match packet {
+            AudioPacket::Samples(mut samples) => {
+                let gain = self.timer.gain();
+                if gain < 1.0 {
+                    samples.iter_mut().for_each(|sample| *sample *= gain);
+                }
+                self.inner.write(AudioPacket::Samples(samples), converter)
+            }
+            // passed through undecoded, there are no samples to fade
+            packet => self.inner.write(packet, converter),
+        }
+    }
+}
+
+/// Pauses the playback when the timer runs out. The audio stays silent until
+/// the pause has been applied and the playback is started again.
+pub(crate) async fn run_sleep_timer(
+    timer: Arc<SleepTimer>,
+    control: ControlHandle,
+    mut events: EventSubscriber,
+) {
+    let mut deadline = timer.deadline.subscribe();
+    loop {
+        let until = *deadline.borrow_and_update();
+        tokio::select! {
+            _ = tokio::time::sleep_until(until.unwrap_or_else(Instant::now)), if until.is_some() => {
+                info!("The sleep timer ran out, pausing the playback");
+                timer.silenced.store(true, Ordering::Relaxed);
+                if let Err(e) = control.send(ControlCommand::Pause) {
+                    error!("The sleep timer failed to pause the playback: {}", e);
+                }
+                timer.clear();
+            }
+            changed = deadline.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+            Some(event) = events.recv() => {
+                if let SpotifydEvent::Playing { .. } = event {
+                    timer.silenced.store(false, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fade() {
+        assert_eq!(fade_gain(Duration::from_secs(90)), 1.0);
+        assert_eq!(fade_gain(Duration::from_secs(30)), 0.5);
+        assert_eq!(fade_gain(Duration::ZERO), 0.0);
+    }
+
+    #[test]
+    fn test_timer() {
+        let timer = SleepTimer::default();
+        assert_eq!(timer.report(), SleepTimerReport { remaining_ms: None });
+        assert_eq!(timer.gain(), 1.0);
+        assert!(!timer.cancel());
+
+        assert!(timer.set(Duration::MAX).is_err());
+        assert_eq!(timer.remaining(), None);
+
+        timer.set(Duration::from_secs(30 * 60)).unwrap();
+        let remaining = timer.remaining().unwrap();
+        assert!(remaining > Duration::from_secs(29 * 60));
+        assert_eq!(timer.gain(), 1.0);
+        timer.set(Duration::from_secs(30)).unwrap();
+        assert!(timer.gain() <= 0.5);
+
+        timer.silenced.store(true, Ordering::Relaxed);
+        assert_eq!(timer.gain(), 0.0);
+        assert!(timer.cancel());
+        assert_eq!(timer.remaining(), None);
+        assert_eq!(timer.gain(), 1.0);
+    }
+}
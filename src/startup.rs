@@ -0,0 +1,84 @@
+use log::debug;
+use std::time::{Duration, Instant};
+
+/// Measures the phases of the startup, until the device is visible in the
+/// Spotify apps, to find out what delays it.
+#[derive(Debug)]
+pub(crate) struct StartupTimer {
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+    finished: bool,
+}
+
+impl StartupTimer {
+    pub(crate) fn new() -> Self {
+        Self {
+            last: Instant::now(),
+            phases: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Ends the current phase. Once the startup is finished, later sessions
+    /// aren't measured.
+    pub(crate) fn phase(&mut self, name: &'static str) {
+        if self.finished {
+            return;
+        }
+        let now = Instant::now();
+        self.phases.push((name, now - self.last));
+        self.last = now;
+    }
+
+    /// Ends the last phase and logs the breakdown.
+    pub(crate) fn finish(&mut self, name: &'static str) {
+        if self.finished {
+            return;
+        }
+        self.phase(name);
+        self.finished = true;
+        debug!("{}", self.summary());
+    }
+
+    fn summary(&self) -> String {
+        let total: Duration = self.phases.iter().map(|(_, duration)| *duration).sum();
+        let phases: Vec<_> = self
+            .phases
+            .iter()
+            .map(|(name, duration)| format!("{} {}ms", name, duration.as_millis()))
+            .collect();
+        format!(
+            "Startup took {}ms: {}",
+            total.as_millis(),
+            phases.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let mut timer = StartupTimer::new();
+        timer.phases = vec![
+            ("setup", Duration::from_millis(12)),
+            ("credentials", Duration::from_millis(250)),
+        ];
+        timer.finish("spirc");
+        let last = timer.phases[2].1.as_millis();
+        assert_eq!(
+            timer.summary(),
+            format!(
+                "Startup took {}ms: setup 12ms, credentials 250ms, spirc {}ms",
+                262 + last,
+                last
+            )
+        );
+
+        // later sessions don't change the breakdown
+        timer.phase("connect");
+        assert_eq!(timer.phases.len(), 3);
+    }
+}
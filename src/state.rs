@@ -0,0 +1,456 @@
+use crate::{
+    control::{volume_db, volume_percent},
+    events::{Chapter, SpotifydEvent, TrackInfo},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, RwLock},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The number of unavailable tracks that are remembered.
+const UNAVAILABLE_HISTORY_SIZE: usize = 50;
+
+/// How far into a chapter going to the previous chapter restarts the current
+/// one instead, like for tracks.
+const CHAPTER_RESTART_MS: u32 = 3000;
+
+/// The playback state shared between the main loop, which updates it, and
+/// the integrations (MPRIS, hooks, ...), which read from it.
+pub type SharedPlaybackState = Arc<RwLock<PlaybackState>>;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlaybackStatus {
+    Playing,
+    Paused,
+    #[default]
+    Stopped,
+}
+
+impl PlaybackStatus {
+    /// The name of the status as used by MPRIS.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlaybackStatus::Playing => "Playing",
+            PlaybackStatus::Paused => "Paused",
+            PlaybackStatus::Stopped => "Stopped",
+        }
+    }
+}
+
+/// What the device is doing, which unlike the status tells a playback that
+/// was stopped from there being no session at all, e.g. after another device
+/// took over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Activity {
+    Playing,
+    Paused,
+    Stopped,
+    Inactive,
+}
+
+impl Activity {
+    /// The name of the activity, as passed to hooks in `ACTIVITY`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Activity::Playing => "playing",
+            Activity::Paused => "paused",
+            Activity::Stopped => "stopped",
+            Activity::Inactive => "inactive",
+        }
+    }
+}
+
+/// A track that could not be played, e.g. because it is restricted in the
+/// account's country.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnavailableTrack {
+    pub track_id: String,
+    /// The name of the track, if its metadata had been loaded.
+    pub name: Option<String>,
+    pub time: SystemTime,
+}
+
+/// Everything spotifyd knows about the current playback, as derived from the
+/// events.
+#[derive(Clone, Debug)]
+pub struct PlaybackState {
+    pub status: PlaybackStatus,
+    /// The base62 id of the current track.
+    pub track_id: Option<String>,
+    /// The metadata of the current track, once it has been loaded.
+    pub track: Option<TrackInfo>,
+    /// The position at the time of `position_anchor`.
+    position_ms: u32,
+    position_anchor: Instant,
+    pub volume: Option<u16>,
+    pub shuffle: bool,
+    pub repeat: bool,
+    pub autoplay: bool,
+    /// The name of the client that currently controls the playback.
+    pub controller: Option<String>,
+    pub user_name: Option<String>,
+    /// The country of the account, as detected by Spotify. This decides which
+    /// tracks are available.
+    pub country: Option<String>,
+    /// The product of the account, e.g. `premium`.
+    pub product: Option<String>,
+    /// The tracks that could not be played recently, the latest last. Unlike
+    /// the rest of the state, this is kept across sessions.
+    pub unavailable_tracks: VecDeque<UnavailableTrack>,
+    /// The time from loading the last track until its audio started.
+    pub time_to_audio_ms: Option<u32>,
+}
+
+impl Default for PlaybackState {
+    fn default() -> Self {
+        Self {
+            status: PlaybackStatus::default(),
+            track_id: None,
+            track: None,
+            position_ms: 0,
+            position_anchor: Instant::now(),
+            volume: None,
+            shuffle: false,
+            repeat: false,
+            autoplay: false,
+            controller: None,
+            user_name: None,
+            country: None,
+            product: None,
+            unavailable_tracks: VecDeque::new(),
+            time_to_audio_ms: None,
+        }
+    }
+}
+
+impl PlaybackState {
+    /// Resets the state for a new session.
+    pub(crate) fn reset(&mut self) {
+        *self = Self {
+            unavailable_tracks: std::mem::take(&mut self.unavailable_tracks),
+            ..Default::default()
+        };
+    }
+
+    pub(crate) fn update(&mut self, event: &SpotifydEvent) {
+        match event {
+            SpotifydEvent::Playing {
+                track_id,
+                position_ms,
+                ..
+            } => {
+                self.set_track(track_id);
+                self.status = PlaybackStatus::Playing;
+                self.set_position(*position_ms);
+            }
+            SpotifydEvent::Paused {
+                track_id,
+                position_ms,
+                ..
+            } => {
+                self.set_track(track_id);
+                self.status = PlaybackStatus::Paused;
+                self.set_position(*position_ms);
+            }
+            SpotifydEvent::Stopped { .. } => {
+                self.status = PlaybackStatus::Stopped;
+                self.set_position(0);
+            }
+            SpotifydEvent::Loading {
+                track_id,
+                position_ms,
+                ..
+            }
+            | SpotifydEvent::Seeked {
+                track_id,
+                position_ms,
+                ..
+            }
+            | SpotifydEvent::PositionCorrection {
+                track_id,
+                position_ms,
+                ..
+            } => {
+                self.set_track(track_id);
+                self.set_position(*position_ms);
+            }
+            SpotifydEvent::TrackChanged(info) => {
+                self.set_track(&info.track_id);
+                self.track = Some(info.clone());
+            }
+            SpotifydEvent::Unavailable { track_id, .. } => {
+                let name = self
+                    .track
+                    .as_ref()
+                    .filter(|track| &track.track_id == track_id)
+                    .map(|track| track.name.clone());
+                if self.unavailable_tracks.len() == UNAVAILABLE_HISTORY_SIZE {
+                    self.unavailable_tracks.pop_front();
+                }
+                self.unavailable_tracks.push_back(UnavailableTrack {
+                    track_id: track_id.clone(),
+                    name,
+                    time: SystemTime::now(),
+                });
+            }
+            SpotifydEvent::VolumeChanged { volume } => self.volume = Some(*volume),
+            SpotifydEvent::ShuffleChanged { shuffle } => self.shuffle = *shuffle,
+            SpotifydEvent::RepeatChanged { repeat } => self.repeat = *repeat,
+            SpotifydEvent::AutoPlayChanged { auto_play } => self.autoplay = *auto_play,
+            SpotifydEvent::SessionConnected { user_name, .. } => {
+                self.user_name = Some(user_name.clone());
+            }
+            SpotifydEvent::SessionDisconnected { .. } => {
+                self.user_name = None;
+                self.controller = None;
+            }
+            SpotifydEvent::SessionClientChanged { client_name, .. } => {
+                self.controller = Some(client_name.clone());
+            }
+            _ => (),
+        }
+    }
+
+    pub fn activity(&self) -> Activity {
+        match (&self.user_name, self.status) {
+            (None, _) => Activity::Inactive,
+            (Some(_), PlaybackStatus::Playing) => Activity::Playing,
+            (Some(_), PlaybackStatus::Paused) => Activity::Paused,
+            (Some(_), PlaybackStatus::Stopped) => Activity::Stopped,
+        }
+    }
+
+    /// The duration of the current track, if known.
+    pub fn duration_ms(&self) -> Option<u32> {
+        self.track.as_ref().map(|track| track.duration_ms)
+    }
+
+    /// The current position, extrapolated from the last known one while playing.
+    pub fn position_ms(&self) -> u32 {
+        if self.status != PlaybackStatus::Playing {
+            return self.position_ms;
+        }
+        let elapsed = self.position_anchor.elapsed().as_millis();
+        let position = (self.position_ms as u128 + elapsed).min(u32::MAX as u128) as u32;
+        match self.duration_ms() {
+            Some(duration) => position.min(duration),
+            None => position,
+        }
+    }
+
+    /// The chapters of the current episode, if it lists any.
+    pub fn chapters(&self) -> &[Chapter] {
+        self.track.as_ref().map_or(&[], |track| &track.chapters)
+    }
+
+    /// The index of the chapter at the current position.
+    pub fn current_chapter(&self) -> Option<usize> {
+        let position = self.position_ms();
+        self.chapters()
+            .iter()
+            .rposition(|chapter| chapter.start_ms <= position)
+    }
+
+    /// The position to seek to for going to the next or previous chapter.
+    pub(crate) fn chapter_position(&self, forward: bool) -> Option<u32> {
+        let chapters = self.chapters();
+        let current = self.current_chapter();
+        let target = match (current, forward) {
+            (None, true) => 0,
+            (None, false) => return Some(0),
+            (Some(current), true) => current + 1,
+            (Some(current), false) => {
+                let into_chapter = self.position_ms() - chapters[current].start_ms;
+                if into_chapter > CHAPTER_RESTART_MS || current == 0 {
+                    current
+                } else {
+                    current - 1
+                }
+            }
+        };
+        chapters.get(target).map(|chapter| chapter.start_ms)
+    }
+
+    fn set_track(&mut self, track_id: &str) {
+        if self.track_id.as_deref() != Some(track_id) {
+            self.track_id = Some(track_id.to_string());
+            // only known once the track changed event arrives
+            self.track = None;
+        }
+    }
+
+    fn set_position(&mut self, position_ms: u32) {
+        self.position_ms = position_ms;
+        self.position_anchor = Instant::now();
+    }
+}
+
+/// The tracks that could not be played, as reported by `GET /history` and
+/// the control socket, the oldest first.
+#[derive(Debug, Serialize)]
+pub(crate) struct HistoryReport {
+    pub(crate) unavailable_tracks: Vec<UnavailableReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct UnavailableReport {
+    pub(crate) track_id: String,
+    pub(crate) name: Option<String>,
+    /// When the track was unavailable, in seconds since the epoch.
+    pub(crate) time: u64,
+}
+
+impl HistoryReport {
+    pub(crate) fn new(state: &PlaybackState) -> Self {
+        let unavailable_tracks = state
+            .unavailable_tracks
+            .iter()
+            .map(|track| UnavailableReport {
+                track_id: track.track_id.clone(),
+                name: track.name.clone(),
+                time: track
+                    .time
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            })
+            .collect();
+        Self { unavailable_tracks }
+    }
+}
+
+/// A summary of the playback, as reported by the control socket and the HTTP
+/// API.
+#[derive(Debug, Serialize)]
+pub(crate) struct StatusReport {
+    pub(crate) status: &'static str,
+    /// The status, or `inactive` without a session.
+    pub(crate) activity: &'static str,
+    pub(crate) track: Option<TrackInfo>,
+    pub(crate) position_ms: u32,
+    /// The volume, between 0 and 100.
+    pub(crate) volume: Option<u8>,
+    /// The volume in decibels, down to -60 dB, missing when muted.
+    pub(crate) volume_db: Option<f64>,
+    pub(crate) shuffle: bool,
+    pub(crate) repeat: bool,
+    /// The Spotify Connect client in control.
+    pub(crate) controller: Option<String>,
+    /// Whether the "do not disturb" lock is taken.
+    pub(crate) locked: bool,
+    /// The country and the product of the account, once connected.
+    pub(crate) country: Option<String>,
+    pub(crate) product: Option<String>,
+}
+
+impl StatusReport {
+    pub(crate) fn new(state: &PlaybackState, locked: bool) -> Self {
+        Self {
+            status: state.status.as_str(),
+            activity: state.activity().as_str(),
+            track: state.track.clone(),
+            position_ms: state.position_ms(),
+            volume: state.volume.map(volume_percent),
+            volume_db: state
+                .volume
+                .filter(|volume| *volume > 0)
+                .map(|volume| (volume_db(volume) * 10.0).round() / 10.0),
+            shuffle: state.shuffle,
+            repeat: state.repeat,
+            controller: state.controller.clone(),
+            locked,
+            country: state.country.clone(),
+            product: state.product.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_follows_events() {
+        let track_id = "4uLU6hMCjMI75M1A2tKUQC".to_string();
+        let mut state = PlaybackState::default();
+
+        state.update(&SpotifydEvent::Paused {
+            play_request_id: 1,
+            track_id: track_id.clone(),
+            position_ms: 1000,
+        });
+        state.update(&SpotifydEvent::VolumeChanged { volume: 100 });
+        state.update(&SpotifydEvent::ShuffleChanged { shuffle: true });
+
+        assert_eq!(state.status, PlaybackStatus::Paused);
+        assert_eq!(state.track_id.as_ref(), Some(&track_id));
+        assert_eq!(state.position_ms(), 1000);
+        assert_eq!(state.volume, Some(100));
+        assert!(state.shuffle);
+
+        state.update(&SpotifydEvent::Stopped {
+            play_request_id: 1,
+            track_id,
+        });
+        assert_eq!(state.status, PlaybackStatus::Stopped);
+        assert_eq!(state.position_ms(), 0);
+        assert_eq!(state.activity(), Activity::Inactive);
+        state.update(&SpotifydEvent::SessionConnected {
+            connection_id: "connection".to_string(),
+            user_name: "user".to_string(),
+            client_ip: None,
+        });
+        assert_eq!(state.activity(), Activity::Stopped);
+    }
+
+    #[test]
+    fn test_chapter_navigation() {
+        let chapter = |start_ms| Chapter {
+            start_ms,
+            title: String::new(),
+        };
+        let mut state = PlaybackState::default();
+        state.update(&SpotifydEvent::TrackChanged(TrackInfo {
+            track_id: "episode".to_string(),
+            chapters: vec![chapter(0), chapter(60_000), chapter(120_000)],
+            ..Default::default()
+        }));
+        state.update(&SpotifydEvent::Paused {
+            play_request_id: 1,
+            track_id: "episode".to_string(),
+            position_ms: 61_000,
+        });
+
+        assert_eq!(state.current_chapter(), Some(1));
+        assert_eq!(state.chapter_position(true), Some(120_000));
+        assert_eq!(state.chapter_position(false), Some(0));
+
+        state.set_position(90_000);
+        assert_eq!(state.chapter_position(false), Some(60_000));
+        state.set_position(125_000);
+        assert_eq!(state.chapter_position(true), None);
+    }
+
+    #[test]
+    fn test_unavailable_tracks_survive_reset() {
+        let mut state = PlaybackState::default();
+        state.update(&SpotifydEvent::Unavailable {
+            play_request_id: 1,
+            track_id: "4uLU6hMCjMI75M1A2tKUQC".to_string(),
+        });
+        state.update(&SpotifydEvent::VolumeChanged { volume: 100 });
+
+        state.reset();
+        assert_eq!(state.volume, None);
+        assert_eq!(state.unavailable_tracks.len(), 1);
+        assert_eq!(
+            state.unavailable_tracks[0].track_id,
+            "4uLU6hMCjMI75M1A2tKUQC"
+        );
+        let history = HistoryReport::new(&state);
+        assert_eq!(history.unavailable_tracks[0].name, None);
+    }
+}
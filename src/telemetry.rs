@@ -0,0 +1,136 @@
+use crate::events::SpotifydEvent;
+#[cfg(feature = "otlp")]
+use opentelemetry::{
+    global::{self, BoxedSpan},
+    trace::{Span as _, Status, TraceError, Tracer},
+    KeyValue,
+};
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+/// A span of the daemon's work, exported to an OpenTelemetry collector via
+/// OTLP when the `otlp` feature is enabled and an endpoint is configured.
+/// Until the export is set up, it doesn't do anything.
+///
+/// The span ends when it is dropped.
+pub(crate) struct Span {
+    #[cfg(feature = "otlp")]
+    inner: BoxedSpan,
+}
+
+impl Span {
+    pub(crate) fn start(name: &'static str) -> Self {
+        #[cfg(not(feature = "otlp"))]
+        let _ = name;
+        Self {
+            #[cfg(feature = "otlp")]
+            inner: global::tracer("spotifyd").start(name),
+        }
+    }
+
+    pub(crate) fn set_attribute(&mut self, key: &'static str, value: impl ToString) {
+        #[cfg(feature = "otlp")]
+        self.inner
+            .set_attribute(KeyValue::new(key, value.to_string()));
+        #[cfg(not(feature = "otlp"))]
+        let _ = (key, value);
+    }
+
+    pub(crate) fn set_error(&mut self, error: &dyn Display) {
+        #[cfg(feature = "otlp")]
+        self.inner.set_status(Status::error(error.to_string()));
+        #[cfg(not(feature = "otlp"))]
+        let _ = error;
+    }
+}
+
+/// Sets up the export of the spans to the collector at the endpoint, e.g.
+/// `http://localhost:4317`.
+#[cfg(feature = "otlp")]
+pub(crate) fn init(endpoint: &str) -> Result<(), TraceError> {
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace, Resource};
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_resource(Resource::new([KeyValue::new("service.name", "spotifyd")])),
+        )
+        .install_batch(runtime::Tokio)?;
+    Ok(())
+}
+
+/// Exports the remaining spans.
+#[cfg(feature = "otlp")]
+pub(crate) fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Follows the loading of the tracks in the events, with a span until the
+/// track's metadata is loaded and one until its audio starts playing.
+#[derive(Default)]
+pub(crate) struct PlaybackSpans {
+    track_load: Option<Span>,
+    first_audio: Option<Span>,
+    loading_started: Option<Instant>,
+}
+
+impl PlaybackSpans {
+    /// Returns the time from loading the track until its audio started, once
+    /// it has started.
+    pub(crate) fn observe(&mut self, event: &SpotifydEvent) -> Option<Duration> {
+        match event {
+            SpotifydEvent::Loading {
+                play_request_id,
+                track_id,
+                position_ms,
+            } => {
+                let start = |name| {
+                    let mut span = Span::start(name);
+                    span.set_attribute("play_request_id", play_request_id);
+                    span.set_attribute("track_id", track_id);
+                    span.set_attribute("position_ms", position_ms);
+                    span
+                };
+                self.track_load = Some(start("track_load"));
+                self.first_audio = Some(start("first_audio"));
+                self.loading_started = Some(Instant::now());
+            }
+            SpotifydEvent::TrackChanged(_) => self.track_load = None,
+            SpotifydEvent::Playing { .. } => {
+                self.track_load = None;
+                let time_to_audio = self.loading_started.take()?.elapsed();
+                if let Some(mut span) = self.first_audio.take() {
+                    span.set_attribute("time_to_audio_ms", time_to_audio.as_millis());
+                }
+                return Some(time_to_audio);
+            }
+            SpotifydEvent::Unavailable { .. } => {
+                self.loading_started = None;
+                for span in [self.track_load.take(), self.first_audio.take()]
+                    .iter_mut()
+                    .flatten()
+                {
+                    span.set_error(&"the track is unavailable");
+                }
+            }
+            // the track was loaded paused, or replaced before playing
+            SpotifydEvent::Paused { .. } | SpotifydEvent::Stopped { .. } => {
+                self.loading_started = None;
+                if let Some(mut span) = self.first_audio.take() {
+                    span.set_attribute("interrupted", true);
+                }
+            }
+            _ => (),
+        }
+        None
+    }
+}
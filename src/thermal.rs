@@ -0,0 +1,108 @@
+use crate::dsp::DspSwitches;
+use log::{info, warn};
+use std::{
+    fs,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How long the firmware has to report no throttling before the DSP stages
+/// are restored.
+const RESTORE_AFTER: Duration = Duration::from_secs(60);
+
+/// The throttling flags of the Raspberry Pi firmware, on newer kernels.
+const THROTTLED_PATH: &str = "/sys/devices/platform/soc/soc:firmware/get_throttled";
+
+/// The flags of the throttling that is going on right now. The higher bits
+/// tell whether it happened since boot.
+const FLAGS: &[(u32, &str)] = &[
+    (0x1, "under-voltage"),
+    (0x2, "frequency capped"),
+    (0x4, "throttled"),
+    (0x8, "soft temperature limit"),
+];
+
+/// Parses the flags as reported by `get_throttled`, e.g. `50005`, or by
+/// `vcgencmd get_throttled`, e.g. `throttled=0x50005`.
+fn parse_throttled(output: &str) -> Option<u32> {
+    let value = output.trim();
+    let value = value.strip_prefix("throttled=").unwrap_or(value);
+    u32::from_str_radix(value.trim_start_matches("0x"), 16).ok()
+}
+
+/// The current throttling, e.g. `under-voltage, throttled`, if any.
+fn describe(flags: u32) -> Option<String> {
+    let current: Vec<_> = FLAGS
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    (!current.is_empty()).then(|| current.join(", "))
+}
+
+fn read_throttled() -> Option<u32> {
+    if let Ok(content) = fs::read_to_string(THROTTLED_PATH) {
+        return parse_throttled(&content);
+    }
+    let output = Command::new("vcgencmd")
+        .arg("get_throttled")
+        .output()
+        .ok()?;
+    parse_throttled(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Bypasses the heavy stages of the DSP chain in a thread of its own while
+/// the firmware of the Raspberry Pi reports under-voltage or throttling, so
+/// that the audio doesn't crackle when the SoC slows down.
+///
+/// The stages are restored once nothing has been reported for a while.
+pub(crate) fn watch_throttling(switches: DspSwitches) {
+    std::thread::spawn(move || {
+        if read_throttled().is_none() {
+            warn!("The throttling of the SoC can't be read, it's only reported on a Raspberry Pi");
+            return;
+        }
+        let mut last_throttled: Option<Instant> = None;
+        loop {
+            let throttling = read_throttled().and_then(describe);
+            match (throttling, last_throttled) {
+                (Some(throttling), None) => {
+                    warn!(
+                        "The SoC reports {}, bypassing the heavy DSP stages",
+                        throttling
+                    );
+                    switches.set_reduced(true);
+                    last_throttled = Some(Instant::now());
+                }
+                (Some(_), Some(_)) => last_throttled = Some(Instant::now()),
+                (None, Some(since)) if since.elapsed() >= RESTORE_AFTER => {
+                    info!("The SoC isn't throttled anymore, restoring the DSP stages");
+                    switches.set_reduced(false);
+                    last_throttled = None;
+                }
+                (None, _) => (),
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttled() {
+        assert_eq!(parse_throttled("throttled=0x50005\n"), Some(0x50005));
+        assert_eq!(parse_throttled("50005\n"), Some(0x50005));
+        assert_eq!(parse_throttled("0\n"), Some(0));
+        assert_eq!(parse_throttled("error"), None);
+        assert_eq!(
+            describe(0x50005).as_deref(),
+            Some("under-voltage, throttled")
+        );
+        // only happened since boot
+        assert_eq!(describe(0x50000), None);
+    }
+}
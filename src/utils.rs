@@ -77,6 +77,94 @@ pub(crate) fn get_shell() -> Option<String> {
     shell
 }
 
+/// Resolves a user given by name or id to its uid and primary gid.
+#[cfg(unix)]
+pub(crate) fn lookup_user(user: &str) -> Option<(u32, u32)> {
+    use libc::getpwnam_r;
+    use std::{ffi::CString, mem, ptr};
+
+    trace!("Looking up user {:?}", user);
+
+    let name = match user.parse::<u32>() {
+        Ok(uid) => unsafe {
+            // resolve the name to find the primary group of the user
+            let passwd = libc::getpwuid(uid);
+            if passwd.is_null() {
+                return None;
+            }
+            return Some((uid, (*passwd).pw_gid));
+        },
+        Err(_) => CString::new(user).ok()?,
+    };
+
+    let mut result = ptr::null_mut();
+    unsafe {
+        let amt: usize = match libc::sysconf(libc::_SC_GETPW_R_SIZE_MAX) {
+            n if n < 0 => 512,
+            n => n as usize,
+        };
+        let mut buf = Vec::with_capacity(amt);
+        let mut passwd: libc::passwd = mem::zeroed();
+
+        match getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.capacity() as libc::size_t,
+            &mut result,
+        ) {
+            0 if !result.is_null() => Some((passwd.pw_uid, passwd.pw_gid)),
+            _ => None,
+        }
+    }
+}
+
+/// Resolves a group given by name or id to its gid.
+#[cfg(unix)]
+pub(crate) fn lookup_group(group: &str) -> Option<u32> {
+    use libc::getgrnam_r;
+    use std::{ffi::CString, mem, ptr};
+
+    trace!("Looking up group {:?}", group);
+
+    if let Ok(gid) = group.parse::<u32>() {
+        return Some(gid);
+    }
+    let name = CString::new(group).ok()?;
+
+    let mut result = ptr::null_mut();
+    unsafe {
+        let amt: usize = match libc::sysconf(libc::_SC_GETGR_R_SIZE_MAX) {
+            // groups with many members need a bigger buffer than users
+            n if n < 0 => 4096,
+            n => n as usize,
+        };
+        let mut buf = Vec::with_capacity(amt);
+        let mut group: libc::group = mem::zeroed();
+
+        match getgrnam_r(
+            name.as_ptr(),
+            &mut group,
+            buf.as_mut_ptr(),
+            buf.capacity() as libc::size_t,
+            &mut result,
+        ) {
+            0 if !result.is_null() => Some(group.gr_gid),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn lookup_user(_user: &str) -> Option<(u32, u32)> {
+    None
+}
+
+#[cfg(not(unix))]
+pub(crate) fn lookup_group(_group: &str) -> Option<u32> {
+    None
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -95,6 +183,16 @@ mod tests {
         assert_eq!(shell, "fantasy_shell");
     }
 
+    #[test]
+    fn test_lookup_root() {
+        init_logger();
+
+        assert_eq!(lookup_user("root"), Some((0, 0)));
+        assert_eq!(lookup_user("0"), Some((0, 0)));
+        assert_eq!(lookup_group("0"), Some(0));
+        assert_eq!(lookup_user("no-such-user-for-spotifyd"), None);
+    }
+
     #[test]
     fn test_ffi_discovery() {
         init_logger();
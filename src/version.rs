@@ -0,0 +1,87 @@
+use serde::Serialize;
+
+/// The features that can be enabled at build time, with whether they are.
+const FEATURES: &[(&str, bool)] = &[
+    ("alsa_backend", cfg!(feature = "alsa_backend")),
+    ("backend_plugins", cfg!(feature = "backend_plugins")),
+    ("cache_encryption", cfg!(feature = "cache_encryption")),
+    ("camilladsp", cfg!(feature = "camilladsp")),
+    ("dbus_keyring", cfg!(feature = "dbus_keyring")),
+    ("dbus_mpris", cfg!(feature = "dbus_mpris")),
+    ("http_api", cfg!(feature = "http_api")),
+    ("jack_backend", cfg!(feature = "jack_backend")),
+    ("ladspa", cfg!(feature = "ladspa")),
+    ("lastfm", cfg!(feature = "lastfm")),
+    ("listenbrainz", cfg!(feature = "listenbrainz")),
+    ("mqtt", cfg!(feature = "mqtt")),
+    ("network_manager", cfg!(feature = "network_manager")),
+    ("oauth", cfg!(feature = "oauth")),
+    ("otlp", cfg!(feature = "otlp")),
+    ("pipewire_backend", cfg!(feature = "pipewire_backend")),
+    ("portaudio_backend", cfg!(feature = "portaudio_backend")),
+    ("pulseaudio_backend", cfg!(feature = "pulseaudio_backend")),
+    ("rodio_backend", cfg!(feature = "rodio_backend")),
+    ("rodiojack_backend", cfg!(feature = "rodiojack_backend")),
+    ("web_api", cfg!(feature = "web_api")),
+    ("web_ui", cfg!(feature = "web_ui")),
+    ("webhook", cfg!(feature = "webhook")),
+];
+
+/// What spotifyd was built from and with.
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    /// The commit, if it was built from a git checkout.
+    pub git_hash: Option<&'static str>,
+    pub features: Vec<&'static str>,
+    pub librespot_version: &'static str,
+}
+
+impl VersionInfo {
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: option_env!("SPOTIFYD_GIT_HASH"),
+            features: FEATURES
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| *name)
+                .collect(),
+            librespot_version: librespot_core::version::SEMVER,
+        }
+    }
+}
+
+/// Prints the version, e.g. `spotifyd 0.3.5 (1a2b3c4)`, or all of the
+/// version info as JSON.
+pub fn print(json: bool) {
+    let info = VersionInfo::current();
+    if json {
+        println!("{}", serde_json::to_string(&info).unwrap());
+        return;
+    }
+    match info.git_hash {
+        Some(hash) => println!("spotifyd {} ({})", info.version, hash),
+        None => println!("spotifyd {}", info.version),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_info() {
+        let info = serde_json::to_value(VersionInfo::current()).unwrap();
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert!(info["features"].is_array());
+        assert!(info["librespot_version"].is_string());
+        assert_eq!(
+            cfg!(feature = "alsa_backend"),
+            info["features"]
+                .as_array()
+                .unwrap()
+                .contains(&"alsa_backend".into())
+        );
+    }
+}
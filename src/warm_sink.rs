@@ -0,0 +1,74 @@
+use librespot_playback::{
+    audio_backend::{Sink, SinkResult},
+    convert::Converter,
+    decoder::AudioPacket,
+};
+use log::{debug, warn};
+
+/// A sink that is started as soon as it has been created and that isn't
+/// stopped between tracks or while paused, so that the audio device is ready
+/// when the playback starts instead of being opened after the track has been
+/// loaded.
+///
+/// Other programs can't use the audio device meanwhile with backends that
+/// open it exclusively, like ALSA's `hw` devices.
+pub(crate) struct WarmSink {
+    inner: Box<dyn Sink>,
+    running: bool,
+}
+
+impl WarmSink {
+    pub(crate) fn new(mut inner: Box<dyn Sink>) -> Self {
+        let running = match inner.start() {
+            Ok(()) => {
+                debug!("Opened the audio device ahead of the playback");
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to open the audio device ahead of the playback: {}",
+                    e
+                );
+                false
+            }
+        };
+        Self { inner, running }
+    }
+}
+
+impl Sink for WarmSink {
+    fn start(&mut self) -> SinkResult<()> {
+        if !self.running {
+            self.inner.start()?;
+            self.running = true;
+        }
+        Ok(())
+    }
+
+    fn stop(&mut self) -> SinkResult<()> {
+        // the device stays open until the session ends
+        Ok(())
+    }
+
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        self.inner.write(packet, converter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulate::FakeSink;
+
+    #[test]
+    fn test_stays_open_between_tracks() {
+        let fake = FakeSink::default();
+        let mut sink = WarmSink::new(Box::new(fake.clone()));
+        assert!(fake.is_running());
+
+        sink.stop().unwrap();
+        assert!(fake.is_running());
+        sink.start().unwrap();
+        assert!(fake.is_running());
+    }
+}
@@ -0,0 +1,329 @@
+use crate::{
+    config::VolumeStep,
+    control::{volume_from_percent, PlaybackControl},
+};
+use chrono::{prelude::*, Duration};
+use librespot_core::{session::Session, token::Token, Error};
+use log::info;
+use rspotify::{
+    model::{
+        offset::Offset, parse_uri, AlbumId, ArtistId, CurrentPlaybackContext, EpisodeId, IdError,
+        PlayContextId, PlayableId, PlaylistId, RepeatState, ShowId, TrackId, Type,
+    },
+    prelude::*,
+    AuthCodeSpotify, ClientResult, Token as RspotifyToken,
+};
+use std::sync::Arc;
+
+/// The scopes requested for the token of the Web API.
+const SCOPES: &str = "user-read-playback-state,user-modify-playback-state,\
+    user-read-currently-playing,playlist-read-private,user-library-read";
+
+/// A client of Spotify's Web API, authorized with the token of the session.
+///
+/// The client is blocking, so it must only be used in blocking tasks.
+pub(crate) async fn client(session: &Session) -> Result<AuthCodeSpotify, Error> {
+    let token = session.token_provider().get_token(SCOPES).await?;
+    Ok(AuthCodeSpotify::from_token(convert_token(token)))
+}
+
+fn convert_token(token: Token) -> RspotifyToken {
+    let expires_in = Duration::from_std(token.expires_in).unwrap_or_else(|_| Duration::zero());
+    RspotifyToken {
+        access_token: token.access_token,
+        expires_in,
+        expires_at: Some(Utc::now() + expires_in),
+        ..RspotifyToken::default()
+    }
+}
+
+/// Runs the function with a client in a blocking task.
+pub(crate) async fn with_client<T, F>(session: &Session, f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce(AuthCodeSpotify) -> Result<T, Error> + Send + 'static,
+{
+    let client = client(session).await?;
+    tokio::task::spawn_blocking(move || f(client))
+        .await
+        .map_err(Error::internal)?
+}
+
+/// Starts playing the context, e.g. a playlist, on the device with the given name.
+pub(crate) async fn play_context(
+    session: &Session,
+    device_name: &str,
+    uri: &str,
+) -> Result<(), Error> {
+    let uri = spotify_uri(uri);
+    let context = PlayContextId::from_uri(&uri)
+        .map_err(Error::invalid_argument)?
+        .into_static();
+    let device_name = device_name.to_string();
+    with_client(session, move |client| {
+        let device_id = device_id(&client, &device_name)?;
+        client
+            .start_context_playback(context, Some(&device_id), None, None)
+            .map_err(Error::unavailable)
+    })
+    .await
+}
+
+/// Converts a link to the item in the web player, as copied from the apps,
+/// to its URI. Anything else is returned as is.
+///
+/// For example, `https://open.spotify.com/intl-de/album/<id>?si=<tracking>`
+/// becomes `spotify:album:<id>`.
+pub(crate) fn spotify_uri(link: &str) -> String {
+    let Some(path) = link
+        .strip_prefix("https://open.spotify.com/")
+        .or_else(|| link.strip_prefix("http://open.spotify.com/"))
+    else {
+        return link.to_string();
+    };
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    let mut segments: Vec<_> = path.split('/').filter(|s| !s.is_empty()).collect();
+    // localized and embedded links have a prefix
+    if segments
+        .first()
+        .map_or(false, |s| s.starts_with("intl-") || *s == "embed")
+    {
+        segments.remove(0);
+    }
+    if segments.len() < 2 {
+        return link.to_string();
+    }
+    format!("spotify:{}", segments.join(":"))
+}
+
+/// What the item of a URI is played as.
+pub(crate) enum SpotifyUri {
+    /// A track or an episode.
+    Playable(PlayableId<'static>),
+    /// A context of tracks, e.g. an album.
+    Context(PlayContextId<'static>),
+}
+
+impl SpotifyUri {
+    /// Parses the URI, or the link to the item in the web player.
+    pub(crate) fn parse(uri: &str) -> Result<Self, IdError> {
+        use SpotifyUri::*;
+        let uri = spotify_uri(uri);
+        Ok(match parse_uri(&uri)? {
+            (Type::Track, id) => Playable(TrackId::from_id(id)?.into_static().into()),
+            (Type::Episode, id) => Playable(EpisodeId::from_id(id)?.into_static().into()),
+            (Type::Artist, id) => Context(ArtistId::from_id(id)?.into_static().into()),
+            (Type::Album, id) => Context(AlbumId::from_id(id)?.into_static().into()),
+            (Type::Playlist, id) => Context(PlaylistId::from_id(id)?.into_static().into()),
+            (Type::Show, id) => Context(ShowId::from_id(id)?.into_static().into()),
+            (Type::User | Type::Collection | Type::Collectionyourepisodes, _) => {
+                Err(IdError::InvalidType)?
+            }
+        })
+    }
+}
+
+/// Starts playing the item of the URI from its start on the device.
+pub(crate) fn open_uri(
+    client: &AuthCodeSpotify,
+    device_id: &str,
+    uri: SpotifyUri,
+) -> ClientResult<()> {
+    let offset = Some(Offset::Position(Duration::zero()));
+    match uri {
+        SpotifyUri::Playable(id) => {
+            client.start_uris_playback(Some(id), Some(device_id), offset, None)
+        }
+        SpotifyUri::Context(id) => client.start_context_playback(id, Some(device_id), offset, None),
+    }
+}
+
+/// Looks up the Web API id of the device with the given name.
+pub(crate) fn device_id(client: &AuthCodeSpotify, device_name: &str) -> Result<String, Error> {
+    let devices = client.device().map_err(Error::unavailable)?;
+    devices
+        .into_iter()
+        .find(|d| d.name == device_name)
+        .and_then(|d| {
+            info!("Found device: {}, active: {}", d.name, d.is_active);
+            d.id
+        })
+        .ok_or_else(|| Error::not_found(format!("could not find device {:?}", device_name)))
+}
+
+/// Moves the playback to the device with the given name, keeping it paused
+/// or playing.
+pub(crate) fn transfer(client: &AuthCodeSpotify, device_name: &str) -> Result<(), Error> {
+    let device_id = device_id(client, device_name)?;
+    client
+        .transfer_playback(&device_id, None)
+        .map_err(Error::unavailable)
+}
+
+/// Replaces the token of the client with the session's current one, which
+/// librespot renews before it expires.
+pub(crate) async fn refresh_token(
+    session: &Session,
+    client: &AuthCodeSpotify,
+) -> Result<(), Error> {
+    let token = session.token_provider().get_token(SCOPES).await?;
+    *client.get_token().lock().unwrap() = Some(convert_token(token));
+    Ok(())
+}
+
+/// How much the volume changes with [`PlaybackControl::volume_up`] and
+/// [`PlaybackControl::volume_down`] when no step is configured.
+const DEFAULT_VOLUME_STEP: VolumeStep = VolumeStep::Percent(10.0);
+
+/// Controls the playback of another Spotify Connect device through the Web
+/// API, or of the account's active device if no device is given.
+///
+/// The calls are blocking.
+pub(crate) struct RemoteControl {
+    client: Arc<AuthCodeSpotify>,
+    device_id: Option<String>,
+    volume_step: VolumeStep,
+}
+
+impl RemoteControl {
+    pub(crate) fn new(
+        client: Arc<AuthCodeSpotify>,
+        device_id: Option<String>,
+        volume_step: Option<VolumeStep>,
+    ) -> Self {
+        Self {
+            client,
+            device_id,
+            volume_step: volume_step.unwrap_or(DEFAULT_VOLUME_STEP),
+        }
+    }
+
+    fn device_id(&self) -> Option<&str> {
+        self.device_id.as_deref()
+    }
+
+    fn playback(&self) -> Result<CurrentPlaybackContext, Error> {
+        self.client
+            .current_playback(None, None::<Vec<_>>)
+            .map_err(Error::unavailable)?
+            .ok_or_else(|| Error::failed_precondition("nothing is playing"))
+    }
+
+    fn change_volume(&self, up: bool) -> Result<(), Error> {
+        let percent = self.playback()?.device.volume_percent.unwrap_or(0).min(100) as u8;
+        let volume = self.volume_step.apply(volume_from_percent(percent), up);
+        let mut changed = (volume as f64 * 100.0 / 0xFFFF as f64).round() as u8;
+        // the Web API only takes percents, a finer step still moves by one
+        if changed == percent {
+            changed = if up {
+                percent.saturating_add(1).min(100)
+            } else {
+                percent.saturating_sub(1)
+            };
+        }
+        self.client
+            .volume(changed, self.device_id())
+            .map_err(Error::unavailable)
+    }
+}
+
+impl PlaybackControl for RemoteControl {
+    fn play(&self) -> Result<(), Error> {
+        self.client
+            .resume_playback(self.device_id(), None)
+            .map_err(Error::unavailable)
+    }
+
+    fn pause(&self) -> Result<(), Error> {
+        self.client
+            .pause_playback(self.device_id())
+            .map_err(Error::unavailable)
+    }
+
+    fn play_pause(&self) -> Result<(), Error> {
+        if self.playback()?.is_playing {
+            self.pause()
+        } else {
+            self.play()
+        }
+    }
+
+    fn next(&self) -> Result<(), Error> {
+        self.client
+            .next_track(self.device_id())
+            .map_err(Error::unavailable)
+    }
+
+    fn prev(&self) -> Result<(), Error> {
+        self.client
+            .previous_track(self.device_id())
+            .map_err(Error::unavailable)
+    }
+
+    fn volume_up(&self) -> Result<(), Error> {
+        self.change_volume(true)
+    }
+
+    fn volume_down(&self) -> Result<(), Error> {
+        self.change_volume(false)
+    }
+
+    fn set_volume(&self, volume: u16) -> Result<(), Error> {
+        let percent = (volume as u32 * 100 / 0xFFFF) as u8;
+        self.client
+            .volume(percent, self.device_id())
+            .map_err(Error::unavailable)
+    }
+
+    fn shuffle(&self, shuffle: bool) -> Result<(), Error> {
+        self.client
+            .shuffle(shuffle, self.device_id())
+            .map_err(Error::unavailable)
+    }
+
+    fn repeat(&self, repeat: bool) -> Result<(), Error> {
+        let state = if repeat {
+            RepeatState::Context
+        } else {
+            RepeatState::Off
+        };
+        self.client
+            .repeat(state, self.device_id())
+            .map_err(Error::unavailable)
+    }
+
+    fn seek(&self, position_ms: u32) -> Result<(), Error> {
+        self.client
+            .seek_track(Duration::milliseconds(position_ms as i64), self.device_id())
+            .map_err(Error::unavailable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spotify_uri() {
+        assert_eq!(
+            spotify_uri("https://open.spotify.com/album/4aawyAB9vmqN3uQ7FjRGTy?si=abc"),
+            "spotify:album:4aawyAB9vmqN3uQ7FjRGTy"
+        );
+        assert_eq!(
+            spotify_uri("https://open.spotify.com/intl-de/track/6rqhFgbbKwnb9MLmUQDhG6"),
+            "spotify:track:6rqhFgbbKwnb9MLmUQDhG6"
+        );
+        assert_eq!(
+            spotify_uri("spotify:playlist:37i9dQZF1DXcBWIGoYBM5M"),
+            "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M"
+        );
+        assert_eq!(
+            spotify_uri("https://open.spotify.com/"),
+            "https://open.spotify.com/"
+        );
+        assert!(matches!(
+            SpotifyUri::parse("https://open.spotify.com/episode/512ojhOuo1ktJprKbVcKyQ"),
+            Ok(SpotifyUri::Playable(_))
+        ));
+    }
+}
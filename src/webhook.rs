@@ -0,0 +1,90 @@
+use crate::events::EventSubscriber;
+use log::{debug, warn};
+use std::time::Duration;
+use url::Url;
+
+/// How long a single delivery may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the delivery of an event is attempted before it's dropped.
+const MAX_ATTEMPTS: u32 = 5;
+/// The delay before the first retry, doubled for each following one.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The delay before retrying after the given attempt failed, counting from 1.
+fn backoff(attempt: u32) -> Duration {
+    INITIAL_BACKOFF * 2u32.pow(attempt.saturating_sub(1))
+}
+
+/// Whether a delivery that failed with the response status may succeed
+/// later. Other client errors won't go away by retrying.
+fn is_transient(status: u16) -> bool {
+    status == 408 || status == 429 || status >= 500
+}
+
+/// POSTs the event to the URL, retrying transient failures with an
+/// exponential backoff.
+async fn deliver(agent: &ureq::Agent, url: &Url, name: &str, body: String) {
+    let mut attempt = 1;
+    loop {
+        let request = agent
+            .post(url.as_str())
+            .set("Content-Type", "application/json");
+        let result = {
+            let body = body.clone();
+            tokio::task::spawn_blocking(move || request.send_string(&body))
+                .await
+                .unwrap()
+        };
+        let error = match result {
+            Ok(_) => return,
+            Err(ureq::Error::Status(status, _)) if !is_transient(status) => {
+                warn!("The webhook rejected the {} event with {}", name, status);
+                return;
+            }
+            Err(e) => e,
+        };
+        if attempt == MAX_ATTEMPTS {
+            warn!(
+                "Dropped the {} event after {} failed deliveries to the webhook: {}",
+                name, attempt, error
+            );
+            return;
+        }
+        let delay = backoff(attempt);
+        debug!(
+            "Failed to deliver the {} event to the webhook, retrying in {:?}: {}",
+            name, delay, error
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// POSTs every event received by the subscriber as JSON, in the format of the
+/// event log, to the URL.
+///
+/// The events are delivered one after the other, in order, so while one is
+/// retried, the following ones wait for it.
+pub(crate) async fn deliver_events(url: Url, mut events: EventSubscriber) {
+    let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+    while let Some(event) = events.recv().await {
+        let body = serde_json::to_string(&event).unwrap();
+        deliver(&agent, &url, event.name(), body).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retries() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(4), Duration::from_secs(8));
+
+        assert!(is_transient(503));
+        assert!(is_transient(429));
+        assert!(!is_transient(404));
+        assert!(!is_transient(401));
+    }
+}
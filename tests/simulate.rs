@@ -0,0 +1,105 @@
+use librespot_playback::{audio_backend::Sink, convert::Converter, decoder::AudioPacket};
+use spotifyd::{
+    config::{get_internal_config, CliConfig},
+    events::{EventBus, SpotifydEvent},
+    setup,
+    simulate::{simulate_session, test_track, FakeSink, MockPlayer},
+    state::{PlaybackStatus, SharedPlaybackState},
+};
+use std::{fs, time::Duration};
+use structopt::StructOpt;
+
+#[tokio::test]
+async fn test_subscribers_receive_simulated_session() {
+    let event_bus = EventBus::default();
+    let playback_state = SharedPlaybackState::default();
+    let mut events = event_bus.subscribe();
+
+    let mut player = MockPlayer::new(event_bus, playback_state.clone());
+    simulate_session(&mut player);
+    drop(player);
+
+    let mut names = Vec::new();
+    while let Some(event) = events.recv().await {
+        names.push(event.name());
+    }
+    assert_eq!(names.first(), Some(&"session_connected"));
+    assert!(names.contains(&"track_changed"));
+    assert_eq!(names.last(), Some(&"stop"));
+
+    let state = playback_state.read().unwrap();
+    assert_eq!(state.status, PlaybackStatus::Stopped);
+    assert_eq!(state.track, Some(test_track()));
+    assert_eq!(state.volume, Some(32768));
+}
+
+#[tokio::test]
+async fn test_late_subscriber_catches_up() {
+    let event_bus = EventBus::default();
+    let mut player = MockPlayer::new(event_bus.clone(), Default::default());
+    player.play_track(test_track());
+
+    let mut events = event_bus.subscribe();
+    let mut replayed = Vec::new();
+    drop(player);
+    drop(event_bus);
+    while let Some(event) = events.recv().await {
+        replayed.push(event);
+    }
+
+    assert!(replayed.contains(&SpotifydEvent::TrackChanged(test_track())));
+    assert!(replayed
+        .iter()
+        .any(|event| matches!(event, SpotifydEvent::Playing { .. })));
+}
+
+#[tokio::test]
+async fn test_main_loop_runs_the_simulated_session() {
+    let dir = std::env::temp_dir().join(format!("spotifyd-simulate-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let hook_output = dir.join("events");
+    let hook = format!("echo $PLAYER_EVENT >> '{}'", hook_output.display());
+    let cli_config = CliConfig::from_iter(["spotifyd", "--simulate", "--onevent", &hook]);
+    let mut main_loop = setup::initial_state(get_internal_config(cli_config));
+
+    let sink = FakeSink::default();
+    main_loop.simulate(Box::new(sink.clone()), simulate_session);
+    assert!(sink.samples_written() > 0);
+    assert!(!sink.is_running());
+    let status = main_loop.playback_state().read().unwrap().status;
+    assert_eq!(status, PlaybackStatus::Stopped);
+
+    // the hooks are run one at a time, after the session
+    let mut events = String::new();
+    for _ in 0..100 {
+        events = fs::read_to_string(&hook_output).unwrap_or_default();
+        if events.lines().last() == Some("stop") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    fs::remove_dir_all(&dir).unwrap();
+    let events: Vec<_> = events.lines().collect();
+    assert_eq!(events.first(), Some(&"session_connected"));
+    assert!(events.contains(&"track_changed"));
+    assert_eq!(events.last(), Some(&"stop"));
+}
+
+#[test]
+fn test_fake_sink_counts_samples() {
+    let sink = FakeSink::default();
+    let mut player_sink = sink.clone();
+
+    player_sink.start().unwrap();
+    assert!(sink.is_running());
+    player_sink
+        .write(
+            AudioPacket::Samples(vec![0.0; 64]),
+            &mut Converter::new(None),
+        )
+        .unwrap();
+    player_sink.stop().unwrap();
+
+    assert!(!sink.is_running());
+    assert_eq!(sink.samples_written(), 64);
+}